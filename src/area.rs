@@ -0,0 +1,224 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::error::Error;
+use std::fmt::Display;
+
+use json::JsonValue;
+
+use crate::error::InvalidGeometryError;
+use crate::geometry::{Geometry, Point, Polygon};
+use crate::wkt;
+
+
+/// Formaat waarin een interessegebied opgegeven is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AreaFormat {
+  Wkt,
+  Ewkt,
+  GeoJson,
+  BoundingBox,
+}
+
+impl Display for AreaFormat {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.write_str(match self {
+      AreaFormat::Wkt => "WKT",
+      AreaFormat::Ewkt => "EWKT",
+      AreaFormat::GeoJson => "GeoJSON",
+      AreaFormat::BoundingBox => "bounding box",
+    })
+  }
+}
+
+/// Een gelezen interessegebied, nog niet gevalideerd.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AreaInput {
+  pub geometry: Geometry,
+  /// EPSG code uit de invoer zelf (EWKT `SRID=` of GeoJSON `crs`), indien aanwezig. GeoJSON zonder `crs` is volgens
+  /// RFC 7946 WGS84; dat wordt hier niet ingevuld, zodat `--input-crs` er niet mee in tegenspraak is.
+  pub srid: Option<u32>,
+  pub format: AreaFormat,
+}
+
+/// Leest een interessegebied en herkent zelf het formaat:
+///
+/// * GeoJSON `Polygon`, `MultiPolygon`, `Feature` of `FeatureCollection`
+/// * EWKT, bijv. `SRID=28992;POLYGON(...)`
+/// * een bounding box `minx,miny,maxx,maxy`
+/// * WKT `POLYGON` of `MULTIPOLYGON`
+pub fn parse(text: &str) -> Result<AreaInput, Box<dyn Error + Send + Sync>> {
+  let trimmed = text.trim();
+  if trimmed.starts_with('{') {
+    let (geometry, srid) = parse_geojson(trimmed)?;
+    return Ok(AreaInput { geometry, srid, format: AreaFormat::GeoJson });
+  }
+  if trimmed.get(..5).is_some_and(|prefix| prefix.eq_ignore_ascii_case("SRID=")) {
+    let (srid, rest) = match trimmed[5..].find(';') {
+      Some(i) => (&trimmed[5..5 + i], &trimmed[5 + i + 1..]),
+      None => return Err(Box::new(InvalidGeometryError::new("EWKT mist een ';' na SRID=<code>"))),
+    };
+    let srid: u32 = srid.trim().parse()
+      .map_err(|_| InvalidGeometryError::new(format!("'{}' is geen geldige SRID", srid.trim())))?;
+    return Ok(AreaInput { geometry: wkt::parse(rest)?, srid: Some(srid), format: AreaFormat::Ewkt });
+  }
+  if let Some(geometry) = parse_bbox(trimmed)? {
+    return Ok(AreaInput { geometry, srid: None, format: AreaFormat::BoundingBox });
+  }
+  Ok(AreaInput { geometry: wkt::parse(trimmed)?, srid: None, format: AreaFormat::Wkt })
+}
+
+/// Leest `minx,miny,maxx,maxy`. Geeft `None` als de tekst er niet als bounding box uitziet.
+fn parse_bbox(text: &str) -> Result<Option<Geometry>, InvalidGeometryError> {
+  let parts: Vec<&str> = text.split(',').map(str::trim).collect();
+  if parts.len() != 4 {
+    return Ok(None);
+  }
+  let mut values = [0f64; 4];
+  for (value, part) in values.iter_mut().zip(&parts) {
+    *value = match part.parse() {
+      Ok(v) => v,
+      Err(_) => return Ok(None),
+    };
+  }
+  let [minx, miny, maxx, maxy] = values;
+  if minx >= maxx || miny >= maxy {
+    return Err(InvalidGeometryError::new(format!(
+      "bounding box moet minx,miny,maxx,maxy zijn met minx < maxx en miny < maxy, maar is {},{},{},{}", minx, miny, maxx, maxy)));
+  }
+  let exterior = vec![
+    Point::new(minx, miny),
+    Point::new(maxx, miny),
+    Point::new(maxx, maxy),
+    Point::new(minx, maxy),
+    Point::new(minx, miny),
+  ];
+  Ok(Some(Geometry::Polygon(Polygon::new(exterior, Vec::new()))))
+}
+
+fn parse_geojson(text: &str) -> Result<(Geometry, Option<u32>), Box<dyn Error + Send + Sync>> {
+  let value = json::parse(text)?;
+  let srid = geojson_srid(&value)?;
+  let mut polygons = Vec::new();
+  collect_geojson_polygons(&value, &mut polygons)?;
+  // RFC 7946 schrijft voor dat een verkeerde ringrichting geen reden is om GeoJSON te weigeren,
+  // en veel programma's (QGIS via shapefiles) schrijven de buitenring met de klok mee.
+  for polygon in &mut polygons {
+    polygon.orient();
+  }
+  let geometry = match polygons.len() {
+    0 => return Err(Box::new(InvalidGeometryError::new("GeoJSON bevat geen (multi)polygon"))),
+    1 => Geometry::Polygon(polygons.remove(0)),
+    _ => Geometry::MultiPolygon(polygons),
+  };
+  Ok((geometry, srid))
+}
+
+/// EPSG code uit het (pre-RFC 7946) `crs` lid, bijv. `urn:ogc:def:crs:EPSG::28992` of `EPSG:28992`.
+fn geojson_srid(value: &JsonValue) -> Result<Option<u32>, InvalidGeometryError> {
+  let name = match value["crs"]["properties"]["name"].as_str() {
+    Some(name) => name,
+    None => return Ok(None),
+  };
+  if name.ends_with("CRS84") {
+    return Ok(Some(4326));
+  }
+  match name.rsplit(':').next().and_then(|code| code.parse().ok()) {
+    Some(code) => Ok(Some(code)),
+    None => Err(InvalidGeometryError::new(format!("onbekend GeoJSON crs '{}'", name))),
+  }
+}
+
+fn collect_geojson_polygons(value: &JsonValue, polygons: &mut Vec<Polygon>) -> Result<(), InvalidGeometryError> {
+  match value["type"].as_str() {
+    Some("FeatureCollection") => {
+      for feature in value["features"].members() {
+        collect_geojson_polygons(feature, polygons)?;
+      }
+    },
+    Some("Feature") => {
+      if !value["geometry"].is_null() {
+        collect_geojson_polygons(&value["geometry"], polygons)?;
+      }
+    },
+    Some("GeometryCollection") => {
+      for geometry in value["geometries"].members() {
+        collect_geojson_polygons(geometry, polygons)?;
+      }
+    },
+    Some("Polygon") => polygons.push(geojson_polygon(&value["coordinates"])?),
+    Some("MultiPolygon") => {
+      for polygon in value["coordinates"].members() {
+        polygons.push(geojson_polygon(polygon)?);
+      }
+    },
+    Some(other) => return Err(InvalidGeometryError::new(format!("GeoJSON type '{}' kan geen interessegebied zijn", other))),
+    None => return Err(InvalidGeometryError::new("GeoJSON object zonder \"type\"")),
+  }
+  Ok(())
+}
+
+fn geojson_polygon(coordinates: &JsonValue) -> Result<Polygon, InvalidGeometryError> {
+  let mut rings = Vec::new();
+  for ring in coordinates.members() {
+    let mut points = Vec::new();
+    for position in ring.members() {
+      match (position[0].as_f64(), position[1].as_f64()) {
+        (Some(x), Some(y)) => points.push(Point::new(x, y)),
+        _ => return Err(InvalidGeometryError::new(format!("ongeldige GeoJSON positie {}", position.dump()))),
+      }
+    }
+    rings.push(points);
+  }
+  if rings.is_empty() {
+    return Err(InvalidGeometryError::new("GeoJSON polygon zonder ringen"));
+  }
+  let exterior = rings.remove(0);
+  Ok(Polygon::new(exterior, rings))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SQUARE: &str = "POLYGON((155000 463000,155010 463000,155010 463010,155000 463010,155000 463000))";
+
+  fn square() -> Geometry {
+    wkt::parse(SQUARE).unwrap()
+  }
+
+  #[test]
+  fn recognizes_each_format() {
+    assert_eq!(parse(SQUARE).unwrap(), AreaInput { geometry: square(), srid: None, format: AreaFormat::Wkt });
+    assert_eq!(parse(&format!(" srid=28992; {}", SQUARE)).unwrap(),
+      AreaInput { geometry: square(), srid: Some(28992), format: AreaFormat::Ewkt });
+    assert_eq!(parse("155000, 463000, 155010, 463010").unwrap(),
+      AreaInput { geometry: square(), srid: None, format: AreaFormat::BoundingBox });
+    let geojson = r#"{"type":"Feature","properties":{},"crs":{"type":"name","properties":{"name":"urn:ogc:def:crs:EPSG::28992"}},
+      "geometry":{"type":"Polygon","coordinates":[[[155000,463000],[155010,463000],[155010,463010],[155000,463010],[155000,463000]]]}}"#;
+    assert_eq!(parse(geojson).unwrap(), AreaInput { geometry: square(), srid: Some(28992), format: AreaFormat::GeoJson });
+  }
+
+  #[test]
+  fn orients_geojson_rings() {
+    // Buitenring met de klok mee, zoals QGIS hem vaak schrijft.
+    let geojson = r#"{"type":"Polygon","coordinates":[[[155000,463000],[155000,463010],[155010,463010],[155010,463000],[155000,463000]]]}"#;
+    let area = parse(geojson).unwrap();
+    area.geometry.validate().unwrap();
+  }
+
+  #[test]
+  fn rejects_invalid_input_without_panicking() {
+    // Niet-ASCII tekens rond de vijfde byte, waar `SRID=` gezocht wordt.
+    for text in ["abcdé", "SRIDé", "é", "srid=é;POLYGON", "日本語のテキスト"] {
+      assert!(parse(text).is_err(), "{}", text);
+    }
+    assert!(parse(&format!("SRID=28992{}", SQUARE)).unwrap_err().to_string().contains("';'"));
+    assert!(parse(&format!("SRID=rd;{}", SQUARE)).unwrap_err().to_string().contains("'rd' is geen geldige SRID"));
+    assert!(parse("155010,463000,155000,463010").unwrap_err().to_string().contains("minx < maxx"));
+    assert!(parse(r#"{"type":"Point","coordinates":[155000,463000]}"#).unwrap_err().to_string().contains("'Point'"));
+    assert!(parse(r#"{"type":"FeatureCollection","features":[]}"#).unwrap_err().to_string().contains("geen (multi)polygon"));
+  }
+}
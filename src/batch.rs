@@ -0,0 +1,143 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::collections::HashSet;
+
+use json::JsonValue;
+
+use crate::area::{self, AreaInput};
+use crate::error::BatchInputError;
+
+
+/// Een gebied uit de invoer van `batch`, met de naam waarnaar de uitvoer genoemd wordt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchArea {
+  pub name: String,
+  pub area: AreaInput,
+}
+
+/// Leest de gebieden van een batch. Het formaat wordt zelf herkend:
+///
+/// * een GeoJSON `FeatureCollection`; de naam komt uit `properties.name`, `properties.naam` of de `id` van elke feature.
+///   Een `crs` van de FeatureCollection geldt voor alle features; zonder `crs` is het WGS84 (RFC 7946).
+/// * CSV met per regel een naam en een WKT, EWKT of bounding box, bijv. `noord,"POLYGON((...))"`. Aanhalingstekens om
+///   de geometrie zijn niet verplicht, want alles na de eerste komma hoort erbij. Een kopregel `name,wkt` of
+///   `naam,...`, lege regels en regels die met `#` beginnen worden overgeslagen.
+///
+/// Elk gebied moet een naam hebben die, na `file_name`, uniek is.
+pub fn read_areas(text: &str) -> Result<Vec<BatchArea>, BatchInputError> {
+  let areas = if text.trim_start().starts_with('{') { read_geojson(text)? } else { read_csv(text)? };
+  if areas.is_empty() {
+    return Err(BatchInputError::new("invoer", "geen gebieden gevonden"));
+  }
+  let mut names = HashSet::new();
+  for area in &areas {
+    let name = file_name(&area.name);
+    if name.is_empty() {
+      return Err(BatchInputError::new(format!("gebied '{}'", area.name), "naam is niet bruikbaar als bestandsnaam"));
+    }
+    if !names.insert(name.to_lowercase()) {
+      return Err(BatchInputError::new(format!("gebied '{}'", area.name), format!("meerdere gebieden zouden naar {}.zip geschreven worden", name)));
+    }
+  }
+  Ok(areas)
+}
+
+/// Bestandsnaam (zonder extensie) voor de uitvoer van een gebied: letters, cijfers, `-`, `_` en `.`, en verder `_`.
+pub fn file_name(name: &str) -> String {
+  let name: String = name.trim().chars()
+    .map(|c| if c.is_alphanumeric() || c == '-' || c == '_' || c == '.' { c } else { '_' })
+    .collect();
+  // Geen verborgen bestanden, en geen `.` of `..`.
+  String::from(name.trim_start_matches('.'))
+}
+
+fn read_geojson(text: &str) -> Result<Vec<BatchArea>, BatchInputError> {
+  let collection = json::parse(text).map_err(|e| BatchInputError::new("GeoJSON", e.to_string()))?;
+  if collection["type"] != "FeatureCollection" {
+    return Err(BatchInputError::new("GeoJSON", "verwacht een FeatureCollection met een feature per gebied"));
+  }
+  let mut areas = Vec::new();
+  for (i, feature) in collection["features"].members().enumerate() {
+    let name = feature_name(feature)
+      .ok_or_else(|| BatchInputError::new(format!("feature {}", i + 1), "geen naam in properties.name, properties.naam of id"))?;
+    let mut feature = feature.clone();
+    if feature["crs"].is_null() && !collection["crs"].is_null() {
+      feature["crs"] = collection["crs"].clone();
+    }
+    let area = area::parse(&feature.dump()).map_err(|e| BatchInputError::new(format!("gebied '{}'", name), e.to_string()))?;
+    areas.push(BatchArea { name, area });
+  }
+  Ok(areas)
+}
+
+fn feature_name(feature: &JsonValue) -> Option<String> {
+  [&feature["properties"]["name"], &feature["properties"]["naam"], &feature["id"]].iter()
+    .find_map(|value| match value {
+      JsonValue::String(_) | JsonValue::Short(_) => value.as_str().map(String::from),
+      JsonValue::Number(_) => Some(value.dump()),
+      _ => None,
+    })
+    .filter(|name| !name.trim().is_empty())
+}
+
+fn read_csv(text: &str) -> Result<Vec<BatchArea>, BatchInputError> {
+  let mut areas = Vec::new();
+  for (i, line) in text.lines().enumerate() {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with('#') {
+      continue;
+    }
+    let location = || format!("regel {}", i + 1);
+    let (name, geometry) = split_csv_line(line).ok_or_else(|| BatchInputError::new(location(), "verwacht naam,WKT"))?;
+    if areas.is_empty() && (name.eq_ignore_ascii_case("name") || name.eq_ignore_ascii_case("naam")) {
+      continue;
+    }
+    if name.is_empty() {
+      return Err(BatchInputError::new(location(), "naam ontbreekt"));
+    }
+    let area = area::parse(&geometry).map_err(|e| BatchInputError::new(format!("{}, gebied '{}'", location(), name), e.to_string()))?;
+    areas.push(BatchArea { name, area });
+  }
+  Ok(areas)
+}
+
+/// Splitst een CSV-regel in de naam en de rest. Beide mogen tussen dubbele aanhalingstekens staan, met `""` voor een
+/// aanhalingsteken erin.
+fn split_csv_line(line: &str) -> Option<(String, String)> {
+  let (name, rest) = match line.strip_prefix('"') {
+    Some(quoted) => {
+      let (name, rest) = unquote(quoted)?;
+      (name, rest.trim_start().strip_prefix(',')?)
+    },
+    None => {
+      let comma = line.find(',')?;
+      (String::from(line[..comma].trim()), &line[comma + 1..])
+    },
+  };
+  let rest = rest.trim();
+  let geometry = match rest.strip_prefix('"') {
+    Some(quoted) => unquote(quoted)?.0,
+    None => String::from(rest),
+  };
+  Some((name, geometry))
+}
+
+/// Leest een veld tot het afsluitende aanhalingsteken, en geeft het veld en wat erna komt terug.
+fn unquote(text: &str) -> Option<(String, &str)> {
+  let mut value = String::new();
+  let mut chars = text.char_indices().peekable();
+  while let Some((i, c)) = chars.next() {
+    if c != '"' {
+      value.push(c);
+    } else if chars.peek().map(|(_, next)| *next) == Some('"') {
+      value.push('"');
+      chars.next();
+    } else {
+      return Some((value, &text[i + 1..]));
+    }
+  }
+  None
+}
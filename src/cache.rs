@@ -0,0 +1,258 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use json::object;
+
+use crate::checksum::Checksum;
+use crate::client::part_path;
+use crate::geometry::{Geometry, Point, Polygon};
+use crate::wkt;
+
+
+/// Hoe lang een ZIP-bestand in de cache standaard bruikbaar is. De DKK wordt dagelijks bijgewerkt.
+pub const DEFAULT_TTL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Sleutel van een ZIP-bestand in de cache: de URL van de API (met versie), het genormaliseerde geofilter en de
+/// gesorteerde feature types. Een andere `--base-url` of `--api-version` levert dus nooit een ZIP-bestand van een andere
+/// API op.
+///
+/// Het geofilter wordt genormaliseerd zodat hetzelfde gebied dezelfde sleutel krijgt, ook als het in een andere
+/// schrijfwijze, ringrichting of met een ander beginpunt van de ringen opgegeven is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey {
+  pub api_url: String,
+  pub geofilter: String,
+  pub feature_types: Vec<String>,
+}
+
+impl CacheKey {
+  /// `api_url` is `DkkClient::api_url`.
+  pub fn new<S: AsRef<str>>(api_url: &str, geometry: &Geometry, feature_types: &[S]) -> Self {
+    let mut polygons: Vec<String> = geometry.polygons().iter()
+      .map(|polygon| wkt::to_wkt(&Geometry::Polygon(normalize_polygon(polygon))))
+      .collect();
+    polygons.sort();
+    let geofilter = match polygons.len() {
+      1 => polygons.remove(0),
+      _ => format!("MULTIPOLYGON({})", polygons.iter().map(|polygon| &polygon["POLYGON".len()..]).collect::<Vec<_>>().join(",")),
+    };
+    let mut feature_types: Vec<String> = feature_types.iter().map(|name| name.as_ref().to_lowercase()).collect();
+    feature_types.sort();
+    feature_types.dedup();
+    Self { api_url: String::from(api_url), geofilter, feature_types }
+  }
+
+  /// SHA-256 van de sleutel, als bestandsnaam in de cache.
+  pub fn hash(&self) -> String {
+    Checksum::of_bytes(format!("{}\n{}\n{}", self.api_url, self.geofilter, self.feature_types.join(",")).as_bytes()).sha256
+  }
+}
+
+/// Zelfde richting als PDOK verwacht (buitenring tegen de klok in), en elke ring beginnend bij zijn kleinste punt.
+fn normalize_polygon(polygon: &Polygon) -> Polygon {
+  let mut polygon = polygon.clone();
+  polygon.orient();
+  let mut interiors: Vec<Vec<Point>> = polygon.interiors.iter().map(|ring| normalize_ring(ring)).collect();
+  interiors.sort_by(|a, b| compare_points(&a[0], &b[0]));
+  Polygon::new(normalize_ring(&polygon.exterior), interiors)
+}
+
+fn normalize_ring(ring: &[Point]) -> Vec<Point> {
+  let open = match ring.split_last() {
+    Some((last, rest)) if !rest.is_empty() && *last == rest[0] => rest,
+    _ => ring,
+  };
+  let start = (0..open.len()).min_by(|a, b| compare_points(&open[*a], &open[*b])).unwrap_or(0);
+  let mut normalized: Vec<Point> = open[start..].iter().chain(&open[..start]).copied().collect();
+  if let Some(first) = normalized.first().copied() {
+    normalized.push(first);
+  }
+  normalized
+}
+
+fn compare_points(a: &Point, b: &Point) -> std::cmp::Ordering {
+  a.x.total_cmp(&b.x).then(a.y.total_cmp(&b.y))
+}
+
+/// Een ZIP-bestand in de cache.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CacheEntry {
+  pub key: CacheKey,
+  pub hash: String,
+  pub created_at: SystemTime,
+  /// De download requests waar het ZIP-bestand van komt; meer dan één als het gebied in tegels opgedeeld was.
+  pub request_ids: Vec<String>,
+  pub checksum: Checksum,
+  pub zip_path: PathBuf,
+}
+
+impl CacheEntry {
+  pub fn age(&self) -> Duration {
+    SystemTime::now().duration_since(self.created_at).unwrap_or_default()
+  }
+
+  /// Met een `ttl` van 0 is elk ZIP-bestand verlopen.
+  pub fn is_expired(&self, ttl: Duration) -> bool {
+    self.age() >= ttl
+  }
+}
+
+/// Lokale cache van gedownloade ZIP-bestanden, zodat hetzelfde gebied met dezelfde lagen niet steeds opnieuw bij de
+/// PDOK API aangevraagd wordt.
+///
+/// Per sleutel staan er twee bestanden in de map: `<hash>.zip` en `<hash>.json` met de sleutel, het tijdstip, de
+/// downloadRequestIds en de checksum. Het JSON-bestand wordt als laatste geschreven, zodat een half opgeslagen
+/// ZIP-bestand nooit gebruikt wordt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cache {
+  dir: PathBuf,
+  ttl: Duration,
+}
+
+impl Cache {
+  pub fn new<P: Into<PathBuf>>(dir: P, ttl: Duration) -> Self {
+    Self { dir: dir.into(), ttl }
+  }
+
+  /// `$XDG_CACHE_HOME/dkkdownload`, `~/.cache/dkkdownload` of op Windows `%LOCALAPPDATA%\dkkdownload\cache`.
+  pub fn default_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CACHE_HOME").map(|dir| Path::new(&dir).join("dkkdownload"))
+      .or_else(|| env::var_os("LOCALAPPDATA").map(|dir| Path::new(&dir).join("dkkdownload").join("cache")))
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache").join("dkkdownload")))
+  }
+
+  pub fn dir(&self) -> &Path {
+    &self.dir
+  }
+
+  pub fn ttl(&self) -> Duration {
+    self.ttl
+  }
+
+  /// Het ZIP-bestand voor `key`, als het er is, niet verlopen is en de checksum nog klopt.
+  pub fn get(&self, key: &CacheKey) -> io::Result<Option<CacheEntry>> {
+    let entry = match self.read_entry(&key.hash()) {
+      Some(entry) if entry.key == *key && !entry.is_expired(self.ttl) => entry,
+      _ => return Ok(None),
+    };
+    match Checksum::of_file(&entry.zip_path) {
+      Ok(checksum) if checksum == entry.checksum => Ok(Some(entry)),
+      // Beschadigd of half verwijderd; opruimen, dan wordt het opnieuw gedownload.
+      Ok(_) => {
+        self.remove(&entry)?;
+        Ok(None)
+      },
+      Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+      Err(e) => Err(e),
+    }
+  }
+
+  /// Bewaart een kopie van het ZIP-bestand op `zip` onder `key`.
+  pub fn put_file(&self, key: &CacheKey, zip: &Path, request_ids: &[String]) -> io::Result<CacheEntry> {
+    self.put(key, request_ids, |part| fs::copy(zip, part).map(|_| ()))
+  }
+
+  /// Bewaart een ZIP-bestand uit het geheugen onder `key`.
+  pub fn put_bytes(&self, key: &CacheKey, zip: &[u8], request_ids: &[String]) -> io::Result<CacheEntry> {
+    self.put(key, request_ids, |part| fs::write(part, zip))
+  }
+
+  fn put<F: FnOnce(&Path) -> io::Result<()>>(&self, key: &CacheKey, request_ids: &[String], write: F) -> io::Result<CacheEntry> {
+    fs::create_dir_all(&self.dir)?;
+    let hash = key.hash();
+    let zip_path = self.dir.join(format!("{}.zip", hash));
+    let zip_part = part_path(&zip_path);
+    write(&zip_part)?;
+    let checksum = Checksum::of_file(&zip_part)?;
+    fs::rename(&zip_part, &zip_path)?;
+
+    let entry = CacheEntry { key: key.clone(), hash, created_at: SystemTime::now(), request_ids: request_ids.to_vec(), checksum, zip_path };
+    let created_at = entry.created_at.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let metadata = object!{
+      "api_url" => entry.key.api_url.as_str(),
+      "geofilter" => entry.key.geofilter.as_str(),
+      "feature_types" => entry.key.feature_types.clone(),
+      "created_at" => created_at,
+      "request_ids" => entry.request_ids.clone(),
+      "size" => entry.checksum.size,
+      "sha256" => entry.checksum.sha256.as_str()
+    };
+    let metadata_path = self.metadata_path(&entry.hash);
+    let metadata_part = part_path(&metadata_path);
+    fs::write(&metadata_part, json::stringify(metadata))?;
+    fs::rename(&metadata_part, &metadata_path)?;
+    Ok(entry)
+  }
+
+  /// Alle ZIP-bestanden in de cache, ook de verlopen, van oud naar nieuw.
+  pub fn entries(&self) -> io::Result<Vec<CacheEntry>> {
+    let dir = match fs::read_dir(&self.dir) {
+      Ok(dir) => dir,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e),
+    };
+    let mut entries = Vec::new();
+    for file in dir {
+      let name = file?.file_name();
+      if let Some(hash) = name.to_str().and_then(|name| name.strip_suffix(".json")) {
+        entries.extend(self.read_entry(hash));
+      }
+    }
+    entries.sort_by_key(|entry| entry.created_at);
+    Ok(entries)
+  }
+
+  /// Verwijdert de verlopen ZIP-bestanden, of met `all` alles, en geeft terug wat er verwijderd is.
+  pub fn purge(&self, all: bool) -> io::Result<Vec<CacheEntry>> {
+    let mut removed = Vec::new();
+    for entry in self.entries()? {
+      if all || entry.is_expired(self.ttl) {
+        self.remove(&entry)?;
+        removed.push(entry);
+      }
+    }
+    Ok(removed)
+  }
+
+  pub fn remove(&self, entry: &CacheEntry) -> io::Result<()> {
+    // Eerst het JSON-bestand, zodat een half verwijderde entry niet meer gevonden wordt.
+    for path in [self.metadata_path(&entry.hash), entry.zip_path.clone()] {
+      match fs::remove_file(&path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {},
+      }
+    }
+    Ok(())
+  }
+
+  fn metadata_path(&self, hash: &str) -> PathBuf {
+    self.dir.join(format!("{}.json", hash))
+  }
+
+  /// Leest de gegevens van een entry. Een onleesbaar JSON-bestand telt als een ontbrekende entry.
+  fn read_entry(&self, hash: &str) -> Option<CacheEntry> {
+    let metadata = json::parse(&fs::read_to_string(self.metadata_path(hash)).ok()?).ok()?;
+    let strings = |value: &json::JsonValue| value.members().map(|v| v.as_str().map(String::from)).collect::<Option<Vec<_>>>();
+    Some(CacheEntry {
+      key: CacheKey {
+        // Ontbreekt in entries van vóór de API-URL in de sleutel; die worden nooit meer gevonden, maar wel opgeruimd.
+        api_url: metadata["api_url"].as_str().unwrap_or_default().to_string(),
+        geofilter: metadata["geofilter"].as_str()?.to_string(),
+        feature_types: strings(&metadata["feature_types"])?,
+      },
+      hash: String::from(hash),
+      created_at: UNIX_EPOCH + Duration::from_secs(metadata["created_at"].as_u64()?),
+      request_ids: strings(&metadata["request_ids"])?,
+      checksum: Checksum { size: metadata["size"].as_u64()?, sha256: metadata["sha256"].as_str()?.to_string() },
+      zip_path: self.dir.join(format!("{}.zip", hash)),
+    })
+  }
+}
@@ -0,0 +1,60 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+
+/// Grootte en SHA-256 (hexadecimaal, kleine letters) van een bestand of datastroom.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+  pub size: u64,
+  pub sha256: String,
+}
+
+impl Checksum {
+  pub fn of_bytes(data: &[u8]) -> Checksum {
+    Checksum { size: data.len() as u64, sha256: hex(&Sha256::digest(data)) }
+  }
+
+  pub fn of_file(path: &Path) -> io::Result<Checksum> {
+    let mut reader = HashingReader::new(File::open(path)?);
+    io::copy(&mut reader, &mut io::sink())?;
+    Ok(reader.finish().1)
+  }
+}
+
+/// Berekent de checksum van alles wat er doorheen gelezen wordt.
+pub struct HashingReader<R: Read> {
+  inner: R,
+  hasher: Sha256,
+  size: u64,
+}
+
+impl<R: Read> HashingReader<R> {
+  pub fn new(inner: R) -> Self {
+    Self { inner, hasher: Sha256::new(), size: 0 }
+  }
+
+  pub fn finish(self) -> (R, Checksum) {
+    (self.inner, Checksum { size: self.size, sha256: hex(&self.hasher.finalize()) })
+  }
+}
+
+impl<R: Read> Read for HashingReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let n = self.inner.read(buf)?;
+    self.hasher.update(&buf[..n]);
+    self.size += n as u64;
+    Ok(n)
+  }
+}
+
+fn hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -0,0 +1,523 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::fs;
+use std::fs::OpenOptions;
+use std::io;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::thread::sleep;
+use std::time::Duration;
+
+use reqwest::{StatusCode, Url};
+
+use json::object;
+use json::JsonValue;
+
+use crate::extract::verify_zip_file;
+use crate::error::{ConfigError, DkkError, InvalidResponseError, JobFailedError, UnexpectedStatusCodeError};
+use crate::retry::{RetryPolicy, is_connect_error, is_refused_for_now, is_transient_error, is_transient_status, retry_after};
+
+
+pub const DEFAULT_ROOT_URL: &str = "https://api.pdok.nl";
+/// Pad van de DKK Download API onder de root url, zonder versie.
+pub const API_PATH: &str = "/kadaster/kadastralekaart/download";
+pub const DEFAULT_API_VERSION: &str = "v5_0";
+
+/// Statussen waarmee de PDOK API aangeeft dat een download request niet meer gereed komt.
+const FAILED_STATUSES: &[&str] = &["FAILED", "ERROR", "CANCELLED", "EXPIRED"];
+
+/// Soort download request: een volledige download of alleen de mutaties sinds een delta.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RequestKind {
+  Full,
+  Delta,
+}
+
+impl RequestKind {
+  fn path(self) -> &'static str {
+    match self {
+      RequestKind::Full => "/full/custom",
+      RequestKind::Delta => "/delta/custom",
+    }
+  }
+}
+
+/// Een ingediend download request bij de PDOK API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DownloadRequest {
+  pub id: String,
+  pub kind: RequestKind,
+}
+
+impl DownloadRequest {
+  pub fn full<S: Into<String>>(id: S) -> Self {
+    Self { id: id.into(), kind: RequestKind::Full }
+  }
+
+  pub fn delta<S: Into<String>>(id: S) -> Self {
+    Self { id: id.into(), kind: RequestKind::Delta }
+  }
+}
+
+/// Een delta (mutatieset) zoals bekend bij de PDOK API.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Delta {
+  pub id: String,
+  pub timestamp: String,
+}
+
+/// Status van een download request, zoals teruggegeven door `DkkClient::poll_status`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DownloadStatus {
+  /// De PDOK API is nog bezig. `progress` is een percentage, indien de API dat meegeeft.
+  Pending { progress: Option<u64> },
+  /// Het ZIP-bestand staat klaar op `download_url`.
+  Ready { download_url: String },
+}
+
+/// Een lopende download van het ZIP-bestand, zie `DkkClient::start_download`.
+pub struct Download {
+  response: reqwest::Response,
+  offset: u64,
+}
+
+impl Download {
+  /// Aantal bytes in dit antwoord, dus zonder het deel dat al eerder gedownload was.
+  pub fn content_length(&self) -> Option<u64> {
+    self.response.content_length()
+  }
+
+  /// Positie in het ZIP-bestand vanaf waar dit antwoord begint. Is 0 tenzij er hervat wordt.
+  pub fn offset(&self) -> u64 {
+    self.offset
+  }
+
+  /// Totale grootte van het ZIP-bestand, indien bekend.
+  pub fn total_length(&self) -> Option<u64> {
+    self.content_length().map(|length| self.offset + length)
+  }
+
+  /// Schrijft de rest van het ZIP-bestand naar `writer`. Een afgebroken verbinding is een `DkkError::Network`,
+  /// een fout bij het schrijven een `DkkError::Io`.
+  pub fn copy_to<W: Write + ?Sized>(mut self, writer: &mut W) -> Result<u64, DkkError> {
+    let mut buf = [0u8; 64 * 1024];
+    let mut written = 0;
+    loop {
+      let n = match self.response.read(&mut buf) {
+        Ok(0) => return Ok(written),
+        Ok(n) => n,
+        Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+        Err(e) => return Err(DkkError::Network(Box::new(e))),
+      };
+      writer.write_all(&buf[..n])?;
+      written += n as u64;
+    }
+  }
+}
+
+impl Read for Download {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    self.response.read(buf)
+  }
+}
+
+/// Client voor de PDOK DKK Download API.
+pub struct DkkClient {
+  http: reqwest::Client,
+  root_url: String,
+  api_version: String,
+  user_agent: String,
+  retry: RetryPolicy,
+}
+
+impl Default for DkkClient {
+  fn default() -> Self {
+    Self::new()
+  }
+}
+
+impl DkkClient {
+  pub fn new() -> Self {
+    Self {
+      http: reqwest::Client::new(),
+      root_url: String::from(DEFAULT_ROOT_URL),
+      api_version: String::from(DEFAULT_API_VERSION),
+      user_agent: format!("DKKdownload/{}", env!("CARGO_PKG_VERSION")),
+      retry: RetryPolicy::default(),
+    }
+  }
+
+  pub fn with_user_agent<S: Into<String>>(mut self, user_agent: S) -> Self {
+    self.user_agent = user_agent.into();
+    self
+  }
+
+  pub fn with_retry_policy(mut self, retry: RetryPolicy) -> Self {
+    self.retry = retry;
+    self
+  }
+
+  /// Maximale duur van één HTTP request, inclusief het ontvangen van het antwoord. Standaard 30 seconden.
+  ///
+  /// Een verlopen timeout geldt als tijdelijke fout en wordt volgens de `RetryPolicy` opnieuw geprobeerd.
+  pub fn with_timeout(mut self, timeout: Duration) -> Result<Self, DkkError> {
+    self.http = reqwest::Client::builder().timeout(timeout).build()?;
+    Ok(self)
+  }
+
+  /// Gebruikt een andere root url dan `https://api.pdok.nl`, zoals de acceptatieomgeving van PDOK of een proxy.
+  ///
+  /// De url mag een pad bevatten (bijv. `http://proxy.local/pdok`); de API wordt daaronder aangesproken.
+  pub fn with_root_url(mut self, root_url: &str) -> Result<Self, ConfigError> {
+    let url = Url::parse(root_url).map_err(|e| ConfigError::new(format!("ongeldige base url '{}': {}", root_url, e)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+      return Err(ConfigError::new(format!("base url '{}' moet met http:// of https:// beginnen", root_url)));
+    }
+    self.root_url = String::from(root_url.trim_end_matches('/'));
+    Ok(self)
+  }
+
+  /// Gebruikt een andere versie van de API dan `v5_0`.
+  pub fn with_api_version<S: Into<String>>(mut self, api_version: S) -> Self {
+    self.api_version = api_version.into();
+    self
+  }
+
+  pub fn root_url(&self) -> &str {
+    &self.root_url
+  }
+
+  /// Url van de API, bijv. `https://api.pdok.nl/kadaster/kadastralekaart/download/v5_0`.
+  pub fn api_url(&self) -> String {
+    format!("{}{}/{}", self.root_url, API_PATH, self.api_version)
+  }
+
+  /// Maakt een url uit een antwoord van de API absoluut.
+  ///
+  /// Paden die met `/` beginnen vallen onder de root url, inclusief een eventueel pad daarin, zodat een proxy ook
+  /// voor de downloads gebruikt wordt. Andere relatieve urls worden t.o.v. `relative_to` opgelost.
+  fn resolve_href(&self, href: &str, relative_to: &str) -> Result<String, InvalidResponseError> {
+    let resolved = if href.starts_with('/') && !href.starts_with("//") {
+      Url::parse(&format!("{}{}", self.root_url, href))
+    } else {
+      Url::parse(relative_to).and_then(|base| base.join(href))
+    };
+    resolved
+      .map(|url| url.into_string())
+      .map_err(|e| InvalidResponseError::new(format!("ongeldige link '{}': {}", href, e)))
+  }
+
+  /// Verstuurt het request dat `build` maakt, en herhaalt dat bij tijdelijke fouten volgens de `RetryPolicy`.
+  ///
+  /// Geeft na het opraken van de herhalingen het laatste antwoord terug, ook als dat een foutstatus heeft. Een request
+  /// dat niet `idempotent` is (POST) wordt alleen herhaald als de server het zeker niet verwerkt heeft: bij een 500 of
+  /// een time-out kan het download request al aangemaakt zijn.
+  fn send<F>(&self, idempotent: bool, build: F) -> Result<reqwest::Response, DkkError>
+      where F: Fn() -> reqwest::RequestBuilder {
+    let mut attempt = 0;
+    loop {
+      let wait = match build().send() {
+        Ok(res) => {
+          let transient = if idempotent { is_transient_status(res.status()) } else { is_refused_for_now(&res) };
+          if !transient || attempt >= self.retry.max_retries {
+            return Ok(res);
+          }
+          retry_after(&res).unwrap_or_else(|| self.retry.backoff(attempt))
+        },
+        Err(e) => {
+          let transient = if idempotent { is_transient_error(&e) } else { is_connect_error(&e) };
+          if !transient || attempt >= self.retry.max_retries {
+            return Err(e.into());
+          }
+          self.retry.backoff(attempt)
+        },
+      };
+      sleep(wait);
+      attempt += 1;
+    }
+  }
+
+  /// Dient een full custom download request in.
+  ///
+  /// `geofilter` is een Well-Known Text (WKT) polygon.
+  pub fn submit_custom_request<S: AsRef<str>>(&self, featuretypes: &[S], geofilter: &str) -> Result<DownloadRequest, DkkError> {
+    // featuretypes kan bijv. het volgende zijn;
+    // array![
+    //    "perceel",
+    //    "kadastralegrens",
+    //    "pand",
+    //    "openbareruimtelabel",
+    //    "kwaliteit"
+    //  ],
+    let featuretypes: Vec<&str> = featuretypes.iter().map(|s| s.as_ref()).collect();
+    let body = object!{
+      "featuretypes" => JsonValue::from(featuretypes),
+      "format" => "gml", // "gml" is per najaar 2023 ook de enige toegestane waarde.
+      "geofilter" => geofilter
+    };
+    self.submit(RequestKind::Full, body)
+  }
+
+  /// Dient een delta custom download request in, met alleen de mutaties sinds `delta_id`.
+  pub fn submit_delta_request<S: AsRef<str>>(&self, featuretypes: &[S], geofilter: &str, delta_id: &str) -> Result<DownloadRequest, DkkError> {
+    let featuretypes: Vec<&str> = featuretypes.iter().map(|s| s.as_ref()).collect();
+    let body = object!{
+      "deltaid" => delta_id,
+      "featuretypes" => JsonValue::from(featuretypes),
+      "format" => "gml",
+      "geofilter" => geofilter
+    };
+    self.submit(RequestKind::Delta, body)
+  }
+
+  fn submit(&self, kind: RequestKind, body: JsonValue) -> Result<DownloadRequest, DkkError> {
+    let requrl = format!("{}{}", self.api_url(), kind.path());
+    let jsonbody = json::stringify(body);
+    let mut res = self.send(false, || self.http.post(requrl.as_str())
+      .header(reqwest::header::USER_AGENT, self.user_agent.as_str())
+      .header(reqwest::header::ACCEPT, "application/json")
+      .header(reqwest::header::CONTENT_TYPE, "application/json") // Als je deze niet zend, zend de PDOK API een 500tje terug: stand 2019-10-2
+      .body(jsonbody.clone()))?;
+
+    if res.status() != StatusCode::ACCEPTED {
+      return Err(UnexpectedStatusCodeError::new(res, reqwest::Method::POST).into());
+    }
+
+    let resjson = json::parse(&res.text()?)?;
+    match resjson["downloadRequestId"].as_str() {
+      Some(reqid) => Ok(DownloadRequest { id: String::from(reqid), kind }),
+      None => Err(InvalidResponseError::new("verkregen downloadRequestId is geen string").into()),
+    }
+  }
+
+  /// Geeft de meest recente delta die de PDOK API kent, indien er een is.
+  pub fn latest_delta(&self) -> Result<Option<Delta>, DkkError> {
+    const PAGE_SIZE: usize = 100;
+    let mut latest: Option<Delta> = None;
+    let mut page = 1;
+    loop {
+      let url = format!("{}/delta?page={}&count={}", self.api_url(), page, PAGE_SIZE);
+      let mut res = self.send(true, || self.http.get(url.as_str())
+        .header(reqwest::header::USER_AGENT, self.user_agent.as_str())
+        .header(reqwest::header::ACCEPT, "application/json"))?;
+      if res.status() != StatusCode::OK {
+        return Err(UnexpectedStatusCodeError::new(res, reqwest::Method::GET).into());
+      }
+      let resjson = json::parse(&res.text()?)?;
+      let deltas = &resjson["deltas"];
+      for delta in deltas.members() {
+        let (id, timestamp) = match (delta["id"].as_str(), delta["timeStamp"].as_str()) {
+          (Some(id), Some(timestamp)) => (id, timestamp),
+          _ => return Err(InvalidResponseError::new("delta zonder id of timeStamp").into()),
+        };
+        // Tijdstempels zijn ISO 8601 en dus lexicografisch te vergelijken.
+        if latest.as_ref().is_none_or(|l| timestamp > l.timestamp.as_str()) {
+          latest = Some(Delta { id: String::from(id), timestamp: String::from(timestamp) });
+        }
+      }
+      if deltas.len() < PAGE_SIZE {
+        return Ok(latest);
+      }
+      page += 1;
+    }
+  }
+
+  /// Vraagt eenmalig de status van een download request op.
+  pub fn poll_status(&self, request: &DownloadRequest) -> Result<DownloadStatus, DkkError> {
+    let status_url = format!("{}{}/{}/status", self.api_url(), request.kind.path(), request.id);
+    let mut res = self.send(true, || self.http.get(status_url.as_str())
+      .header(reqwest::header::USER_AGENT, self.user_agent.as_str())
+      .header(reqwest::header::ACCEPT, "application/json"))?;
+    match res.status() {
+      StatusCode::OK => { // "Full custom download nog niet gereed"
+        // De voortgang is informatief; een onleesbaar antwoord is hier geen fout.
+        let statusjson = res.text().ok().and_then(|text| json::parse(&text).ok()).unwrap_or(JsonValue::Null);
+        if let Some(status) = statusjson["status"].as_str().filter(|s| FAILED_STATUSES.contains(&s.to_ascii_uppercase().as_str())) {
+          return Err(JobFailedError::new(request.id.as_str(), status).into());
+        }
+        // Een percentage boven de 100 zou een voortgangsbalk laten overlopen.
+        let progress = statusjson["progress"].as_u64().map(|progress| progress.min(100));
+        Ok(DownloadStatus::Pending { progress })
+      },
+      StatusCode::CREATED => {
+        let resjson = json::parse(&res.text()?)?;
+        match resjson["_links"]["download"]["href"].as_str() {
+          Some(href) => Ok(DownloadStatus::Ready { download_url: self.resolve_href(href, &status_url)? }),
+          None => Err(InvalidResponseError::new("download link ontbreekt in status").into()),
+        }
+      },
+      _ => Err(UnexpectedStatusCodeError::new(res, reqwest::Method::GET).into()),
+    }
+  }
+
+  /// Vraagt elke `interval` de status op totdat het ZIP-bestand klaarstaat, en geeft dan de download url terug.
+  ///
+  /// `on_progress` wordt na elke status-aanvraag aangeroepen.
+  pub fn wait_until_ready<F>(&self, request: &DownloadRequest, interval: Duration, mut on_progress: F) -> Result<String, DkkError>
+      where F: FnMut(Option<u64>) {
+    loop {
+      match self.poll_status(request)? {
+        DownloadStatus::Pending { progress } => {
+          on_progress(progress);
+          sleep(interval);
+        },
+        DownloadStatus::Ready { download_url } => return Ok(download_url),
+      }
+    }
+  }
+
+  /// Begint met het downloaden van het ZIP-bestand.
+  pub fn start_download(&self, download_url: &str) -> Result<Download, DkkError> {
+    self.start_download_at(download_url, 0)
+  }
+
+  /// Begint met het downloaden van het ZIP-bestand vanaf byte `offset`, d.m.v. een HTTP Range request.
+  ///
+  /// Als de server de range niet (correct) honoreert begint de download opnieuw vanaf het begin;
+  /// controleer daarom altijd `Download::offset`.
+  pub fn start_download_at(&self, download_url: &str, offset: u64) -> Result<Download, DkkError> {
+    // Download url verwijst naar een zip bestand
+    let res = self.send(true, || {
+      let req = self.http.get(download_url)
+        .header(reqwest::header::USER_AGENT, self.user_agent.as_str())
+        .header(reqwest::header::ACCEPT, "application/zip");
+      if offset > 0 {
+        req.header(reqwest::header::RANGE, format!("bytes={}-", offset))
+      } else {
+        req
+      }
+    })?;
+    match res.status() {
+      StatusCode::OK => Ok(Download { response: res, offset: 0 }), // Range genegeerd; volledig bestand.
+      StatusCode::PARTIAL_CONTENT if offset > 0 && content_range_start(&res) == Some(offset) => {
+        Ok(Download { response: res, offset })
+      },
+      StatusCode::PARTIAL_CONTENT | StatusCode::RANGE_NOT_SATISFIABLE if offset > 0 => {
+        // Een range die we niet gevraagd hebben, of een deel dat al groter is dan het bestand; opnieuw beginnen.
+        self.start_download_at(download_url, 0)
+      },
+      _ => Err(UnexpectedStatusCodeError::new(res, reqwest::Method::GET).into()),
+    }
+  }
+
+  /// Downloadt het ZIP-bestand naar `writer` en geeft het aantal geschreven bytes terug.
+  pub fn download_to<W: Write + ?Sized>(&self, download_url: &str, writer: &mut W) -> Result<u64, DkkError> {
+    self.start_download(download_url)?.copy_to(writer)
+  }
+
+  /// Downloadt het ZIP-bestand van `request` naar `path` en geeft de grootte ervan terug.
+  ///
+  /// Tijdens het downloaden wordt naar `<path>.part` geschreven, dat pas na een controle van de central directory en de
+  /// CRC van elk bestand (zie `extract::verify_zip`) naar `path` hernoemd wordt; een bestaand bestand op `path` blijft tot
+  /// dan intact.
+  /// Wanneer de verbinding wegvalt wordt de download met een Range request hervat, ook als een `.part` bestand van een
+  /// eerdere run is blijven staan. Dat gebeurt alleen als `<path>.part.json` (zie `part_info_path`) aangeeft dat het van
+  /// hetzelfde request en dezelfde download link is; anders, of als een hervat bestand de controle niet doorstaat, begint
+  /// de download opnieuw vanaf het begin.
+  /// `on_progress` krijgt het aantal bytes dat tot nu toe in het bestand staat, en de totale grootte indien bekend.
+  pub fn download_to_file<P, F>(&self, request: &DownloadRequest, download_url: &str, path: P, mut on_progress: F) -> Result<u64, DkkError>
+      where P: AsRef<Path>, F: FnMut(u64, Option<u64>) {
+    let path = path.as_ref();
+    let part_path = part_path(path);
+    let info_path = part_info_path(path);
+    let info = object!{ "request_id" => request.id.as_str(), "download_url" => download_url };
+    let same_download = fs::read_to_string(&info_path).ok().and_then(|text| json::parse(&text).ok()).is_some_and(|found| found == info);
+    if !same_download {
+      remove_if_exists(&part_path)?;
+    }
+    fs::write(&info_path, info.dump())?;
+
+    let mut attempts = 0;
+    let mut resumed = false;
+    loop {
+      let existing = match fs::metadata(&part_path) {
+        Ok(metadata) => metadata.len(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => 0,
+        Err(e) => return Err(e.into()),
+      };
+      let mut download = self.start_download_at(download_url, existing)?;
+      let total = download.total_length();
+      resumed |= download.offset() > 0;
+
+      let mut file = OpenOptions::new().create(true).truncate(false).write(true).open(&part_path)?;
+      file.set_len(download.offset())?;
+      file.seek(SeekFrom::End(0))?;
+
+      let mut written = download.offset();
+      on_progress(written, total);
+      let mut buf = [0u8; 64 * 1024];
+      let read_error = loop {
+        let n = match download.response.read(&mut buf) {
+          Ok(0) => break None,
+          Ok(n) => n,
+          Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+          Err(e) => break Some(e),
+        };
+        file.write_all(&buf[..n])?;
+        written += n as u64;
+        on_progress(written, total);
+      };
+      file.flush()?;
+
+      let complete = read_error.is_none() && total.is_none_or(|total| written == total);
+      if complete {
+        drop(file);
+        // Een beschadigd bestand niet laten staan, anders zou een volgende run het proberen te hervatten.
+        if let Err(e) = verify_zip_file(&part_path) {
+          remove_if_exists(&part_path)?;
+          if resumed {
+            // Wat er al stond hoorde misschien niet bij deze download; één keer helemaal opnieuw.
+            resumed = false;
+            continue;
+          }
+          remove_if_exists(&info_path)?;
+          return Err(e.into());
+        }
+        fs::rename(&part_path, path)?;
+        remove_if_exists(&info_path)?;
+        return Ok(written);
+      }
+
+      if attempts >= self.retry.max_retries {
+        return Err(match read_error {
+          Some(e) => DkkError::Network(Box::new(e)),
+          None => DkkError::Network(Box::new(InvalidResponseError::new(format!("ZIP-bestand onvolledig ontvangen ({} van {:?} bytes)", written, total)))),
+        });
+      }
+      // Verbinding weggevallen of te weinig bytes ontvangen; hervatten vanaf wat er al staat.
+      sleep(self.retry.backoff(attempts));
+      attempts += 1;
+    }
+  }
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+  match fs::remove_file(path) {
+    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+    _ => Ok(()),
+  }
+}
+
+/// Pad van het tijdelijke bestand waarin een download naar `path` wordt opgebouwd.
+pub fn part_path(path: &Path) -> PathBuf {
+  let mut part = path.as_os_str().to_owned();
+  part.push(".part");
+  PathBuf::from(part)
+}
+
+/// Pad van het bestand naast `part_path(path)` met het downloadRequestId en de download link waar de `.part` van is.
+pub fn part_info_path(path: &Path) -> PathBuf {
+  let mut info = part_path(path).into_os_string();
+  info.push(".json");
+  PathBuf::from(info)
+}
+
+/// Beginpositie uit een `Content-Range: bytes <start>-<end>/<totaal>` header.
+fn content_range_start(res: &reqwest::Response) -> Option<u64> {
+  let value = res.headers().get(reqwest::header::CONTENT_RANGE)?.to_str().ok()?;
+  let range = value.trim().strip_prefix("bytes ")?;
+  range.split('-').next()?.trim().parse().ok()
+}
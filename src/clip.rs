@@ -0,0 +1,279 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use geo::{Area, BooleanOps, EuclideanLength};
+
+use crate::error::InvalidGeometryError;
+use crate::geometry::{contains_point, FeatureGeometry, Geometry, Point, Polygon};
+use crate::gml::Layer;
+
+
+/// Relatieve afwijking in oppervlakte of lengte waaronder een geometrie als niet bijgesneden geldt.
+const TOLERANCE: f64 = 1e-9;
+
+/// Hoe features die over de rand van het interessegebied steken behandeld worden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClipMode {
+  /// Geometrieën op de rand van het gebied afsnijden.
+  Cut,
+  /// Alleen features die helemaal binnen het gebied liggen behouden.
+  Within,
+}
+
+impl Display for ClipMode {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      ClipMode::Cut => write!(f, "cut"),
+      ClipMode::Within => write!(f, "within"),
+    }
+  }
+}
+
+impl FromStr for ClipMode {
+  type Err = InvalidGeometryError;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "cut" => Ok(ClipMode::Cut),
+      "within" => Ok(ClipMode::Within),
+      _ => Err(InvalidGeometryError::new(format!("onbekende clip modus '{}'; gebruik 'cut' of 'within'", s))),
+    }
+  }
+}
+
+/// Resultaat van het bijsnijden van één laag.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ClipStats {
+  pub name: String,
+  /// Features die ongewijzigd binnen het gebied liggen.
+  pub kept: usize,
+  /// Features waarvan de geometrie afgesneden is (alleen bij `ClipMode::Cut`).
+  pub clipped: usize,
+  /// Features die weggelaten zijn: helemaal buiten het gebied, of bij `ClipMode::Within` deels erbuiten.
+  pub dropped: usize,
+  /// De `identificatie` van elke bijgesneden feature (zie `Feature::identificatie`), in documentvolgorde.
+  pub clipped_ids: Vec<String>,
+  /// De `identificatie` van elke weggelaten feature.
+  pub dropped_ids: Vec<String>,
+}
+
+/// Snijdt de hoofdgeometrie van alle features bij tot `area` (in RD New).
+///
+/// PDOK levert alle features die het geofilter raken, dus ook percelen die er ver buiten steken. Features zonder
+/// geometrie blijven staan; overige geometrieën, zoals plaatsingspunten van labels, worden niet bijgesneden.
+pub fn clip_layers(layers: &mut [Layer], area: &Geometry, mode: ClipMode) -> Vec<ClipStats> {
+  let area_polygons = geo::MultiPolygon::from(area);
+  let polygons = area.polygons();
+  layers.iter_mut().map(|layer| {
+    let mut stats = ClipStats {
+      name: layer.name.clone(), kept: 0, clipped: 0, dropped: 0, clipped_ids: Vec::new(), dropped_ids: Vec::new(),
+    };
+    layer.features.retain_mut(|feature| {
+      let geometry = match &feature.geometry {
+        Some(geometry) => geometry,
+        None => {
+          stats.kept += 1;
+          return true;
+        },
+      };
+      match (clip_geometry(geometry, &area_polygons, polygons), mode) {
+        (Clipped::Inside, _) => {
+          stats.kept += 1;
+          true
+        },
+        (Clipped::Partly(clipped), ClipMode::Cut) => {
+          feature.geometry = Some(clipped);
+          stats.clipped += 1;
+          stats.clipped_ids.extend(feature.identificatie());
+          true
+        },
+        (Clipped::Partly(_), ClipMode::Within) | (Clipped::Outside, _) => {
+          stats.dropped += 1;
+          stats.dropped_ids.extend(feature.identificatie());
+          false
+        },
+      }
+    });
+    stats
+  }).collect()
+}
+
+enum Clipped {
+  Inside,
+  Partly(FeatureGeometry),
+  Outside,
+}
+
+fn clip_geometry(geometry: &FeatureGeometry, area: &geo::MultiPolygon<f64>, polygons: &[Polygon]) -> Clipped {
+  match geometry {
+    FeatureGeometry::Point(point) => if contains(polygons, *point) { Clipped::Inside } else { Clipped::Outside },
+    FeatureGeometry::MultiPoint(points) => {
+      let inside: Vec<Point> = points.iter().copied().filter(|p| contains(polygons, *p)).collect();
+      match inside.len() {
+        0 => Clipped::Outside,
+        n if n == points.len() => Clipped::Inside,
+        _ => Clipped::Partly(FeatureGeometry::MultiPoint(inside)),
+      }
+    },
+    FeatureGeometry::LineString(points) => clip_lines(std::slice::from_ref(points), area),
+    FeatureGeometry::MultiLineString(lines) => clip_lines(lines, area),
+    FeatureGeometry::Polygon(polygon) => clip_polygons(std::slice::from_ref(polygon), area),
+    FeatureGeometry::MultiPolygon(polygons) => clip_polygons(polygons, area),
+  }
+}
+
+fn clip_lines(lines: &[Vec<Point>], area: &geo::MultiPolygon<f64>) -> Clipped {
+  let original = geo::MultiLineString::new(lines.iter().map(|line| line_string(line)).collect());
+  let clipped = area.clip(&original, false);
+  compare(original.euclidean_length(), clipped.euclidean_length(), || {
+    let mut lines: Vec<Vec<Point>> = clipped.iter().map(|line| line.coords().map(|c| Point::new(c.x, c.y)).collect()).collect();
+    if lines.len() == 1 { FeatureGeometry::LineString(lines.remove(0)) } else { FeatureGeometry::MultiLineString(lines) }
+  })
+}
+
+fn clip_polygons(polygons: &[Polygon], area: &geo::MultiPolygon<f64>) -> Clipped {
+  let original = geo::MultiPolygon::new(polygons.iter().map(geo::Polygon::from).collect());
+  let clipped = original.intersection(area);
+  compare(original.unsigned_area(), clipped.unsigned_area(), || {
+    let mut polygons: Vec<Polygon> = clipped.iter().map(Polygon::from).collect();
+    if polygons.len() == 1 { FeatureGeometry::Polygon(polygons.remove(0)) } else { FeatureGeometry::MultiPolygon(polygons) }
+  })
+}
+
+/// Vergelijkt de oppervlakte of lengte voor en na het bijsnijden.
+fn compare<F: FnOnce() -> FeatureGeometry>(original: f64, clipped: f64, geometry: F) -> Clipped {
+  if clipped <= original * TOLERANCE {
+    Clipped::Outside
+  } else if original - clipped <= original * TOLERANCE {
+    Clipped::Inside
+  } else {
+    Clipped::Partly(geometry())
+  }
+}
+
+fn line_string(points: &[Point]) -> geo::LineString<f64> {
+  geo::LineString::from(points.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>())
+}
+
+fn contains(polygons: &[Polygon], point: Point) -> bool {
+  polygons.iter().any(|polygon| contains_point(&polygon.exterior, point) && !polygon.interiors.iter().any(|hole| contains_point(hole, point)))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::gml::Feature;
+
+  fn points(coordinates: &[(f64, f64)]) -> Vec<Point> {
+    coordinates.iter().map(|(x, y)| Point::new(155000.0 + x, 463000.0 + y)).collect()
+  }
+
+  fn rectangle(min: (f64, f64), max: (f64, f64)) -> Polygon {
+    Polygon::new(points(&[min, (max.0, min.1), max, (min.0, max.1), min]), Vec::new())
+  }
+
+  fn feature(id: &str, geometry: Option<FeatureGeometry>) -> Feature {
+    Feature {
+      feature_type: String::from("Perceel"),
+      id: Some(String::from(id)),
+      properties: Vec::new(),
+      geometry,
+      geometry_property: None,
+      other_geometries: Vec::new(),
+    }
+  }
+
+  /// Een vierkant van 100 bij 100 m met een gat van 10 bij 10 m in het midden.
+  fn area() -> Geometry {
+    let mut area = rectangle((0.0, 0.0), (100.0, 100.0));
+    area.interiors.push(rectangle((45.0, 45.0), (55.0, 55.0)).exterior.into_iter().rev().collect());
+    Geometry::Polygon(area)
+  }
+
+  fn layer() -> Layer {
+    Layer { name: String::from("perceel"), features: vec![
+      feature("binnen", Some(FeatureGeometry::Polygon(rectangle((10.0, 10.0), (20.0, 20.0))))),
+      // Half binnen: 10 van de 20 m².
+      feature("over de rand", Some(FeatureGeometry::Polygon(rectangle((95.0, 10.0), (105.0, 12.0))))),
+      feature("buiten", Some(FeatureGeometry::Polygon(rectangle((110.0, 10.0), (120.0, 20.0))))),
+      // Langs de rand van het gat: raakt het gebied maar ligt er niet in.
+      feature("in het gat", Some(FeatureGeometry::Polygon(rectangle((46.0, 46.0), (54.0, 54.0))))),
+      // 40 van de 60 m binnen.
+      feature("lijn", Some(FeatureGeometry::LineString(points(&[(-20.0, 30.0), (40.0, 30.0)])))),
+      feature("punten", Some(FeatureGeometry::MultiPoint(points(&[(1.0, 1.0), (50.0, 50.0), (99.0, 99.0)])))),
+      feature("punt", Some(FeatureGeometry::Point(Point::new(155050.0, 463050.0)))),
+      feature("zonder geometrie", None),
+    ] }
+  }
+
+  fn strings(values: &[&str]) -> Vec<String> {
+    values.iter().map(|value| String::from(*value)).collect()
+  }
+
+  fn ids(layer: &Layer) -> Vec<&str> {
+    layer.features.iter().filter_map(|feature| feature.id.as_deref()).collect()
+  }
+
+  fn geometry<'a>(layer: &'a Layer, id: &str) -> &'a FeatureGeometry {
+    layer.features.iter().find(|feature| feature.id.as_deref() == Some(id)).and_then(|feature| feature.geometry.as_ref()).unwrap()
+  }
+
+  #[test]
+  fn cut_keeps_the_part_inside_the_area() {
+    let mut layers = vec![layer()];
+    let stats = clip_layers(&mut layers, &area(), ClipMode::Cut);
+    assert_eq!(stats, vec![ClipStats {
+      name: String::from("perceel"), kept: 2, clipped: 3, dropped: 3,
+      clipped_ids: strings(&["over de rand", "lijn", "punten"]),
+      dropped_ids: strings(&["buiten", "in het gat", "punt"]),
+    }]);
+    assert_eq!(ids(&layers[0]), vec!["binnen", "over de rand", "lijn", "punten", "zonder geometrie"]);
+
+    assert_eq!(geometry(&layers[0], "binnen"), &FeatureGeometry::Polygon(rectangle((10.0, 10.0), (20.0, 20.0))));
+    let cut = geometry(&layers[0], "over de rand");
+    assert_eq!(cut.bounds(), (155095.0, 463010.0, 155100.0, 463012.0));
+    match cut {
+      FeatureGeometry::Polygon(polygon) => assert!((geo::Polygon::from(polygon).unsigned_area() - 10.0).abs() < 1e-9),
+      other => panic!("{:?}", other),
+    }
+    match geometry(&layers[0], "lijn") {
+      FeatureGeometry::LineString(line) => assert!((line_string(line).euclidean_length() - 40.0).abs() < 1e-9, "{:?}", line),
+      other => panic!("{:?}", other),
+    }
+    assert_eq!(geometry(&layers[0], "punten"), &FeatureGeometry::MultiPoint(points(&[(1.0, 1.0), (99.0, 99.0)])));
+  }
+
+  #[test]
+  fn within_drops_what_cut_would_cut() {
+    let mut cut = vec![layer()];
+    let cut_stats = clip_layers(&mut cut, &area(), ClipMode::Cut);
+    let mut within = vec![layer()];
+    let within_stats = clip_layers(&mut within, &area(), ClipMode::Within);
+
+    assert_eq!(within_stats, vec![ClipStats {
+      name: String::from("perceel"), kept: 2, clipped: 0, dropped: 6,
+      clipped_ids: Vec::new(),
+      dropped_ids: strings(&["over de rand", "buiten", "in het gat", "lijn", "punten", "punt"]),
+    }]);
+    assert_eq!(within_stats[0].kept, cut_stats[0].kept);
+    assert_eq!(within_stats[0].dropped, cut_stats[0].dropped + cut_stats[0].clipped);
+    assert_eq!(ids(&within[0]), vec!["binnen", "zonder geometrie"]);
+    // Wat behouden wordt, is in beide gevallen ongewijzigd.
+    for feature in &within[0].features {
+      assert!(cut[0].features.contains(feature), "{:?}", feature);
+    }
+  }
+
+  #[test]
+  fn parses_clip_modes() {
+    assert_eq!("cut".parse::<ClipMode>().unwrap(), ClipMode::Cut);
+    assert_eq!("within".parse::<ClipMode>().unwrap(), ClipMode::Within);
+    assert_eq!(ClipMode::Within.to_string(), "within");
+    assert!("intersects".parse::<ClipMode>().is_err());
+  }
+}
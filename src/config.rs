@@ -0,0 +1,84 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::error::ConfigError;
+
+
+pub const ENV_CONFIG: &str = "DKKDOWNLOAD_CONFIG";
+pub const ENV_BASE_URL: &str = "DKKDOWNLOAD_BASE_URL";
+pub const ENV_API_VERSION: &str = "DKKDOWNLOAD_API_VERSION";
+pub const ENV_CACHE_DIR: &str = "DKKDOWNLOAD_CACHE_DIR";
+pub const ENV_CACHE_TTL: &str = "DKKDOWNLOAD_CACHE_TTL";
+pub const ENV_HISTORY_FILE: &str = "DKKDOWNLOAD_HISTORY_FILE";
+
+/// Instellingen uit het configuratiebestand. Opties en omgevingsvariabelen gaan hier voor.
+///
+/// Het bestand is een JSON object, bijvoorbeeld:
+///
+/// ```json
+/// { "base_url": "https://api.pdok.nl", "api_version": "v5_0", "cache_ttl": 3600 }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Config {
+  pub base_url: Option<String>,
+  pub api_version: Option<String>,
+  pub cache_dir: Option<String>,
+  /// In seconden.
+  pub cache_ttl: Option<u64>,
+}
+
+impl Config {
+  /// Leest een configuratiebestand. Onbekende sleutels worden geweigerd, zodat een tikfout niet stil genegeerd wordt.
+  pub fn load(path: &Path) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    let text = fs::read_to_string(path)?;
+    let invalid = |message: String| ConfigError::new(format!("{}: {}", path.display(), message));
+    let value = json::parse(&text).map_err(|e| invalid(e.to_string()))?;
+    if !value.is_object() {
+      return Err(Box::new(invalid(String::from("verwacht een JSON object"))));
+    }
+
+    let mut config = Config::default();
+    for (key, value) in value.entries() {
+      let text = || value.as_str().map(String::from).ok_or_else(|| invalid(format!("'{}' moet een tekst zijn", key)));
+      match key {
+        "base_url" => config.base_url = Some(text()?),
+        "api_version" => config.api_version = Some(text()?),
+        "cache_dir" => config.cache_dir = Some(text()?),
+        "cache_ttl" => config.cache_ttl = Some(value.as_u64().ok_or_else(|| invalid(format!("'{}' moet een aantal seconden zijn", key)))?),
+        _ => return Err(Box::new(invalid(format!("onbekende instelling '{}'", key)))),
+      }
+    }
+    Ok(config)
+  }
+
+  /// Leest het configuratiebestand op `path`, of anders op de standaardplek als dat bestaat.
+  pub fn load_or_default(path: Option<&Path>) -> Result<Config, Box<dyn Error + Send + Sync>> {
+    match path {
+      Some(path) => Config::load(path),
+      None => match Config::default_path() {
+        Some(path) => match Config::load(&path) {
+          Err(e) if e.downcast_ref::<io::Error>().is_some_and(|e| e.kind() == io::ErrorKind::NotFound) => Ok(Config::default()),
+          result => result,
+        },
+        None => Ok(Config::default()),
+      },
+    }
+  }
+
+  /// `$XDG_CONFIG_HOME/dkkdownload/config.json`, `~/.config/dkkdownload/config.json` of op Windows
+  /// `%APPDATA%\dkkdownload\config.json`.
+  pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_CONFIG_HOME").map(PathBuf::from)
+      .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(dir.join("dkkdownload").join("config.json"))
+  }
+}
@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::fmt::Display;
+use std::str::FromStr;
+
+use crate::error::InvalidGeometryError;
+use crate::geometry::{Geometry, Point, Polygon};
+
+
+/// Coördinaatreferentiesystemen die als invoer geaccepteerd worden.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Crs {
+  /// EPSG:28992, Amersfoort / RD New. Dit is wat de PDOK API verwacht.
+  RdNew,
+  /// EPSG:4326, lengte- en breedtegraad in graden (x = lengte, y = breedte).
+  Wgs84,
+  /// EPSG:4258, als `Wgs84`. Het verschil tussen beide (< 1 m) wordt verwaarloosd.
+  Etrs89,
+}
+
+impl Crs {
+  pub fn from_epsg(code: u32) -> Option<Crs> {
+    match code {
+      28992 => Some(Crs::RdNew),
+      4326 => Some(Crs::Wgs84),
+      4258 => Some(Crs::Etrs89),
+      _ => None,
+    }
+  }
+
+  pub fn epsg(self) -> u32 {
+    match self {
+      Crs::RdNew => 28992,
+      Crs::Wgs84 => 4326,
+      Crs::Etrs89 => 4258,
+    }
+  }
+}
+
+impl Display for Crs {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "EPSG:{}", self.epsg())
+  }
+}
+
+impl FromStr for Crs {
+  type Err = InvalidGeometryError;
+
+  /// Accepteert `28992`, `EPSG:28992` en de namen `rd`, `wgs84` en `etrs89`.
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let lower = s.trim().to_ascii_lowercase();
+    let crs = match lower.as_str() {
+      "rd" | "rdnew" | "rd-new" => Some(Crs::RdNew),
+      "wgs84" => Some(Crs::Wgs84),
+      "etrs89" => Some(Crs::Etrs89),
+      other => other.trim_start_matches("epsg:").parse().ok().and_then(Crs::from_epsg),
+    };
+    crs.ok_or_else(|| InvalidGeometryError::new(format!(
+      "coördinaatreferentiesysteem '{}' wordt niet ondersteund; gebruik EPSG:28992, EPSG:4326 of EPSG:4258", s.trim())))
+  }
+}
+
+/// Transformeert een geometrie van `from` naar RD New (EPSG:28992).
+///
+/// Coördinaten worden op millimeters afgerond.
+pub fn to_rd_new(geometry: &Geometry, from: Crs) -> Geometry {
+  if from == Crs::RdNew {
+    return geometry.clone();
+  }
+  let transform_ring = |ring: &Vec<Point>| -> Vec<Point> {
+    ring.iter().map(|p| {
+      let (x, y) = etrs89_to_rd(p.y, p.x);
+      Point::new(round_mm(x), round_mm(y))
+    }).collect()
+  };
+  let transform_polygon = |polygon: &Polygon| Polygon::new(
+    transform_ring(&polygon.exterior),
+    polygon.interiors.iter().map(transform_ring).collect());
+  match geometry {
+    Geometry::Polygon(polygon) => Geometry::Polygon(transform_polygon(polygon)),
+    Geometry::MultiPolygon(polygons) => Geometry::MultiPolygon(polygons.iter().map(transform_polygon).collect()),
+  }
+}
+
+fn round_mm(v: f64) -> f64 {
+  (v * 1000.0).round() / 1000.0
+}
+
+// GRS80 (ETRS89/WGS84) en Bessel 1841 ellipsoïden.
+const GRS80_A: f64 = 6378137.0;
+const GRS80_INV_F: f64 = 298.257222101;
+const BESSEL_A: f64 = 6377397.155;
+const BESSEL_INV_F: f64 = 299.1528128;
+
+// Datumtransformatie Amersfoort -> WGS 84 (EPSG:15739), position vector conventie:
+// verschuiving in meters, rotaties in boogseconden, schaal in ppm.
+const TOWGS84: [f64; 7] = [565.417, 50.3319, 465.552, -0.398957, 0.343988, -1.8774, 4.0725];
+
+// Stereografische projectie van RD (EPSG:28992).
+const RD_LAT0: f64 = 52.156_160_555_555_55;
+const RD_LON0: f64 = 5.387_638_888_888_89;
+const RD_K0: f64 = 0.9999079;
+const RD_FALSE_EASTING: f64 = 155000.0;
+const RD_FALSE_NORTHING: f64 = 463000.0;
+
+/// Transformeert ETRS89 breedte- en lengtegraad (graden) naar RD New x en y.
+///
+/// Volgt de stappen van RDNAPTRANS: van ETRS89 naar geocentrische coördinaten, een 7-parameter
+/// gelijkvormigheidstransformatie naar het Bessel-ellipsoïde, en de stereografische RD-projectie.
+/// De correctiegrid van RDNAPTRANS (maximaal enkele decimeters) wordt niet toegepast.
+pub fn etrs89_to_rd(lat: f64, lon: f64) -> (f64, f64) {
+  // Ellipsoïdische hoogte doet er voor x en y nauwelijks toe; 43 m is het Nederlandse gemiddelde boven GRS80.
+  let (x, y, z) = geographic_to_geocentric(lat, lon, 43.0, GRS80_A, GRS80_INV_F);
+  let (x, y, z) = wgs84_to_amersfoort(x, y, z);
+  let (lat_b, lon_b) = geocentric_to_geographic(x, y, z, BESSEL_A, BESSEL_INV_F);
+  oblique_stereographic(lat_b, lon_b)
+}
+
+fn geographic_to_geocentric(lat: f64, lon: f64, h: f64, a: f64, inv_f: f64) -> (f64, f64, f64) {
+  let f = 1.0 / inv_f;
+  let e2 = f * (2.0 - f);
+  let (phi, lambda) = (lat.to_radians(), lon.to_radians());
+  let nu = a / (1.0 - e2 * phi.sin().powi(2)).sqrt();
+  (
+    (nu + h) * phi.cos() * lambda.cos(),
+    (nu + h) * phi.cos() * lambda.sin(),
+    (nu * (1.0 - e2) + h) * phi.sin(),
+  )
+}
+
+fn geocentric_to_geographic(x: f64, y: f64, z: f64, a: f64, inv_f: f64) -> (f64, f64) {
+  let f = 1.0 / inv_f;
+  let e2 = f * (2.0 - f);
+  let p = (x * x + y * y).sqrt();
+  let mut phi = (z / (p * (1.0 - e2))).atan();
+  for _ in 0..10 {
+    let nu = a / (1.0 - e2 * phi.sin().powi(2)).sqrt();
+    phi = ((z + e2 * nu * phi.sin()) / p).atan();
+  }
+  (phi.to_degrees(), y.atan2(x).to_degrees())
+}
+
+/// Inverse van de Amersfoort -> WGS 84 transformatie. Door de kleine rotaties volstaat het om de parameters om te keren.
+fn wgs84_to_amersfoort(x: f64, y: f64, z: f64) -> (f64, f64, f64) {
+  let arcsec = std::f64::consts::PI / (180.0 * 3600.0);
+  let [tx, ty, tz, rx, ry, rz, ppm] = TOWGS84;
+  let (rx, ry, rz) = (-rx * arcsec, -ry * arcsec, -rz * arcsec);
+  let m = 1.0 - ppm * 1e-6;
+  let (x, y, z) = (x - tx, y - ty, z - tz);
+  (
+    m * (x - rz * y + ry * z),
+    m * (rz * x + y - rx * z),
+    m * (-ry * x + rx * y + z),
+  )
+}
+
+/// Dubbele stereografische projectie (EPSG methode 9809) van Bessel breedte- en lengtegraad naar RD.
+fn oblique_stereographic(lat: f64, lon: f64) -> (f64, f64) {
+  let f = 1.0 / BESSEL_INV_F;
+  let e2 = f * (2.0 - f);
+  let e = e2.sqrt();
+  let phi0 = RD_LAT0.to_radians();
+  let lambda0 = RD_LON0.to_radians();
+
+  let rho0 = BESSEL_A * (1.0 - e2) / (1.0 - e2 * phi0.sin().powi(2)).powf(1.5);
+  let nu0 = BESSEL_A / (1.0 - e2 * phi0.sin().powi(2)).sqrt();
+  let r = (rho0 * nu0).sqrt();
+  let n = (1.0 + e2 * phi0.cos().powi(4) / (1.0 - e2)).sqrt();
+
+  let w = |phi: f64| {
+    let sa = (1.0 + phi.sin()) / (1.0 - phi.sin());
+    let sb = (1.0 - e * phi.sin()) / (1.0 + e * phi.sin());
+    (sa * sb.powf(e)).powf(n)
+  };
+  let w1 = w(phi0);
+  let sin_chi00 = (w1 - 1.0) / (w1 + 1.0);
+  let c = (n + phi0.sin()) * (1.0 - sin_chi00) / ((n - phi0.sin()) * (1.0 + sin_chi00));
+  let w2 = c * w1;
+  let chi0 = ((w2 - 1.0) / (w2 + 1.0)).asin();
+
+  let big_lambda = n * (lon.to_radians() - lambda0);
+  let wp = c * w(lat.to_radians());
+  let chi = ((wp - 1.0) / (wp + 1.0)).asin();
+
+  let b = 1.0 + chi.sin() * chi0.sin() + chi.cos() * chi0.cos() * big_lambda.cos();
+  let x = RD_FALSE_EASTING + 2.0 * r * RD_K0 * chi.cos() * big_lambda.sin() / b;
+  let y = RD_FALSE_NORTHING + 2.0 * r * RD_K0 * (chi.sin() * chi0.cos() - chi.cos() * chi0.sin() * big_lambda.cos()) / b;
+  (x, y)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Onnauwkeurigheid door het ontbreken van de correctiegrid van RDNAPTRANS.
+  const TOLERANCE: f64 = 0.5;
+
+  fn assert_close((x, y): (f64, f64), (expected_x, expected_y): (f64, f64)) {
+    assert!((x - expected_x).abs() < TOLERANCE && (y - expected_y).abs() < TOLERANCE,
+      "({} {}) in plaats van ({} {})", x, y, expected_x, expected_y);
+  }
+
+  #[test]
+  fn transforms_the_amersfoort_reference_point() {
+    // Onze Lieve Vrouwetoren in Amersfoort, het oorsprongspunt van RD.
+    assert_close(etrs89_to_rd(52.155_174_40, 5.387_206_21), (155000.0, 463000.0));
+  }
+
+  #[test]
+  fn transforms_geometries_to_rd_new_in_millimeters() {
+    let geometry = Geometry::Polygon(Polygon::new(vec![
+      Point::new(5.387_206_21, 52.155_174_40),
+      Point::new(5.387_206_21, 52.155_264_40),
+      Point::new(5.387_306_21, 52.155_174_40),
+      Point::new(5.387_206_21, 52.155_174_40),
+    ], Vec::new()));
+    let exterior = match to_rd_new(&geometry, Crs::Wgs84) {
+      Geometry::Polygon(polygon) => polygon.exterior,
+      other => panic!("{:?}", other),
+    };
+    assert_close((exterior[0].x, exterior[0].y), (155000.0, 463000.0));
+    assert!(exterior.iter().all(|p| p.x == round_mm(p.x) && p.y == round_mm(p.y)), "{:?}", exterior);
+    assert_eq!(exterior.first(), exterior.last());
+    assert_eq!(to_rd_new(&geometry, Crs::RdNew), geometry);
+  }
+
+  #[test]
+  fn parses_epsg_codes_and_names() {
+    assert_eq!("EPSG:28992".parse::<Crs>().unwrap(), Crs::RdNew);
+    assert_eq!(" 4326 ".parse::<Crs>().unwrap(), Crs::Wgs84);
+    assert_eq!("ETRS89".parse::<Crs>().unwrap(), Crs::Etrs89);
+    assert!("EPSG:3857".parse::<Crs>().is_err());
+  }
+}
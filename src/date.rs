@@ -0,0 +1,45 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+
+/// Seconden in een dag.
+pub const SECONDS_PER_DAY: u64 = 86_400;
+
+/// Datum (jaar, maand, dag) in UTC van `time`. Tijdstippen vóór 1970 worden 1970-01-01.
+pub fn utc_date(time: SystemTime) -> (u64, u64, u64) {
+  civil_from_days(time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs()) / SECONDS_PER_DAY)
+}
+
+/// Datum (jaar, maand, dag) van het aantal dagen sinds 1970-01-01 in de proleptische Gregoriaanse kalender, volgens
+/// het algoritme van Howard Hinnant.
+pub fn civil_from_days(days: u64) -> (u64, u64, u64) {
+  let z = days + 719_468;
+  let era = z / 146_097;
+  let doe = z - era * 146_097;
+  let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+  let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+  let mp = (5 * doy + 2) / 153;
+  let day = doy - (153 * mp + 2) / 5 + 1;
+  let month = if mp < 10 { mp + 3 } else { mp - 9 };
+  let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+  (year, month, day)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn converts_days_to_dates() {
+    assert_eq!(civil_from_days(0), (1970, 1, 1));
+    assert_eq!(civil_from_days(11_016), (2000, 2, 29));
+    assert_eq!(civil_from_days(19_631), (2023, 10, 1));
+    // 2100 is geen schrikkeljaar.
+    assert_eq!(civil_from_days(47_540), (2100, 2, 28));
+    assert_eq!(civil_from_days(47_541), (2100, 3, 1));
+  }
+}
@@ -0,0 +1,354 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::error::Error;
+use std::fmt::Display;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::geometry::{FeatureGeometry, Point};
+use crate::gml::{Feature, Layer};
+
+
+/// Teksthoogte in meters van perceelnummers.
+pub const PERCEELNUMMER_TEXT_HEIGHT: f64 = 1.0;
+/// Teksthoogte in meters van straatnamen en andere labels.
+pub const LABEL_TEXT_HEIGHT: f64 = 2.0;
+
+/// DXF-laag en AutoCAD kleurnummer per feature type. Onbekende feature types krijgen een laag met hun eigen naam.
+const LAYER_STYLES: &[(&str, &str, i32)] = &[
+  ("perceel", "PERCEEL", 7),
+  ("kadastralegrens", "KADASTRALEGRENS", 1),
+  ("pand", "PAND", 8),
+  ("openbareruimtelabel", "OPENBARERUIMTELABEL", 5),
+  ("nummeraanduidingreeks", "NUMMERAANDUIDINGREEKS", 3),
+];
+const PERCEELNUMMER_LAYER: (&str, i32) = ("PERCEELNUMMER", 7);
+
+/// Eigenschappen waarvan de waarde als tekst van een label gebruikt wordt, op volgorde van voorkeur.
+const LABEL_TEXT_PROPERTIES: &[&str] = &["openbareRuimteNaam", "tekst", "huisnummer"];
+
+/// Schrijft alle lagen naar één DXF-bestand (AutoCAD R12) in RD New-coördinaten, met een DXF-laag per feature type.
+///
+/// Vlakken worden gesloten polylijnen (één per ring), lijnen open polylijnen en punten `POINT`s. Van percelen komt
+/// het perceelnummer als tekst op het plaatsingspunt in de laag `PERCEELNUMMER`. Labels (zoals straatnamen van
+/// `openbareruimtelabel`) worden tekst op hun positie. Beide behouden hun rotatie (in graden, tegen de klok in).
+/// Het bestand is puur ASCII; tekens als `ë` worden `\U+00EB`.
+pub fn write_dxf(layers: &[Layer], path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let mut writer = DxfWriter { out: BufWriter::new(File::create(path)?) };
+
+  let mut dxf_layers: Vec<(String, i32)> = layers.iter().map(|layer| layer_style(&layer.name)).collect();
+  if layers.iter().any(|layer| layer.name == "perceel") {
+    dxf_layers.push((String::from(PERCEELNUMMER_LAYER.0), PERCEELNUMMER_LAYER.1));
+  }
+  writer.header(&dxf_layers)?;
+
+  writer.pair(0, "SECTION")?;
+  writer.pair(2, "ENTITIES")?;
+  for layer in layers {
+    let (dxf_layer, _) = layer_style(&layer.name);
+    let labels = labels_of(layer);
+    for feature in &layer.features {
+      // Van labellagen is de positie zelf geen tekening, alleen de tekst.
+      if !labels {
+        if let Some(geometry) = &feature.geometry {
+          writer.geometry(&dxf_layer, geometry)?;
+        }
+      }
+      if layer.name == "perceel" {
+        if let Some((text, position, rotation)) = perceelnummer(feature) {
+          writer.text(PERCEELNUMMER_LAYER.0, &text, position, rotation, PERCEELNUMMER_TEXT_HEIGHT)?;
+        }
+      } else if labels {
+        for (text, position, rotation) in label_texts(feature) {
+          writer.text(&dxf_layer, &text, position, rotation, LABEL_TEXT_HEIGHT)?;
+        }
+      }
+    }
+  }
+  writer.pair(0, "ENDSEC")?;
+  writer.pair(0, "EOF")?;
+  writer.out.flush()?;
+  Ok(())
+}
+
+fn layer_style(name: &str) -> (String, i32) {
+  LAYER_STYLES.iter()
+    .find(|(feature_type, _, _)| *feature_type == name)
+    .map(|(_, dxf_layer, color)| (String::from(*dxf_layer), *color))
+    .unwrap_or_else(|| (name.to_ascii_uppercase(), 7))
+}
+
+/// Of de features van de laag labels zijn: alleen een punt en een tekst.
+fn labels_of(layer: &Layer) -> bool {
+  matches!(layer.name.as_str(), "openbareruimtelabel" | "nummeraanduidingreeks")
+}
+
+/// Laatste deel van een platgeslagen sleutel: `kadastraleAanduiding.perceelnummer` wordt `perceelnummer`.
+fn last_segment(key: &str) -> &str {
+  key.rsplit('.').next().unwrap_or(key)
+}
+
+fn property_by_name<'a>(feature: &'a Feature, name: &str) -> Option<&'a str> {
+  feature.properties.iter().find(|(k, _)| last_segment(k) == name).map(|(_, v)| v.as_str())
+}
+
+/// Tekst, plaatsingspunt en rotatie van het perceelnummer.
+fn perceelnummer(feature: &Feature) -> Option<(String, Point, f64)> {
+  let text = property_by_name(feature, "perceelnummer")?;
+  let position = feature.other_geometries.iter()
+    .find(|(k, g)| k.to_ascii_lowercase().contains("plaatscoordina") && matches!(g, FeatureGeometry::Point(_)))
+    .or_else(|| feature.other_geometries.iter().find(|(_, g)| matches!(g, FeatureGeometry::Point(_))))
+    .and_then(|(_, g)| match g {
+      FeatureGeometry::Point(point) => Some(*point),
+      _ => None,
+    })?;
+  let rotation = property_by_name(feature, "perceelnummerRotatie").and_then(|r| r.parse().ok()).unwrap_or(0.0);
+  Some((String::from(text), position, rotation))
+}
+
+/// Teksten van een label. Een label kan meerdere posities hebben; de n-de hoek hoort bij de n-de positie.
+fn label_texts(feature: &Feature) -> Vec<(String, Point, f64)> {
+  let text = match LABEL_TEXT_PROPERTIES.iter().find_map(|name| property_by_name(feature, name)) {
+    Some(text) => text,
+    None => return Vec::new(),
+  };
+  let rotations: Vec<f64> = feature.properties.iter()
+    .filter(|(k, _)| last_segment(k) == "hoek")
+    .filter_map(|(_, v)| v.parse().ok())
+    .collect();
+  let positions = feature.geometry.iter().chain(feature.other_geometries.iter().map(|(_, g)| g))
+    .flat_map(|g| match g {
+      FeatureGeometry::Point(point) => vec![*point],
+      FeatureGeometry::MultiPoint(points) => points.clone(),
+      _ => Vec::new(),
+    });
+  positions.enumerate()
+    .map(|(i, position)| (String::from(text), position, rotations.get(i).copied().unwrap_or(0.0)))
+    .collect()
+}
+
+/// R12 kent geen UTF-8: het bestand is in de codepagina van `$DWGCODEPAGE`. Alles buiten ASCII wordt daarom als
+/// `\U+XXXX` (UTF-16) geschreven, wat AutoCAD en andere lezers weer als dat teken tonen, ongeacht de codepagina.
+fn escape_non_ascii(text: &str) -> String {
+  if text.is_ascii() {
+    return String::from(text);
+  }
+  let mut escaped = String::with_capacity(text.len());
+  for c in text.chars() {
+    if c.is_ascii() {
+      escaped.push(c);
+    } else {
+      for unit in c.encode_utf16(&mut [0; 2]) {
+        escaped.push_str(&format!("\\U+{:04X}", unit));
+      }
+    }
+  }
+  escaped
+}
+
+struct DxfWriter<W: Write> {
+  out: W,
+}
+
+impl<W: Write> DxfWriter<W> {
+  fn pair<V: Display>(&mut self, code: i32, value: V) -> std::io::Result<()> {
+    write!(self.out, "{:>3}\n{}\n", code, escape_non_ascii(&value.to_string()))
+  }
+
+  fn header(&mut self, layers: &[(String, i32)]) -> std::io::Result<()> {
+    self.pair(0, "SECTION")?;
+    self.pair(2, "HEADER")?;
+    self.pair(9, "$ACADVER")?;
+    self.pair(1, "AC1009")?;
+    self.pair(9, "$DWGCODEPAGE")?;
+    self.pair(3, "ANSI_1252")?;
+    self.pair(0, "ENDSEC")?;
+
+    self.pair(0, "SECTION")?;
+    self.pair(2, "TABLES")?;
+    self.pair(0, "TABLE")?;
+    self.pair(2, "LAYER")?;
+    self.pair(70, layers.len())?;
+    for (name, color) in layers {
+      self.pair(0, "LAYER")?;
+      self.pair(2, name)?;
+      self.pair(70, 0)?;
+      self.pair(62, color)?;
+      self.pair(6, "CONTINUOUS")?;
+    }
+    self.pair(0, "ENDTAB")?;
+    self.pair(0, "ENDSEC")
+  }
+
+  fn geometry(&mut self, layer: &str, geometry: &FeatureGeometry) -> std::io::Result<()> {
+    match geometry {
+      FeatureGeometry::Point(point) => self.point(layer, point),
+      FeatureGeometry::MultiPoint(points) => points.iter().try_for_each(|point| self.point(layer, point)),
+      FeatureGeometry::LineString(points) => self.polyline(layer, points, false),
+      FeatureGeometry::MultiLineString(lines) => lines.iter().try_for_each(|line| self.polyline(layer, line, false)),
+      FeatureGeometry::Polygon(polygon) => polygon.rings().try_for_each(|ring| self.ring(layer, ring)),
+      FeatureGeometry::MultiPolygon(polygons) => polygons.iter().flat_map(|p| p.rings()).try_for_each(|ring| self.ring(layer, ring)),
+    }
+  }
+
+  fn point(&mut self, layer: &str, point: &Point) -> std::io::Result<()> {
+    self.pair(0, "POINT")?;
+    self.pair(8, layer)?;
+    self.coordinates(10, point)
+  }
+
+  /// Een ring als gesloten polylijn, zonder het herhaalde eindpunt.
+  fn ring(&mut self, layer: &str, ring: &[Point]) -> std::io::Result<()> {
+    let points = if ring.len() > 1 && ring.first() == ring.last() { &ring[..ring.len() - 1] } else { ring };
+    self.polyline(layer, points, true)
+  }
+
+  fn polyline(&mut self, layer: &str, points: &[Point], closed: bool) -> std::io::Result<()> {
+    self.pair(0, "POLYLINE")?;
+    self.pair(8, layer)?;
+    self.pair(66, 1)?;
+    self.coordinates(10, &Point::new(0.0, 0.0))?;
+    self.pair(70, if closed { 1 } else { 0 })?;
+    for point in points {
+      self.pair(0, "VERTEX")?;
+      self.pair(8, layer)?;
+      self.coordinates(10, point)?;
+    }
+    self.pair(0, "SEQEND")?;
+    self.pair(8, layer)
+  }
+
+  /// Tekst, horizontaal en verticaal gecentreerd op `position`.
+  fn text(&mut self, layer: &str, text: &str, position: Point, rotation: f64, height: f64) -> std::io::Result<()> {
+    self.pair(0, "TEXT")?;
+    self.pair(8, layer)?;
+    self.coordinates(10, &position)?;
+    self.pair(40, height)?;
+    self.pair(1, text.replace(['\r', '\n'], " "))?;
+    self.pair(50, rotation)?;
+    self.pair(72, 1)?;
+    self.coordinates(11, &position)?;
+    self.pair(73, 2)
+  }
+
+  fn coordinates(&mut self, code: i32, point: &Point) -> std::io::Result<()> {
+    self.pair(code, point.x)?;
+    self.pair(code + 10, point.y)?;
+    self.pair(code + 20, 0.0)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+  use crate::geometry::Polygon;
+
+  fn feature(feature_type: &str, geometry: FeatureGeometry, properties: &[(&str, &str)], other_geometries: Vec<(&str, FeatureGeometry)>) -> Feature {
+    Feature {
+      feature_type: String::from(feature_type),
+      id: None,
+      properties: properties.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect(),
+      geometry: Some(geometry),
+      geometry_property: None,
+      other_geometries: other_geometries.into_iter().map(|(k, g)| (String::from(k), g)).collect(),
+    }
+  }
+
+  /// Schrijft `layers` en geeft de groepcodes met hun waarden terug.
+  fn write(layers: &[Layer]) -> Vec<(i32, String)> {
+    let path = std::env::temp_dir().join(format!("dkkdownload-dxf-test-{}-{}.dxf", std::process::id(), rand::random::<u32>()));
+    write_dxf(layers, &path).unwrap();
+    let text = fs::read_to_string(&path).unwrap();
+    let _ = fs::remove_file(&path);
+    let lines: Vec<&str> = text.lines().collect();
+    lines.chunks(2).map(|pair| (pair[0].trim().parse().unwrap(), String::from(pair[1]))).collect()
+  }
+
+  /// De entiteiten van de sectie `name`, elk als de groepcodes vanaf de `0` waar hij mee begint.
+  fn section<'a>(pairs: &'a [(i32, String)], name: &str) -> Vec<&'a [(i32, String)]> {
+    let start = pairs.windows(2).position(|w| w[0] == (0, String::from("SECTION")) && w[1] == (2, String::from(name))).unwrap() + 2;
+    let end = start + pairs[start..].iter().position(|pair| *pair == (0, String::from("ENDSEC"))).unwrap();
+    let mut entities: Vec<&[(i32, String)]> = Vec::new();
+    let mut begin = start;
+    for i in start + 1..=end {
+      if pairs[i].0 == 0 {
+        entities.push(&pairs[begin..i]);
+        begin = i;
+      }
+    }
+    entities
+  }
+
+  fn value(entity: &[(i32, String)], code: i32) -> &str {
+    entity.iter().find(|(c, _)| *c == code).map(|(_, v)| v.as_str()).unwrap()
+  }
+
+  #[test]
+  fn writes_a_layer_per_feature_type_with_its_entities() {
+    let square = Polygon::new(vec![
+      Point::new(155000.0, 463000.0), Point::new(155000.0, 463010.0), Point::new(155010.0, 463010.0),
+      Point::new(155010.0, 463000.0), Point::new(155000.0, 463000.0),
+    ], Vec::new());
+    let layers = vec![
+      Layer { name: String::from("perceel"), features: vec![feature("Perceel", FeatureGeometry::Polygon(square),
+        &[("kadastraleAanduiding.perceelnummer", "101"), ("perceelnummerRotatie", "12.5")],
+        vec![("plaatscoordinaten", FeatureGeometry::Point(Point::new(155005.0, 463005.0)))])] },
+      Layer { name: String::from("openbareruimtelabel"), features: vec![feature("OpenbareRuimteLabel",
+        FeatureGeometry::MultiPoint(vec![Point::new(155001.0, 463001.0), Point::new(155009.0, 463009.0)]),
+        &[("openbareRuimteNaam", "Stationsstraat"), ("hoek", "90"), ("hoek", "-45")], Vec::new())] },
+      Layer { name: String::from("wegdeel"), features: vec![feature("Wegdeel",
+        FeatureGeometry::LineString(vec![Point::new(155000.0, 463000.0), Point::new(155010.0, 463000.0)]), &[], Vec::new())] },
+    ];
+    let pairs = write(&layers);
+
+    let header = section(&pairs, "HEADER");
+    assert_eq!(header[0], &[
+      (9, String::from("$ACADVER")), (1, String::from("AC1009")),
+      (9, String::from("$DWGCODEPAGE")), (3, String::from("ANSI_1252")),
+    ][..]);
+
+    let tables = section(&pairs, "TABLES");
+    assert_eq!(value(tables[0], 2), "LAYER");
+    assert_eq!(value(tables[0], 70), "4");
+    let dxf_layers: Vec<(&str, &str)> = tables.iter().filter(|entity| entity[0].1 == "LAYER")
+      .map(|entity| (value(entity, 2), value(entity, 62)))
+      .collect();
+    assert_eq!(dxf_layers, vec![("PERCEEL", "7"), ("OPENBARERUIMTELABEL", "5"), ("WEGDEEL", "7"), ("PERCEELNUMMER", "7")]);
+
+    let entities = section(&pairs, "ENTITIES");
+    let polylines: Vec<(&str, &str, usize)> = entities.iter().enumerate()
+      .filter(|(_, entity)| entity[0].1 == "POLYLINE")
+      .map(|(i, entity)| (value(entity, 8), value(entity, 70), entities[i + 1..].iter().take_while(|e| e[0].1 == "VERTEX").count()))
+      .collect();
+    // De ring zonder het herhaalde eindpunt, gesloten; de lijn open.
+    assert_eq!(polylines, vec![("PERCEEL", "1", 4), ("WEGDEEL", "0", 2)]);
+
+    let texts: Vec<(&str, &str, &str, &str, &str, &str)> = entities.iter().filter(|entity| entity[0].1 == "TEXT")
+      .map(|entity| (value(entity, 8), value(entity, 1), value(entity, 11), value(entity, 21), value(entity, 50), value(entity, 40)))
+      .collect();
+    assert_eq!(texts, vec![
+      ("PERCEELNUMMER", "101", "155005", "463005", "12.5", "1"),
+      ("OPENBARERUIMTELABEL", "Stationsstraat", "155001", "463001", "90", "2"),
+      ("OPENBARERUIMTELABEL", "Stationsstraat", "155009", "463009", "-45", "2"),
+    ]);
+    // Labels hebben alleen tekst, geen punt.
+    assert!(!entities.iter().any(|entity| entity[0].1 == "POINT"));
+    assert_eq!(pairs.last(), Some(&(0, String::from("EOF"))));
+  }
+
+  #[test]
+  fn escapes_text_outside_ascii() {
+    let layers = vec![Layer { name: String::from("openbareruimtelabel"), features: vec![feature("OpenbareRuimteLabel",
+      FeatureGeometry::Point(Point::new(155000.0, 463000.0)), &[("openbareRuimteNaam", "Heuvelrugweg 1ª Réünie 😀")], Vec::new())] }];
+    let pairs = write(&layers);
+    let text = section(&pairs, "ENTITIES").into_iter().find(|entity| entity[0].1 == "TEXT").map(|entity| value(entity, 1).to_string());
+    assert_eq!(text.as_deref(), Some("Heuvelrugweg 1\\U+00AA R\\U+00E9\\U+00FCnie \\U+D83D\\U+DE00"));
+    assert!(pairs.iter().all(|(_, value)| value.is_ascii()));
+  }
+}
@@ -0,0 +1,401 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::error::Error;
+use std::fmt::Display;
+use std::io;
+use std::num::{ParseFloatError, ParseIntError};
+use std::path::{Path, PathBuf};
+
+
+/// Wordt teruggegeven wanneer de PDOK API antwoordt met een status code die we niet verwachten.
+#[derive(Debug)]
+pub struct UnexpectedStatusCodeError {
+  status: reqwest::StatusCode,
+  url: reqwest::Url,
+  response_text: Option<String>,
+  method: reqwest::Method,
+}
+
+impl UnexpectedStatusCodeError {
+  pub fn new(mut response: reqwest::Response, method: reqwest::Method) -> Self {
+    let response_text = response.text().ok();
+    Self { status: response.status(), url: response.url().clone(), response_text, method }
+  }
+
+  pub fn status(&self) -> reqwest::StatusCode {
+    self.status
+  }
+
+  /// Of de PDOK API het request zelf weigert (4xx), in plaats van tijdelijk niet beschikbaar te zijn (5xx of 429).
+  pub fn is_rejection(&self) -> bool {
+    let status = self.status;
+    status.is_client_error() && status != reqwest::StatusCode::TOO_MANY_REQUESTS
+  }
+}
+
+impl std::error::Error for UnexpectedStatusCodeError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    None
+  }
+}
+
+impl Display for UnexpectedStatusCodeError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    writeln!(f, "Onverwachte status code ({}) gekregen als antwoord op {} {}",
+        self.status, self.method, self.url)?;
+    if let Some(text) = &self.response_text {
+      write!(f, "De PDOK API zegt:\n{}", text)?;
+    }
+    Ok(())
+  }
+}
+
+/// Wordt teruggegeven wanneer een antwoord van de PDOK API niet de verwachte inhoud heeft.
+#[derive(Debug)]
+pub struct InvalidResponseError {
+  message: String,
+}
+
+impl InvalidResponseError {
+  pub fn new<S: Into<String>>(message: S) -> Self {
+    Self { message: message.into() }
+  }
+}
+
+impl std::error::Error for InvalidResponseError {}
+
+impl Display for InvalidResponseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Ongeldig antwoord van de PDOK API: {}", self.message)
+  }
+}
+
+/// Wordt teruggegeven wanneer de PDOK API meldt dat een download request mislukt is.
+#[derive(Debug)]
+pub struct JobFailedError {
+  request_id: String,
+  status: String,
+}
+
+impl JobFailedError {
+  pub fn new<S: Into<String>, T: Into<String>>(request_id: S, status: T) -> Self {
+    Self { request_id: request_id.into(), status: status.into() }
+  }
+
+  pub fn request_id(&self) -> &str {
+    &self.request_id
+  }
+}
+
+impl std::error::Error for JobFailedError {}
+
+impl Display for JobFailedError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Download request {} is door de PDOK API afgebroken met status {}", self.request_id, self.status)
+  }
+}
+
+/// Wordt teruggegeven wanneer een Well-Known Text (WKT) string niet gelezen kan worden.
+#[derive(Debug)]
+pub struct WktParseError {
+  message: String,
+  position: usize,
+}
+
+impl WktParseError {
+  pub fn new<S: Into<String>>(message: S, position: usize) -> Self {
+    Self { message: message.into(), position }
+  }
+
+  /// Positie (in tekens, vanaf 0) in de WKT string waar de fout gevonden is.
+  pub fn position(&self) -> usize {
+    self.position
+  }
+}
+
+impl std::error::Error for WktParseError {}
+
+impl Display for WktParseError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Ongeldige WKT op positie {}: {}", self.position, self.message)
+  }
+}
+
+/// Wordt teruggegeven wanneer een polygon niet geschikt is als geofilter.
+#[derive(Debug)]
+pub struct InvalidGeometryError {
+  message: String,
+}
+
+impl InvalidGeometryError {
+  pub fn new<S: Into<String>>(message: S) -> Self {
+    Self { message: message.into() }
+  }
+}
+
+impl std::error::Error for InvalidGeometryError {}
+
+impl Display for InvalidGeometryError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Ongeldig polygon: {}", self.message)
+  }
+}
+
+/// Wordt teruggegeven wanneer een laag (feature type) niet bestaat in de DKK Download API.
+#[derive(Debug)]
+pub struct UnknownLayerError {
+  name: String,
+  suggestions: Vec<&'static str>,
+}
+
+impl UnknownLayerError {
+  pub fn new<S: Into<String>>(name: S, suggestions: Vec<&'static str>) -> Self {
+    Self { name: name.into(), suggestions }
+  }
+
+  pub fn name(&self) -> &str {
+    &self.name
+  }
+
+  /// Bestaande lagen die op de gegeven naam lijken.
+  pub fn suggestions(&self) -> &[&'static str] {
+    &self.suggestions
+  }
+}
+
+impl std::error::Error for UnknownLayerError {}
+
+impl Display for UnknownLayerError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Onbekende laag '{}'.", self.name)?;
+    if !self.suggestions.is_empty() {
+      write!(f, " Bedoelde je {}?", self.suggestions.join(" of "))?;
+    }
+    write!(f, " Gebruik 'list-layers' voor een overzicht van alle lagen.")
+  }
+}
+
+/// Wordt teruggegeven bij een ongeldige instelling, uit een optie, omgevingsvariabele of configuratiebestand.
+#[derive(Debug)]
+pub struct ConfigError {
+  message: String,
+}
+
+impl ConfigError {
+  pub fn new<S: Into<String>>(message: S) -> Self {
+    Self { message: message.into() }
+  }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl Display for ConfigError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Ongeldige configuratie: {}", self.message)
+  }
+}
+
+/// Wordt teruggegeven wanneer de uitvoer al bestaat en niet overschreven mag worden.
+#[derive(Debug)]
+pub struct OutputExistsError {
+  path: PathBuf,
+}
+
+impl OutputExistsError {
+  pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+    Self { path: path.into() }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl std::error::Error for OutputExistsError {}
+
+impl Display for OutputExistsError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} bestaat al. Gebruik --force om het te overschrijven.", self.path.display())
+  }
+}
+
+/// Wordt teruggegeven wanneer het invoerbestand van `batch` niet gelezen kan worden.
+#[derive(Debug)]
+pub struct BatchInputError {
+  location: String,
+  message: String,
+}
+
+impl BatchInputError {
+  /// `location` geeft aan waar in de invoer de fout zit, bijv. `regel 3` of `gebied 'noord'`.
+  pub fn new<L: Into<String>, S: Into<String>>(location: L, message: S) -> Self {
+    Self { location: location.into(), message: message.into() }
+  }
+}
+
+impl std::error::Error for BatchInputError {}
+
+impl Display for BatchInputError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "Ongeldige batch invoer ({}): {}", self.location, self.message)
+  }
+}
+
+/// Wordt teruggegeven wanneer een of meer gebieden van een batch mislukt zijn. De fout per gebied is al gemeld.
+#[derive(Debug)]
+pub struct BatchError {
+  failed: Vec<String>,
+  total: usize,
+}
+
+impl BatchError {
+  pub fn new(failed: Vec<String>, total: usize) -> Self {
+    Self { failed, total }
+  }
+
+  /// Namen van de mislukte gebieden.
+  pub fn failed(&self) -> &[String] {
+    &self.failed
+  }
+
+  pub fn total(&self) -> usize {
+    self.total
+  }
+}
+
+impl std::error::Error for BatchError {}
+
+impl Display for BatchError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} van {} gebieden mislukt: {}", self.failed.len(), self.total, self.failed.join(", "))
+  }
+}
+
+/// Fout van dkkdownload, ingedeeld naar oorzaak. Elke soort heeft een eigen exit code, zodat scripts ze uit elkaar
+/// kunnen houden:
+///
+/// | Code | Soort                                                                  |
+/// |------|------------------------------------------------------------------------|
+/// | 0    | Geslaagd                                                               |
+/// | 1    | `Other`: overige fouten                                                |
+/// | 2    | `InvalidInput`: ongeldige opties, polygon, lagen of configuratie, of   |
+/// |      | uitvoer die al bestaat                                                 |
+/// | 3    | `Network`: geen verbinding, time-out, of PDOK API onbereikbaar (5xx)   |
+/// | 4    | `Rejected`: de PDOK API weigert het request (4xx)                      |
+/// | 5    | `JobFailed`: download request mislukt, of onbruikbaar antwoord of ZIP  |
+/// | 6    | `Io`: lezen of schrijven van lokale bestanden mislukt                  |
+/// | 7    | `PartialFailure`: een deel van de gebieden van een batch is mislukt    |
+#[derive(Debug)]
+pub enum DkkError {
+  InvalidInput(Box<dyn Error + Send + Sync>),
+  /// Ook na opnieuw proberen; bij een 5xx of 429 is dit een `UnexpectedStatusCodeError`.
+  Network(Box<dyn Error + Send + Sync>),
+  Rejected(Box<UnexpectedStatusCodeError>),
+  JobFailed(Box<dyn Error + Send + Sync>),
+  Io(Box<dyn Error + Send + Sync>),
+  Other(Box<dyn Error + Send + Sync>),
+  PartialFailure(Box<BatchError>),
+}
+
+impl DkkError {
+  pub fn exit_code(&self) -> i32 {
+    match self {
+      DkkError::Other(_) => 1,
+      DkkError::InvalidInput(_) => 2,
+      DkkError::Network(_) => 3,
+      DkkError::Rejected(_) => 4,
+      DkkError::JobFailed(_) => 5,
+      DkkError::Io(_) => 6,
+      DkkError::PartialFailure(_) => 7,
+    }
+  }
+
+  /// Naam van de soort fout, zoals in het `error` event van `--log-format json`.
+  pub fn kind_name(&self) -> &'static str {
+    match self {
+      DkkError::Other(_) => "other",
+      DkkError::InvalidInput(_) => "invalid_input",
+      DkkError::Network(_) => "network",
+      DkkError::Rejected(_) => "rejected",
+      DkkError::JobFailed(_) => "job_failed",
+      DkkError::Io(_) => "io",
+      DkkError::PartialFailure(_) => "partial_failure",
+    }
+  }
+
+  /// De onderliggende fout.
+  pub fn inner(&self) -> &(dyn Error + 'static) {
+    match self {
+      DkkError::Rejected(e) => e.as_ref(),
+      DkkError::PartialFailure(e) => e.as_ref(),
+      DkkError::InvalidInput(e) | DkkError::Network(e) | DkkError::JobFailed(e) | DkkError::Io(e) | DkkError::Other(e) => e.as_ref(),
+    }
+  }
+}
+
+impl Error for DkkError {
+  fn source(&self) -> Option<&(dyn Error + 'static)> {
+    self.inner().source()
+  }
+}
+
+impl Display for DkkError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    Display::fmt(self.inner(), f)
+  }
+}
+
+impl From<UnexpectedStatusCodeError> for DkkError {
+  fn from(e: UnexpectedStatusCodeError) -> Self {
+    if e.is_rejection() { DkkError::Rejected(Box::new(e)) } else { DkkError::Network(Box::new(e)) }
+  }
+}
+
+macro_rules! dkk_error_from {
+  ($variant:ident: $($error:ty),+) => {
+    $(
+      impl From<$error> for DkkError {
+        fn from(e: $error) -> Self {
+          DkkError::$variant(Box::new(e))
+        }
+      }
+    )+
+  };
+}
+
+dkk_error_from!(InvalidInput: WktParseError, InvalidGeometryError, UnknownLayerError, ConfigError, OutputExistsError, BatchInputError, ParseIntError, ParseFloatError);
+dkk_error_from!(Network: reqwest::Error);
+dkk_error_from!(JobFailed: InvalidResponseError, JobFailedError, json::Error);
+dkk_error_from!(Io: io::Error);
+
+/// Deelt een fout uit een van de andere modules in naar soort.
+impl From<Box<dyn Error + Send + Sync>> for DkkError {
+  fn from(e: Box<dyn Error + Send + Sync>) -> Self {
+    let e = match e.downcast::<DkkError>() {
+      Ok(e) => return *e,
+      Err(e) => e,
+    };
+    let e = match e.downcast::<UnexpectedStatusCodeError>() {
+      Ok(e) if e.is_rejection() => return DkkError::Rejected(e),
+      Ok(e) => return DkkError::Network(e),
+      Err(e) => e,
+    };
+    if e.is::<WktParseError>() || e.is::<InvalidGeometryError>() || e.is::<UnknownLayerError>() || e.is::<ConfigError>()
+        || e.is::<OutputExistsError>() || e.is::<BatchInputError>() || e.is::<ParseIntError>() || e.is::<ParseFloatError>() {
+      DkkError::InvalidInput(e)
+    } else if e.is::<reqwest::Error>() {
+      DkkError::Network(e)
+    } else if e.is::<InvalidResponseError>() || e.is::<JobFailedError>() || e.is::<json::Error>()
+        || e.is::<zip::result::ZipError>() || e.is::<quick_xml::Error>() {
+      // ZIP- en GML-bestanden komen van de PDOK API.
+      DkkError::JobFailed(e)
+    } else if e.is::<io::Error>() || e.is::<rusqlite::Error>() {
+      DkkError::Io(e)
+    } else {
+      DkkError::Other(e)
+    }
+  }
+}
@@ -0,0 +1,244 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use json::object;
+use json::JsonValue;
+
+use crate::cache::CacheEntry;
+use crate::checksum::Checksum;
+use crate::client::{DownloadRequest, RequestKind};
+use crate::clip::ClipStats;
+use crate::error::{DkkError, UnexpectedStatusCodeError};
+use crate::extract::ExtractedFile;
+use crate::history::HistoryRun;
+use crate::manifest::format_timestamp;
+use crate::merge::LayerMergeStats;
+
+
+/// Minimale tijd tussen twee `download_progress` events.
+const DOWNLOAD_PROGRESS_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Een gebeurtenis tijdens een run van dkkdownload, voor `--log-format json`.
+///
+/// Elk event wordt één regel JSON met een `event` veld dat de soort aangeeft, bijvoorbeeld
+/// `{"event":"submitted","request_id":"...","kind":"full"}`.
+#[derive(Debug)]
+pub enum Event<'a> {
+  /// Het gebied wordt in `count` tegels opgedeeld die elk een eigen download request krijgen.
+  Tiles { count: usize, area_km2: f64 },
+  /// Een download request is ingediend.
+  Submitted(&'a DownloadRequest),
+  /// De PDOK API is nog bezig; `percent` indien de API een voortgang meegeeft.
+  Progress { request: &'a DownloadRequest, percent: Option<u64> },
+  /// Hetzelfde gebied met dezelfde lagen staat in de cache; er wordt niets bij de PDOK API aangevraagd.
+  CacheHit(&'a CacheEntry),
+  /// Het ZIP-bestand staat klaar.
+  Ready { request: &'a DownloadRequest, download_url: &'a str },
+  /// Aantal bytes van het ZIP-bestand dat binnen is, en de totale grootte indien bekend.
+  DownloadProgress { bytes: u64, total: Option<u64> },
+  /// Resultaat van het samenvoegen van de tegels, per laag.
+  Merged(&'a LayerMergeStats),
+  /// Resultaat van `--clip`, per laag.
+  Clipped(&'a ClipStats),
+  Extracted(&'a ExtractedFile),
+  /// De run is geslaagd. `path` is de uitvoer (`None` voor stdout); grootte en checksum zijn die van het ZIP-bestand.
+  Completed { path: Option<&'a Path>, checksum: &'a Checksum },
+  Error(&'a DkkError),
+  /// Een gebied van `batch` is gedownload naar `path`, of met `cached` uit de cache gekopieerd.
+  AreaCompleted { name: &'a str, request: &'a DownloadRequest, path: &'a Path, checksum: &'a Checksum, cached: bool },
+  /// Een gebied van `batch` is mislukt; `request` is `None` als het indienen al mislukte. De batch gaat verder.
+  AreaFailed { name: &'a str, request: Option<&'a DownloadRequest>, error: &'a DkkError },
+}
+
+impl Event<'_> {
+  pub fn to_json(&self) -> JsonValue {
+    match self {
+      Event::Tiles { count, area_km2 } => object!{ "event" => "tiles", "count" => *count, "area_km2" => *area_km2 },
+      Event::Submitted(request) => object!{
+        "event" => "submitted",
+        "request_id" => request.id.as_str(),
+        "kind" => kind_name(request.kind)
+      },
+      Event::Progress { request, percent } => object!{
+        "event" => "progress",
+        "request_id" => request.id.as_str(),
+        "percent" => *percent
+      },
+      Event::CacheHit(entry) => object!{
+        "event" => "cache_hit",
+        "request_ids" => entry.request_ids.clone(),
+        "created_at" => format_timestamp(entry.created_at),
+        "size" => entry.checksum.size,
+        "sha256" => entry.checksum.sha256.as_str()
+      },
+      Event::Ready { request, download_url } => object!{
+        "event" => "ready",
+        "request_id" => request.id.as_str(),
+        "download_url" => *download_url
+      },
+      Event::DownloadProgress { bytes, total } => object!{ "event" => "download_progress", "bytes" => *bytes, "total" => *total },
+      Event::Merged(stats) => object!{
+        "event" => "merged",
+        "layer" => stats.name.as_str(),
+        "features" => stats.features,
+        "duplicates" => stats.duplicates
+      },
+      Event::Clipped(stats) => object!{
+        "event" => "clipped",
+        "layer" => stats.name.as_str(),
+        "kept" => stats.kept,
+        "clipped" => stats.clipped,
+        "dropped" => stats.dropped,
+        "clipped_ids" => stats.clipped_ids.clone(),
+        "dropped_ids" => stats.dropped_ids.clone()
+      },
+      Event::Extracted(file) => object!{
+        "event" => "extracted",
+        "name" => file.name.as_str(),
+        "path" => file.path.to_string_lossy().into_owned(),
+        "size" => file.size
+      },
+      Event::Completed { path, checksum } => object!{
+        "event" => "completed",
+        "path" => path.map(|path| path.to_string_lossy().into_owned()),
+        "size" => checksum.size,
+        "sha256" => checksum.sha256.as_str()
+      },
+      Event::Error(error) => object!{
+        "event" => "error",
+        "kind" => error.kind_name(),
+        "exit_code" => error.exit_code(),
+        "status" => status_code(error),
+        "message" => error.to_string().trim_end()
+      },
+      Event::AreaCompleted { name, request, path, checksum, cached } => object!{
+        "event" => "area_completed",
+        "name" => *name,
+        "request_id" => request.id.as_str(),
+        "path" => path.to_string_lossy().into_owned(),
+        "size" => checksum.size,
+        "sha256" => checksum.sha256.as_str(),
+        "cached" => *cached
+      },
+      Event::AreaFailed { name, request, error } => object!{
+        "event" => "area_failed",
+        "name" => *name,
+        "request_id" => request.map(|request| request.id.as_str()),
+        "kind" => error.kind_name(),
+        "status" => status_code(error),
+        "message" => error.to_string().trim_end()
+      },
+    }
+  }
+}
+
+/// HTTP status code van de PDOK API, als de fout daarvan komt.
+fn status_code(error: &DkkError) -> Option<u16> {
+  match error {
+    DkkError::Rejected(e) => Some(e.status().as_u16()),
+    DkkError::Network(e) => e.downcast_ref::<UnexpectedStatusCodeError>().map(|e| e.status().as_u16()),
+    _ => None,
+  }
+}
+
+pub(crate) fn kind_name(kind: RequestKind) -> &'static str {
+  match kind {
+    RequestKind::Full => "full",
+    RequestKind::Delta => "delta",
+  }
+}
+
+/// Schrijft events als JSON-regels (JSON Lines) naar bijv. stderr. Een uitgeschakelde log schrijft niets.
+///
+/// Met `set_history` gaan de events ook naar de geschiedenis, of de log nu ingeschakeld is of niet.
+pub struct EventLog<W: Write> {
+  out: Option<W>,
+  history: Option<HistoryRun>,
+  last_download_progress: Option<Instant>,
+}
+
+impl<W: Write> EventLog<W> {
+  pub fn new(out: W) -> Self {
+    Self { out: Some(out), history: None, last_download_progress: None }
+  }
+
+  pub fn disabled() -> Self {
+    Self { out: None, history: None, last_download_progress: None }
+  }
+
+  pub fn is_enabled(&self) -> bool {
+    self.out.is_some()
+  }
+
+  pub fn set_history(&mut self, run: HistoryRun) {
+    self.history = Some(run);
+  }
+
+  pub fn history_mut(&mut self) -> Option<&mut HistoryRun> {
+    self.history.as_mut()
+  }
+
+  pub fn take_history(&mut self) -> Option<HistoryRun> {
+    self.history.take()
+  }
+
+  /// Of `emit` ergens heen schrijft: naar de log of naar de geschiedenis.
+  pub fn is_recording(&self) -> bool {
+    self.out.is_some() || self.history.is_some()
+  }
+
+  /// Schrijft `event`. `download_progress` wordt hoogstens eens per seconde geschreven, behalve als de download compleet is.
+  ///
+  /// Net als bij de voortgangsbalken worden schrijffouten genegeerd; de download zelf gaat voor.
+  pub fn emit(&mut self, event: Event) {
+    if let Some(history) = self.history.as_mut() {
+      history.record(&event);
+    }
+    let out = match self.out.as_mut() {
+      Some(out) => out,
+      None => return,
+    };
+    if let Event::DownloadProgress { bytes, total } = event {
+      let now = Instant::now();
+      let recent = self.last_download_progress.is_some_and(|last| now.duration_since(last) < DOWNLOAD_PROGRESS_INTERVAL);
+      if recent && Some(bytes) != total {
+        return;
+      }
+      self.last_download_progress = Some(now);
+    }
+    let _ = writeln!(out, "{}", json::stringify(event.to_json()));
+    let _ = out.flush();
+  }
+}
+
+/// Meldt de voortgang van een download als `download_progress` events. Alles wat hierheen geschreven wordt telt als
+/// ontvangen, zodat het net als een voortgangsbalk naast de download gezet kan worden met een `TeeReader` of `TeeWriter`.
+pub struct DownloadProgressWriter<'a, W: Write> {
+  log: &'a mut EventLog<W>,
+  bytes: u64,
+  total: Option<u64>,
+}
+
+impl<'a, W: Write> DownloadProgressWriter<'a, W> {
+  pub fn new(log: &'a mut EventLog<W>, total: Option<u64>) -> Self {
+    Self { log, bytes: 0, total }
+  }
+}
+
+impl<W: Write> Write for DownloadProgressWriter<'_, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.bytes += buf.len() as u64;
+    self.log.emit(Event::DownloadProgress { bytes: self.bytes, total: self.total });
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    Ok(())
+  }
+}
@@ -0,0 +1,285 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io;
+use std::io::{BufRead, BufReader, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+use flate2::bufread::DeflateDecoder;
+
+use crate::client::part_path;
+use crate::error::InvalidResponseError;
+use crate::layers::feature_type_of_file;
+use crate::output::check_overwrite;
+
+
+const LOCAL_FILE_HEADER_SIGNATURE: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR_SIGNATURE: u32 = 0x0807_4b50;
+const CENTRAL_DIRECTORY_HEADER_SIGNATURE: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY_SIGNATURE: u32 = 0x0605_4b50;
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExtractOptions {
+  /// Sla GML-bestanden op als `<feature type>.gml`, bijv. `perceel.gml`, i.p.v. onder hun naam in de ZIP.
+  pub rename_by_feature_type: bool,
+  /// Overschrijf bestanden die al in de doelmap staan. Zonder deze optie is een bestaand bestand een `OutputExistsError`.
+  pub overwrite: bool,
+}
+
+/// Een uitgepakt bestand.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtractedFile {
+  /// Naam in de ZIP.
+  pub name: String,
+  pub path: PathBuf,
+  pub size: u64,
+  pub crc32: u32,
+}
+
+/// Een bestand in een ZIP-bestand, zoals gecontroleerd door `verify_zip`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ZipEntry {
+  pub name: String,
+  /// Ongecomprimeerde grootte.
+  pub size: u64,
+  pub crc32: u32,
+}
+
+impl From<&ExtractedFile> for ZipEntry {
+  fn from(file: &ExtractedFile) -> Self {
+    ZipEntry { name: file.name.clone(), size: file.size, crc32: file.crc32 }
+  }
+}
+
+/// Pakt een ZIP-bestand uit naar `dir`, terwijl het binnenkomt.
+///
+/// Alleen de lokale headers worden gebruikt, dus `reader` hoeft niet seekable te zijn en kan direct een download zijn.
+/// Bestandsnamen die buiten `dir` zouden uitkomen (absolute paden, `..`) worden geweigerd. Elk bestand wordt eerst
+/// naar `<naam>.part` geschreven en pas na een geslaagde CRC-controle hernoemd.
+pub fn extract_stream<R: Read>(reader: R, dir: &Path, options: &ExtractOptions) -> Result<Vec<ExtractedFile>, Box<dyn Error + Send + Sync>> {
+  fs::create_dir_all(dir)?;
+  let mut reader = BufReader::with_capacity(64 * 1024, reader);
+  let mut extracted = Vec::new();
+  let mut targets = HashSet::new();
+
+  loop {
+    match read_u32(&mut reader)? {
+      LOCAL_FILE_HEADER_SIGNATURE => {},
+      CENTRAL_DIRECTORY_HEADER_SIGNATURE | END_OF_CENTRAL_DIRECTORY_SIGNATURE => break,
+      signature => return Err(Box::new(InvalidResponseError::new(format!("ongeldige ZIP header (signature {:#010x})", signature)))),
+    }
+    let header = LocalHeader::read(&mut reader)?;
+    if header.flags & 1 != 0 {
+      return Err(Box::new(InvalidResponseError::new(format!("{}: versleutelde bestanden worden niet ondersteund", header.name))));
+    }
+
+    let relative = safe_relative_path(&header.name)?;
+    if header.name.ends_with('/') {
+      fs::create_dir_all(dir.join(&relative))?;
+      skip_entry_data(&mut reader, &header)?;
+      continue;
+    }
+    let relative = match feature_type_of_file(&header.name) {
+      Some(feature_type) if options.rename_by_feature_type && header.name.to_ascii_lowercase().ends_with(".gml") => {
+        PathBuf::from(format!("{}.gml", feature_type))
+      },
+      _ => relative,
+    };
+    if !targets.insert(relative.clone()) {
+      return Err(Box::new(InvalidResponseError::new(format!("{}: meerdere bestanden in de ZIP zouden naar {} geschreven worden", header.name, relative.display()))));
+    }
+
+    let path = dir.join(&relative);
+    check_overwrite(&path, options.overwrite)?;
+    if let Some(parent) = path.parent() {
+      fs::create_dir_all(parent)?;
+    }
+    let part = part_path(&path);
+    let mut output = CrcWriter { inner: io::BufWriter::new(File::create(&part)?), hasher: crc32fast::Hasher::new(), size: 0 };
+
+    let uses_descriptor = header.flags & (1 << 3) != 0;
+    match (header.method, uses_descriptor) {
+      (0, false) => { io::copy(&mut (&mut reader).take(header.compressed_size), &mut output)?; },
+      (8, false) => { io::copy(&mut DeflateDecoder::new((&mut reader).take(header.compressed_size)), &mut output)?; },
+      // Deflate geeft zelf het einde van de data aan; de bufread decoder leest niet verder dan dat.
+      (8, true) => { io::copy(&mut DeflateDecoder::new(&mut reader), &mut output)?; },
+      (method, _) => {
+        return Err(Box::new(InvalidResponseError::new(format!("{}: compressiemethode {} wordt niet ondersteund bij uitpakken tijdens downloaden", header.name, method))));
+      },
+    }
+    let expected_crc = if uses_descriptor { read_data_descriptor(&mut reader, header.zip64)? } else { header.crc32 };
+
+    output.inner.flush()?;
+    let (crc, size) = (output.hasher.clone().finalize(), output.size);
+    drop(output);
+    if crc != expected_crc {
+      fs::remove_file(&part)?;
+      return Err(Box::new(InvalidResponseError::new(format!("{}: CRC klopt niet, het bestand is beschadigd", header.name))));
+    }
+    fs::rename(&part, &path)?;
+    extracted.push(ExtractedFile { name: header.name, path, size, crc32: crc });
+  }
+
+  // De rest (central directory) nog leeglezen, zodat een download netjes afgerond wordt.
+  io::copy(&mut reader, &mut io::sink())?;
+  Ok(extracted)
+}
+
+/// Pakt een ZIP-bestand van schijf uit, zie `extract_stream`.
+pub fn extract_file(zip_path: &Path, dir: &Path, options: &ExtractOptions) -> Result<Vec<ExtractedFile>, Box<dyn Error + Send + Sync>> {
+  extract_stream(File::open(zip_path)?, dir, options)
+}
+
+/// Controleert of een ZIP-bestand compleet en onbeschadigd is: de central directory aan het eind moet te lezen zijn, en
+/// elk bestand wordt helemaal uitgepakt (zonder het op te slaan) zodat de CRC gecontroleerd wordt.
+///
+/// Geeft de bestanden in de ZIP terug, zonder mappen.
+pub fn verify_zip<R: Read + Seek>(reader: R) -> Result<Vec<ZipEntry>, Box<dyn Error + Send + Sync>> {
+  verify_archive(reader, "")
+}
+
+/// Controleert een ZIP-bestand op schijf, zie `verify_zip`.
+pub fn verify_zip_file(zip_path: &Path) -> Result<Vec<ZipEntry>, Box<dyn Error + Send + Sync>> {
+  verify_archive(BufReader::new(File::open(zip_path)?), &format!("{}: ", zip_path.display()))
+}
+
+/// `prefix` komt voor elke foutmelding, bijv. het pad van het ZIP-bestand.
+fn verify_archive<R: Read + Seek>(reader: R, prefix: &str) -> Result<Vec<ZipEntry>, Box<dyn Error + Send + Sync>> {
+  let damaged = |e: zip::result::ZipError| InvalidResponseError::new(format!("{}ZIP-bestand is beschadigd: {}", prefix, e));
+  let mut archive = zip::ZipArchive::new(reader).map_err(damaged)?;
+  let mut entries = Vec::new();
+  for i in 0..archive.len() {
+    let mut file = archive.by_index(i).map_err(damaged)?;
+    if file.is_dir() {
+      continue;
+    }
+    // De zip crate vergelijkt de CRC aan het eind van het bestand, en geeft een leesfout als die niet klopt.
+    if let Err(e) = io::copy(&mut file, &mut io::sink()) {
+      return Err(Box::new(InvalidResponseError::new(format!("{}{}: CRC klopt niet, het bestand is beschadigd ({})", prefix, file.name(), e))));
+    }
+    entries.push(ZipEntry { name: file.name().to_string(), size: file.size(), crc32: file.crc32() });
+  }
+  Ok(entries)
+}
+
+/// Controleert dat een bestandsnaam uit de ZIP binnen de doelmap blijft.
+fn safe_relative_path(name: &str) -> Result<PathBuf, InvalidResponseError> {
+  let unsafe_name = || InvalidResponseError::new(format!("onveilige bestandsnaam '{}' in ZIP geweigerd", name));
+  if name.contains('\0') || name.contains('\\') || name.starts_with('/') || name.contains(':') {
+    return Err(unsafe_name());
+  }
+  let mut relative = PathBuf::new();
+  for component in Path::new(name).components() {
+    match component {
+      Component::Normal(part) => relative.push(part),
+      Component::CurDir => {},
+      _ => return Err(unsafe_name()),
+    }
+  }
+  if relative.as_os_str().is_empty() {
+    return Err(unsafe_name());
+  }
+  Ok(relative)
+}
+
+struct LocalHeader {
+  flags: u16,
+  method: u16,
+  crc32: u32,
+  compressed_size: u64,
+  name: String,
+  zip64: bool,
+}
+
+impl LocalHeader {
+  fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+    let _version = read_u16(reader)?;
+    let flags = read_u16(reader)?;
+    let method = read_u16(reader)?;
+    let _time = read_u16(reader)?;
+    let _date = read_u16(reader)?;
+    let crc32 = read_u32(reader)?;
+    let mut compressed_size = read_u32(reader)? as u64;
+    let _uncompressed_size = read_u32(reader)?;
+    let name_length = read_u16(reader)? as usize;
+    let extra_length = read_u16(reader)? as usize;
+    let mut name = vec![0u8; name_length];
+    reader.read_exact(&mut name)?;
+    let mut extra = vec![0u8; extra_length];
+    reader.read_exact(&mut extra)?;
+
+    // Zip64: de echte groottes staan in extra veld 0x0001 (eerst ongecomprimeerd, dan gecomprimeerd).
+    let mut zip64 = false;
+    let mut rest = extra.as_slice();
+    while rest.len() >= 4 {
+      let id = u16::from_le_bytes([rest[0], rest[1]]);
+      let len = u16::from_le_bytes([rest[2], rest[3]]) as usize;
+      let data = &rest[4..(4 + len).min(rest.len())];
+      if id == 0x0001 {
+        zip64 = true;
+        if data.len() >= 16 {
+          compressed_size = u64::from_le_bytes([data[8], data[9], data[10], data[11], data[12], data[13], data[14], data[15]]);
+        }
+      }
+      rest = &rest[(4 + len).min(rest.len())..];
+    }
+
+    Ok(Self { flags, method, crc32, compressed_size, name: String::from_utf8_lossy(&name).into_owned(), zip64 })
+  }
+}
+
+fn skip_entry_data<R: BufRead>(reader: &mut R, header: &LocalHeader) -> io::Result<()> {
+  io::copy(&mut reader.take(header.compressed_size), &mut io::sink())?;
+  if header.flags & (1 << 3) != 0 {
+    read_data_descriptor(reader, header.zip64)?;
+  }
+  Ok(())
+}
+
+/// Leest een data descriptor (met of zonder signature) en geeft de CRC terug.
+fn read_data_descriptor<R: BufRead>(reader: &mut R, zip64: bool) -> io::Result<u32> {
+  let mut crc = read_u32(reader)?;
+  if crc == DATA_DESCRIPTOR_SIGNATURE {
+    crc = read_u32(reader)?;
+  }
+  let sizes = if zip64 { 16 } else { 8 };
+  io::copy(&mut reader.take(sizes), &mut io::sink())?;
+  Ok(crc)
+}
+
+fn read_u16<R: Read>(reader: &mut R) -> io::Result<u16> {
+  let mut buf = [0u8; 2];
+  reader.read_exact(&mut buf)?;
+  Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> io::Result<u32> {
+  let mut buf = [0u8; 4];
+  reader.read_exact(&mut buf)?;
+  Ok(u32::from_le_bytes(buf))
+}
+
+struct CrcWriter<W: Write> {
+  inner: W,
+  hasher: crc32fast::Hasher,
+  size: u64,
+}
+
+impl<W: Write> Write for CrcWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let n = self.inner.write(buf)?;
+    self.hasher.update(&buf[..n]);
+    self.size += n as u64;
+    Ok(n)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
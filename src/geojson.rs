@@ -0,0 +1,177 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use json::object;
+use json::JsonValue;
+
+use crate::geometry::{FeatureGeometry, Point, Polygon};
+use crate::gml::{Feature, Layer};
+
+
+/// Naam van RD New in het (pre-RFC 7946) GeoJSON `crs` lid.
+pub const RD_NEW_CRS_NAME: &str = "urn:ogc:def:crs:EPSG::28992";
+
+/// Voorvoegsel van een overige geometrie in `properties` die dezelfde naam heeft als een eigenschap.
+const GEOMETRY_PREFIX: &str = "geometrie.";
+
+/// Schrijft elke laag als eigen FeatureCollection naar `<dir>/<laag>.geojson` en geeft de geschreven paden terug.
+pub fn write_layers(layers: &[Layer], dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+  fs::create_dir_all(dir)?;
+  let mut paths = Vec::new();
+  for layer in layers {
+    let path = dir.join(format!("{}.geojson", layer.name));
+    let mut writer = BufWriter::new(File::create(&path)?);
+    write_feature_collection(&mut writer, layer)?;
+    writer.flush()?;
+    paths.push(path);
+  }
+  Ok(paths)
+}
+
+/// Schrijft een laag als GeoJSON FeatureCollection in RD New.
+///
+/// De coördinaten blijven in RD New; dat wordt aangegeven met het `crs` lid zoals QGIS en GDAL dat begrijpen,
+/// ook al kent RFC 7946 alleen WGS84.
+pub fn write_feature_collection<W: Write>(writer: &mut W, layer: &Layer) -> Result<(), Box<dyn Error + Send + Sync>> {
+  write!(writer, "{{\"type\":\"FeatureCollection\",\"name\":{},\"crs\":{},\"features\":[",
+    json::stringify(layer.name.as_str()),
+    json::stringify(object!{ "type" => "name", "properties" => object!{ "name" => RD_NEW_CRS_NAME } }))?;
+  for (i, feature) in layer.features.iter().enumerate() {
+    if i > 0 {
+      writer.write_all(b",")?;
+    }
+    writer.write_all(b"\n")?;
+    writer.write_all(json::stringify(feature_to_json(feature)).as_bytes())?;
+  }
+  writer.write_all(b"\n]}\n")?;
+  Ok(())
+}
+
+/// Zet een feature om in een GeoJSON Feature. De overige geometrieën komen als GeoJSON geometrie in `properties`;
+/// heeft een eigenschap dezelfde naam, dan krijgt de geometrie het voorvoegsel `geometrie.`.
+pub fn feature_to_json(feature: &Feature) -> JsonValue {
+  let mut properties = JsonValue::new_object();
+  for (key, value) in &feature.properties {
+    add_property(&mut properties, key, value.as_str().into());
+  }
+  for (key, geometry) in &feature.other_geometries {
+    let key = if feature.properties.iter().any(|(k, _)| k == key) { format!("{}{}", GEOMETRY_PREFIX, key) } else { key.clone() };
+    add_property(&mut properties, &key, geometry_to_json(geometry));
+  }
+  object!{
+    "type" => "Feature",
+    "id" => feature.id.clone(),
+    "properties" => properties,
+    "geometry" => feature.geometry.as_ref().map(geometry_to_json).unwrap_or(JsonValue::Null)
+  }
+}
+
+/// Herhaalde eigenschappen worden een array.
+fn add_property(properties: &mut JsonValue, key: &str, value: JsonValue) {
+  if properties.has_key(key) {
+    let existing = properties.remove(key);
+    let mut array = if existing.is_array() { existing } else { JsonValue::Array(vec![existing]) };
+    let _ = array.push(value);
+    properties[key] = array;
+  } else {
+    properties[key] = value;
+  }
+}
+
+pub fn geometry_to_json(geometry: &FeatureGeometry) -> JsonValue {
+  let (kind, coordinates) = match geometry {
+    FeatureGeometry::Point(point) => ("Point", position(point)),
+    FeatureGeometry::LineString(points) => ("LineString", positions(points)),
+    FeatureGeometry::Polygon(polygon) => ("Polygon", polygon_coordinates(polygon)),
+    FeatureGeometry::MultiPoint(points) => ("MultiPoint", positions(points)),
+    FeatureGeometry::MultiLineString(lines) => ("MultiLineString", JsonValue::Array(lines.iter().map(|l| positions(l)).collect())),
+    FeatureGeometry::MultiPolygon(polygons) => ("MultiPolygon", JsonValue::Array(polygons.iter().map(polygon_coordinates).collect())),
+  };
+  object!{ "type" => kind, "coordinates" => coordinates }
+}
+
+fn position(point: &Point) -> JsonValue {
+  JsonValue::Array(vec![point.x.into(), point.y.into()])
+}
+
+fn positions(points: &[Point]) -> JsonValue {
+  JsonValue::Array(points.iter().map(position).collect())
+}
+
+fn polygon_coordinates(polygon: &Polygon) -> JsonValue {
+  JsonValue::Array(polygon.rings().map(|ring| positions(ring)).collect())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn feature(properties: &[(&str, &str)], geometry: Option<FeatureGeometry>, other_geometries: Vec<(&str, FeatureGeometry)>) -> Feature {
+    Feature {
+      feature_type: String::from("Perceel"),
+      id: Some(String::from("p1")),
+      properties: properties.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect(),
+      geometry,
+      geometry_property: None,
+      other_geometries: other_geometries.into_iter().map(|(k, g)| (String::from(k), g)).collect(),
+    }
+  }
+
+  fn square(x: f64) -> Polygon {
+    Polygon::new(vec![Point::new(x, 0.0), Point::new(x + 1.0, 0.0), Point::new(x + 1.0, 1.0), Point::new(x, 0.0)], Vec::new())
+  }
+
+  #[test]
+  fn writes_properties_and_geometries() {
+    let feature = feature(
+      &[("sectie", "A"), ("soortGrootte", "vastgesteld"), ("soortGrootte", "voorlopig")],
+      Some(FeatureGeometry::MultiPolygon(vec![square(0.0), square(2.0)])),
+      vec![("plaatscoordinaten", FeatureGeometry::Point(Point::new(0.5, 0.25)))],
+    );
+    let json = feature_to_json(&feature);
+    assert_eq!(json["id"], "p1");
+    assert_eq!(json["properties"]["sectie"], "A");
+    assert_eq!(json["properties"]["soortGrootte"], json::array!["vastgesteld", "voorlopig"]);
+    assert_eq!(json["properties"]["plaatscoordinaten"], object!{ "type" => "Point", "coordinates" => json::array![0.5, 0.25] });
+    assert_eq!(json["geometry"]["type"], "MultiPolygon");
+    assert_eq!(json["geometry"]["coordinates"].len(), 2);
+    assert_eq!(json["geometry"]["coordinates"][1][0][1], json::array![3.0, 0.0]);
+  }
+
+  #[test]
+  fn writes_null_for_a_missing_geometry() {
+    let json = feature_to_json(&feature(&[("sectie", "A")], None, Vec::new()));
+    assert!(json["geometry"].is_null());
+    assert!(json.has_key("geometry"));
+  }
+
+  #[test]
+  fn does_not_overwrite_properties_with_geometries() {
+    let point = |x| FeatureGeometry::Point(Point::new(x, 0.0));
+    let feature = feature(&[("positie", "midden")], None, vec![("positie", point(1.0)), ("label", point(2.0)), ("label", point(3.0))]);
+    let properties = &feature_to_json(&feature)["properties"];
+    assert_eq!(properties["positie"], "midden");
+    assert_eq!(properties["geometrie.positie"]["coordinates"], json::array![1.0, 0.0]);
+    assert_eq!(properties["label"].len(), 2);
+    assert_eq!(properties["label"][1]["coordinates"], json::array![3.0, 0.0]);
+  }
+
+  #[test]
+  fn writes_the_rd_new_crs() {
+    let layer = Layer { name: String::from("perceel"), features: vec![feature(&[], None, Vec::new())] };
+    let mut out = Vec::new();
+    write_feature_collection(&mut out, &layer).unwrap();
+    let json = json::parse(std::str::from_utf8(&out).unwrap()).unwrap();
+    assert_eq!(json["name"], "perceel");
+    assert_eq!(json["crs"]["properties"]["name"], RD_NEW_CRS_NAME);
+    assert_eq!(json["features"].len(), 1);
+  }
+}
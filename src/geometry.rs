@@ -0,0 +1,409 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::fmt::Display;
+
+use crate::error::InvalidGeometryError;
+
+
+/// Geldigheidsgebied van RD New (EPSG:28992), zoals gebruikt door RDNAPTRANS: minx, miny, maxx, maxy.
+pub const RD_NEW_EXTENT: (f64, f64, f64, f64) = (-7000.0, 289000.0, 300000.0, 629000.0);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Point {
+  pub x: f64,
+  pub y: f64,
+}
+
+impl Point {
+  pub fn new(x: f64, y: f64) -> Self {
+    Self { x, y }
+  }
+}
+
+impl Display for Point {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{} {}", self.x, self.y)
+  }
+}
+
+/// Een polygon met een buitenring en nul of meer gaten. Ringen zijn gesloten: het eerste punt is gelijk aan het laatste.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Polygon {
+  pub exterior: Vec<Point>,
+  pub interiors: Vec<Vec<Point>>,
+}
+
+impl Polygon {
+  pub fn new(exterior: Vec<Point>, interiors: Vec<Vec<Point>>) -> Self {
+    Self { exterior, interiors }
+  }
+
+  pub fn rings(&self) -> impl Iterator<Item = &Vec<Point>> {
+    std::iter::once(&self.exterior).chain(self.interiors.iter())
+  }
+
+  /// Zet de ringen in de OGC-richting: buitenring tegen de klok in, gaten met de klok mee.
+  pub fn orient(&mut self) {
+    if signed_area(&self.exterior) < 0.0 {
+      self.exterior.reverse();
+    }
+    for interior in &mut self.interiors {
+      if signed_area(interior) > 0.0 {
+        interior.reverse();
+      }
+    }
+  }
+}
+
+/// Een interessegebied: een polygon of een multipolygon.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Geometry {
+  Polygon(Polygon),
+  MultiPolygon(Vec<Polygon>),
+}
+
+impl Geometry {
+  pub fn polygons(&self) -> &[Polygon] {
+    match self {
+      Geometry::Polygon(polygon) => std::slice::from_ref(polygon),
+      Geometry::MultiPolygon(polygons) => polygons,
+    }
+  }
+
+  /// Controleert of dit gebied als geofilter gebruikt kan worden: gesloten, niet zelf-snijdende ringen,
+  /// buitenringen tegen de klok in en gaten met de klok mee (OGC), en alle punten binnen RD New.
+  pub fn validate(&self) -> Result<(), InvalidGeometryError> {
+    if self.polygons().is_empty() {
+      return Err(InvalidGeometryError::new("geen enkel polygon opgegeven"));
+    }
+    for (p, polygon) in self.polygons().iter().enumerate() {
+      validate_polygon(polygon, &Location { multi: self.is_multi(), polygon: p })?;
+    }
+    Ok(())
+  }
+
+  fn is_multi(&self) -> bool {
+    matches!(self, Geometry::MultiPolygon(_))
+  }
+
+  /// Oppervlakte in de eenheid van de coördinaten in het kwadraat, dus m² in RD New.
+  pub fn area(&self) -> f64 {
+    self.polygons().iter().map(|polygon| {
+      signed_area(&polygon.exterior).abs() - polygon.interiors.iter().map(|ring| signed_area(ring).abs()).sum::<f64>()
+    }).sum()
+  }
+
+  /// Omhullende rechthoek: minx, miny, maxx, maxy.
+  pub fn bounds(&self) -> (f64, f64, f64, f64) {
+    let mut bounds = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for point in self.polygons().iter().flat_map(|polygon| polygon.exterior.iter()) {
+      bounds.0 = bounds.0.min(point.x);
+      bounds.1 = bounds.1.min(point.y);
+      bounds.2 = bounds.2.max(point.x);
+      bounds.3 = bounds.3.max(point.y);
+    }
+    bounds
+  }
+
+  /// Maakt een `Geometry` van een aantal polygonen; `None` als er geen zijn.
+  pub fn from_polygons(mut polygons: Vec<Polygon>) -> Option<Geometry> {
+    match polygons.len() {
+      0 => None,
+      1 => polygons.pop().map(Geometry::Polygon),
+      _ => Some(Geometry::MultiPolygon(polygons)),
+    }
+  }
+}
+
+impl From<&Polygon> for geo::Polygon<f64> {
+  fn from(polygon: &Polygon) -> Self {
+    let ring = |points: &Vec<Point>| geo::LineString::from(points.iter().map(|p| (p.x, p.y)).collect::<Vec<_>>());
+    geo::Polygon::new(ring(&polygon.exterior), polygon.interiors.iter().map(ring).collect())
+  }
+}
+
+impl From<&geo::Polygon<f64>> for Polygon {
+  fn from(polygon: &geo::Polygon<f64>) -> Self {
+    let ring = |line: &geo::LineString<f64>| line.coords().map(|c| Point::new(c.x, c.y)).collect::<Vec<_>>();
+    let mut polygon = Polygon::new(ring(polygon.exterior()), polygon.interiors().iter().map(ring).collect());
+    polygon.orient();
+    polygon
+  }
+}
+
+impl From<&Geometry> for geo::MultiPolygon<f64> {
+  fn from(geometry: &Geometry) -> Self {
+    geo::MultiPolygon::new(geometry.polygons().iter().map(geo::Polygon::from).collect())
+  }
+}
+
+/// Plaatsaanduiding voor foutmeldingen.
+struct Location {
+  multi: bool,
+  polygon: usize,
+}
+
+impl Location {
+  fn ring(&self, ring: usize) -> String {
+    let ring_name = match ring {
+      0 => String::from("buitenring"),
+      n => format!("gat {}", n),
+    };
+    if self.multi {
+      format!("polygon {}, {}", self.polygon + 1, ring_name)
+    } else {
+      ring_name
+    }
+  }
+
+  fn vertex(&self, ring: usize, vertex: usize, point: Point) -> String {
+    format!("{}, punt {} ({})", self.ring(ring), vertex + 1, point)
+  }
+}
+
+fn validate_polygon(polygon: &Polygon, loc: &Location) -> Result<(), InvalidGeometryError> {
+  let (minx, miny, maxx, maxy) = RD_NEW_EXTENT;
+  for (r, ring) in polygon.rings().enumerate() {
+    for (v, point) in ring.iter().enumerate() {
+      if !point.x.is_finite() || !point.y.is_finite() {
+        return Err(InvalidGeometryError::new(format!("{}: coördinaat is geen eindig getal", loc.vertex(r, v, *point))));
+      }
+      if point.x < minx || point.x > maxx || point.y < miny || point.y > maxy {
+        return Err(InvalidGeometryError::new(format!(
+          "{}: ligt buiten het RD New (EPSG:28992) gebied van Nederland ({} {}, {} {})",
+          loc.vertex(r, v, *point), minx, miny, maxx, maxy)));
+      }
+    }
+    if ring.len() < 4 {
+      return Err(InvalidGeometryError::new(format!("{}: een ring heeft minimaal 4 punten nodig, maar heeft er {}", loc.ring(r), ring.len())));
+    }
+    let first = ring[0];
+    let last = ring[ring.len() - 1];
+    if first != last {
+      return Err(InvalidGeometryError::new(format!(
+        "{}: ring is niet gesloten, het laatste punt ({}) moet gelijk zijn aan het eerste ({})", loc.ring(r), last, first)));
+    }
+    let area = signed_area(ring);
+    if area == 0.0 {
+      return Err(InvalidGeometryError::new(format!("{}: ring heeft geen oppervlakte", loc.ring(r))));
+    }
+    if r == 0 && area < 0.0 {
+      return Err(InvalidGeometryError::new(format!("{}: de buitenring moet tegen de klok in lopen", loc.ring(r))));
+    }
+    if r > 0 && area > 0.0 {
+      return Err(InvalidGeometryError::new(format!("{}: een gat moet met de klok mee lopen", loc.ring(r))));
+    }
+    if r > 0 && !contains_point(&polygon.exterior, ring[0]) {
+      return Err(InvalidGeometryError::new(format!("{}: ligt buiten de buitenring", loc.vertex(r, 0, ring[0]))));
+    }
+  }
+
+  // Elk segment tegen elk ander segment; buren binnen dezelfde ring mogen een eindpunt delen.
+  let rings: Vec<&Vec<Point>> = polygon.rings().collect();
+  for (ra, ring_a) in rings.iter().enumerate() {
+    let segments_a = ring_a.len() - 1;
+    for i in 0..segments_a {
+      for (rb, ring_b) in rings.iter().enumerate().skip(ra) {
+        let segments_b = ring_b.len() - 1;
+        let start = if ra == rb { i + 1 } else { 0 };
+        for j in start..segments_b {
+          let adjacent = ra == rb && (j == i + 1 || (i == 0 && j == segments_a - 1));
+          let (a1, a2) = (ring_a[i], ring_a[i + 1]);
+          let (b1, b2) = (ring_b[j], ring_b[j + 1]);
+          let intersects = if adjacent {
+            segments_overlap(a1, a2, b1, b2)
+          } else {
+            segments_intersect(a1, a2, b1, b2)
+          };
+          if intersects {
+            return Err(InvalidGeometryError::new(format!(
+              "{} snijdt {}", describe_segment(loc, ra, i, a1), describe_segment(loc, rb, j, b1))));
+          }
+        }
+      }
+    }
+  }
+  Ok(())
+}
+
+fn describe_segment(loc: &Location, ring: usize, segment: usize, start: Point) -> String {
+  format!("segment vanaf {}", loc.vertex(ring, segment, start))
+}
+
+/// Oppervlakte volgens de shoelace-formule; positief voor een ring tegen de klok in.
+pub fn signed_area(ring: &[Point]) -> f64 {
+  ring.windows(2).map(|w| w[0].x * w[1].y - w[1].x * w[0].y).sum::<f64>() / 2.0
+}
+
+/// Even-odd test of `point` binnen de gesloten `ring` ligt.
+pub fn contains_point(ring: &[Point], point: Point) -> bool {
+  let mut inside = false;
+  for w in ring.windows(2) {
+    let (a, b) = (w[0], w[1]);
+    if (a.y > point.y) != (b.y > point.y) {
+      let x = a.x + (point.y - a.y) / (b.y - a.y) * (b.x - a.x);
+      if point.x < x {
+        inside = !inside;
+      }
+    }
+  }
+  inside
+}
+
+fn orientation(a: Point, b: Point, c: Point) -> f64 {
+  (b.x - a.x) * (c.y - a.y) - (b.y - a.y) * (c.x - a.x)
+}
+
+fn on_segment(a: Point, b: Point, p: Point) -> bool {
+  p.x >= a.x.min(b.x) && p.x <= a.x.max(b.x) && p.y >= a.y.min(b.y) && p.y <= a.y.max(b.y)
+}
+
+/// Of de gesloten segmenten a1-a2 en b1-b2 een punt gemeen hebben.
+pub fn segments_intersect(a1: Point, a2: Point, b1: Point, b2: Point) -> bool {
+  let d1 = orientation(b1, b2, a1);
+  let d2 = orientation(b1, b2, a2);
+  let d3 = orientation(a1, a2, b1);
+  let d4 = orientation(a1, a2, b2);
+  if ((d1 > 0.0 && d2 < 0.0) || (d1 < 0.0 && d2 > 0.0)) && ((d3 > 0.0 && d4 < 0.0) || (d3 < 0.0 && d4 > 0.0)) {
+    return true;
+  }
+  (d1 == 0.0 && on_segment(b1, b2, a1))
+    || (d2 == 0.0 && on_segment(b1, b2, a2))
+    || (d3 == 0.0 && on_segment(a1, a2, b1))
+    || (d4 == 0.0 && on_segment(a1, a2, b2))
+}
+
+/// Of twee segmenten die een eindpunt delen verder over elkaar heen liggen (een ring die terugkeert op zichzelf).
+fn segments_overlap(a1: Point, a2: Point, b1: Point, b2: Point) -> bool {
+  if orientation(a1, a2, b1) != 0.0 || orientation(a1, a2, b2) != 0.0 {
+    return false;
+  }
+  // Collineair; overlap als een niet-gedeeld eindpunt binnen het andere segment valt.
+  let shared = if a2 == b1 || a2 == b2 { a2 } else { a1 };
+  [a1, a2].iter().any(|&p| p != shared && on_segment(b1, b2, p))
+    || [b1, b2].iter().any(|&p| p != shared && on_segment(a1, a2, p))
+}
+
+/// Geometrie van een feature uit de DKK, in RD New.
+#[derive(Debug, Clone, PartialEq)]
+pub enum FeatureGeometry {
+  Point(Point),
+  LineString(Vec<Point>),
+  Polygon(Polygon),
+  MultiPoint(Vec<Point>),
+  MultiLineString(Vec<Vec<Point>>),
+  MultiPolygon(Vec<Polygon>),
+}
+
+impl FeatureGeometry {
+  /// Topologische dimensie: 0 voor punten, 1 voor lijnen en 2 voor vlakken.
+  pub fn dimension(&self) -> u8 {
+    match self {
+      FeatureGeometry::Point(_) | FeatureGeometry::MultiPoint(_) => 0,
+      FeatureGeometry::LineString(_) | FeatureGeometry::MultiLineString(_) => 1,
+      FeatureGeometry::Polygon(_) | FeatureGeometry::MultiPolygon(_) => 2,
+    }
+  }
+
+  pub fn points(&self) -> Box<dyn Iterator<Item = &Point> + '_> {
+    match self {
+      FeatureGeometry::Point(point) => Box::new(std::iter::once(point)),
+      FeatureGeometry::LineString(points) | FeatureGeometry::MultiPoint(points) => Box::new(points.iter()),
+      FeatureGeometry::Polygon(polygon) => Box::new(polygon.rings().flatten()),
+      FeatureGeometry::MultiLineString(lines) => Box::new(lines.iter().flatten()),
+      FeatureGeometry::MultiPolygon(polygons) => Box::new(polygons.iter().flat_map(|polygon| polygon.rings().flatten())),
+    }
+  }
+
+  /// Omhullende rechthoek: minx, miny, maxx, maxy.
+  pub fn bounds(&self) -> (f64, f64, f64, f64) {
+    let mut bounds = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+    for point in self.points() {
+      bounds.0 = bounds.0.min(point.x);
+      bounds.1 = bounds.1.min(point.y);
+      bounds.2 = bounds.2.max(point.x);
+      bounds.3 = bounds.3.max(point.y);
+    }
+    bounds
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn ring(points: &[(f64, f64)]) -> Vec<Point> {
+    points.iter().map(|(x, y)| Point::new(155_000.0 + x, 463_000.0 + y)).collect()
+  }
+
+  fn square(min: f64, max: f64) -> Vec<Point> {
+    ring(&[(min, min), (max, min), (max, max), (min, max), (min, min)])
+  }
+
+  fn polygon(exterior: Vec<Point>, interiors: Vec<Vec<Point>>) -> Geometry {
+    Geometry::Polygon(Polygon::new(exterior, interiors))
+  }
+
+  fn rejection(geometry: &Geometry) -> String {
+    geometry.validate().expect_err("ongeldig gebied goedgekeurd").to_string()
+  }
+
+  #[test]
+  fn accepts_polygons_with_holes_and_multipolygons() {
+    let mut hole = square(2.0, 4.0);
+    hole.reverse();
+    polygon(square(0.0, 10.0), vec![hole]).validate().unwrap();
+    let multi = Geometry::MultiPolygon(vec![Polygon::new(square(0.0, 10.0), Vec::new()), Polygon::new(square(20.0, 30.0), Vec::new())]);
+    multi.validate().unwrap();
+  }
+
+  #[test]
+  fn rejects_empty_and_degenerate_rings() {
+    assert!(rejection(&Geometry::MultiPolygon(Vec::new())).contains("geen enkel polygon"));
+    assert!(rejection(&polygon(ring(&[(0.0, 0.0), (10.0, 0.0), (0.0, 0.0)]), Vec::new())).contains("minimaal 4 punten"));
+    assert!(rejection(&polygon(ring(&[(0.0, 0.0), (10.0, 0.0), (20.0, 0.0), (0.0, 0.0)]), Vec::new())).contains("geen oppervlakte"));
+  }
+
+  #[test]
+  fn rejects_unclosed_rings() {
+    let error = rejection(&polygon(ring(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (0.0, 10.0)]), Vec::new()));
+    assert!(error.contains("buitenring: ring is niet gesloten"), "{}", error);
+  }
+
+  #[test]
+  fn rejects_self_intersecting_rings() {
+    // De derde zijde steekt onder de eerste door.
+    let crossing = ring(&[(0.0, 0.0), (10.0, 0.0), (10.0, 10.0), (5.0, -2.0), (0.0, 10.0), (0.0, 0.0)]);
+    let error = rejection(&polygon(crossing, Vec::new()));
+    assert!(error.ends_with("buitenring, punt 1 (155000 463000) snijdt segment vanaf buitenring, punt 3 (155010 463010)"), "{}", error);
+    // Een gat dat de buitenring raakt.
+    let mut hole = ring(&[(5.0, 5.0), (12.0, 5.0), (12.0, 8.0), (5.0, 8.0), (5.0, 5.0)]);
+    hole.reverse();
+    let error = rejection(&polygon(square(0.0, 10.0), vec![hole]));
+    assert!(error.contains("buitenring") && error.contains("snijdt") && error.contains("gat 1"), "{}", error);
+  }
+
+  #[test]
+  fn rejects_wrong_orientation_and_holes_outside_the_exterior() {
+    let mut clockwise = square(0.0, 10.0);
+    clockwise.reverse();
+    assert!(rejection(&polygon(clockwise, Vec::new())).contains("tegen de klok in"));
+    assert!(rejection(&polygon(square(0.0, 10.0), vec![square(2.0, 4.0)])).contains("met de klok mee"));
+    let mut outside = square(20.0, 30.0);
+    outside.reverse();
+    assert!(rejection(&polygon(square(0.0, 10.0), vec![outside])).contains("ligt buiten de buitenring"));
+  }
+
+  #[test]
+  fn rejects_coordinates_outside_rd_new() {
+    // WGS84-coördinaten die als RD New opgegeven zijn.
+    let wgs84 = vec![Point::new(5.0, 52.0), Point::new(5.1, 52.0), Point::new(5.1, 52.1), Point::new(5.0, 52.0)];
+    let error = rejection(&polygon(wgs84, Vec::new()));
+    assert!(error.contains("buiten het RD New"), "{}", error);
+    let multi = Geometry::MultiPolygon(vec![Polygon::new(square(0.0, 10.0), Vec::new()), Polygon::new(ring(&[(0.0, f64::NAN)]), Vec::new())]);
+    assert!(rejection(&multi).starts_with("Ongeldig polygon: polygon 2, buitenring, punt 1"));
+  }
+}
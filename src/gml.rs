@@ -0,0 +1,558 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use quick_xml::events::{BytesStart, Event};
+use quick_xml::Reader;
+use zip::ZipArchive;
+
+use crate::error::InvalidResponseError;
+use crate::geometry::{FeatureGeometry, Point, Polygon};
+use crate::layers::feature_type_of_file;
+
+
+/// Maximale afwijking in meters tussen een boog (`gml:Arc`) en de lijnstukken waarmee hij benaderd wordt.
+const ARC_TOLERANCE: f64 = 0.01;
+
+/// Een feature uit een DKK GML-bestand.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Feature {
+  /// Naam van het feature element, bijv. `Perceel`.
+  pub feature_type: String,
+  /// Het `gml:id` attribuut.
+  pub id: Option<String>,
+  /// Alle niet-geometrische eigenschappen, platgeslagen tot `pad.naar.waarde` en in documentvolgorde.
+  /// Attributen krijgen de vorm `pad@attribuut`. Een sleutel kan meerdere keren voorkomen.
+  pub properties: Vec<(String, String)>,
+  /// De hoofdgeometrie: de eigenschap met de hoogste dimensie (vlak boven lijn boven punt).
+  pub geometry: Option<FeatureGeometry>,
+  /// Naam van de eigenschap waar `geometry` vandaan komt, bijv. `begrenzingPerceel`.
+  pub geometry_property: Option<String>,
+  /// Overige geometrische eigenschappen, zoals `plaatscoordinaten` van een perceel. Een sleutel kan meerdere keren voorkomen.
+  pub other_geometries: Vec<(String, FeatureGeometry)>,
+}
+
+impl Feature {
+  /// Eerste waarde van een eigenschap.
+  pub fn property(&self, key: &str) -> Option<&str> {
+    self.properties.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+  }
+
+  /// De `identificatie` van de feature, bij een NEN3610ID als `namespace.lokaalID`
+  /// (bijv. `NL.IMKAD.KadastraalObject.1`), of anders het `gml:id`.
+  pub fn identificatie(&self) -> Option<String> {
+    let parts: Vec<&str> = self.properties.iter()
+      .filter(|(k, v)| (k == "identificatie" || k.starts_with("identificatie.")) && !k.contains('@') && !v.is_empty())
+      .map(|(_, v)| v.as_str())
+      .collect();
+    if parts.is_empty() { self.id.clone() } else { Some(parts.join(".")) }
+  }
+
+  /// Geometrie van een eigenschap, hoofdgeometrie of overig.
+  pub fn geometry_of(&self, property: &str) -> Option<&FeatureGeometry> {
+    if self.geometry_property.as_deref() == Some(property) {
+      return self.geometry.as_ref();
+    }
+    self.other_geometries.iter().find(|(k, _)| k == property).map(|(_, g)| g)
+  }
+}
+
+/// Alle features van één feature type uit de ZIP.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Layer {
+  /// Het feature type, bijv. `perceel`, of de bestandsnaam zonder extensie als het type niet herkend wordt.
+  pub name: String,
+  pub features: Vec<Feature>,
+}
+
+/// Leest alle GML-bestanden uit een gedownload ZIP-bestand.
+pub fn read_zip_layers(zip_path: &Path) -> Result<Vec<Layer>, Box<dyn Error + Send + Sync>> {
+  let mut archive = ZipArchive::new(File::open(zip_path)?)?;
+  let mut layers = Vec::new();
+  for i in 0..archive.len() {
+    let mut entry = archive.by_index(i)?;
+    let entry_name = String::from(entry.name());
+    if entry.is_dir() || !entry_name.to_ascii_lowercase().ends_with(".gml") {
+      continue;
+    }
+    let mut data = Vec::new();
+    entry.read_to_end(&mut data)?;
+    let features = parse_features(&data).map_err(|e| InvalidResponseError::new(format!("{}: {}", entry_name, e)))?;
+    let name = match feature_type_of_file(&entry_name) {
+      Some(feature_type) => String::from(feature_type),
+      None => {
+        let base = entry_name.rsplit('/').next().unwrap_or(&entry_name);
+        String::from(&base[..base.len() - ".gml".len()])
+      },
+    };
+    layers.push(Layer { name, features });
+  }
+  Ok(layers)
+}
+
+/// Een XML element, met alleen lokale namen.
+#[derive(Debug, Default)]
+struct Element {
+  name: String,
+  attributes: Vec<(String, String)>,
+  children: Vec<Element>,
+  text: String,
+}
+
+impl Element {
+  fn from_start(e: &BytesStart) -> Self {
+    let attributes = e.attributes().flatten().filter_map(|a| {
+      let key = a.key;
+      // Namespace declaraties zijn geen gegevens.
+      if key.as_namespace_binding().is_some() {
+        return None;
+      }
+      let value = a.unescape_value().map(|v| v.into_owned()).unwrap_or_else(|_| String::from_utf8_lossy(&a.value).into_owned());
+      Some((String::from_utf8_lossy(key.local_name().as_ref()).into_owned(), value))
+    }).collect();
+    Element { name: String::from_utf8_lossy(e.local_name().as_ref()).into_owned(), attributes, children: Vec::new(), text: String::new() }
+  }
+
+  fn child(&self, name: &str) -> Option<&Element> {
+    self.children.iter().find(|c| c.name == name)
+  }
+
+  fn attribute(&self, name: &str) -> Option<&str> {
+    self.attributes.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str())
+  }
+}
+
+/// Leest de features uit een GML FeatureCollection (`featureMember`, `member` of `featureMembers`).
+pub fn parse_features(data: &[u8]) -> Result<Vec<Feature>, Box<dyn Error + Send + Sync>> {
+  let mut reader = Reader::from_reader(data);
+  let mut stack: Vec<Element> = Vec::new();
+  let mut features = Vec::new();
+
+  loop {
+    match reader.read_event()? {
+      Event::Start(ref e) => stack.push(Element::from_start(e)),
+      Event::Empty(ref e) => {
+        let element = Element::from_start(e);
+        close_element(&mut stack, element, &mut features);
+      },
+      Event::End(_) => {
+        if let Some(element) = stack.pop() {
+          close_element(&mut stack, element, &mut features);
+        }
+      },
+      Event::Text(ref e) => {
+        if let Some(top) = stack.last_mut() {
+          top.text.push_str(&e.unescape()?);
+        }
+      },
+      Event::CData(ref e) => {
+        if let Some(top) = stack.last_mut() {
+          top.text.push_str(&String::from_utf8_lossy(e));
+        }
+      },
+      Event::Eof => break,
+      _ => {},
+    }
+  }
+  Ok(features)
+}
+
+/// Hangt een afgesloten element aan zijn ouder, of zet het om in een feature als het een feature is.
+///
+/// Features zijn de kinderen van `featureMember`/`member`/`featureMembers` direct onder het root element;
+/// alleen die worden als boom bewaard, zodat de rest van het document geen geheugen kost.
+fn close_element(stack: &mut [Element], element: Element, features: &mut Vec<Feature>) {
+  let depth = stack.len();
+  let is_feature = depth == 2 && matches!(stack[1].name.as_str(), "featureMember" | "member" | "featureMembers");
+  if is_feature {
+    features.push(to_feature(element));
+  } else if depth > 2 {
+    stack[depth - 1].children.push(element);
+  }
+}
+
+fn to_feature(element: Element) -> Feature {
+  let mut properties = Vec::new();
+  let mut geometries: Vec<(String, FeatureGeometry)> = Vec::new();
+  for child in &element.children {
+    flatten(child, &child.name, &mut properties, &mut geometries);
+  }
+
+  // Hoofdgeometrie: hoogste dimensie, bij gelijke dimensie de eerste.
+  let main = geometries.iter().enumerate()
+    .max_by(|(ia, (_, a)), (ib, (_, b))| a.dimension().cmp(&b.dimension()).then(ib.cmp(ia)))
+    .map(|(i, _)| i);
+  let (geometry_property, geometry) = match main {
+    Some(i) => {
+      let (name, geometry) = geometries.remove(i);
+      (Some(name), Some(geometry))
+    },
+    None => (None, None),
+  };
+
+  Feature {
+    id: element.attribute("id").map(String::from),
+    feature_type: element.name,
+    properties,
+    geometry,
+    geometry_property,
+    other_geometries: geometries,
+  }
+}
+
+/// Slaat een eigenschap plat. Geometrieën, ook die dieper in de eigenschap (zoals `positie.plaatscoordinaten`),
+/// komen onder hun pad in `geometries` terecht.
+fn flatten(element: &Element, key: &str, out: &mut Vec<(String, String)>, geometries: &mut Vec<(String, FeatureGeometry)>) {
+  if let Some(geometry) = element.children.iter().find_map(parse_geometry) {
+    geometries.push((String::from(key), geometry));
+    return;
+  }
+  for (name, value) in &element.attributes {
+    out.push((format!("{}@{}", key, name), value.clone()));
+  }
+  if element.children.is_empty() {
+    let text = element.text.trim();
+    if !text.is_empty() || element.attributes.is_empty() {
+      out.push((String::from(key), String::from(text)));
+    }
+    return;
+  }
+  for child in &element.children {
+    // Elementen met een hoofdletter zijn in GML objecttypes (bijv. `TypeKadastraleAanduiding`) en voegen niets toe aan de naam.
+    if child.name.starts_with(|c: char| c.is_ascii_uppercase()) {
+      flatten(child, key, out, geometries);
+    } else {
+      flatten(child, &format!("{}.{}", key, child.name), out, geometries);
+    }
+  }
+}
+
+fn parse_geometry(element: &Element) -> Option<FeatureGeometry> {
+  let dimension = srs_dimension(element, 2);
+  match element.name.as_str() {
+    "Point" => coordinates(element, dimension).first().map(|p| FeatureGeometry::Point(*p)),
+    "LineString" | "Curve" | "CompositeCurve" | "OrientableCurve" => {
+      let points = curve_points(element, dimension);
+      if points.len() < 2 { None } else { Some(FeatureGeometry::LineString(points)) }
+    },
+    "Polygon" => polygon(element, dimension).map(FeatureGeometry::Polygon),
+    "Surface" | "CompositeSurface" | "MultiSurface" | "MultiPolygon" => {
+      let mut polygons = Vec::new();
+      collect_polygons(element, dimension, &mut polygons);
+      match (element.name.as_str(), polygons.len()) {
+        (_, 0) => None,
+        ("Surface", 1) | ("CompositeSurface", 1) => polygons.pop().map(FeatureGeometry::Polygon),
+        _ => Some(FeatureGeometry::MultiPolygon(polygons)),
+      }
+    },
+    "MultiCurve" | "MultiLineString" => {
+      let lines: Vec<Vec<Point>> = member_geometries(element)
+        .map(|member| curve_points(member, srs_dimension(member, dimension)))
+        .filter(|points| points.len() >= 2)
+        .collect();
+      if lines.is_empty() { None } else { Some(FeatureGeometry::MultiLineString(lines)) }
+    },
+    "MultiPoint" => {
+      let points: Vec<Point> = member_geometries(element)
+        .filter_map(|member| coordinates(member, srs_dimension(member, dimension)).first().copied())
+        .collect();
+      if points.is_empty() { None } else { Some(FeatureGeometry::MultiPoint(points)) }
+    },
+    _ => None,
+  }
+}
+
+/// Geometrieën binnen `*Member` en `*Members` elementen van een multi-geometrie.
+fn member_geometries(element: &Element) -> impl Iterator<Item = &Element> {
+  element.children.iter()
+    .filter(|c| c.name.ends_with("Member") || c.name.ends_with("Members"))
+    .flat_map(|c| c.children.iter())
+}
+
+fn collect_polygons(element: &Element, dimension: usize, out: &mut Vec<Polygon>) {
+  for child in &element.children {
+    let child_dimension = srs_dimension(child, dimension);
+    match child.name.as_str() {
+      "Polygon" | "PolygonPatch" => out.extend(polygon(child, child_dimension)),
+      _ => collect_polygons(child, child_dimension, out),
+    }
+  }
+}
+
+fn polygon(element: &Element, dimension: usize) -> Option<Polygon> {
+  let mut exterior = None;
+  let mut interiors = Vec::new();
+  for child in &element.children {
+    let ring = child.children.first().map(|ring| ring_points(ring, srs_dimension(ring, dimension)));
+    match (child.name.as_str(), ring) {
+      ("exterior", Some(ring)) | ("outerBoundaryIs", Some(ring)) => exterior = Some(ring),
+      ("interior", Some(ring)) | ("innerBoundaryIs", Some(ring)) => interiors.push(ring),
+      _ => {},
+    }
+  }
+  exterior.map(|exterior| Polygon::new(exterior, interiors))
+}
+
+fn ring_points(ring: &Element, dimension: usize) -> Vec<Point> {
+  match ring.name.as_str() {
+    // gml:Ring bestaat uit curveMembers die samen de ring vormen.
+    "Ring" => {
+      let mut points = Vec::new();
+      for curve in ring.children.iter().filter(|c| c.name == "curveMember").flat_map(|c| c.children.iter()) {
+        append_points(&mut points, curve_points(curve, srs_dimension(curve, dimension)));
+      }
+      points
+    },
+    _ => coordinates(ring, dimension),
+  }
+}
+
+/// Punten van een LineString of Curve, waarbij bogen in lijnstukken omgezet worden.
+fn curve_points(element: &Element, dimension: usize) -> Vec<Point> {
+  match element.name.as_str() {
+    "Curve" => {
+      let mut points = Vec::new();
+      for segment in element.child("segments").map(|s| s.children.iter()).into_iter().flatten() {
+        let segment_dimension = srs_dimension(segment, dimension);
+        let segment_points = match segment.name.as_str() {
+          "Arc" | "ArcString" => linearize_arcs(&coordinates(segment, segment_dimension)),
+          _ => coordinates(segment, segment_dimension),
+        };
+        append_points(&mut points, segment_points);
+      }
+      points
+    },
+    "CompositeCurve" | "OrientableCurve" => {
+      let mut points = Vec::new();
+      for curve in element.children.iter().flat_map(|c| c.children.iter()) {
+        append_points(&mut points, curve_points(curve, srs_dimension(curve, dimension)));
+      }
+      if element.name == "OrientableCurve" && element.attribute("orientation") == Some("-") {
+        points.reverse();
+      }
+      points
+    },
+    _ => coordinates(element, dimension),
+  }
+}
+
+/// Voegt punten toe, zonder het aansluitpunt van twee opeenvolgende segmenten dubbel op te nemen.
+fn append_points(points: &mut Vec<Point>, more: Vec<Point>) {
+  let skip = match (points.last(), more.first()) {
+    (Some(last), Some(first)) if last == first => 1,
+    _ => 0,
+  };
+  points.extend(more.into_iter().skip(skip));
+}
+
+/// Coördinaten uit `posList`, of uit opeenvolgende `pos`/`coordinates` elementen.
+fn coordinates(element: &Element, dimension: usize) -> Vec<Point> {
+  let mut points = Vec::new();
+  for child in &element.children {
+    let child_dimension = srs_dimension(child, dimension);
+    match child.name.as_str() {
+      "posList" | "pos" => {
+        let values: Vec<f64> = child.text.split_whitespace().filter_map(|v| v.parse().ok()).collect();
+        points.extend(values.chunks(child_dimension.max(2)).filter(|c| c.len() >= 2).map(|c| Point::new(c[0], c[1])));
+      },
+      "coordinates" => {
+        for tuple in child.text.split_whitespace() {
+          let values: Vec<f64> = tuple.split(',').filter_map(|v| v.parse().ok()).collect();
+          if values.len() >= 2 {
+            points.push(Point::new(values[0], values[1]));
+          }
+        }
+      },
+      "pointProperty" | "pointRep" => {
+        points.extend(child.children.first().and_then(|p| coordinates(p, child_dimension).first().copied()));
+      },
+      _ => {},
+    }
+  }
+  points
+}
+
+fn srs_dimension(element: &Element, inherited: usize) -> usize {
+  element.attribute("srsDimension").and_then(|d| d.parse().ok()).unwrap_or(inherited)
+}
+
+/// Zet een `ArcString` (punten p0, p1, p2, p3, p4, ... met elke boog door drie punten) om in lijnstukken.
+fn linearize_arcs(points: &[Point]) -> Vec<Point> {
+  if points.len() < 3 {
+    return points.to_vec();
+  }
+  let mut result = vec![points[0]];
+  let mut i = 0;
+  while i + 2 < points.len() {
+    let arc = linearize_arc(points[i], points[i + 1], points[i + 2]);
+    result.extend(arc.into_iter().skip(1));
+    i += 2;
+  }
+  result
+}
+
+fn linearize_arc(p0: Point, p1: Point, p2: Point) -> Vec<Point> {
+  let d = 2.0 * (p0.x * (p1.y - p2.y) + p1.x * (p2.y - p0.y) + p2.x * (p0.y - p1.y));
+  if d.abs() < 1e-12 {
+    return vec![p0, p1, p2]; // Collineair: geen echte boog.
+  }
+  let sq = |p: Point| p.x * p.x + p.y * p.y;
+  let cx = (sq(p0) * (p1.y - p2.y) + sq(p1) * (p2.y - p0.y) + sq(p2) * (p0.y - p1.y)) / d;
+  let cy = (sq(p0) * (p2.x - p1.x) + sq(p1) * (p0.x - p2.x) + sq(p2) * (p1.x - p0.x)) / d;
+  let radius = ((p0.x - cx).powi(2) + (p0.y - cy).powi(2)).sqrt();
+
+  let angle = |p: Point| (p.y - cy).atan2(p.x - cx);
+  let (a0, a2) = (angle(p0), angle(p2));
+  let counter_clockwise = (p1.x - p0.x) * (p2.y - p1.y) - (p1.y - p0.y) * (p2.x - p1.x) > 0.0;
+  let normalize = |a: f64| if a < 0.0 { a + 2.0 * PI } else { a };
+  let sweep = if counter_clockwise { normalize(a2 - a0) } else { -normalize(a0 - a2) };
+
+  let step = if radius > ARC_TOLERANCE { 2.0 * (1.0 - ARC_TOLERANCE / radius).acos() } else { PI / 4.0 };
+  let segments = ((sweep.abs() / step).ceil() as usize).clamp(2, 1000);
+  let mut result = Vec::with_capacity(segments + 1);
+  result.push(p0);
+  for s in 1..segments {
+    let a = a0 + sweep * s as f64 / segments as f64;
+    result.push(Point::new(cx + radius * a.cos(), cy + radius * a.sin()));
+  }
+  result.push(p2);
+  result
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::geometry::signed_area;
+
+  /// Een FeatureCollection met `members` als featureMembers.
+  fn parse(members: &[&str]) -> Vec<Feature> {
+    let members: String = members.iter().map(|member| format!("<gml:featureMember>{}</gml:featureMember>", member)).collect();
+    let gml = format!("<gml:FeatureCollection xmlns:gml=\"http://www.opengis.net/gml/3.2\" xmlns:kk=\"http://kadaster.nl\">\
+      <gml:boundedBy><gml:Envelope><gml:lowerCorner>0 0</gml:lowerCorner></gml:Envelope></gml:boundedBy>{}</gml:FeatureCollection>", members);
+    parse_features(gml.as_bytes()).unwrap()
+  }
+
+  fn distance(a: Point, b: Point) -> f64 {
+    ((a.x - b.x).powi(2) + (a.y - b.y).powi(2)).sqrt()
+  }
+
+  #[test]
+  fn flattens_properties_and_attributes() {
+    let features = parse(&["<kk:Perceel gml:id=\"p1\">\
+      <kk:identificatie><kk:NEN3610ID><kk:namespace>NL.IMKAD</kk:namespace><kk:lokaalID>1</kk:lokaalID></kk:NEN3610ID></kk:identificatie>\
+      <kk:kadastraleGrootte><kk:waarde uom=\"m2\">200</kk:waarde></kk:kadastraleGrootte>\
+      <kk:status codeSpace=\"urn:status\"/>\
+      <kk:soortGrootte>vastgesteld</kk:soortGrootte><kk:soortGrootte>voorlopig</kk:soortGrootte>\
+      </kk:Perceel>"]);
+    assert_eq!(features.len(), 1);
+    let feature = &features[0];
+    assert_eq!(feature.feature_type, "Perceel");
+    assert_eq!(feature.id.as_deref(), Some("p1"));
+    assert_eq!(feature.properties, vec![
+      (String::from("identificatie.namespace"), String::from("NL.IMKAD")),
+      (String::from("identificatie.lokaalID"), String::from("1")),
+      (String::from("kadastraleGrootte.waarde@uom"), String::from("m2")),
+      (String::from("kadastraleGrootte.waarde"), String::from("200")),
+      (String::from("status@codeSpace"), String::from("urn:status")),
+      (String::from("soortGrootte"), String::from("vastgesteld")),
+      (String::from("soortGrootte"), String::from("voorlopig")),
+    ]);
+    assert_eq!(feature.property("soortGrootte"), Some("vastgesteld"));
+    assert_eq!(feature.identificatie().as_deref(), Some("NL.IMKAD.1"));
+  }
+
+  #[test]
+  fn keeps_features_without_geometry() {
+    let features = parse(&["<kk:Perceel gml:id=\"p1\"><kk:sectie>A</kk:sectie><kk:begrenzingPerceel/></kk:Perceel>"]);
+    assert_eq!(features[0].geometry, None);
+    assert_eq!(features[0].geometry_property, None);
+    assert!(features[0].other_geometries.is_empty());
+    assert_eq!(features[0].property("sectie"), Some("A"));
+    // Zonder identificatie het gml:id.
+    assert_eq!(features[0].identificatie().as_deref(), Some("p1"));
+  }
+
+  #[test]
+  fn takes_the_highest_dimension_as_main_geometry() {
+    let features = parse(&["<kk:Perceel>\
+      <kk:plaatscoordinaten><gml:Point><gml:pos>5 5</gml:pos></gml:Point></kk:plaatscoordinaten>\
+      <kk:begrenzingPerceel><gml:Polygon><gml:exterior><gml:LinearRing>\
+        <gml:posList>0 0 10 0 10 10 0 10 0 0</gml:posList></gml:LinearRing></gml:exterior></gml:Polygon></kk:begrenzingPerceel>\
+      </kk:Perceel>"]);
+    let feature = &features[0];
+    assert_eq!(feature.geometry_property.as_deref(), Some("begrenzingPerceel"));
+    assert!(matches!(feature.geometry, Some(FeatureGeometry::Polygon(_))));
+    assert_eq!(feature.geometry_of("plaatscoordinaten"), Some(&FeatureGeometry::Point(Point::new(5.0, 5.0))));
+  }
+
+  #[test]
+  fn reads_multi_surfaces_with_holes() {
+    let features = parse(&["<kk:Pand><kk:geometrie><gml:MultiSurface>\
+      <gml:surfaceMember><gml:Polygon>\
+        <gml:exterior><gml:LinearRing><gml:posList srsDimension=\"3\">0 0 1 10 0 1 10 10 1 0 10 1 0 0 1</gml:posList></gml:LinearRing></gml:exterior>\
+        <gml:interior><gml:LinearRing><gml:posList srsDimension=\"3\">4 4 1 6 4 1 6 6 1 4 6 1 4 4 1</gml:posList></gml:LinearRing></gml:interior>\
+      </gml:Polygon></gml:surfaceMember>\
+      <gml:surfaceMember><gml:Polygon><gml:exterior><gml:LinearRing>\
+        <gml:posList>20 0 30 0 30 10 20 0</gml:posList></gml:LinearRing></gml:exterior></gml:Polygon></gml:surfaceMember>\
+      </gml:MultiSurface></kk:geometrie></kk:Pand>"]);
+    let polygons = match &features[0].geometry {
+      Some(FeatureGeometry::MultiPolygon(polygons)) => polygons,
+      other => panic!("{:?}", other),
+    };
+    assert_eq!(polygons.len(), 2);
+    assert_eq!(polygons[0].exterior.len(), 5);
+    assert_eq!(polygons[0].exterior[1], Point::new(10.0, 0.0));
+    assert_eq!(polygons[0].interiors, vec![vec![
+      Point::new(4.0, 4.0), Point::new(6.0, 4.0), Point::new(6.0, 6.0), Point::new(4.0, 6.0), Point::new(4.0, 4.0),
+    ]]);
+    assert_eq!(polygons[1].exterior[2], Point::new(30.0, 10.0));
+  }
+
+  #[test]
+  fn linearizes_arcs() {
+    let features = parse(&["<kk:Grens><kk:geometrie><gml:Curve><gml:segments>\
+      <gml:LineStringSegment><gml:posList>0 0 10 0</gml:posList></gml:LineStringSegment>\
+      <gml:Arc><gml:posList>10 0 15 5 20 0</gml:posList></gml:Arc>\
+      </gml:segments></gml:Curve></kk:geometrie></kk:Grens>"]);
+    let points = match &features[0].geometry {
+      Some(FeatureGeometry::LineString(points)) => points,
+      other => panic!("{:?}", other),
+    };
+    assert_eq!(points[0], Point::new(0.0, 0.0));
+    assert_eq!(points[1], Point::new(10.0, 0.0));
+    assert_eq!(*points.last().unwrap(), Point::new(20.0, 0.0));
+    // Alle punten van de boog liggen op de cirkel, en geen lijnstuk wijkt meer dan de tolerantie af.
+    let center = Point::new(15.0, 0.0);
+    let arc = &points[1..];
+    assert!(arc.len() > 10, "{:?}", arc);
+    assert!(arc.iter().all(|p| (distance(*p, center) - 5.0).abs() < 1e-9), "{:?}", arc);
+    assert!(arc.iter().all(|p| p.y >= 0.0), "{:?}", arc);
+    for pair in arc.windows(2) {
+      let middle = Point::new((pair[0].x + pair[1].x) / 2.0, (pair[0].y + pair[1].y) / 2.0);
+      assert!(5.0 - distance(middle, center) <= ARC_TOLERANCE);
+    }
+  }
+
+  #[test]
+  fn reads_rings_of_arcs() {
+    // Een cirkel met straal 5 als twee bogen.
+    let features = parse(&["<kk:Bouwvlak><kk:geometrie><gml:Polygon><gml:exterior><gml:Ring><gml:curveMember><gml:Curve><gml:segments>\
+      <gml:ArcString><gml:posList>5 0 0 5 -5 0 0 -5 5 0</gml:posList></gml:ArcString>\
+      </gml:segments></gml:Curve></gml:curveMember></gml:Ring></gml:exterior></gml:Polygon></kk:geometrie></kk:Bouwvlak>"]);
+    let polygon = match &features[0].geometry {
+      Some(FeatureGeometry::Polygon(polygon)) => polygon,
+      other => panic!("{:?}", other),
+    };
+    assert_eq!(polygon.exterior.first(), polygon.exterior.last());
+    // Tegen de klok in, en binnen de cirkel maar niet meer dan de omtrek maal de tolerantie kleiner.
+    let area = signed_area(&polygon.exterior);
+    assert!(area < PI * 25.0 && area > PI * 25.0 - 2.0 * PI * 5.0 * ARC_TOLERANCE, "{}", area);
+  }
+}
@@ -0,0 +1,466 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use rusqlite::types::Value;
+use rusqlite::{params, params_from_iter, Connection};
+
+use crate::geometry::{FeatureGeometry, Point, Polygon};
+use crate::gml::{Feature, Layer};
+use crate::wkt::feature_geometry_to_wkt;
+
+
+const RD_NEW_SRS_ID: i32 = 28992;
+const GEOMETRY_COLUMN: &str = "geom";
+const ID_COLUMN: &str = "fid";
+const GML_ID_COLUMN: &str = "gml_id";
+/// Tabel met per laag welke kolom bij welke eigenschap hoort.
+const MAPPING_TABLE: &str = "dkkdownload_kolommen";
+
+const RD_NEW_DEFINITION: &str = "PROJCS[\"Amersfoort / RD New\",GEOGCS[\"Amersfoort\",DATUM[\"Amersfoort\",\
+  SPHEROID[\"Bessel 1841\",6377397.155,299.1528128,AUTHORITY[\"EPSG\",\"7004\"]],\
+  TOWGS84[565.417,50.3319,465.552,-0.398957,0.343988,-1.8774,4.0725],AUTHORITY[\"EPSG\",\"6289\"]],\
+  PRIMEM[\"Greenwich\",0,AUTHORITY[\"EPSG\",\"8901\"]],UNIT[\"degree\",0.0174532925199433,AUTHORITY[\"EPSG\",\"9122\"]],\
+  AUTHORITY[\"EPSG\",\"4289\"]],PROJECTION[\"Oblique_Stereographic\"],PARAMETER[\"latitude_of_origin\",52.1561605555556],\
+  PARAMETER[\"central_meridian\",5.38763888888889],PARAMETER[\"scale_factor\",0.9999079],PARAMETER[\"false_easting\",155000],\
+  PARAMETER[\"false_northing\",463000],UNIT[\"metre\",1,AUTHORITY[\"EPSG\",\"9001\"]],AXIS[\"Easting\",EAST],\
+  AXIS[\"Northing\",NORTH],AUTHORITY[\"EPSG\",\"28992\"]]";
+
+const WGS84_DEFINITION: &str = "GEOGCS[\"WGS 84\",DATUM[\"WGS_1984\",SPHEROID[\"WGS 84\",6378137,298.257223563,\
+  AUTHORITY[\"EPSG\",\"7030\"]],AUTHORITY[\"EPSG\",\"6326\"]],PRIMEM[\"Greenwich\",0,AUTHORITY[\"EPSG\",\"8901\"]],\
+  UNIT[\"degree\",0.0174532925199433,AUTHORITY[\"EPSG\",\"9122\"]],AXIS[\"Latitude\",NORTH],AXIS[\"Longitude\",EAST],\
+  AUTHORITY[\"EPSG\",\"4326\"]]";
+
+/// Schrijft alle lagen naar één GeoPackage, met per laag een tabel in RD New (EPSG:28992).
+///
+/// Elke tabel heeft een `fid`, een `geom` kolom met de hoofdgeometrie, een `gml_id` en een tekstkolom per eigenschap.
+/// Herhaalde eigenschappen worden als JSON-array opgeslagen, overige geometrieën (zoals `plaatscoordinaten`) als WKT.
+/// Omdat SQLite bij kolomnamen geen onderscheid maakt tussen hoofd- en kleine letters, worden eigenschappen die alleen
+/// daarin verschillen, of die zo heten als een van de vaste kolommen, genummerd (`naam_1`). Welke kolom bij welke
+/// eigenschap hoort staat in de tabel `dkkdownload_kolommen`.
+/// Per laag wordt een R-tree spatial index aangemaakt. Een bestaand bestand op `path` wordt overschreven.
+pub fn write_geopackage(layers: &[Layer], path: &Path) -> Result<(), Box<dyn Error + Send + Sync>> {
+  if path.exists() {
+    fs::remove_file(path)?;
+  }
+  let mut connection = Connection::open(path)?;
+  // 'GPKG' en versie 1.2, waar de onderstaande R-tree triggers bij horen.
+  connection.execute_batch("PRAGMA application_id = 1196444487; PRAGMA user_version = 10200;")?;
+
+  let transaction = connection.transaction()?;
+  create_metadata_tables(&transaction)?;
+  create_mapping_table(&transaction)?;
+  for layer in layers {
+    write_layer(&transaction, layer)?;
+  }
+  transaction.commit()?;
+  Ok(())
+}
+
+fn create_metadata_tables(connection: &Connection) -> rusqlite::Result<()> {
+  connection.execute_batch("
+    CREATE TABLE gpkg_spatial_ref_sys (
+      srs_name TEXT NOT NULL,
+      srs_id INTEGER NOT NULL PRIMARY KEY,
+      organization TEXT NOT NULL,
+      organization_coordsys_id INTEGER NOT NULL,
+      definition TEXT NOT NULL,
+      description TEXT
+    );
+    CREATE TABLE gpkg_contents (
+      table_name TEXT NOT NULL PRIMARY KEY,
+      data_type TEXT NOT NULL,
+      identifier TEXT UNIQUE,
+      description TEXT DEFAULT '',
+      last_change DATETIME NOT NULL DEFAULT (strftime('%Y-%m-%dT%H:%M:%fZ','now')),
+      min_x DOUBLE,
+      min_y DOUBLE,
+      max_x DOUBLE,
+      max_y DOUBLE,
+      srs_id INTEGER,
+      CONSTRAINT fk_gc_r_srs_id FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys(srs_id)
+    );
+    CREATE TABLE gpkg_geometry_columns (
+      table_name TEXT NOT NULL,
+      column_name TEXT NOT NULL,
+      geometry_type_name TEXT NOT NULL,
+      srs_id INTEGER NOT NULL,
+      z TINYINT NOT NULL,
+      m TINYINT NOT NULL,
+      CONSTRAINT pk_geom_cols PRIMARY KEY (table_name, column_name),
+      CONSTRAINT uk_gc_table_name UNIQUE (table_name),
+      CONSTRAINT fk_gc_tn FOREIGN KEY (table_name) REFERENCES gpkg_contents(table_name),
+      CONSTRAINT fk_gc_srs FOREIGN KEY (srs_id) REFERENCES gpkg_spatial_ref_sys (srs_id)
+    );
+    CREATE TABLE gpkg_extensions (
+      table_name TEXT,
+      column_name TEXT,
+      extension_name TEXT NOT NULL,
+      definition TEXT NOT NULL,
+      scope TEXT NOT NULL,
+      CONSTRAINT ge_tce UNIQUE (table_name, column_name, extension_name)
+    );
+  ")?;
+
+  let mut insert = connection.prepare("INSERT INTO gpkg_spatial_ref_sys VALUES (?1, ?2, ?3, ?4, ?5, ?6)")?;
+  insert.execute(params!["Undefined cartesian SRS", -1, "NONE", -1, "undefined", "undefined cartesian coordinate reference system"])?;
+  insert.execute(params!["Undefined geographic SRS", 0, "NONE", 0, "undefined", "undefined geographic coordinate reference system"])?;
+  insert.execute(params!["WGS 84 geodetic", 4326, "EPSG", 4326, WGS84_DEFINITION, "longitude/latitude coordinates in decimal degrees on the WGS 84 spheroid"])?;
+  insert.execute(params!["Amersfoort / RD New", RD_NEW_SRS_ID, "EPSG", RD_NEW_SRS_ID, RD_NEW_DEFINITION, "Rijksdriehoeksstelsel"])?;
+  Ok(())
+}
+
+fn create_mapping_table(connection: &Connection) -> rusqlite::Result<()> {
+  connection.execute(&format!("CREATE TABLE {} (id INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, laag TEXT NOT NULL, \
+    kolom TEXT NOT NULL, eigenschap TEXT NOT NULL)", quote(MAPPING_TABLE)), [])?;
+  connection.execute("INSERT INTO gpkg_contents (table_name, data_type, identifier, description) VALUES (?1, 'attributes', ?1, ?2)",
+    params![MAPPING_TABLE, "Kolommen van de lagen en de eigenschappen uit de GML waar ze vandaan komen"])?;
+  Ok(())
+}
+
+fn write_layer(connection: &Connection, layer: &Layer) -> rusqlite::Result<()> {
+  // Kolommen in de volgorde waarin de eigenschappen voor het eerst voorkomen.
+  let mut properties: Vec<&str> = Vec::new();
+  for feature in &layer.features {
+    let keys = feature.properties.iter().map(|(k, _)| k.as_str()).chain(feature.other_geometries.iter().map(|(k, _)| k.as_str()));
+    for key in keys {
+      if !properties.contains(&key) {
+        properties.push(key);
+      }
+    }
+  }
+  let columns = column_names(&properties);
+
+  let table = quote(&layer.name);
+  let mut definition = format!("CREATE TABLE {} ({} INTEGER PRIMARY KEY AUTOINCREMENT NOT NULL, {} {}, {} TEXT",
+    table, ID_COLUMN, GEOMETRY_COLUMN, geometry_type_name(layer), GML_ID_COLUMN);
+  for column in &columns {
+    definition.push_str(&format!(", {} TEXT", quote(column)));
+  }
+  definition.push(')');
+  connection.execute(&definition, [])?;
+
+  let mut insert_mapping = connection.prepare(&format!("INSERT INTO {} (laag, kolom, eigenschap) VALUES (?1, ?2, ?3)", quote(MAPPING_TABLE)))?;
+  for (column, property) in columns.iter().zip(&properties) {
+    insert_mapping.execute(params![layer.name, column, property])?;
+  }
+
+  let placeholders: Vec<String> = (1..=columns.len() + 2).map(|i| format!("?{}", i)).collect();
+  let mut insert = connection.prepare(&format!("INSERT INTO {} ({}, {}{}) VALUES ({})",
+    table, GEOMETRY_COLUMN, GML_ID_COLUMN,
+    columns.iter().map(|c| format!(", {}", quote(c))).collect::<String>(),
+    placeholders.join(", ")))?;
+  let rtree = quote(&format!("rtree_{}_{}", layer.name, GEOMETRY_COLUMN));
+  connection.execute(&format!("CREATE VIRTUAL TABLE {} USING rtree(id, minx, maxx, miny, maxy)", rtree), [])?;
+  let mut insert_rtree = connection.prepare(&format!("INSERT INTO {} VALUES (?1, ?2, ?3, ?4, ?5)", rtree))?;
+
+  let mut extent = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+  for feature in &layer.features {
+    let mut values = vec![
+      feature.geometry.as_ref().map_or(Value::Null, |g| Value::Blob(geometry_blob(g))),
+      feature.id.clone().map_or(Value::Null, Value::Text),
+    ];
+    values.extend(properties.iter().map(|property| column_value(feature, property)));
+    insert.execute(params_from_iter(values.iter()))?;
+
+    if let Some(geometry) = &feature.geometry {
+      let (min_x, min_y, max_x, max_y) = geometry.bounds();
+      insert_rtree.execute(params![connection.last_insert_rowid(), min_x, max_x, min_y, max_y])?;
+      extent = (extent.0.min(min_x), extent.1.min(min_y), extent.2.max(max_x), extent.3.max(max_y));
+    }
+  }
+
+  let extent: [Option<f64>; 4] = if extent.0.is_finite() {
+    [Some(extent.0), Some(extent.1), Some(extent.2), Some(extent.3)]
+  } else {
+    [None; 4]
+  };
+  connection.execute(
+    "INSERT INTO gpkg_contents (table_name, data_type, identifier, min_x, min_y, max_x, max_y, srs_id) VALUES (?1, 'features', ?1, ?2, ?3, ?4, ?5, ?6)",
+    params![layer.name, extent[0], extent[1], extent[2], extent[3], RD_NEW_SRS_ID])?;
+  connection.execute("INSERT INTO gpkg_geometry_columns VALUES (?1, ?2, ?3, ?4, 0, 0)",
+    params![layer.name, GEOMETRY_COLUMN, geometry_type_name(layer), RD_NEW_SRS_ID])?;
+  connection.execute("INSERT INTO gpkg_extensions VALUES (?1, ?2, 'gpkg_rtree_index', 'http://www.geopackage.org/spec120/#extension_rtree', 'write-only')",
+    params![layer.name, GEOMETRY_COLUMN])?;
+  // De triggers pas na het vullen aanmaken: ze gebruiken ST_* functies die alleen GeoPackage-lezers zoals GDAL leveren.
+  connection.execute_batch(&rtree_triggers(&layer.name, &table, &rtree))?;
+  Ok(())
+}
+
+/// Unieke kolomnamen voor `properties`. SQLite vergelijkt kolomnamen zonder op (ASCII) hoofdletters te letten; bij een
+/// botsing, ook met een vaste kolom, krijgt de eigenschap een nummer.
+fn column_names(properties: &[&str]) -> Vec<String> {
+  let mut used: HashSet<String> = [ID_COLUMN, GEOMETRY_COLUMN, GML_ID_COLUMN].iter().map(|c| c.to_ascii_lowercase()).collect();
+  properties.iter().map(|property| {
+    let mut name = String::from(*property);
+    let mut n = 1;
+    while !used.insert(name.to_ascii_lowercase()) {
+      name = format!("{}_{}", property, n);
+      n += 1;
+    }
+    name
+  }).collect()
+}
+
+fn column_value(feature: &Feature, property: &str) -> Value {
+  if let Some(geometry) = feature.other_geometries.iter().find(|(k, _)| k == property).map(|(_, g)| g) {
+    return Value::Text(feature_geometry_to_wkt(geometry));
+  }
+  let values: Vec<&str> = feature.properties.iter().filter(|(k, _)| k == property).map(|(_, v)| v.as_str()).collect();
+  match values.len() {
+    0 => Value::Null,
+    1 => Value::Text(String::from(values[0])),
+    _ => Value::Text(json::stringify(values)),
+  }
+}
+
+/// Geometrietype van de laag, of `GEOMETRY` als de features verschillende typen hebben.
+fn geometry_type_name(layer: &Layer) -> &'static str {
+  let mut names = layer.features.iter().filter_map(|f| f.geometry.as_ref()).map(|g| match g {
+    FeatureGeometry::Point(_) => "POINT",
+    FeatureGeometry::LineString(_) => "LINESTRING",
+    FeatureGeometry::Polygon(_) => "POLYGON",
+    FeatureGeometry::MultiPoint(_) => "MULTIPOINT",
+    FeatureGeometry::MultiLineString(_) => "MULTILINESTRING",
+    FeatureGeometry::MultiPolygon(_) => "MULTIPOLYGON",
+  });
+  match names.next() {
+    Some(first) if names.all(|name| name == first) => first,
+    _ => "GEOMETRY",
+  }
+}
+
+fn rtree_triggers(name: &str, table: &str, rtree: &str) -> String {
+  let trigger = |suffix: &str| quote(&format!("rtree_{}_{}_{}", name, GEOMETRY_COLUMN, suffix));
+  let bounds = format!("ST_MinX(NEW.{g}), ST_MaxX(NEW.{g}), ST_MinY(NEW.{g}), ST_MaxY(NEW.{g})", g = GEOMETRY_COLUMN);
+  format!("
+    CREATE TRIGGER {insert} AFTER INSERT ON {t} WHEN (NEW.{g} NOT NULL AND NOT ST_IsEmpty(NEW.{g}))
+    BEGIN INSERT OR REPLACE INTO {r} VALUES (NEW.{i}, {b}); END;
+    CREATE TRIGGER {update1} AFTER UPDATE OF {g} ON {t} WHEN OLD.{i} = NEW.{i} AND (NEW.{g} NOTNULL AND NOT ST_IsEmpty(NEW.{g}))
+    BEGIN INSERT OR REPLACE INTO {r} VALUES (NEW.{i}, {b}); END;
+    CREATE TRIGGER {update2} AFTER UPDATE OF {g} ON {t} WHEN OLD.{i} = NEW.{i} AND (NEW.{g} ISNULL OR ST_IsEmpty(NEW.{g}))
+    BEGIN DELETE FROM {r} WHERE id = OLD.{i}; END;
+    CREATE TRIGGER {update3} AFTER UPDATE ON {t} WHEN OLD.{i} != NEW.{i} AND (NEW.{g} NOTNULL AND NOT ST_IsEmpty(NEW.{g}))
+    BEGIN DELETE FROM {r} WHERE id = OLD.{i}; INSERT OR REPLACE INTO {r} VALUES (NEW.{i}, {b}); END;
+    CREATE TRIGGER {update4} AFTER UPDATE ON {t} WHEN OLD.{i} != NEW.{i} AND (NEW.{g} ISNULL OR ST_IsEmpty(NEW.{g}))
+    BEGIN DELETE FROM {r} WHERE id IN (OLD.{i}, NEW.{i}); END;
+    CREATE TRIGGER {delete} AFTER DELETE ON {t} WHEN OLD.{g} NOT NULL
+    BEGIN DELETE FROM {r} WHERE id = OLD.{i}; END;
+  ",
+    insert = trigger("insert"), update1 = trigger("update1"), update2 = trigger("update2"),
+    update3 = trigger("update3"), update4 = trigger("update4"), delete = trigger("delete"),
+    t = table, r = rtree, g = GEOMETRY_COLUMN, i = ID_COLUMN, b = bounds)
+}
+
+fn quote(identifier: &str) -> String {
+  format!("\"{}\"", identifier.replace('"', "\"\""))
+}
+
+/// GeoPackage geometrie: header met SRS en omhullende rechthoek, gevolgd door little-endian WKB.
+fn geometry_blob(geometry: &FeatureGeometry) -> Vec<u8> {
+  let mut blob = Vec::new();
+  blob.extend_from_slice(b"GP");
+  blob.push(0);
+  // Little endian, envelope [minx, maxx, miny, maxy].
+  blob.push(0b0000_0011);
+  blob.extend_from_slice(&RD_NEW_SRS_ID.to_le_bytes());
+  let (min_x, min_y, max_x, max_y) = geometry.bounds();
+  for v in [min_x, max_x, min_y, max_y] {
+    blob.extend_from_slice(&v.to_le_bytes());
+  }
+  write_wkb(&mut blob, geometry);
+  blob
+}
+
+fn write_wkb(out: &mut Vec<u8>, geometry: &FeatureGeometry) {
+  match geometry {
+    FeatureGeometry::Point(point) => {
+      wkb_header(out, 1);
+      wkb_point(out, point);
+    },
+    FeatureGeometry::LineString(points) => {
+      wkb_header(out, 2);
+      wkb_points(out, points);
+    },
+    FeatureGeometry::Polygon(polygon) => wkb_polygon(out, polygon),
+    FeatureGeometry::MultiPoint(points) => {
+      wkb_header(out, 4);
+      out.extend_from_slice(&(points.len() as u32).to_le_bytes());
+      for point in points {
+        wkb_header(out, 1);
+        wkb_point(out, point);
+      }
+    },
+    FeatureGeometry::MultiLineString(lines) => {
+      wkb_header(out, 5);
+      out.extend_from_slice(&(lines.len() as u32).to_le_bytes());
+      for line in lines {
+        wkb_header(out, 2);
+        wkb_points(out, line);
+      }
+    },
+    FeatureGeometry::MultiPolygon(polygons) => {
+      wkb_header(out, 6);
+      out.extend_from_slice(&(polygons.len() as u32).to_le_bytes());
+      for polygon in polygons {
+        wkb_polygon(out, polygon);
+      }
+    },
+  }
+}
+
+fn wkb_header(out: &mut Vec<u8>, geometry_type: u32) {
+  out.push(1);
+  out.extend_from_slice(&geometry_type.to_le_bytes());
+}
+
+fn wkb_point(out: &mut Vec<u8>, point: &Point) {
+  out.extend_from_slice(&point.x.to_le_bytes());
+  out.extend_from_slice(&point.y.to_le_bytes());
+}
+
+fn wkb_points(out: &mut Vec<u8>, points: &[Point]) {
+  out.extend_from_slice(&(points.len() as u32).to_le_bytes());
+  for point in points {
+    wkb_point(out, point);
+  }
+}
+
+fn wkb_polygon(out: &mut Vec<u8>, polygon: &Polygon) {
+  wkb_header(out, 3);
+  out.extend_from_slice(&(1 + polygon.interiors.len() as u32).to_le_bytes());
+  for ring in polygon.rings() {
+    wkb_points(out, ring);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::convert::TryInto;
+
+  use super::*;
+
+  fn feature(id: &str, geometry: Option<FeatureGeometry>, properties: &[(&str, &str)]) -> Feature {
+    Feature {
+      feature_type: String::from("Perceel"),
+      id: Some(String::from(id)),
+      properties: properties.iter().map(|(k, v)| (String::from(*k), String::from(*v))).collect(),
+      geometry,
+      geometry_property: Some(String::from("begrenzingPerceel")),
+      other_geometries: vec![(String::from("plaatscoordinaten"), FeatureGeometry::Point(Point::new(155005.0, 463005.0)))],
+    }
+  }
+
+  fn square(min_x: f64, max_x: f64) -> FeatureGeometry {
+    FeatureGeometry::Polygon(Polygon::new(vec![
+      Point::new(min_x, 463000.0), Point::new(min_x, 463010.0), Point::new(max_x, 463010.0),
+      Point::new(max_x, 463000.0), Point::new(min_x, 463000.0),
+    ], Vec::new()))
+  }
+
+  #[test]
+  fn writes_layers_that_can_be_read_back() {
+    let path = std::env::temp_dir().join(format!("dkkdownload-gpkg-test-{}.gpkg", std::process::id()));
+    let layers = vec![
+      Layer { name: String::from("perceel"), features: vec![
+        feature("p1", Some(square(155000.0, 155010.0)), &[("perceelnummer", "101"), ("naam", "a"), ("naam", "b")]),
+        feature("p2", Some(square(155010.0, 155020.0)), &[("perceelnummer", "102")]),
+      ] },
+      Layer { name: String::from("leeg"), features: vec![feature("l1", None, &[])] },
+    ];
+    write_geopackage(&layers, &path).unwrap();
+    // In een eigen blok, zodat de verbinding gesloten is voordat het bestand verwijderd wordt.
+    {
+      let connection = Connection::open(&path).unwrap();
+
+      let application_id: i32 = connection.query_row("PRAGMA application_id", [], |row| row.get(0)).unwrap();
+      assert_eq!(application_id, 0x4750_4B47);
+
+      let mut statement = connection.prepare("SELECT table_name, data_type, identifier, srs_id FROM gpkg_contents WHERE srs_id IS NOT NULL ORDER BY table_name").unwrap();
+      let contents: Vec<(String, String, String, i32)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+        .unwrap().collect::<Result<_, _>>().unwrap();
+      assert_eq!(contents, vec![
+        (String::from("leeg"), String::from("features"), String::from("leeg"), RD_NEW_SRS_ID),
+        (String::from("perceel"), String::from("features"), String::from("perceel"), RD_NEW_SRS_ID),
+      ]);
+      let extent = |table: &str| -> (Option<f64>, Option<f64>, Option<f64>, Option<f64>) {
+        connection.query_row("SELECT min_x, min_y, max_x, max_y FROM gpkg_contents WHERE table_name = ?1", [table],
+          |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))).unwrap()
+      };
+      assert_eq!(extent("perceel"), (Some(155000.0), Some(463000.0), Some(155020.0), Some(463010.0)));
+      assert_eq!(extent("leeg"), (None, None, None, None));
+
+      let mut statement = connection.prepare(
+        "SELECT table_name, column_name, geometry_type_name, srs_id, z, m FROM gpkg_geometry_columns ORDER BY table_name").unwrap();
+      let columns: Vec<(String, String, String, i32, i32, i32)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?, row.get(5)?)))
+        .unwrap().collect::<Result<_, _>>().unwrap();
+      assert_eq!(columns, vec![
+        (String::from("leeg"), String::from("geom"), String::from("GEOMETRY"), RD_NEW_SRS_ID, 0, 0),
+        (String::from("perceel"), String::from("geom"), String::from("POLYGON"), RD_NEW_SRS_ID, 0, 0),
+      ]);
+
+      let mut statement = connection.prepare("SELECT gml_id, perceelnummer, naam FROM perceel ORDER BY fid").unwrap();
+      let rows: Vec<(String, String, Option<String>)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap().collect::<Result<_, _>>().unwrap();
+      assert_eq!(rows, vec![
+        (String::from("p1"), String::from("101"), Some(String::from("[\"a\",\"b\"]"))),
+        (String::from("p2"), String::from("102"), None),
+      ]);
+      let (plaatscoordinaten, blob): (String, Vec<u8>) = connection
+        .query_row("SELECT plaatscoordinaten, geom FROM perceel WHERE gml_id = 'p2'", [], |row| Ok((row.get(0)?, row.get(1)?))).unwrap();
+      assert_eq!(plaatscoordinaten, "POINT(155005 463005)");
+      // Header met SRS 28992 en de envelop, gevolgd door een WKB polygon met één ring van vijf punten.
+      assert_eq!(&blob[..4], b"GP\x00\x03");
+      assert_eq!(&blob[4..8], &RD_NEW_SRS_ID.to_le_bytes());
+      assert_eq!(f64::from_le_bytes(blob[8..16].try_into().unwrap()), 155010.0);
+      assert_eq!(&blob[40..53], &[1, 3, 0, 0, 0, 1, 0, 0, 0, 5, 0, 0, 0]);
+
+      let indexed: i64 = connection.query_row("SELECT count(*) FROM rtree_perceel_geom WHERE minx >= 155010", [], |row| row.get(0)).unwrap();
+      assert_eq!(indexed, 1);
+      let empty: Option<Vec<u8>> = connection.query_row("SELECT geom FROM leeg", [], |row| row.get(0)).unwrap();
+      assert_eq!(empty, None);
+    }
+    let _ = fs::remove_file(&path);
+  }
+
+  #[test]
+  fn numbers_columns_that_differ_only_in_case() {
+    assert_eq!(column_names(&["naam", "Naam", "NAAM", "naam_1", "geom", "FID", "gml_id", "straat"]),
+      vec!["naam", "Naam_1", "NAAM_2", "naam_1_1", "geom_1", "FID_1", "gml_id_1", "straat"]);
+
+    let path = std::env::temp_dir().join(format!("dkkdownload-gpkg-columns-test-{}.gpkg", std::process::id()));
+    let layers = vec![Layer { name: String::from("perceel"), features: vec![
+      feature("p1", Some(square(155000.0, 155010.0)), &[("naam", "klein"), ("Naam", "groot"), ("geom", "tekst")]),
+    ] }];
+    write_geopackage(&layers, &path).unwrap();
+    {
+      let connection = Connection::open(&path).unwrap();
+      let row: (String, String, String, String) = connection
+        .query_row("SELECT gml_id, naam, Naam_1, geom_1 FROM perceel", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))
+        .unwrap();
+      assert_eq!(row, (String::from("p1"), String::from("klein"), String::from("groot"), String::from("tekst")));
+
+      let data_type: String = connection
+        .query_row("SELECT data_type FROM gpkg_contents WHERE table_name = 'dkkdownload_kolommen'", [], |row| row.get(0)).unwrap();
+      assert_eq!(data_type, "attributes");
+      let mut statement = connection.prepare("SELECT laag, kolom, eigenschap FROM dkkdownload_kolommen ORDER BY id").unwrap();
+      let mappings: Vec<(String, String, String)> = statement
+        .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+        .unwrap().collect::<Result<_, _>>().unwrap();
+      let mapping = |column: &str, property: &str| (String::from("perceel"), String::from(column), String::from(property));
+      assert_eq!(mappings, vec![
+        mapping("naam", "naam"), mapping("Naam_1", "Naam"), mapping("geom_1", "geom"), mapping("plaatscoordinaten", "plaatscoordinaten"),
+      ]);
+    }
+    let _ = fs::remove_file(&path);
+  }
+}
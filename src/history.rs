@@ -0,0 +1,231 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::collections::HashMap;
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use json::{object, JsonValue};
+
+use crate::error::DkkError;
+use crate::events::Event;
+use crate::manifest::format_timestamp;
+
+
+/// Geschiedenis van de downloads: welke gebieden en lagen wanneer aangevraagd zijn, met de downloadRequestIds, de
+/// statusovergangen, download links, uitvoer en fouten.
+///
+/// Het is één bestand met JSON Lines waar elke run regels aan toevoegt, elk met het id van de run en een tijdstip:
+/// eerst `started` met de opties, dan de events van de run (zie `Event`) en tot slot `finished` met de exit code. Een
+/// run zonder `finished` is onderbroken. Omdat er alleen toegevoegd wordt, kunnen meerdere runs tegelijk schrijven.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct History {
+  path: PathBuf,
+}
+
+impl History {
+  pub fn new<P: Into<PathBuf>>(path: P) -> Self {
+    Self { path: path.into() }
+  }
+
+  /// `$XDG_DATA_HOME/dkkdownload/history.jsonl`, `~/.local/share/dkkdownload/history.jsonl` of op Windows
+  /// `%APPDATA%\dkkdownload\history.jsonl`.
+  pub fn default_path() -> Option<PathBuf> {
+    let dir = env::var_os("XDG_DATA_HOME").map(PathBuf::from)
+      .or_else(|| env::var_os("APPDATA").map(PathBuf::from))
+      .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("share")))?;
+    Some(dir.join("dkkdownload").join("history.jsonl"))
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  /// Begint een nieuwe run met de opties `args` (zonder de programmanaam), uitgevoerd in `cwd`. `rerun_of` is het id
+  /// van de run die met `history rerun` herhaald wordt.
+  pub fn start(&self, args: &[String], cwd: &Path, rerun_of: Option<&str>) -> io::Result<HistoryRun> {
+    if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+      fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+    let mut run = HistoryRun { id: format!("{:08x}", rand::random::<u32>()), file, last_progress: HashMap::new() };
+    run.write(object!{
+      "event" => "started",
+      "args" => args.to_vec(),
+      "cwd" => cwd.to_string_lossy().into_owned(),
+      "version" => env!("CARGO_PKG_VERSION"),
+      "rerun_of" => rerun_of
+    });
+    Ok(run)
+  }
+
+  /// Alle runs, van oud naar nieuw. Regels die geen JSON zijn, bijv. een half geschreven regel, worden overgeslagen.
+  pub fn runs(&self) -> io::Result<Vec<Run>> {
+    let text = match fs::read_to_string(&self.path) {
+      Ok(text) => text,
+      Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(e) => return Err(e),
+    };
+    let mut runs: Vec<Run> = Vec::new();
+    let mut index: HashMap<String, usize> = HashMap::new();
+    for record in text.lines().filter_map(|line| json::parse(line).ok()) {
+      let id = match record["run"].as_str() {
+        Some(id) => String::from(id),
+        None => continue,
+      };
+      let i = *index.entry(id.clone()).or_insert_with(|| {
+        runs.push(Run { id, records: Vec::new() });
+        runs.len() - 1
+      });
+      runs[i].records.push(record);
+    }
+    Ok(runs)
+  }
+
+  /// De run met dit id, of het begin ervan zolang dat maar bij één run past.
+  pub fn find(&self, id: &str) -> Result<Run, DkkError> {
+    let mut matching: Vec<Run> = self.runs()?.into_iter().filter(|run| run.id.starts_with(id)).collect();
+    match matching.len() {
+      1 => Ok(matching.remove(0)),
+      0 => Err(DkkError::InvalidInput(format!("geen run '{}' in de geschiedenis in {}", id, self.path.display()).into())),
+      _ => Err(DkkError::InvalidInput(format!("'{}' past bij meerdere runs; geef meer tekens van het id", id).into())),
+    }
+  }
+}
+
+/// Een run die nu loopt en naar de geschiedenis geschreven wordt.
+///
+/// Net als bij de events in stderr worden schrijffouten genegeerd; de download zelf gaat voor.
+#[derive(Debug)]
+pub struct HistoryRun {
+  id: String,
+  file: File,
+  /// Laatst vastgelegde voortgang per downloadRequestId, zodat alleen een verandering van status een regel wordt.
+  last_progress: HashMap<String, Option<u64>>,
+}
+
+impl HistoryRun {
+  pub fn id(&self) -> &str {
+    &self.id
+  }
+
+  /// Legt `event` vast. De voortgang van het downloaden zelf wordt niet vastgelegd, en `progress` alleen als die veranderd is.
+  pub fn record(&mut self, event: &Event) {
+    let skip = match event {
+      Event::DownloadProgress { .. } => true,
+      Event::Progress { request, percent } => self.last_progress.insert(request.id.clone(), *percent) == Some(*percent),
+      _ => false,
+    };
+    if !skip {
+      self.write(event.to_json());
+    }
+  }
+
+  /// Legt het gebied en de lagen vast, met bij `batch` de naam van het gebied.
+  pub fn record_area<S: AsRef<str>>(&mut self, name: Option<&str>, geofilter: &str, feature_types: &[S]) {
+    let feature_types: Vec<&str> = feature_types.iter().map(AsRef::as_ref).collect();
+    self.write(object!{ "event" => "area", "name" => name, "geofilter" => geofilter, "feature_types" => feature_types });
+  }
+
+  /// Sluit de run af met de exit code en, als de run mislukt is, de fout.
+  pub fn finish(mut self, error: Option<&DkkError>) {
+    self.write(object!{
+      "event" => "finished",
+      "exit_code" => error.map(DkkError::exit_code).unwrap_or(0),
+      "kind" => error.map(DkkError::kind_name),
+      "message" => error.map(|error| error.to_string().trim_end().to_string())
+    });
+  }
+
+  fn write(&mut self, mut record: JsonValue) {
+    let mut line = object!{ "run" => self.id.as_str(), "time" => format_timestamp(SystemTime::now()) };
+    for (key, value) in record.entries_mut() {
+      line[key] = value.take();
+    }
+    // In één keer, zodat regels van runs die tegelijk schrijven niet door elkaar komen.
+    let _ = self.file.write_all(format!("{}\n", json::stringify(line)).as_bytes());
+  }
+}
+
+/// Een run uit de geschiedenis, met alle vastgelegde regels.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Run {
+  pub id: String,
+  pub records: Vec<JsonValue>,
+}
+
+impl Run {
+  fn find(&self, event: &str) -> Option<&JsonValue> {
+    self.records.iter().find(|record| record["event"] == event)
+  }
+
+  pub fn started_at(&self) -> Option<&str> {
+    self.records.first().and_then(|record| record["time"].as_str())
+  }
+
+  /// De opties van de run, zonder de programmanaam.
+  pub fn args(&self) -> Vec<String> {
+    self.find("started")
+      .map(|started| started["args"].members().filter_map(JsonValue::as_str).map(String::from).collect())
+      .unwrap_or_default()
+  }
+
+  pub fn cwd(&self) -> Option<&str> {
+    self.find("started").and_then(|started| started["cwd"].as_str())
+  }
+
+  pub fn rerun_of(&self) -> Option<&str> {
+    self.find("started").and_then(|started| started["rerun_of"].as_str())
+  }
+
+  /// `None` als de run nog bezig is of onderbroken is.
+  pub fn exit_code(&self) -> Option<i32> {
+    self.find("finished").and_then(|finished| finished["exit_code"].as_i32())
+  }
+
+  pub fn error(&self) -> Option<&str> {
+    self.find("finished").and_then(|finished| finished["message"].as_str())
+  }
+
+  /// De downloadRequestIds, ook die van requests die hervat of uit de cache gehaald zijn, in volgorde van voorkomen.
+  pub fn request_ids(&self) -> Vec<&str> {
+    let mut ids: Vec<&str> = Vec::new();
+    for record in &self.records {
+      let found = record["request_id"].as_str().into_iter().chain(record["request_ids"].members().filter_map(JsonValue::as_str));
+      for id in found {
+        if !ids.contains(&id) {
+          ids.push(id);
+        }
+      }
+    }
+    ids
+  }
+
+  /// De geschreven uitvoer: `-o` of `--extract`, of de ZIP-bestanden van `batch`.
+  pub fn outputs(&self) -> Vec<&str> {
+    self.records.iter()
+      .filter(|record| record["event"] == "completed" || record["event"] == "area_completed")
+      .filter_map(|record| record["path"].as_str())
+      .collect()
+  }
+
+  /// De opdracht zoals hij in een shell ingetypt kan worden.
+  pub fn command_line(&self) -> String {
+    let mut words = vec![String::from("dkkdownload")];
+    words.extend(self.args().iter().map(|arg| shell_quote(arg)));
+    words.join(" ")
+  }
+}
+
+fn shell_quote(arg: &str) -> String {
+  if !arg.is_empty() && arg.chars().all(|c| c.is_alphanumeric() || "-_./:=,@%+".contains(c)) {
+    String::from(arg)
+  } else {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+  }
+}
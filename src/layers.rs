@@ -0,0 +1,137 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use crate::error::UnknownLayerError;
+
+
+/// Een feature type (laag) van de DKK Download API.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeatureType {
+  /// Naam zoals de API hem in `featuretypes` verwacht.
+  pub name: &'static str,
+  pub description: &'static str,
+  /// Andere namen die ook geaccepteerd worden, zoals `percelen`.
+  pub aliases: &'static [&'static str],
+}
+
+/// Feature types (lagen) van de DKK Download API v5_0.
+pub const FEATURE_TYPES: &[FeatureType] = &[
+  FeatureType {
+    name: "perceel",
+    description: "Kadastrale percelen: begrenzing, kadastrale aanduiding en plaats en rotatie van het perceelnummer",
+    aliases: &["percelen", "parcel", "parcels"],
+  },
+  FeatureType {
+    name: "kadastralegrens",
+    description: "Kadastrale grenzen tussen percelen, met de percelen aan weerszijden",
+    aliases: &["kadastralegrenzen", "grens", "grenzen"],
+  },
+  FeatureType {
+    name: "pand",
+    description: "Omtrek van gebouwen (BAG-panden), ter oriëntatie",
+    aliases: &["panden", "gebouw", "gebouwen", "bebouwing", "buildings"],
+  },
+  FeatureType {
+    name: "openbareruimtelabel",
+    description: "Namen van straten, water en andere openbare ruimten, met positie en hoek",
+    aliases: &["openbareruimtelabels", "openbareruimte", "straatnaam", "straatnamen"],
+  },
+  FeatureType {
+    name: "nummeraanduidingreeks",
+    description: "Huisnummers en huisnummerreeksen van adressen, met positie en hoek",
+    aliases: &["nummeraanduidingreeksen", "nummeraanduiding", "huisnummer", "huisnummers"],
+  },
+];
+
+/// Zoekt de API-naam bij een laagnaam of alias, ongeacht hoofdletters.
+///
+/// Bij een onbekende naam bevat de fout de lagen waarvan de naam of een alias er het meest op lijkt.
+pub fn resolve(name: &str) -> Result<&'static str, UnknownLayerError> {
+  let lower = name.trim().to_lowercase();
+  let found = FEATURE_TYPES.iter()
+    .find(|feature_type| feature_type.name == lower || feature_type.aliases.contains(&lower.as_str()));
+  match found {
+    Some(feature_type) => Ok(feature_type.name),
+    None => Err(UnknownLayerError::new(name.trim(), suggestions(&lower))),
+  }
+}
+
+/// Lagen waarvan de naam of een alias binnen een paar tikfouten van `name` ligt, de beste eerst.
+fn suggestions(name: &str) -> Vec<&'static str> {
+  let max_distance = (name.chars().count() / 3).max(2);
+  let mut candidates: Vec<(usize, &'static str)> = FEATURE_TYPES.iter().filter_map(|feature_type| {
+    std::iter::once(&feature_type.name).chain(feature_type.aliases.iter())
+      .map(|candidate| if candidate.starts_with(name) && name.len() >= 3 { 0 } else { edit_distance(name, candidate) })
+      .min()
+      .filter(|distance| *distance <= max_distance)
+      .map(|distance| (distance, feature_type.name))
+  }).collect();
+  candidates.sort();
+  candidates.into_iter().map(|(_, name)| name).collect()
+}
+
+/// Levenshtein-afstand tussen twee teksten, in tekens.
+fn edit_distance(a: &str, b: &str) -> usize {
+  let b: Vec<char> = b.chars().collect();
+  let mut previous: Vec<usize> = (0..=b.len()).collect();
+  for (i, ca) in a.chars().enumerate() {
+    let mut current = vec![i + 1; b.len() + 1];
+    for (j, cb) in b.iter().enumerate() {
+      let substitution = previous[j] + if ca == *cb { 0 } else { 1 };
+      current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+    }
+    previous = current;
+  }
+  previous[b.len()]
+}
+
+/// Herkent het feature type aan de naam van een bestand uit de ZIP, bijv. `kadastralekaart_perceel.gml`.
+pub fn feature_type_of_file(file_name: &str) -> Option<&'static str> {
+  let base = file_name.rsplit('/').next().unwrap_or(file_name).to_ascii_lowercase();
+  FEATURE_TYPES.iter()
+    .map(|feature_type| feature_type.name)
+    .filter(|name| base.contains(name))
+    // Bij meerdere treffers de langste naam, zodat een toekomstig "perceelvlak" niet als "perceel" geldt.
+    .max_by_key(|name| name.len())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn resolves_names_and_aliases_in_any_case() {
+    assert_eq!(resolve("perceel").unwrap(), "perceel");
+    assert_eq!(resolve(" Percelen ").unwrap(), "perceel");
+    assert_eq!(resolve("STRAATNAMEN").unwrap(), "openbareruimtelabel");
+  }
+
+  #[test]
+  fn suggests_the_layer_a_near_miss_was_meant_to_be() {
+    let error = resolve("perceeel").unwrap_err();
+    assert_eq!(error.name(), "perceeel");
+    assert_eq!(error.suggestions(), &["perceel"]);
+    assert_eq!(error.to_string(),
+      "Onbekende laag 'perceeel'. Bedoelde je perceel? Gebruik 'list-layers' voor een overzicht van alle lagen.");
+
+    // Een tikfout in een alias, en het begin van een naam.
+    assert_eq!(resolve("gebouwn").unwrap_err().suggestions(), &["pand"]);
+    assert_eq!(resolve("kadastrale").unwrap_err().suggestions(), &["kadastralegrens"]);
+  }
+
+  #[test]
+  fn suggests_nothing_for_unrelated_names() {
+    let error = resolve("wegdeel").unwrap_err();
+    assert!(error.suggestions().is_empty());
+    assert_eq!(error.to_string(), "Onbekende laag 'wegdeel'. Gebruik 'list-layers' voor een overzicht van alle lagen.");
+  }
+
+  #[test]
+  fn recognizes_feature_types_by_file_name() {
+    assert_eq!(feature_type_of_file("kadastralekaartv5_perceel.gml"), Some("perceel"));
+    assert_eq!(feature_type_of_file("dir/Kadastralekaart_OpenbareRuimteLabel.gml"), Some("openbareruimtelabel"));
+    assert_eq!(feature_type_of_file("leesmij.txt"), None);
+  }
+}
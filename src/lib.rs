@@ -0,0 +1,50 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+//! Bibliotheek om de Digitale Kadastrale Kaart (DKK) te downloaden via de PDOK DKK Download API.
+
+extern crate reqwest;
+extern crate json;
+extern crate rand;
+extern crate httpdate;
+extern crate geo;
+extern crate zip;
+extern crate quick_xml;
+extern crate flate2;
+extern crate crc32fast;
+extern crate rusqlite;
+extern crate sha2;
+
+mod client;
+mod error;
+mod retry;
+mod date;
+pub mod geometry;
+pub mod wkt;
+pub mod area;
+pub mod crs;
+pub mod tiling;
+pub mod merge;
+pub mod layers;
+pub mod extract;
+pub mod gml;
+pub mod geojson;
+pub mod gpkg;
+pub mod shp;
+pub mod dxf;
+pub mod clip;
+pub mod config;
+pub mod checksum;
+pub mod events;
+pub mod output;
+pub mod manifest;
+pub mod batch;
+pub mod cache;
+pub mod history;
+
+pub use client::{DkkClient, Delta, Download, DownloadRequest, DownloadStatus, RequestKind, part_path, part_info_path, API_PATH, DEFAULT_ROOT_URL, DEFAULT_API_VERSION};
+pub use retry::RetryPolicy;
+pub use geometry::{FeatureGeometry, Geometry, Point, Polygon};
+pub use error::{DkkError, UnexpectedStatusCodeError, InvalidResponseError, JobFailedError, WktParseError, InvalidGeometryError, UnknownLayerError, ConfigError, OutputExistsError, BatchInputError, BatchError};
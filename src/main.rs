@@ -3,245 +3,1215 @@
  * Alle rechten voorbehouden.
  */
 
-extern crate reqwest;
-extern crate json;
 extern crate clap;
 extern crate pbr;
 extern crate tee_readwrite;
+extern crate dkkdownload;
 
-use std::thread::sleep;
-use std::time::Duration;
-use std::fs::File;
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
 use std::fs;
-use std::io::stderr;
-use std::fmt::Display;
+use std::env;
+use std::ffi::OsString;
+use std::fs::File;
+use std::io::{self, stderr, Cursor, Stderr, Write};
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 use clap::{app_from_crate, crate_name, crate_version, crate_authors, crate_description};
-use clap::Arg;
-
-use reqwest::StatusCode;
-
-use json::object;
-use json::JsonValue;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
 
 use pbr::{ProgressBar, Units};
-use tee_readwrite::TeeWriter;
+use tee_readwrite::TeeReader;
 
+use dkkdownload::{DkkClient, DkkError, Delta, DownloadRequest, DownloadStatus, Geometry, RetryPolicy};
+use dkkdownload::{area, batch, crs, dxf, extract, geojson, gml, gpkg, layers, shp, merge, output, tiling, wkt};
+use dkkdownload::{BatchError, BatchInputError, ConfigError, InvalidGeometryError};
+use dkkdownload::area::{AreaFormat, AreaInput};
+use dkkdownload::extract::{ExtractOptions, ExtractedFile, ZipEntry};
+use dkkdownload::crs::Crs;
+use dkkdownload::clip::{self, ClipMode};
+use dkkdownload::config::{self, Config};
+use dkkdownload::checksum::{Checksum, HashingReader};
+use dkkdownload::events::{DownloadProgressWriter, Event, EventLog};
+use dkkdownload::manifest::{self, Manifest, RequestRecord};
+use dkkdownload::cache::{self, Cache, CacheEntry, CacheKey};
+use dkkdownload::history::{History, Run};
+use dkkdownload::output::TempFile;
 
-#[derive(Debug)]
-struct UnexpectedStatusCodeError {
-  response: reqwest::Response,
-  response_text: Option<String>,
-  method: reqwest::Method,
-}
 
-impl UnexpectedStatusCodeError {
-  fn new(mut response: reqwest::Response, method: reqwest::Method) -> Self {
-    let response_text = response.text().ok();
-    Self { response, response_text, method }
-  }
-}
+/// Uitleg van de exit codes voor `--help`; zie `DkkError`.
+const EXIT_CODES_HELP: &str = "EXIT CODES:
+    0    Geslaagd.
+    1    Overige fouten.
+    2    Ongeldige invoer: opties, polygon, lagen of configuratie, of uitvoer die al bestaat (zonder --force).
+    3    Netwerkfout of time-out, of de PDOK API is onbereikbaar (5xx), ook na opnieuw proberen.
+    4    De PDOK API weigert het request (4xx), bijv. een onbekend downloadRequestId.
+    5    Het download request is mislukt, of de PDOK API gaf een onbruikbaar antwoord of ZIP-bestand.
+    6    Lezen of schrijven van lokale bestanden mislukt.
+    7    Batch: een of meer gebieden zijn mislukt (de andere zijn wel gedownload).";
 
-impl std::error::Error for UnexpectedStatusCodeError {
-  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
-    None
-  }
-}
-
-impl Display for UnexpectedStatusCodeError {
-  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-    write!(f, "Onverwachte status code ({}) gekregen als antwoord op {} {}\n",
-        self.response.status(), self.method, self.response.url())?;
-    if let Some(text) = &self.response_text {
-      write!(f, "De PDOK API zegt:\n{}", text)?;
-    }
-    Ok(())
-  }
-}
+/// Id van de run die `history rerun` in een nieuw proces herhaalt; zie `rerun`.
+const ENV_RERUN_OF: &str = "DKKDOWNLOAD_RERUN_OF";
 
 fn main() {
-  std::process::exit(match run_app() {
+  let mut events = EventLog::disabled();
+  let rerun_of = env::var(ENV_RERUN_OF).ok();
+  let result = run_app(env::args_os().collect(), &mut events, rerun_of.as_deref());
+  if let Some(run) = events.take_history() {
+    run.finish(result.as_ref().err());
+  }
+  std::process::exit(match result {
     Err(err) => {
-      eprintln!("Error: {}", err);
-      1
+      match &err {
+        // Clap heeft zijn eigen opmaak, met het gebruik van het programma erbij.
+        DkkError::InvalidInput(inner) if inner.is::<clap::Error>() => eprintln!("{}", inner),
+        _ if events.is_enabled() => events.emit(Event::Error(&err)),
+        _ => eprintln!("Error: {}", err),
+      }
+      err.exit_code()
     }
     Ok(_) => 0
   })
 }
 
-fn run_app() -> Result<(), Box<dyn std::error::Error>> {
-  let user_agent = format!("DKKdownload/{}", env!("CARGO_PKG_VERSION"));
-
-  let matches = app_from_crate!()
+/// Voert dkkdownload uit met `args`, inclusief de programmanaam. `rerun_of` is het id van de run die met `history rerun`
+/// herhaald wordt.
+fn run_app(args: Vec<OsString>, events: &mut EventLog<Stderr>, rerun_of: Option<&str>) -> Result<(), DkkError> {
+  let default_max_area = (tiling::DEFAULT_MAX_AREA / 1_000_000.0).to_string();
+  let app_matches = app_from_crate!()
     .arg(Arg::with_name("boundingpolygon")
       .value_name("BOUNDINGPOLYGON")
-      .help("Bounding Well-Known Text (WKT) polygon")
-      .required(true)
+      .help("Interessegebied als WKT of EWKT polygon, GeoJSON (Polygon, MultiPolygon, Feature of FeatureCollection) \
+        of bounding box 'minx,miny,maxx,maxy'. Mag ook een pad naar een bestand met een van deze zijn. Het formaat wordt zelf herkend.")
+      .required_unless("resume")
       .index(1))
     .arg(Arg::with_name("output_file")
       .value_name("FILE")
       .short("o")
       .long("output")
       .takes_value(true)
-      .help("Pad naar output ZIP-bestand. Bijvoorbeeld: 'output.zip'. Wanneer dit ongespecificeerd wordt gelaten zal het ZIP-bestand naar stdout worden geschreven."))
+      .help("Pad naar output ZIP-bestand. Bijvoorbeeld: 'output.zip'. Wanneer dit ongespecificeerd wordt gelaten zal het ZIP-bestand naar stdout worden geschreven. \
+        Tijdens het downloaden wordt naar 'FILE.part' geschreven; een afgebroken download van hetzelfde request wordt bij een volgende run hervat. \
+        Een bestaand bestand wordt alleen met --force overschreven, en pas als de nieuwe download compleet is."))
+    .arg(Arg::with_name("force")
+      .long("force")
+      .help("Overschrijf bestaande uitvoer (-o, of bestanden in de map van --extract, van --format geojson/shp of van batch). \
+        Ook dan blijft de oude uitvoer staan totdat de nieuwe volledig geschreven is.")
+      .global(true))
+    .arg(Arg::with_name("no_manifest")
+      .long("no-manifest")
+      .help("Schrijf geen manifest. Standaard komt naast de uitvoer (-o, --extract of elk ZIP-bestand van batch) een 'FILE.manifest.json' met de \
+        downloadRequestIds, het polygon, de feature types, de download links, tijdstippen, de SHA-256 van het ZIP-bestand en \
+        de grootte en CRC van elk bestand erin.")
+      .global(true))
+    .arg(Arg::with_name("format")
+      .value_name("FORMAAT")
+      .long("format")
+      .takes_value(true)
+      .possible_values(&["zip", "geojson", "gpkg", "shp", "dxf"])
+      .default_value("zip")
+      .requires_ifs(&[("geojson", "output_file"), ("gpkg", "output_file"), ("shp", "output_file"), ("dxf", "output_file")])
+      .help("Uitvoerformaat. 'zip' is het ZIP-bestand met GML van PDOK. \
+        'geojson' schrijft per feature type een FeatureCollection in RD New naar '<laag>.geojson' in de map -o. \
+        'gpkg' schrijft alle lagen als tabellen met spatial index naar het GeoPackage -o, met in de tabel 'dkkdownload_kolommen' welke eigenschap in welke kolom staat. \
+        'shp' schrijft per feature type een Shapefile naar de map -o, met in '<laag>_velden.csv' welke eigenschap in welk (ingekort) veld staat. \
+        'dxf' schrijft een DXF-tekening met een laag per feature type en de perceelnummers en straatnamen als tekst naar -o."))
+    .arg(Arg::with_name("clip")
+      .value_name("MODUS")
+      .long("clip")
+      .takes_value(true)
+      .possible_values(&["cut", "within"])
+      .conflicts_with("resume")
+      .help("Snijd de features bij tot BOUNDINGPOLYGON; PDOK levert alle features die het gebied raken. \
+        'cut' snijdt geometrieën op de rand af, 'within' laat features weg die niet helemaal binnen het gebied liggen. \
+        Per laag wordt gemeld hoeveel features bijgesneden of weggelaten zijn. Niet mogelijk met --format zip."))
+    .arg(Arg::with_name("extract")
+      .value_name("DIR")
+      .short("x")
+      .long("extract")
+      .takes_value(true)
+      .help("Pak het ZIP-bestand uit naar deze map. Zonder -o wordt er tijdens het downloaden uitgepakt en geen ZIP-bestand bewaard."))
+    .arg(Arg::with_name("rename_layers")
+      .long("rename-layers")
+      .requires("extract")
+      .help("Geef uitgepakte GML-bestanden de naam van hun feature type, bijv. 'perceel.gml' en 'pand.gml'."))
     .arg(Arg::with_name("bounding_polygon_is_file")
       .short("f")
       .long("file")
-      .help("Interpreteer BOUNDINGPOLYGON als pad naar WKT bestand i.p.v. als WKT string."))
+      .help("Interpreteer BOUNDINGPOLYGON altijd als pad naar een bestand. Zonder deze optie wordt een bestaand bestand ook herkend."))
+    .arg(Arg::with_name("input_crs")
+      .value_name("CRS")
+      .long("input-crs")
+      .takes_value(true)
+      .help("Coördinaatreferentiesysteem van BOUNDINGPOLYGON of de gebieden van batch: EPSG:28992 (RD New, standaard), EPSG:4326 (WGS84) of EPSG:4258 (ETRS89). \
+        Bij WGS84 en ETRS89 is de volgorde lengtegraad, breedtegraad. Het polygon wordt lokaal naar RD New getransformeerd. \
+        Een SRID in EWKT of een crs in GeoJSON wordt ook herkend; GeoJSON zonder crs is WGS84, zoals RFC 7946 voorschrijft.")
+      .global(true))
+    .arg(Arg::with_name("max_area")
+      .value_name("KM2")
+      .long("max-area")
+      .takes_value(true)
+      .default_value(&default_max_area)
+      .help("Maximale oppervlakte per download request in km². Een groter gebied wordt in tegels opgedeeld die los aangevraagd \
+        en daarna per laag samengevoegd worden, waarbij features die in meerdere tegels voorkomen één keer worden opgenomen."))
     .arg(Arg::with_name("lagen")
       .value_name("LAGEN")
-      .help("Lijst van lagen om te downloaden, met een spatie tussen elke laag.")
+      .help("Lijst van lagen om te downloaden, met een spatie tussen elke laag. Zie 'list-layers' voor de beschikbare lagen; \
+        aliassen zoals 'percelen' en 'gebouwen' worden ook herkend.")
       .multiple(true)
       .index(2)
-      .required(true))
+      .required_unless("resume"))
+    .arg(Arg::with_name("delta")
+      .value_name("DELTAID")
+      .long("delta")
+      .takes_value(true)
+      .help("Download alleen de mutaties sinds de delta met dit id, i.p.v. het volledige gebied."))
+    .arg(Arg::with_name("delta_state")
+      .value_name("FILE")
+      .long("delta-state")
+      .takes_value(true)
+      .help("Bestand met het laatst bekende delta id. Wanneer het bestand bestaat en --delta niet gegeven is, wordt een delta download gedaan vanaf het id in dit bestand. Na een geslaagde download wordt het nieuwe delta id hierin opgeslagen."))
+    .arg(Arg::with_name("resume")
+      .value_name("REQUESTID")
+      .long("resume")
+      .takes_value(true)
+      .help("Hervat een eerder ingediend download request met deze downloadRequestId, i.p.v. een nieuw request in te dienen. \
+        Geef bij een delta request ook --delta of --delta-state mee. Het delta id in --delta-state wordt bij hervatten niet bijgewerkt."))
+    .arg(Arg::with_name("retries")
+      .value_name("N")
+      .long("retries")
+      .takes_value(true)
+      .default_value("5")
+      .help("Aantal keer dat een request opnieuw geprobeerd wordt bij een tijdelijke fout (5xx, 429 of verbindingsfout).")
+      .global(true))
+    .arg(Arg::with_name("retry_max_wait")
+      .value_name("SECONDEN")
+      .long("retry-max-wait")
+      .takes_value(true)
+      .default_value("60")
+      .help("Maximale wachttijd tussen twee pogingen. Een Retry-After van de PDOK API gaat hier altijd voor.")
+      .global(true))
+    .arg(Arg::with_name("base_url")
+      .value_name("URL")
+      .long("base-url")
+      .takes_value(true)
+      .env(config::ENV_BASE_URL)
+      .help("Root url van de PDOK API, bijv. de acceptatieomgeving, een proxy of een lokale testserver. Standaard https://api.pdok.nl. \
+        Download links uit de API worden hiertegen opgelost.")
+      .global(true))
+    .arg(Arg::with_name("api_version")
+      .value_name("VERSIE")
+      .long("api-version")
+      .takes_value(true)
+      .env(config::ENV_API_VERSION)
+      .help("Versie van de DKK Download API. Standaard v5_0.")
+      .global(true))
+    .arg(Arg::with_name("config")
+      .value_name("FILE")
+      .long("config")
+      .takes_value(true)
+      .env(config::ENV_CONFIG)
+      .help("JSON configuratiebestand met 'base_url' en/of 'api_version'. Standaard ~/.config/dkkdownload/config.json, als dat bestaat. \
+        Opties en omgevingsvariabelen gaan voor het configuratiebestand.")
+      .global(true))
+    .arg(Arg::with_name("cache_dir")
+      .value_name("DIR")
+      .long("cache-dir")
+      .takes_value(true)
+      .env(config::ENV_CACHE_DIR)
+      .global(true)
+      .help("Map van de cache. Standaard ~/.cache/dkkdownload. Ook 'cache_dir' in het configuratiebestand."))
+    .arg(Arg::with_name("cache_ttl")
+      .value_name("SECONDEN")
+      .long("cache-ttl")
+      .takes_value(true)
+      .env(config::ENV_CACHE_TTL)
+      .global(true)
+      .help("Hoe lang een ZIP-bestand in de cache gebruikt wordt. Standaard 43200 (12 uur). Ook 'cache_ttl' in het configuratiebestand. \
+        Een download van hetzelfde gebied met dezelfde lagen binnen deze tijd komt uit de cache, zonder request bij de PDOK API. \
+        Niet bij --resume, --delta en --delta-state. Bij --extract zonder -o wordt het ZIP-bestand niet in de cache bewaard."))
+    .arg(Arg::with_name("no_cache")
+      .long("no-cache")
+      .global(true)
+      .help("Gebruik de cache niet: altijd een nieuw request indienen, en het resultaat niet bewaren."))
+    .arg(Arg::with_name("history_file")
+      .value_name("FILE")
+      .long("history-file")
+      .takes_value(true)
+      .env(config::ENV_HISTORY_FILE)
+      .global(true)
+      .help("Bestand met de geschiedenis van de downloads. Standaard ~/.local/share/dkkdownload/history.jsonl. \
+        Van elke download en batch worden de opties, het gebied, de downloadRequestIds, statusovergangen, download links, \
+        uitvoer en fouten vastgelegd; zie 'history'."))
+    .arg(Arg::with_name("no_history")
+      .long("no-history")
+      .global(true)
+      .help("Leg deze run niet vast in de geschiedenis."))
     .arg(Arg::with_name("progress")
         .short("p")
         .long("progress")
+        .global(true)
         .help("Geef voortgang weer in stderr."))
+    .arg(Arg::with_name("log_format")
+      .value_name("FORMAAT")
+      .long("log-format")
+      .takes_value(true)
+      .possible_values(&["human", "json"])
+      .default_value("human")
+      .help("Formaat van de meldingen in stderr. Met 'json' wordt elke gebeurtenis één regel JSON, voor gebruik door andere \
+        programma's: submitted, progress, ready, download_progress, completed (met grootte en SHA-256 van het ZIP-bestand) en error, \
+        en verder tiles, merged, clipped, extracted en voor batch area_completed en area_failed. Voortgangsbalken worden dan niet getoond.")
+      .global(true))
+    .setting(AppSettings::SubcommandsNegateReqs)
+    .subcommand(SubCommand::with_name("list-layers")
+      .about("Toon de lagen (feature types) die gedownload kunnen worden, met een korte omschrijving."))
+    .subcommand(SubCommand::with_name("cache")
+      .about("Beheer de cache van gedownloade ZIP-bestanden.")
+      .setting(AppSettings::SubcommandRequiredElseHelp)
+      .subcommand(SubCommand::with_name("list")
+        .about("Toon de ZIP-bestanden in de cache, met hun lagen, leeftijd en geofilter."))
+      .subcommand(SubCommand::with_name("purge")
+        .about("Verwijder de verlopen ZIP-bestanden uit de cache.")
+        .arg(Arg::with_name("all")
+          .long("all")
+          .help("Verwijder alle ZIP-bestanden, ook die nog niet verlopen zijn."))))
+    .subcommand(SubCommand::with_name("history")
+      .about("Bekijk de geschiedenis van de downloads, of voer een eerdere download opnieuw uit.")
+      .setting(AppSettings::SubcommandRequiredElseHelp)
+      .subcommand(SubCommand::with_name("list")
+        .about("Toon de runs, van oud naar nieuw, met hun opdracht, resultaat, downloadRequestIds en uitvoer.")
+        .arg(Arg::with_name("limit")
+          .value_name("N")
+          .short("n")
+          .long("limit")
+          .takes_value(true)
+          .help("Toon alleen de laatste N runs.")))
+      .subcommand(SubCommand::with_name("show")
+        .about("Toon alles wat van een run vastgelegd is.")
+        .arg(Arg::with_name("id")
+          .value_name("ID")
+          .help("Id van de run, of het begin ervan.")
+          .required(true)
+          .index(1))
+        .arg(Arg::with_name("json")
+          .long("json")
+          .help("Toon de vastgelegde regels als JSON Lines, zoals ze in de geschiedenis staan.")))
+      .subcommand(SubCommand::with_name("rerun")
+        .about("Voer de opdracht van een eerdere run opnieuw uit, in dezelfde map. Er wordt een nieuw request ingediend, \
+          of het ZIP-bestand komt uit de cache. Met --force wordt de uitvoer van de vorige keer overschreven.")
+        .arg(Arg::with_name("id")
+          .value_name("ID")
+          .help("Id van de run, of het begin ervan.")
+          .required(true)
+          .index(1))))
+    .subcommand(SubCommand::with_name("batch")
+      .about("Download een gebied per feature of regel van INVOER, elk naar een eigen ZIP-bestand in de map -o.")
+      .arg(Arg::with_name("input")
+        .value_name("INVOER")
+        .help("GeoJSON FeatureCollection met een (multi)polygon per gebied, met de naam in properties.name, properties.naam of id. \
+          Of CSV met per regel een naam en een WKT, EWKT of bounding box: 'noord,\"POLYGON((...))\"'.")
+        .required(true)
+        .index(1))
+      .arg(Arg::with_name("lagen")
+        .value_name("LAGEN")
+        .help("Lijst van lagen om voor elk gebied te downloaden, met een spatie tussen elke laag.")
+        .multiple(true)
+        .required(true)
+        .index(2))
+      .arg(Arg::with_name("output_dir")
+        .value_name("DIR")
+        .short("o")
+        .long("output")
+        .takes_value(true)
+        .required(true)
+        .help("Map voor de uitvoer. Elk gebied wordt '<naam>.zip', met tekens die niet in een bestandsnaam horen vervangen door '_'."))
+      .arg(Arg::with_name("concurrency")
+        .value_name("N")
+        .long("concurrency")
+        .takes_value(true)
+        .default_value("4")
+        .help("Maximaal aantal download requests dat tegelijk bij de PDOK API in behandeling is. \
+          Elk gebied wordt één request; een groot gebied wordt in batch niet in tegels opgedeeld."))
+      .after_help(EXIT_CODES_HELP))
     .about("Copyright (c) 2019 Martijn Heil\n\
         Gebruik van dit programma is uitsluitend voorbehouden aan gemeente Lingewaard.\n\
         \nProgramma om de Digitale Kadastrale Kaart (DKK) in vector-formaat te downloaden - gefilterd met een bounding polygon - d.m.v. de PDOK DKK Download API.")
-    .get_matches();
+    .after_help(EXIT_CODES_HELP)
+    .get_matches_from_safe(&args)
+    .or_else(|e| match e.kind {
+      clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+      _ => Err(DkkError::InvalidInput(Box::new(e))),
+    })?;
 
-  let show_progress = matches.is_present("progress");
-
-  let bpf = matches.value_of("boundingpolygon").expect("BOUNDINGPOLYGON mag niet leeg zijn.");
-  let interessegebied: String = match matches.is_present("bounding_polygon_is_file") { // Well-Known Text (WKT) polygon string
-    true => {
-      fs::read_to_string(bpf)?
+  if app_matches.subcommand_matches("list-layers").is_some() {
+    print_layers();
+    return Ok(());
+  }
+  match app_matches.subcommand_matches("cache").map(ArgMatches::subcommand) {
+    Some(("list", Some(matches))) => return list_cache(matches),
+    Some(("purge", Some(matches))) => return purge_cache(matches),
+    _ => {},
+  }
+  match app_matches.subcommand_matches("history").map(ArgMatches::subcommand) {
+    Some(("list", Some(matches))) => return list_history(matches),
+    Some(("show", Some(matches))) => return show_history(matches),
+    Some(("rerun", Some(matches))) => {
+      let run = require_history(matches)?.find(matches.value_of("id").unwrap())?;
+      return rerun(&run, matches);
     },
-    false => {
-      String::from(bpf)
+    _ => {},
+  }
+  // De globale opties staan ook in de matches van `batch`, ook als ze vóór `batch` gegeven zijn.
+  let batch_matches = app_matches.subcommand_matches("batch");
+  let matches = batch_matches.unwrap_or(&app_matches);
+
+  if matches.value_of("log_format") == Some("json") {
+    *events = EventLog::new(stderr());
+  }
+  if let Some(history) = open_history(matches).filter(|_| !matches.is_present("no_history")) {
+    let args: Vec<String> = args.iter().skip(1).map(|arg| arg.to_string_lossy().into_owned()).collect();
+    match history.start(&args, &env::current_dir()?, rerun_of) {
+      Ok(run) => events.set_history(run),
+      // Niet ten koste van de download; in JSON-formaat alleen JSON-regels in stderr.
+      Err(e) if !events.is_enabled() => eprintln!("Waarschuwing: geschiedenis niet bijgehouden in {}: {}", history.path().display(), e),
+      Err(_) => {},
     }
-  };
-  let layers: Vec<&str> = matches.values_of("lagen").expect("Er moet minimaal 1 laag gespecificeerd worden.").collect();
+  }
+  // Voortgangsbalken zouden de JSON-regels in stderr onleesbaar maken.
+  let show_progress = matches.is_present("progress") && !events.is_enabled();
 
   let probing_interval: Duration = Duration::from_millis(1000);
+  if let Some(matches) = batch_matches {
+    return run_batch(matches, events, probing_interval);
+  }
 
   let output_filepath = matches.value_of("output_file");
+  let extract_dir = matches.value_of("extract").map(Path::new);
+  let force = matches.is_present("force");
+  let extract_options = ExtractOptions { rename_by_feature_type: matches.is_present("rename_layers"), overwrite: force };
+
+  let output_format = matches.value_of("format").unwrap_or("zip");
+  let clip_mode: Option<ClipMode> = matches.value_of("clip").map(str::parse).transpose()?;
+  if clip_mode.is_some() && output_format == "zip" {
+    return Err(DkkError::InvalidInput("--clip kan alleen gebruikt worden met --format geojson, gpkg, shp of dxf".into()));
+  }
+  // Vóór het indienen controleren, zodat er niet eerst voor niets gedownload wordt. Bij de mappen van geojson en shp
+  // zijn de bestandsnamen pas bekend na het downloaden; die worden bij het schrijven gecontroleerd.
+  if let Some(path) = output_filepath.filter(|_| matches!(output_format, "zip" | "gpkg" | "dxf")) {
+    output::check_overwrite(Path::new(path), force)?;
+  }
+  let manifest_output = output_filepath.map(Path::new).or(extract_dir).filter(|_| !matches.is_present("no_manifest"));
+  if let Some(path) = manifest_output {
+    output::check_overwrite(&manifest::manifest_path(path), force)?;
+  }
+
+  let delta_state_path = matches.value_of("delta_state");
+  let delta_id: Option<String> = match (matches.value_of("delta"), delta_state_path) {
+    (Some(id), _) => Some(String::from(id)),
+    (None, Some(path)) if Path::new(path).exists() => Some(fs::read_to_string(path)?.trim().to_string()),
+    _ => None,
+  };
+
+  let config = Config::load_or_default(matches.value_of("config").map(Path::new))?;
+  let client = build_client(matches, &config)?;
+  // Een delta, of een volledige download die het startpunt voor delta's wordt, moet actueel zijn.
+  let cache = match open_cache(matches, &config, false)? {
+    Some(cache) if delta_id.is_none() && delta_state_path.is_none() => Some(cache),
+    _ => None,
+  };
 
-  let mut output_writer: Box<dyn std::io::Write> = match output_filepath {
-    Some(path) => {
-      Box::new(File::create(path)?)
+  let started_at = SystemTime::now();
+  let (mut records, latest_delta, clip_area, feature_types, cache_key, cached) = match matches.value_of("resume") {
+    Some(reqid) => {
+      let request = match delta_id {
+        Some(_) => DownloadRequest::delta(reqid),
+        None => DownloadRequest::full(reqid),
+      };
+      (vec![RequestRecord::resumed(request)], None, None, None, None, None)
     },
     None => {
-      Box::new(std::io::stdout())
+      let bpf = matches.value_of("boundingpolygon")
+        .ok_or_else(|| DkkError::InvalidInput("BOUNDINGPOLYGON mag niet leeg zijn.".into()))?;
+      let interessegebied: String = if matches.is_present("bounding_polygon_is_file") || Path::new(bpf).is_file() {
+        fs::read_to_string(bpf)?
+      } else {
+        String::from(bpf)
+      };
+      let layers = resolve_layers(matches)?;
+
+      // Lokaal controleren, zodat een tikfout niet pas als onduidelijke fout van de PDOK API terugkomt.
+      let area = area::parse(&interessegebied)?;
+      let geometry = to_rd_new(&area, matches.value_of("input_crs"))?;
+      let feature_types = layers.iter().map(|layer| String::from(*layer)).collect::<Vec<_>>();
+      if let Some(history) = events.history_mut() {
+        history.record_area(None, &wkt::to_wkt(&geometry), &feature_types);
+      }
+
+      let cache_key = cache.as_ref().map(|_| CacheKey::new(&client.api_url(), &geometry, &layers));
+      let cached = match (&cache, &cache_key) {
+        (Some(cache), Some(key)) => cache.get(key)?,
+        _ => None,
+      };
+      if let Some(entry) = cached {
+        report(events, Event::CacheHit(&entry), || {
+          eprintln!("Uit de cache: ZIP-bestand van {} (downloadRequestId: {}). Gebruik --no-cache voor een nieuwe download.",
+            manifest::format_timestamp(entry.created_at), entry.request_ids.join(", "));
+        });
+        let records = entry.request_ids.iter().map(|id| RequestRecord::resumed(DownloadRequest::full(id.as_str()))).collect();
+        (records, None, Some(geometry), Some(feature_types), None, Some(entry))
+      } else {
+        let max_area: f64 = match matches.value_of("max_area") {
+          Some(km2) => km2.parse::<f64>()? * 1_000_000.0,
+          None => tiling::DEFAULT_MAX_AREA,
+        };
+        let tiles = tiling::split(&geometry, max_area);
+        if tiles.len() > 1 {
+          let area_km2 = geometry.area() / 1_000_000.0;
+          report(events, Event::Tiles { count: tiles.len(), area_km2 }, || {
+            eprintln!("Gebied van {:.1} km² wordt in {} tegels gedownload.", area_km2, tiles.len());
+          });
+        }
+
+        // De nieuwste delta wordt vóór het indienen opgevraagd; een delta die tijdens het verwerken verschijnt
+        // komt dan in de volgende run nogmaals mee, in plaats van dat de mutaties ervan gemist worden.
+        let latest_delta = match delta_state_path {
+          Some(_) => client.latest_delta()?,
+          None => None,
+        };
+
+        let mut records = Vec::new();
+        for tile in &tiles {
+          let geofilter = wkt::to_wkt(tile);
+          let request = match &delta_id {
+            Some(delta_id) => client.submit_delta_request(&layers, &geofilter, delta_id)?,
+            None => client.submit_custom_request(&layers, &geofilter)?,
+          };
+          // Direct melden, zodat het request met --resume hervat kan worden als dit proces onderbroken wordt.
+          report(events, Event::Submitted(&request), || eprintln!("downloadRequestId: {}", request.id));
+          records.push(RequestRecord::submitted(request));
+        }
+        (records, latest_delta, Some(geometry), Some(feature_types), cache_key, None)
+      }
+    },
+  };
+
+  // Voor andere formaten dan ZIP wordt het ZIP-bestand tijdelijk bewaard en daarna omgezet naar -o. Voor stdout
+  // ook, zodat het gecontroleerd is voordat er iets naar stdout gaat.
+  let converted_output = if output_format == "zip" { None } else { output_filepath };
+  let to_stdout = output_filepath.is_none() && extract_dir.is_none();
+  let temporary_zip = converted_output.is_some() || to_stdout;
+  let temp_zip = if temporary_zip { Some(TempFile::new("dkkdownload", "zip")?) } else { None };
+  let zip_path: Option<PathBuf> = match &temp_zip {
+    Some(file) => Some(file.path().to_path_buf()),
+    None => output_filepath.map(PathBuf::from),
+  };
+
+  // Grootte en SHA-256 van het (samengevoegde) ZIP-bestand, voor het completed event en het manifest.
+  let mut checksum: Option<Checksum> = None;
+  let mut entries: Option<Vec<ZipEntry>> = None;
+  if let Some(entry) = &cached {
+    match (&zip_path, extract_dir) {
+      (Some(path), _) => output::write_file(path, force || temporary_zip, |part| Ok(fs::copy(&entry.zip_path, part).map(|_| ())?))?,
+      (None, Some(dir)) => {
+        let extracted = extract::extract_file(&entry.zip_path, dir, &extract_options)?;
+        checksum = Some(entry.checksum.clone());
+        entries = Some(extracted.iter().map(ZipEntry::from).collect());
+        report_extracted(&extracted, show_progress, events);
+      },
+      (None, None) => unreachable!(),
+    }
+  } else if records.len() == 1 {
+    let download_url = wait_for_download(&client, &records[0].request, probing_interval, show_progress, events)?;
+    records[0].ready(&download_url);
+    match (&zip_path, extract_dir) {
+      (Some(path), _) => download_file(&client, &records[0].request, &download_url, path, show_progress, events)?,
+      (None, Some(dir)) => {
+        let download = client.start_download(&download_url)?;
+        let total = download.content_length();
+        let mut reader = HashingReader::new(download);
+        let extracted = match download_progress(events, total, show_progress, "ZIP bestand downloaden en uitpakken ") {
+          Some(progress) => extract::extract_stream(TeeReader::new(&mut reader, progress, false), dir, &extract_options)?,
+          None => extract::extract_stream(&mut reader, dir, &extract_options)?,
+        };
+        // Wat na de laatste entry komt (de central directory) hoort ook bij de checksum.
+        io::copy(&mut reader, &mut io::sink())?;
+        checksum = Some(reader.finish().1);
+        // De CRC van elk bestand is bij het uitpakken al gecontroleerd.
+        entries = Some(extracted.iter().map(ZipEntry::from).collect());
+        report_extracted(&extracted, show_progress, events);
+      },
+      (None, None) => unreachable!(),
+    }
+  } else {
+    // Tegels: alle ZIP-bestanden apart downloaden en daarna per laag samenvoegen.
+    let mut tile_files = Vec::new();
+    for record in &mut records {
+      let download_url = wait_for_download(&client, &record.request, probing_interval, show_progress, events)?;
+      record.ready(&download_url);
+      let tile_file = TempFile::new("dkkdownload-tile", "zip")?;
+      download_file(&client, &record.request, &download_url, tile_file.path(), show_progress, events)?;
+      tile_files.push(tile_file);
+    }
+    let tile_paths: Vec<&Path> = tile_files.iter().map(TempFile::path).collect();
+    let stats = match &zip_path {
+      Some(path) => {
+        output::write_file(path, force || temporary_zip, |part| merge::merge_zips(&tile_paths, File::create(part)?))?
+      },
+      None => {
+        let mut buffer = Cursor::new(Vec::new());
+        let stats = merge::merge_zips(&tile_paths, &mut buffer)?;
+        checksum = Some(Checksum::of_bytes(buffer.get_ref()));
+        if let (Some(cache), Some(key)) = (&cache, &cache_key) {
+          cache.put_bytes(key, buffer.get_ref(), &request_ids(&records))?;
+        }
+        // Zonder ZIP-bestand wordt er altijd uitgepakt; stdout gaat via een tijdelijk bestand.
+        let dir = extract_dir.unwrap();
+        buffer.set_position(0);
+        let extracted = extract::extract_stream(buffer, dir, &extract_options)?;
+        entries = Some(extracted.iter().map(ZipEntry::from).collect());
+        report_extracted(&extracted, show_progress, events);
+        stats
+      },
+    };
+    for layer in &stats {
+      report(events, Event::Merged(layer), || {
+        eprintln!("{}: {} features, {} dubbele features uit overlappende tegels weggelaten", layer.name, layer.features, layer.duplicates);
+      });
+    }
+  }
+
+  if let Some(path) = &zip_path {
+    if events.is_recording() || manifest_output.is_some() {
+      checksum = Some(Checksum::of_file(path)?);
+    }
+    if manifest_output.is_some() {
+      entries = Some(extract::verify_zip_file(path)?);
+    }
+    if let (Some(cache), Some(key)) = (&cache, &cache_key) {
+      cache.put_file(key, path, &request_ids(&records))?;
+    }
+    if let Some(dir) = extract_dir {
+      let extracted = extract::extract_file(path, dir, &extract_options)?;
+      report_extracted(&extracted, show_progress, events);
+    }
+    if let Some(output) = converted_output {
+      let mut layers = gml::read_zip_layers(path)?;
+      if let (Some(mode), Some(area)) = (clip_mode, &clip_area) {
+        for layer in &clip::clip_layers(&mut layers, area, mode) {
+          report(events, Event::Clipped(layer), || {
+            eprintln!("{}: {} features binnen het gebied, {} bijgesneden, {} weggelaten", layer.name, layer.kept, layer.clipped, layer.dropped);
+            if !layer.clipped_ids.is_empty() {
+              eprintln!("  bijgesneden: {}", layer.clipped_ids.join(", "));
+            }
+            if !layer.dropped_ids.is_empty() {
+              eprintln!("  weggelaten: {}", layer.dropped_ids.join(", "));
+            }
+          });
+        }
+      }
+      match output_format {
+        "geojson" => { output::write_files(Path::new(output), force, |dir| geojson::write_layers(&layers, dir))?; },
+        "gpkg" => output::write_file(Path::new(output), force, |path| gpkg::write_geopackage(&layers, path))?,
+        "shp" => { output::write_files(Path::new(output), force, |dir| shp::write_shapefiles(&layers, dir))?; },
+        "dxf" => output::write_file(Path::new(output), force, |path| dxf::write_dxf(&layers, path))?,
+        _ => unreachable!(),
+      }
     }
+    if to_stdout {
+      io::copy(&mut File::open(path)?, &mut io::stdout())?;
+    }
+  }
+
+  if let (Some(output), Some(zip), Some(entries)) = (manifest_output, &checksum, entries) {
+    // Bij --resume is niet bekend welke lagen aangevraagd zijn; dan de lagen die in de ZIP zitten.
+    let feature_types = feature_types.unwrap_or_else(|| {
+      let mut feature_types: Vec<String> = Vec::new();
+      for feature_type in entries.iter().filter_map(|entry| layers::feature_type_of_file(&entry.name)) {
+        if !feature_types.iter().any(|name| name == feature_type) {
+          feature_types.push(String::from(feature_type));
+        }
+      }
+      feature_types
+    });
+    let manifest = Manifest {
+      started_at,
+      completed_at: SystemTime::now(),
+      requests: records,
+      polygon: clip_area.as_ref().map(wkt::to_wkt),
+      feature_types,
+      output: output.to_path_buf(),
+      format: String::from(if output_filepath.is_some() { output_format } else { "extract" }),
+      zip: zip.clone(),
+      cached_at: cached.as_ref().map(|entry| entry.created_at),
+      entries,
+    };
+    output::write_file(&manifest::manifest_path(output), force, |part| Ok(manifest.write(part)?))?;
+  }
+
+  save_delta_state(delta_state_path, latest_delta)?;
+  if let Some(checksum) = &checksum {
+    let path = output_filepath.map(Path::new).or(extract_dir);
+    events.emit(Event::Completed { path, checksum });
+  }
+  Ok(())
+}
+
+fn build_client(matches: &ArgMatches, config: &Config) -> Result<DkkClient, DkkError> {
+  let retry = RetryPolicy {
+    max_retries: matches.value_of("retries").unwrap_or("5").parse()?,
+    max_backoff: Duration::from_secs(matches.value_of("retry_max_wait").unwrap_or("60").parse()?),
+    ..RetryPolicy::default()
   };
+  let mut client = DkkClient::new().with_retry_policy(retry);
+  if let Some(base_url) = matches.value_of("base_url").map(String::from).or_else(|| config.base_url.clone()) {
+    client = client.with_root_url(&base_url)?;
+  }
+  if let Some(api_version) = matches.value_of("api_version").map(String::from).or_else(|| config.api_version.clone()) {
+    client = client.with_api_version(api_version);
+  }
+  Ok(client)
+}
 
-  let root_url = "https://api.pdok.nl";
-  let root_api_url = format!("{}{}", root_url, "/kadaster/kadastralekaart/download/v5_0");
-
-  let client = reqwest::Client::new();
-
-  // featuretypes kan bijv. het volgende zijn;
-  // array![
-  //    "perceel",
-  //    "kadastralegrens",
-  //    "pand",
-  //    "openbareruimtelabel",
-  //    "kwaliteit"
-  //  ],
-  let body = object!{
-    "featuretypes" => JsonValue::from(layers),
-    "format" => "gml", // "gml" is per najaar 2023 ook de enige toegestane waarde.
-    "geofilter" => interessegebied
+/// De cache uit `--cache-dir` en `--cache-ttl`, het configuratiebestand of de standaardwaarden. `None` als er geen
+/// map voor de cache is, of bij `--no-cache` en `use_cache` niet gezet.
+fn open_cache(matches: &ArgMatches, config: &Config, use_cache: bool) -> Result<Option<Cache>, DkkError> {
+  if matches.is_present("no_cache") && !use_cache {
+    return Ok(None);
+  }
+  let ttl = match matches.value_of("cache_ttl") {
+    Some(ttl) => Duration::from_secs(ttl.parse()?),
+    None => config.cache_ttl.map(Duration::from_secs).unwrap_or(cache::DEFAULT_TTL),
   };
-  let requrl = format!("{}{}", root_api_url, "/full/custom");
-  let jsonbody = json::stringify(body.clone());
-  let mut res = client.post(requrl.as_str())
-    .header(reqwest::header::USER_AGENT, &user_agent)
-    .header(reqwest::header::ACCEPT, "application/json")
-    .header(reqwest::header::CONTENT_TYPE, "application/json") // Als je deze niet zend, zend de PDOK API een 500tje terug: stand 2019-10-2
-    .body(jsonbody)
-    .send()?;
+  let dir = matches.value_of("cache_dir").map(PathBuf::from)
+    .or_else(|| config.cache_dir.as_ref().map(PathBuf::from))
+    .or_else(Cache::default_dir);
+  Ok(dir.map(|dir| Cache::new(dir, ttl)))
+}
+
+/// Voor `cache list` en `cache purge`; daar is een cache nodig, ook met `--no-cache`.
+fn require_cache(matches: &ArgMatches) -> Result<Cache, DkkError> {
+  let config = Config::load_or_default(matches.value_of("config").map(Path::new))?;
+  open_cache(matches, &config, true)?.ok_or_else(|| ConfigError::new("geen map voor de cache gevonden; gebruik --cache-dir").into())
+}
 
-  if res.status() != StatusCode::ACCEPTED {
-    return Err(Box::new(UnexpectedStatusCodeError::new(res, reqwest::Method::POST)));
+fn list_cache(matches: &ArgMatches) -> Result<(), DkkError> {
+  let cache = require_cache(matches)?;
+  let entries = cache.entries()?;
+  if entries.is_empty() {
+    eprintln!("De cache in {} is leeg.", cache.dir().display());
+  }
+  for entry in entries {
+    let status = if entry.is_expired(cache.ttl()) { "verlopen" } else { "geldig" };
+    println!("{}  {}  {:8}  {:>10} bytes  {}  downloadRequestId: {}", &entry.hash[..12], manifest::format_timestamp(entry.created_at),
+      status, entry.checksum.size, entry.key.feature_types.join(","), entry.request_ids.join(","));
+    println!("    {}", entry.key.api_url);
+    println!("    {}", abbreviate(&entry.key.geofilter, 100));
   }
+  Ok(())
+}
+
+fn purge_cache(matches: &ArgMatches) -> Result<(), DkkError> {
+  let cache = require_cache(matches)?;
+  let removed = cache.purge(matches.is_present("all"))?;
+  let bytes: u64 = removed.iter().map(|entry| entry.checksum.size).sum();
+  eprintln!("{} ZIP-bestand(en) ({} bytes) uit de cache in {} verwijderd.", removed.len(), bytes, cache.dir().display());
+  Ok(())
+}
 
-  let restext = res.text()?;
-  let resjson = json::parse(&restext)?;
+fn open_history(matches: &ArgMatches) -> Option<History> {
+  matches.value_of("history_file").map(History::new).or_else(|| History::default_path().map(History::new))
+}
 
-  let reqid: &str = resjson["downloadRequestId"].as_str().expect("Verkregen downloadRequestId van de PDOK API is geen string.");
+/// Voor `history list`, `show` en `rerun`; ook met `--no-history`.
+fn require_history(matches: &ArgMatches) -> Result<History, DkkError> {
+  open_history(matches).ok_or_else(|| ConfigError::new("geen plek voor de geschiedenis gevonden; gebruik --history-file").into())
+}
 
-  let mut progress_foreign = None;
+fn run_status(run: &Run) -> String {
+  match run.exit_code() {
+    Some(0) => String::from("geslaagd"),
+    Some(code) => format!("mislukt ({})", code),
+    None => String::from("onvoltooid"),
+  }
+}
 
-  if show_progress {
-    progress_foreign = Some(ProgressBar::on(stderr(), 100));
-    progress_foreign.as_mut().unwrap().message("PDOK API is bezig met processen ");
-    progress_foreign.as_mut().unwrap().show_tick = true;
-  }
-
-  loop {
-    let status_url = format!("{}{}{}/status", root_api_url, "/full/custom/", reqid);
-    let mut res = client.get(status_url.as_str())
-      .header(reqwest::header::USER_AGENT, &user_agent)
-      .header(reqwest::header::ACCEPT, "application/json")
-      .send()?;
-    match res.status() { // "Full custom download nog niet gereed"
-      StatusCode::OK => {
-        if show_progress {
-          progress_foreign.as_mut().unwrap().tick();
-
-          let restext = res.text();
-          match restext {
-            Ok(text) => {
-              let resjson = json::parse(&text);
-              match resjson {
-                Ok(statusjson) => {
-                  let progress = statusjson["progress"].as_u64();
-                  if let Some(progress) = progress {
-                    progress_foreign.as_mut().unwrap().set(progress);
-                  }
-                }
-                Err(_) => {} // Niks doen
-              }
-            }
-            Err(_) => {} // Niks doen
+fn list_history(matches: &ArgMatches) -> Result<(), DkkError> {
+  let history = require_history(matches)?;
+  let runs = history.runs()?;
+  if runs.is_empty() {
+    eprintln!("De geschiedenis in {} is leeg.", history.path().display());
+  }
+  let limit: Option<usize> = matches.value_of("limit").map(str::parse).transpose()?;
+  let skip = limit.map(|limit| runs.len().saturating_sub(limit)).unwrap_or(0);
+  for run in &runs[skip..] {
+    println!("{}  {}  {:12}  {}", run.id, run.started_at().unwrap_or_default(), run_status(run), abbreviate(&run.command_line(), 100));
+    let request_ids = run.request_ids();
+    if !request_ids.is_empty() {
+      println!("    downloadRequestId: {}", abbreviate(&request_ids.join(", "), 100));
+    }
+    let outputs = run.outputs();
+    if !outputs.is_empty() {
+      println!("    uitvoer: {}", abbreviate(&outputs.join(", "), 100));
+    }
+    if let Some(error) = run.error() {
+      println!("    fout: {}", abbreviate(error, 100));
+    }
+  }
+  Ok(())
+}
+
+fn show_history(matches: &ArgMatches) -> Result<(), DkkError> {
+  let run = require_history(matches)?.find(matches.value_of("id").unwrap())?;
+  if matches.is_present("json") {
+    for record in &run.records {
+      println!("{}", json::stringify(record.clone()));
+    }
+    return Ok(());
+  }
+  println!("Run:       {}", run.id);
+  println!("Opdracht:  {}", run.command_line());
+  println!("Map:       {}", run.cwd().unwrap_or_default());
+  if let Some(id) = run.rerun_of() {
+    println!("Herhaalt:  {}", id);
+  }
+  println!("Resultaat: {}", run_status(&run));
+  println!();
+  for record in &run.records {
+    let details: Vec<String> = record.entries()
+      .filter(|(key, value)| !matches!(*key, "run" | "time" | "event") && !value.is_null())
+      .map(|(key, value)| format!("{}={}", key, abbreviate(&value.as_str().map(String::from).unwrap_or_else(|| value.dump()), 100)))
+      .collect();
+    println!("{}  {:15}  {}", record["time"].as_str().unwrap_or_default(), record["event"].as_str().unwrap_or_default(), details.join(" "));
+  }
+  Ok(())
+}
+
+/// Voert de opdracht van `run` opnieuw uit in een nieuw proces, in de map waar hij toen uitgevoerd is. `--force` en
+/// `--history-file` van `history rerun` gaan mee; een `--history-file` uit de opdracht wordt dan vervangen. Eindigt het
+/// proces met een fout, dan heeft het die zelf al gemeld en stopt dkkdownload met dezelfde exit code.
+fn rerun(run: &Run, matches: &ArgMatches) -> Result<(), DkkError> {
+  let mut args: Vec<OsString> = run.args().into_iter().map(OsString::from).collect();
+  if matches.is_present("force") && !args.iter().any(|arg| arg == "--force") {
+    args.push(OsString::from("--force"));
+  }
+  if let Some(path) = matches.value_of_os("history_file") {
+    args = without_option(args, "--history-file");
+    args.push(OsString::from("--history-file"));
+    // Het nieuwe proces draait in een andere map.
+    args.push(env::current_dir()?.join(path).into_os_string());
+  }
+  if matches.value_of("log_format") != Some("json") {
+    eprintln!("Opnieuw: {}", run.command_line());
+  }
+  let mut command = Command::new(env::current_exe()?);
+  command.args(&args).env(ENV_RERUN_OF, &run.id);
+  if let Some(cwd) = run.cwd() {
+    command.current_dir(cwd);
+  }
+  let status = command.status()?;
+  match status.code() {
+    Some(0) => Ok(()),
+    Some(code) => std::process::exit(code),
+    None => Err(DkkError::Other(format!("herhaalde opdracht afgebroken ({})", status).into())),
+  }
+}
+
+/// `args` zonder de optie `name`, zowel als `name VALUE` als `name=VALUE`.
+fn without_option(args: Vec<OsString>, name: &str) -> Vec<OsString> {
+  let prefix = format!("{}=", name);
+  let mut result = Vec::with_capacity(args.len());
+  let mut args = args.into_iter();
+  while let Some(arg) = args.next() {
+    if arg == name {
+      args.next();
+    } else if !arg.to_string_lossy().starts_with(&prefix) {
+      result.push(arg);
+    }
+  }
+  result
+}
+
+fn abbreviate(text: &str, max_chars: usize) -> String {
+  match text.char_indices().nth(max_chars) {
+    Some((i, _)) => format!("{}...", &text[..i]),
+    None => String::from(text),
+  }
+}
+
+fn request_ids(records: &[RequestRecord]) -> Vec<String> {
+  records.iter().map(|record| record.request.id.clone()).collect()
+}
+
+/// De lagen uit LAGEN, zonder dubbele.
+fn resolve_layers(matches: &ArgMatches) -> Result<Vec<&'static str>, DkkError> {
+  let mut layers: Vec<&'static str> = Vec::new();
+  let names = matches.values_of("lagen")
+    .ok_or_else(|| DkkError::InvalidInput("Er moet minimaal 1 laag gespecificeerd worden.".into()))?;
+  for name in names {
+    let layer = layers::resolve(name)?;
+    if !layers.contains(&layer) {
+      layers.push(layer);
+    }
+  }
+  Ok(layers)
+}
+
+/// Transformeert een interessegebied naar RD New, in het CRS van `--input-crs` of anders dat uit de invoer zelf, en
+/// controleert of het als geofilter bruikbaar is. Zonder beide is het RD New, behalve bij GeoJSON: dat is volgens
+/// RFC 7946 WGS84.
+fn to_rd_new(area: &AreaInput, input_crs: Option<&str>) -> Result<Geometry, DkkError> {
+  let input_crs = match (input_crs, area.srid) {
+    (Some(name), srid) => {
+      let input_crs: Crs = name.parse()?;
+      if let Some(srid) = srid.filter(|srid| *srid != input_crs.epsg()) {
+        return Err(InvalidGeometryError::new(format!(
+          "--input-crs {} spreekt de EPSG:{} uit de {} invoer tegen", input_crs, srid, area.format)).into());
+      }
+      input_crs
+    },
+    (None, Some(srid)) => Crs::from_epsg(srid).ok_or_else(|| InvalidGeometryError::new(format!(
+      "{} in EPSG:{} wordt niet ondersteund; gebruik EPSG:28992, EPSG:4326 of EPSG:4258", area.format, srid)))?,
+    (None, None) if area.format == AreaFormat::GeoJson => {
+      let (min_x, min_y, max_x, max_y) = area.geometry.bounds();
+      if min_x < -180.0 || max_x > 180.0 || min_y < -90.0 || max_y > 90.0 {
+        return Err(InvalidGeometryError::new(format!(
+          "GeoJSON zonder crs is volgens RFC 7946 in WGS84 (EPSG:4326), maar ({} {}, {} {}) zijn geen lengte- en \
+          breedtegraden; geef het crs op in de GeoJSON of met --input-crs, bijv. --input-crs EPSG:28992",
+          min_x, min_y, max_x, max_y)).into());
+      }
+      Crs::Wgs84
+    },
+    (None, None) => Crs::RdNew,
+  };
+  let geometry = crs::to_rd_new(&area.geometry, input_crs);
+  geometry.validate()?;
+  Ok(geometry)
+}
+
+/// Een gebied van `batch`, klaar om in te dienen.
+struct BatchJob {
+  name: String,
+  geofilter: String,
+  cache_key: CacheKey,
+  output: PathBuf,
+}
+
+/// Opties die voor alle gebieden van `batch` gelden.
+struct BatchOptions {
+  layers: Vec<&'static str>,
+  force: bool,
+  write_manifests: bool,
+  cache: Option<Cache>,
+}
+
+/// Een ingediend gebied van `batch` dat nog niet klaar is.
+struct ActiveBatchJob<'a> {
+  job: &'a BatchJob,
+  record: RequestRecord,
+  started_at: SystemTime,
+}
+
+/// Bericht van een thread die een gebied van `batch` downloadt.
+enum BatchMessage<'a> {
+  Progress { bytes: u64, total: Option<u64> },
+  Finished(ActiveBatchJob<'a>, Result<Checksum, DkkError>),
+}
+
+/// Downloadt elk gebied uit het invoerbestand naar een eigen ZIP-bestand.
+///
+/// Er zijn maximaal `--concurrency` gebieden tegelijk onderweg, ingediend of aan het downloaden. De status van alle
+/// ingediende requests wordt in één lus opgevraagd; een request dat klaar is wordt op een eigen thread gedownload,
+/// zodat het opvragen doorgaat, en als de download klaar is wordt het volgende gebied ingediend. Events worden alleen
+/// op deze thread gemeld. Omdat er meerdere downloads tegelijk kunnen lopen, is er geen voortgangsbalk per download.
+/// Een mislukt gebied wordt gemeld en houdt de rest van de batch niet op.
+fn run_batch(matches: &ArgMatches, events: &mut EventLog<Stderr>, probing_interval: Duration) -> Result<(), DkkError> {
+  let input = matches.value_of("input").unwrap();
+  let areas = batch::read_areas(&fs::read_to_string(input)?)?;
+  let config = Config::load_or_default(matches.value_of("config").map(Path::new))?;
+  let options = BatchOptions {
+    layers: resolve_layers(matches)?,
+    force: matches.is_present("force"),
+    write_manifests: !matches.is_present("no_manifest"),
+    cache: open_cache(matches, &config, false)?,
+  };
+  let dir = Path::new(matches.value_of("output_dir").unwrap());
+  let concurrency: usize = matches.value_of("concurrency").unwrap_or("4").parse()?;
+  if concurrency == 0 {
+    return Err(DkkError::InvalidInput("--concurrency moet minimaal 1 zijn".into()));
+  }
+
+  let client = build_client(matches, &config)?;
+
+  // Alle gebieden en uitvoerbestanden vooraf controleren, zodat een fout in de invoer niet pas halverwege opvalt.
+  let mut jobs = Vec::new();
+  for area in &areas {
+    let geometry = to_rd_new(&area.area, matches.value_of("input_crs"))
+      .map_err(|e| BatchInputError::new(format!("gebied '{}'", area.name), e.to_string()))?;
+    let output = dir.join(format!("{}.zip", batch::file_name(&area.name)));
+    output::check_overwrite(&output, options.force)?;
+    if options.write_manifests {
+      output::check_overwrite(&manifest::manifest_path(&output), options.force)?;
+    }
+    let cache_key = CacheKey::new(&client.api_url(), &geometry, &options.layers);
+    if let Some(history) = events.history_mut() {
+      history.record_area(Some(&area.name), &wkt::to_wkt(&geometry), &options.layers);
+    }
+    jobs.push(BatchJob { name: area.name.clone(), geofilter: wkt::to_wkt(&geometry), cache_key, output });
+  }
+  fs::create_dir_all(dir)?;
+
+  let mut queue = jobs.iter();
+  let mut active: Vec<ActiveBatchJob> = Vec::new();
+  let mut downloading = 0;
+  let mut failed: Vec<String> = Vec::new();
+  let (sender, receiver) = mpsc::channel();
+  thread::scope(|scope| -> Result<(), DkkError> {
+    loop {
+      while active.len() + downloading < concurrency {
+        let job = match queue.next() {
+          Some(job) => job,
+          None => break,
+        };
+        let cached = match &options.cache {
+          Some(cache) => cache.get(&job.cache_key)?,
+          None => None,
+        };
+        if let Some(entry) = cached {
+          let request = DownloadRequest::full(entry.request_ids.first().map(String::as_str).unwrap_or_default());
+          match copy_cached_batch_job(job, &entry, &options) {
+            Ok(()) => {
+              let (name, path) = (job.name.as_str(), job.output.as_path());
+              report(events, Event::AreaCompleted { name, request: &request, path, checksum: &entry.checksum, cached: true }, || {
+                eprintln!("{}: {} geschreven (uit de cache)", name, path.display());
+              });
+            },
+            Err(e) => report_area_failed(events, &job.name, Some(&request), e, &mut failed),
           }
+          continue;
         }
-
-        sleep(probing_interval);
-        continue;
+        let started_at = SystemTime::now();
+        match client.submit_custom_request(&options.layers, &job.geofilter) {
+          Ok(request) => {
+            report(events, Event::Submitted(&request), || eprintln!("{}: downloadRequestId: {}", job.name, request.id));
+            active.push(ActiveBatchJob { job, record: RequestRecord::submitted(request), started_at });
+          },
+          Err(e) => report_area_failed(events, &job.name, None, e, &mut failed),
+        }
+      }
+      if active.is_empty() && downloading == 0 {
+        return Ok(());
       }
-      StatusCode::CREATED => {
-        if show_progress {
-          progress_foreign.as_mut().unwrap().finish();
+
+      let running = active.len();
+      let mut pending = Vec::new();
+      for mut current in active {
+        let request = &current.record.request;
+        match client.poll_status(request) {
+          Ok(DownloadStatus::Pending { progress }) => {
+            events.emit(Event::Progress { request, percent: progress });
+            pending.push(current);
+          },
+          Ok(DownloadStatus::Ready { download_url }) => {
+            events.emit(Event::Ready { request, download_url: &download_url });
+            current.record.ready(&download_url);
+            let (client, options, sender) = (&client, &options, sender.clone());
+            scope.spawn(move || {
+              let result = download_batch_job(client, &current, &download_url, options, &sender);
+              let _ = sender.send(BatchMessage::Finished(current, result));
+            });
+            downloading += 1;
+          },
+          Err(e) => report_area_failed(events, &current.job.name, Some(request), e, &mut failed),
         }
+      }
+      active = pending;
 
-        let restext = res.text()?;
-        let resjson = json::parse(&restext)?;
-        let download_url = format!("{}{}", root_url, resjson["_links"]["download"]["href"]);
-
-        // Download url verwijst naar een zip bestand
-        let mut zipfileres = client.get(download_url.as_str())
-          .header(reqwest::header::USER_AGENT, &user_agent)
-          .header(reqwest::header::ACCEPT, "application/zip")
-          .send()?;
-        match zipfileres.status() {
-          StatusCode::OK => {
-            if show_progress {
-              if let Some(length) = zipfileres.content_length() {
-                let old_output_writer = output_writer;
-                let mut progress_own = ProgressBar::on(stderr(), length);
-                progress_own.message("ZIP bestand downloaden ");
-                progress_own.set_units(Units::Bytes);
-                output_writer = Box::new(TeeWriter::new(old_output_writer, progress_own));
-                zipfileres.copy_to(&mut output_writer)?;
-                return Ok(());
-              }
+      // Alleen wachten als er niets klaar was; anders kan meteen het volgende gebied ingediend worden. Een download
+      // die klaar is beëindigt het wachten ook.
+      let mut wait_until = if active.len() == running { Some(Instant::now() + probing_interval) } else { None };
+      loop {
+        let message = match wait_until {
+          Some(deadline) => receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())).ok(),
+          None => receiver.try_recv().ok(),
+        };
+        match message {
+          Some(BatchMessage::Progress { bytes, total }) => events.emit(Event::DownloadProgress { bytes, total }),
+          Some(BatchMessage::Finished(current, result)) => {
+            downloading -= 1;
+            wait_until = None;
+            let request = &current.record.request;
+            match result {
+              Ok(checksum) => {
+                let (name, path) = (current.job.name.as_str(), current.job.output.as_path());
+                report(events, Event::AreaCompleted { name, request, path, checksum: &checksum, cached: false }, || {
+                  eprintln!("{}: {} geschreven", name, path.display());
+                });
+              },
+              Err(e) => report_area_failed(events, &current.job.name, Some(request), e, &mut failed),
             }
-
-            zipfileres.copy_to(&mut output_writer)?;
-            return Ok(());
           },
-          _ => {
-            return Err(Box::new(UnexpectedStatusCodeError::new(zipfileres, reqwest::Method::GET)));
-          }
+          None => break,
         }
-      },
-      _ => { return Err(Box::new(UnexpectedStatusCodeError::new(res, reqwest::Method::GET))); }
+      }
     }
+  })?;
+
+  if failed.is_empty() {
+    Ok(())
+  } else {
+    Err(DkkError::PartialFailure(Box::new(BatchError::new(failed, jobs.len()))))
+  }
+}
+
+/// Downloadt een gebied dat klaarstaat, bewaart het in de cache en schrijft het manifest ernaast. Geeft de checksum
+/// van het ZIP-bestand terug. Draait op een eigen thread; de voortgang gaat via `messages` naar `run_batch`.
+fn download_batch_job<'a>(client: &DkkClient, current: &ActiveBatchJob<'a>, download_url: &str, options: &BatchOptions,
+    messages: &Sender<BatchMessage<'a>>) -> Result<Checksum, DkkError> {
+  let output = &current.job.output;
+  client.download_to_file(&current.record.request, download_url, output, |bytes, total| {
+    let _ = messages.send(BatchMessage::Progress { bytes, total });
+  })?;
+  let checksum = Checksum::of_file(output)?;
+  if let Some(cache) = &options.cache {
+    cache.put_file(&current.job.cache_key, output, &request_ids(std::slice::from_ref(&current.record)))?;
+  }
+  write_batch_manifest(current.job, vec![current.record.clone()], current.started_at, None, &checksum, options)?;
+  Ok(checksum)
+}
+
+/// Kopieert het ZIP-bestand van een gebied uit de cache, en schrijft het manifest ernaast.
+fn copy_cached_batch_job(job: &BatchJob, entry: &CacheEntry, options: &BatchOptions) -> Result<(), DkkError> {
+  let started_at = SystemTime::now();
+  output::write_file(&job.output, options.force, |part| Ok(fs::copy(&entry.zip_path, part).map(|_| ())?))?;
+  let records = entry.request_ids.iter().map(|id| RequestRecord::resumed(DownloadRequest::full(id.as_str()))).collect();
+  write_batch_manifest(job, records, started_at, Some(entry.created_at), &entry.checksum, options)
+}
+
+fn write_batch_manifest(job: &BatchJob, requests: Vec<RequestRecord>, started_at: SystemTime, cached_at: Option<SystemTime>,
+    checksum: &Checksum, options: &BatchOptions) -> Result<(), DkkError> {
+  if !options.write_manifests {
+    return Ok(());
+  }
+  let manifest = Manifest {
+    started_at,
+    completed_at: SystemTime::now(),
+    requests,
+    polygon: Some(job.geofilter.clone()),
+    feature_types: options.layers.iter().map(|layer| String::from(*layer)).collect(),
+    output: job.output.clone(),
+    format: String::from("zip"),
+    zip: checksum.clone(),
+    cached_at,
+    entries: extract::verify_zip_file(&job.output)?,
+  };
+  output::write_file(&manifest::manifest_path(&job.output), options.force, |part| Ok(manifest.write(part)?))?;
+  Ok(())
+}
+
+fn report_area_failed(events: &mut EventLog<Stderr>, name: &str, request: Option<&DownloadRequest>, error: DkkError, failed: &mut Vec<String>) {
+  report(events, Event::AreaFailed { name, request, error: &error }, || eprintln!("{}: Error: {}", name, error));
+  failed.push(String::from(name));
+}
+
+/// Meldt `event` als JSON-regel, of anders met `human` als melding voor mensen.
+fn report<F: FnOnce()>(events: &mut EventLog<Stderr>, event: Event, human: F) {
+  // Ook zonder JSON-regels gaat het event naar de geschiedenis.
+  events.emit(event);
+  if !events.is_enabled() {
+    human();
+  }
+}
+
+/// Waar de voortgang van een download heen moet: `download_progress` events, een voortgangsbalk of nergens.
+fn download_progress<'a>(events: &'a mut EventLog<Stderr>, total: Option<u64>, show_progress: bool, message: &str) -> Option<Box<dyn Write + 'a>> {
+  if events.is_enabled() {
+    return Some(Box::new(DownloadProgressWriter::new(events, total)));
+  }
+  match total {
+    Some(length) if show_progress => {
+      let mut progress_own = ProgressBar::on(stderr(), length);
+      progress_own.message(message);
+      progress_own.set_units(Units::Bytes);
+      Some(Box::new(progress_own))
+    },
+    _ => None,
+  }
+}
+
+fn print_layers() {
+  let width = layers::FEATURE_TYPES.iter().map(|feature_type| feature_type.name.len()).max().unwrap_or(0);
+  for feature_type in layers::FEATURE_TYPES {
+    println!("{:width$}  {}", feature_type.name, feature_type.description, width = width);
+    println!("{:width$}  ook: {}", "", feature_type.aliases.join(", "), width = width);
+  }
+}
+
+fn wait_for_download(client: &DkkClient, request: &DownloadRequest, probing_interval: Duration, show_progress: bool,
+    events: &mut EventLog<Stderr>) -> Result<String, DkkError> {
+  let mut progress_foreign = None;
+
+  if show_progress {
+    let mut pb = ProgressBar::on(stderr(), 100);
+    pb.message("PDOK API is bezig met processen ");
+    pb.show_tick = true;
+    progress_foreign = Some(pb);
+  }
+
+  let download_url = client.wait_until_ready(request, probing_interval, |progress| {
+    events.emit(Event::Progress { request, percent: progress });
+    if let Some(pb) = progress_foreign.as_mut() {
+      pb.tick();
+      if let Some(progress) = progress {
+        pb.set(progress);
+      }
+    }
+  })?;
+
+  if let Some(pb) = progress_foreign.as_mut() {
+    pb.finish();
+  }
+  events.emit(Event::Ready { request, download_url: &download_url });
+  Ok(download_url)
+}
+
+fn download_file(client: &DkkClient, request: &DownloadRequest, download_url: &str, path: &Path, show_progress: bool,
+    events: &mut EventLog<Stderr>) -> Result<(), DkkError> {
+  let mut progress_own: Option<ProgressBar<Stderr>> = None;
+  client.download_to_file(request, download_url, path, |written, total| {
+    events.emit(Event::DownloadProgress { bytes: written, total });
+    if !show_progress {
+      return;
+    }
+    if let Some(length) = total {
+      let pb = progress_own.get_or_insert_with(|| {
+        let mut pb = ProgressBar::on(stderr(), length);
+        pb.message("ZIP bestand downloaden ");
+        pb.set_units(Units::Bytes);
+        pb
+      });
+      pb.set(written);
+    }
+  })?;
+  if let Some(pb) = progress_own.as_mut() {
+    pb.finish();
+  }
+  Ok(())
+}
+
+fn report_extracted(extracted: &[ExtractedFile], show_progress: bool, events: &mut EventLog<Stderr>) {
+  for file in extracted {
+    events.emit(Event::Extracted(file));
+    if show_progress {
+      eprintln!("Uitgepakt: {} ({} bytes)", file.path.display(), file.size);
+    }
+  }
+}
+
+fn save_delta_state(path: Option<&str>, latest_delta: Option<Delta>) -> Result<(), DkkError> {
+  if let (Some(path), Some(delta)) = (path, latest_delta) {
+    output::write_file(Path::new(path), true, |part| Ok(fs::write(part, format!("{}\n", delta.id))?))?;
   }
+  Ok(())
 }
@@ -0,0 +1,122 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use json::{object, JsonValue};
+
+use crate::checksum::Checksum;
+use crate::date::{civil_from_days, SECONDS_PER_DAY};
+use crate::client::DownloadRequest;
+use crate::events::kind_name;
+use crate::extract::ZipEntry;
+
+
+/// Een download request zoals het in het manifest komt.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RequestRecord {
+  pub request: DownloadRequest,
+  /// `None` bij `--resume`; het request is dan in een eerdere run ingediend.
+  pub submitted_at: Option<SystemTime>,
+  pub ready_at: Option<SystemTime>,
+  pub download_url: Option<String>,
+}
+
+impl RequestRecord {
+  pub fn submitted(request: DownloadRequest) -> Self {
+    Self { request, submitted_at: Some(SystemTime::now()), ready_at: None, download_url: None }
+  }
+
+  pub fn resumed(request: DownloadRequest) -> Self {
+    Self { request, submitted_at: None, ready_at: None, download_url: None }
+  }
+
+  /// Legt vast dat het ZIP-bestand nu klaarstaat op `download_url`.
+  pub fn ready(&mut self, download_url: &str) {
+    self.ready_at = Some(SystemTime::now());
+    self.download_url = Some(String::from(download_url));
+  }
+}
+
+/// Verantwoording van een download, voor archivering: wat er aangevraagd is, wanneer, en wat er ontvangen is.
+///
+/// Wordt als JSON naast de uitvoer gezet, zie `manifest_path`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+  pub started_at: SystemTime,
+  pub completed_at: SystemTime,
+  pub requests: Vec<RequestRecord>,
+  /// Het interessegebied als WKT in RD New (EPSG:28992), zoals het aangevraagd is. `None` bij `--resume`.
+  pub polygon: Option<String>,
+  pub feature_types: Vec<String>,
+  pub output: PathBuf,
+  pub format: String,
+  /// Grootte en SHA-256 van het (samengevoegde) ZIP-bestand.
+  pub zip: Checksum,
+  /// Wanneer het ZIP-bestand in de cache gezet is, als het daaruit komt.
+  pub cached_at: Option<SystemTime>,
+  pub entries: Vec<ZipEntry>,
+}
+
+impl Manifest {
+  pub fn to_json(&self) -> JsonValue {
+    let mut requests = JsonValue::new_array();
+    for record in &self.requests {
+      let _ = requests.push(object!{
+        "request_id" => record.request.id.as_str(),
+        "kind" => kind_name(record.request.kind),
+        "submitted_at" => record.submitted_at.map(format_timestamp),
+        "ready_at" => record.ready_at.map(format_timestamp),
+        "download_url" => record.download_url.as_deref()
+      });
+    }
+    let mut entries = JsonValue::new_array();
+    for entry in &self.entries {
+      let _ = entries.push(object!{
+        "name" => entry.name.as_str(),
+        "size" => entry.size,
+        "crc32" => format!("{:08x}", entry.crc32)
+      });
+    }
+    object!{
+      "dkkdownload_version" => env!("CARGO_PKG_VERSION"),
+      "started_at" => format_timestamp(self.started_at),
+      "completed_at" => format_timestamp(self.completed_at),
+      "requests" => requests,
+      "polygon" => self.polygon.as_deref(),
+      "crs" => "EPSG:28992",
+      "feature_types" => self.feature_types.clone(),
+      "output" => object!{
+        "path" => self.output.to_string_lossy().into_owned(),
+        "format" => self.format.as_str()
+      },
+      "zip" => object!{ "size" => self.zip.size, "sha256" => self.zip.sha256.as_str() },
+      "cached_at" => self.cached_at.map(format_timestamp),
+      "entries" => entries
+    }
+  }
+
+  pub fn write(&self, path: &Path) -> io::Result<()> {
+    fs::write(path, format!("{}\n", json::stringify_pretty(self.to_json(), 2)))
+  }
+}
+
+/// Het manifest van `output` staat ernaast als `<output>.manifest.json`, ook als `output` een map is.
+pub fn manifest_path(output: &Path) -> PathBuf {
+  let mut name = output.file_name().map(|name| name.to_os_string()).unwrap_or_default();
+  name.push(".manifest.json");
+  output.with_file_name(name)
+}
+
+/// Formatteert een tijdstip als RFC 3339 in UTC, op de seconde, bijv. `2023-10-01T12:00:00Z`.
+pub fn format_timestamp(time: SystemTime) -> String {
+  let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+  let (days, rest) = (secs / SECONDS_PER_DAY, secs % SECONDS_PER_DAY);
+  let (year, month, day) = civil_from_days(days);
+  format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, rest / 3600, rest % 3600 / 60, rest % 60)
+}
@@ -0,0 +1,305 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::collections::{HashMap, HashSet};
+use std::error::Error;
+use std::fs::File;
+use std::io::{Read, Seek, Write};
+use std::path::Path;
+
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::error::InvalidResponseError;
+
+
+/// Resultaat van het samenvoegen van één laag (één GML-bestand in de ZIP).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LayerMergeStats {
+  pub name: String,
+  /// Aantal features in het samengevoegde bestand.
+  pub features: usize,
+  /// Aantal features dat in meerdere tegels voorkwam en daarom weggelaten is.
+  pub duplicates: usize,
+}
+
+/// Voegt de ZIP-bestanden van meerdere download requests (bijv. tegels) samen tot één ZIP.
+///
+/// GML-bestanden met dezelfde naam worden samengevoegd tot één FeatureCollection, waarbij features die
+/// in meerdere invoerbestanden voorkomen op basis van hun `identificatie` maar één keer worden opgenomen.
+/// Van andere bestanden wordt het eerste exemplaar overgenomen.
+pub fn merge_zips<P, W>(inputs: &[P], output: W) -> Result<Vec<LayerMergeStats>, Box<dyn Error + Send + Sync>>
+    where P: AsRef<Path>, W: Write + Seek {
+  let mut order: Vec<String> = Vec::new();
+  let mut layers: HashMap<String, MergedLayer> = HashMap::new();
+  let mut others: HashMap<String, Vec<u8>> = HashMap::new();
+
+  for input in inputs {
+    let mut archive = ZipArchive::new(File::open(input)?)?;
+    for i in 0..archive.len() {
+      let mut entry = archive.by_index(i)?;
+      if entry.is_dir() {
+        continue;
+      }
+      let name = String::from(entry.name());
+      let mut data = Vec::new();
+      entry.read_to_end(&mut data)?;
+
+      if !layers.contains_key(&name) && !others.contains_key(&name) {
+        order.push(name.clone());
+      }
+      if name.to_ascii_lowercase().ends_with(".gml") {
+        let document = parse_gml(&data).map_err(|e| InvalidResponseError::new(format!("{}: {}", name, e)))?;
+        match layers.get_mut(&name) {
+          Some(layer) => layer.add(document.members),
+          None => {
+            let mut layer = MergedLayer { header: document.header, footer: document.footer, members: Vec::new(), seen: HashSet::new(), duplicates: 0 };
+            layer.add(document.members);
+            layers.insert(name, layer);
+          },
+        }
+      } else {
+        others.entry(name).or_insert(data);
+      }
+    }
+  }
+
+  let mut zip = ZipWriter::new(output);
+  let options = FileOptions::default().compression_method(CompressionMethod::Deflated);
+  let mut stats = Vec::new();
+  for name in order {
+    zip.start_file(name.as_str(), options)?;
+    if let Some(layer) = layers.get(&name) {
+      zip.write_all(&layer.header)?;
+      for member in &layer.members {
+        zip.write_all(b"\n")?;
+        zip.write_all(member)?;
+      }
+      zip.write_all(b"\n")?;
+      zip.write_all(&layer.footer)?;
+      stats.push(LayerMergeStats { name, features: layer.members.len(), duplicates: layer.duplicates });
+    } else if let Some(data) = others.get(&name) {
+      zip.write_all(data)?;
+    }
+  }
+  zip.finish()?;
+  Ok(stats)
+}
+
+struct MergedLayer {
+  header: Vec<u8>,
+  footer: Vec<u8>,
+  members: Vec<Vec<u8>>,
+  seen: HashSet<String>,
+  duplicates: usize,
+}
+
+impl MergedLayer {
+  fn add(&mut self, members: Vec<Member>) {
+    for member in members {
+      if self.seen.insert(member.key) {
+        self.members.push(member.raw);
+      } else {
+        self.duplicates += 1;
+      }
+    }
+  }
+}
+
+/// Een GML FeatureCollection, opgedeeld in de tekst tot en met de openingstag van het root element,
+/// de losse feature members, en de sluitingstag.
+struct GmlDocument {
+  header: Vec<u8>,
+  footer: Vec<u8>,
+  members: Vec<Member>,
+}
+
+struct Member {
+  /// `identificatie` van de feature, of bij gebrek daaraan het `gml:id` of de volledige tekst.
+  key: String,
+  raw: Vec<u8>,
+}
+
+fn parse_gml(data: &[u8]) -> Result<GmlDocument, Box<dyn Error + Send + Sync>> {
+  let mut reader = Reader::from_reader(data);
+  let mut depth = 0usize;
+  let mut header = None;
+  let mut footer = Vec::new();
+  let mut members = Vec::new();
+
+  // Feature die nu gelezen wordt: beginpositie, de tag waarin hij verpakt moet worden (bij featureMembers), en zijn sleutel.
+  let mut member_start = 0usize;
+  let mut member_wrapper: Option<String> = None;
+  let mut member_depth = 0usize;
+  let mut gml_id: Option<String> = None;
+  let mut identificatie: Option<Vec<String>> = None;
+  let mut identificatie_depth = 0usize;
+  let mut identificatie_done = false;
+  let mut in_member = false;
+  let mut members_list_depth: Option<usize> = None;
+
+  loop {
+    let position = reader.buffer_position() as usize;
+    let event = reader.read_event()?;
+    match event {
+      Event::Start(ref e) => {
+        depth += 1;
+        let local = String::from_utf8_lossy(e.local_name().as_ref()).into_owned();
+        if depth == 1 {
+          header = Some(data[..reader.buffer_position() as usize].to_vec());
+          footer = format!("</{}>\n", String::from_utf8_lossy(e.name().as_ref())).into_bytes();
+        } else if !in_member && depth == 2 && local == "featureMembers" {
+          members_list_depth = Some(depth);
+          let prefix = e.name().prefix().map(|p| format!("{}:", String::from_utf8_lossy(p.as_ref()))).unwrap_or_default();
+          member_wrapper = Some(format!("{}featureMember", prefix));
+        } else if !in_member && ((depth == 2 && (local == "featureMember" || local == "member")) || members_list_depth == Some(depth - 1)) {
+          in_member = true;
+          member_start = position;
+          member_depth = depth;
+          gml_id = None;
+          identificatie = None;
+          identificatie_done = false;
+          if members_list_depth.is_some() {
+            gml_id = id_attribute(e);
+          }
+        } else if in_member {
+          if gml_id.is_none() && depth == member_depth + 1 {
+            gml_id = id_attribute(e);
+          }
+          if !identificatie_done && identificatie.is_none() && local == "identificatie" {
+            identificatie = Some(Vec::new());
+            identificatie_depth = depth;
+          }
+        }
+      },
+      Event::End(_) => {
+        if in_member && identificatie.is_some() && !identificatie_done && depth == identificatie_depth {
+          identificatie_done = true;
+        }
+        if in_member && depth == member_depth {
+          in_member = false;
+          let end = reader.buffer_position() as usize;
+          let mut raw = data[member_start..end].to_vec();
+          if let Some(wrapper) = &member_wrapper {
+            raw = [format!("<{}>", wrapper).into_bytes(), raw, format!("</{}>", wrapper).into_bytes()].concat();
+          }
+          let key = match (&identificatie, &gml_id) {
+            (Some(parts), _) if !parts.is_empty() => parts.join(":"),
+            (_, Some(id)) => id.clone(),
+            _ => String::from_utf8_lossy(&raw).into_owned(),
+          };
+          members.push(Member { key, raw });
+        }
+        if members_list_depth == Some(depth) {
+          members_list_depth = None;
+          member_wrapper = None;
+        }
+        depth = depth.saturating_sub(1);
+      },
+      Event::Text(ref e) => {
+        if let (Some(parts), false) = (identificatie.as_mut(), identificatie_done) {
+          let text = e.unescape()?;
+          let text = text.trim();
+          if !text.is_empty() {
+            parts.push(String::from(text));
+          }
+        }
+      },
+      Event::Empty(ref e) if in_member && gml_id.is_none() && depth == member_depth => {
+        gml_id = id_attribute(e);
+      },
+      Event::Eof => break,
+      _ => {},
+    }
+  }
+
+  match header {
+    Some(header) => Ok(GmlDocument { header, footer, members }),
+    None => Err(Box::new(InvalidResponseError::new("GML-bestand zonder root element"))),
+  }
+}
+
+fn id_attribute(e: &quick_xml::events::BytesStart) -> Option<String> {
+  e.attributes().flatten()
+    .find(|a| a.key.local_name().as_ref() == b"id")
+    .map(|a| String::from_utf8_lossy(&a.value).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::fs;
+  use std::io::Cursor;
+
+  use crate::gml::parse_features;
+  use crate::output::TempFile;
+
+  const HEADER: &str = "<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n\
+    <gml:FeatureCollection xmlns:gml=\"http://www.opengis.net/gml/3.2\" xmlns:kk=\"http://kadaster.nl\">";
+
+  /// Een perceel met `lokaal_id` als identificatie en `gml_id` als `gml:id`.
+  fn perceel(gml_id: &str, lokaal_id: &str) -> String {
+    format!("<kk:Perceel gml:id=\"{}\"><kk:identificatie><kk:NEN3610ID><kk:namespace>NL.IMKAD</kk:namespace>\
+      <kk:lokaalID>{}</kk:lokaalID></kk:NEN3610ID></kk:identificatie></kk:Perceel>", gml_id, lokaal_id)
+  }
+
+  /// Een tegel: een ZIP met een perceellaag met `members`, met zijn eigen `boundedBy`, en een tekstbestand.
+  fn tile(members: &str, text: &str) -> TempFile {
+    let gml = format!("{}<gml:boundedBy><gml:Envelope><gml:lowerCorner>{}</gml:lowerCorner></gml:Envelope></gml:boundedBy>\
+      {}</gml:FeatureCollection>", HEADER, text, members);
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("kadastralekaartv5_perceel.gml", FileOptions::default()).unwrap();
+    zip.write_all(gml.as_bytes()).unwrap();
+    zip.start_file("leesmij.txt", FileOptions::default()).unwrap();
+    zip.write_all(text.as_bytes()).unwrap();
+    let file = TempFile::new("dkkdownload-merge-test", "zip").unwrap();
+    fs::write(file.path(), zip.finish().unwrap().into_inner()).unwrap();
+    file
+  }
+
+  fn entry(zip: &[u8], name: &str) -> String {
+    let mut text = String::new();
+    ZipArchive::new(Cursor::new(zip)).unwrap().by_name(name).unwrap().read_to_string(&mut text).unwrap();
+    text
+  }
+
+  #[test]
+  fn merges_overlapping_tiles() {
+    let member = |feature: String| format!("<gml:featureMember>{}</gml:featureMember>", feature);
+    let first = tile(&[member(perceel("a1", "1")), member(perceel("a2", "2"))].concat(), "eerste");
+    // Perceel 2 ligt ook in deze tegel, met een ander gml:id; hier in featureMembers.
+    let second = tile(&format!("<gml:featureMembers>{}{}</gml:featureMembers>", perceel("b2", "2"), perceel("b3", "3")), "tweede");
+
+    let mut output = Cursor::new(Vec::new());
+    let stats = merge_zips(&[first.path(), second.path()], &mut output).unwrap();
+    assert_eq!(stats, vec![LayerMergeStats { name: String::from("kadastralekaartv5_perceel.gml"), features: 3, duplicates: 1 }]);
+
+    let zip = output.into_inner();
+    let gml = entry(&zip, "kadastralekaartv5_perceel.gml");
+    assert!(gml.starts_with(HEADER), "{}", gml);
+    assert!(gml.trim_end().ends_with("</gml:FeatureCollection>"), "{}", gml);
+    // De boundedBy van een tegel klopt niet voor het geheel.
+    assert!(!gml.contains("boundedBy"), "{}", gml);
+    assert!(!gml.contains("featureMembers"), "{}", gml);
+    let ids: Vec<Option<String>> = parse_features(gml.as_bytes()).unwrap().into_iter().map(|feature| feature.id).collect();
+    assert_eq!(ids, vec![Some(String::from("a1")), Some(String::from("a2")), Some(String::from("b3"))]);
+    // Andere bestanden uit de eerste tegel.
+    assert_eq!(entry(&zip, "leesmij.txt"), "eerste");
+  }
+
+  #[test]
+  fn rejects_gml_without_root_element() {
+    let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+    zip.start_file("kadastralekaartv5_perceel.gml", FileOptions::default()).unwrap();
+    zip.write_all(b"<?xml version=\"1.0\"?>").unwrap();
+    let file = TempFile::new("dkkdownload-merge-test", "zip").unwrap();
+    fs::write(file.path(), zip.finish().unwrap().into_inner()).unwrap();
+
+    let error = merge_zips(&[file.path()], Cursor::new(Vec::new())).unwrap_err();
+    assert!(error.to_string().contains("kadastralekaartv5_perceel.gml"), "{}", error);
+  }
+}
@@ -0,0 +1,126 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::env;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::client::{part_info_path, part_path};
+use crate::error::OutputExistsError;
+
+
+/// Geeft een `OutputExistsError` als `path` al bestaat en niet overschreven mag worden.
+pub fn check_overwrite(path: &Path, overwrite: bool) -> Result<(), OutputExistsError> {
+  if !overwrite && fs::symlink_metadata(path).is_ok() {
+    return Err(OutputExistsError::new(path));
+  }
+  Ok(())
+}
+
+/// Laat `write` naar `<path>.part` schrijven, en hernoemt dat naar `path` als het slaagt.
+///
+/// Zo blijft een bestaand bestand op `path` intact tot de nieuwe versie compleet is. Mislukt `write`, dan wordt het
+/// tijdelijke bestand weer verwijderd.
+pub fn write_file<T, F>(path: &Path, overwrite: bool, write: F) -> Result<T, Box<dyn Error + Send + Sync>>
+    where F: FnOnce(&Path) -> Result<T, Box<dyn Error + Send + Sync>> {
+  check_overwrite(path, overwrite)?;
+  let part = part_path(path);
+  match write(&part) {
+    Ok(result) => {
+      fs::rename(&part, path)?;
+      Ok(result)
+    },
+    Err(e) => {
+      let _ = fs::remove_file(&part);
+      Err(e)
+    },
+  }
+}
+
+/// Laat `write` bestanden in een tijdelijke map binnen `dir` schrijven, en verplaatst ze naar `dir` als het slaagt.
+///
+/// Bestaande bestanden worden pas vervangen als alle nieuwe bestanden geschreven zijn, en zonder `overwrite` helemaal
+/// niet. Geeft de paden die `write` teruggeeft terug, maar dan in `dir`.
+pub fn write_files<F>(dir: &Path, overwrite: bool, write: F) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>>
+    where F: FnOnce(&Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+  fs::create_dir_all(dir)?;
+  // In `dir` zelf, zodat het verplaatsen een rename binnen hetzelfde bestandssysteem is.
+  let staging = dir.join(format!(".dkkdownload-{}.part", std::process::id()));
+  let _ = fs::remove_dir_all(&staging);
+  let result = write(&staging).and_then(|written| {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(&staging)? {
+      let name = entry?.file_name();
+      check_overwrite(&dir.join(&name), overwrite)?;
+      files.push(name);
+    }
+    for name in files {
+      fs::rename(staging.join(&name), dir.join(&name))?;
+    }
+    Ok(written.iter().filter_map(|path| path.file_name()).map(|name| dir.join(name)).collect())
+  });
+  let _ = fs::remove_dir_all(&staging);
+  result
+}
+
+/// Een tijdelijk bestand in `env::temp_dir()`, dat weer verwijderd wordt als de `TempFile` verdwijnt, ook als er
+/// halverwege iets misgaat.
+///
+/// De naam krijgt een willekeurig achtervoegsel en het bestand wordt meteen (leeg) aangemaakt, zodat een ander proces
+/// of een bestand dat er al stond nooit dezelfde naam krijgt.
+#[derive(Debug)]
+pub struct TempFile {
+  path: PathBuf,
+}
+
+impl TempFile {
+  /// Maakt `<prefix>-<willekeurig>.<extension>` aan.
+  pub fn new(prefix: &str, extension: &str) -> io::Result<Self> {
+    loop {
+      let path = env::temp_dir().join(format!("{}-{:016x}.{}", prefix, rand::random::<u64>(), extension));
+      match OpenOptions::new().write(true).create_new(true).open(&path) {
+        Ok(_) => return Ok(Self { path }),
+        Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+        Err(e) => return Err(e),
+      }
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+}
+
+impl Drop for TempFile {
+  fn drop(&mut self) {
+    // Ook wat er via `write_file` of een download naast geschreven is.
+    let _ = fs::remove_file(part_path(&self.path));
+    let _ = fs::remove_file(part_info_path(&self.path));
+    let _ = fs::remove_file(&self.path);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn temp_files_are_unique_and_removed() {
+    let a = TempFile::new("dkkdownload-output-test", "zip").unwrap();
+    let b = TempFile::new("dkkdownload-output-test", "zip").unwrap();
+    assert_ne!(a.path(), b.path());
+    assert!(a.path().is_file() && b.path().is_file());
+    let (a_path, b_path) = (a.path().to_path_buf(), b.path().to_path_buf());
+    fs::write(part_path(&a_path), "half").unwrap();
+
+    drop(a);
+    assert!(!a_path.exists() && !part_path(&a_path).exists());
+    assert!(b_path.exists());
+    drop(b);
+    assert!(!b_path.exists());
+  }
+}
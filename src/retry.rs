@@ -0,0 +1,80 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::time::{Duration, SystemTime};
+
+use rand::Rng;
+use reqwest::StatusCode;
+
+
+/// Hoe vaak en hoe lang er gewacht wordt bij tijdelijke fouten van de PDOK API.
+///
+/// Tijdelijke fouten zijn 5xx en 429 antwoorden, en verbindingsfouten of time-outs. Een download request indienen
+/// (POST) wordt alleen herhaald als vaststaat dat de server het niet verwerkt heeft, zodat er geen dubbele requests
+/// ontstaan: als er geen verbinding kwam, of bij 429 en 503 met een `Retry-After`. Tussen pogingen wordt exponentieel langer gewacht, met jitter, tenzij de server een `Retry-After` meegeeft.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RetryPolicy {
+  /// Maximaal aantal herhalingen na de eerste poging. 0 schakelt opnieuw proberen uit.
+  pub max_retries: u32,
+  pub initial_backoff: Duration,
+  pub max_backoff: Duration,
+}
+
+impl Default for RetryPolicy {
+  fn default() -> Self {
+    Self {
+      max_retries: 5,
+      initial_backoff: Duration::from_secs(1),
+      max_backoff: Duration::from_secs(60),
+    }
+  }
+}
+
+impl RetryPolicy {
+  pub fn none() -> Self {
+    Self { max_retries: 0, ..Self::default() }
+  }
+
+  /// Wachttijd voor herhaling nummer `attempt` (vanaf 0): tussen de helft en het geheel van de exponentiële backoff.
+  pub fn backoff(&self, attempt: u32) -> Duration {
+    let factor = 1u32.checked_shl(attempt).unwrap_or(u32::MAX);
+    let ceiling = self.initial_backoff.checked_mul(factor).unwrap_or(self.max_backoff).min(self.max_backoff);
+    let ceiling_ms = ceiling.as_millis() as u64;
+    if ceiling_ms < 2 {
+      return ceiling;
+    }
+    Duration::from_millis(rand::thread_rng().gen_range(ceiling_ms / 2, ceiling_ms + 1))
+  }
+}
+
+pub(crate) fn is_transient_status(status: StatusCode) -> bool {
+  status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+}
+
+pub(crate) fn is_transient_error(error: &reqwest::Error) -> bool {
+  // is_http() omvat in reqwest 0.9 ook verbindingsfouten van hyper.
+  error.is_timeout() || error.is_http()
+}
+
+/// Of de verbinding niet tot stand kwam, zodat het request de server zeker niet bereikt heeft.
+pub(crate) fn is_connect_error(error: &reqwest::Error) -> bool {
+  error.get_ref().and_then(|e| e.downcast_ref::<hyper::Error>()).is_some_and(hyper::Error::is_connect)
+}
+
+/// 429 of 503 met een `Retry-After`: de server heeft het request geweigerd zonder het te verwerken.
+pub(crate) fn is_refused_for_now(res: &reqwest::Response) -> bool {
+  (res.status() == StatusCode::TOO_MANY_REQUESTS || res.status() == StatusCode::SERVICE_UNAVAILABLE)
+    && res.headers().contains_key(reqwest::header::RETRY_AFTER)
+}
+
+/// Wachttijd uit een `Retry-After` header, als aantal seconden of als HTTP-datum.
+pub(crate) fn retry_after(res: &reqwest::Response) -> Option<Duration> {
+  let value = res.headers().get(reqwest::header::RETRY_AFTER)?.to_str().ok()?.trim();
+  if let Ok(seconds) = value.parse::<u64>() {
+    return Some(Duration::from_secs(seconds));
+  }
+  let date = httpdate::parse_http_date(value).ok()?;
+  Some(date.duration_since(SystemTime::now()).unwrap_or_default())
+}
@@ -0,0 +1,340 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::fs::File;
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use geo::orient::{Direction, Orient};
+
+use crate::date;
+use crate::geometry::{FeatureGeometry, Point, Polygon};
+use crate::gml::{Feature, Layer};
+use crate::wkt::feature_geometry_to_wkt;
+
+
+/// Maximale lengte van een veldnaam in een DBF-bestand.
+pub const MAX_FIELD_NAME_LENGTH: usize = 10;
+/// Maximale lengte in bytes van een tekstveld in een DBF-bestand.
+pub const MAX_FIELD_LENGTH: usize = 254;
+
+/// RD New in de ESRI WKT-variant die in `.prj` bestanden verwacht wordt.
+const RD_NEW_PRJ: &str = "PROJCS[\"RD_New\",GEOGCS[\"GCS_Amersfoort\",DATUM[\"D_Amersfoort\",\
+  SPHEROID[\"Bessel_1841\",6377397.155,299.1528128]],PRIMEM[\"Greenwich\",0.0],UNIT[\"Degree\",0.0174532925199433]],\
+  PROJECTION[\"Double_Stereographic\"],PARAMETER[\"False_Easting\",155000.0],PARAMETER[\"False_Northing\",463000.0],\
+  PARAMETER[\"Central_Meridian\",5.38763888888889],PARAMETER[\"Scale_Factor\",0.9999079],\
+  PARAMETER[\"Latitude_Of_Origin\",52.15616055555555],UNIT[\"Meter\",1.0]]";
+
+const SHAPE_NULL: i32 = 0;
+const SHAPE_POINT: i32 = 1;
+const SHAPE_POLYLINE: i32 = 3;
+const SHAPE_POLYGON: i32 = 5;
+const SHAPE_MULTIPOINT: i32 = 8;
+
+/// Een DBF-veld en de eigenschap waar het vandaan komt.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldMapping {
+  pub field: String,
+  pub property: String,
+  /// Aantal waarden dat langer was dan `MAX_FIELD_LENGTH` bytes en is afgekapt.
+  pub truncated_values: usize,
+}
+
+/// Schrijft elke laag als Shapefile (`.shp`, `.shx`, `.dbf`, `.prj` en `.cpg`) naar `dir`.
+///
+/// Omdat DBF-veldnamen maximaal 10 tekens lang zijn, worden eigenschappen ingekort en waar nodig genummerd. Welk veld
+/// bij welke eigenschap hoort staat in `<laag>_velden.csv`. Features waarvan de geometrie niet bij het shape type van
+/// de laag past (dat van de hoogste dimensie) krijgen een lege shape. Geeft de geschreven `.shp` paden terug.
+pub fn write_shapefiles(layers: &[Layer], dir: &Path) -> Result<Vec<PathBuf>, Box<dyn Error + Send + Sync>> {
+  fs::create_dir_all(dir)?;
+  let mut paths = Vec::new();
+  for layer in layers {
+    let shape_type = shape_type_of(layer);
+    let shp_path = dir.join(format!("{}.shp", layer.name));
+    write_shapes(&shp_path, &dir.join(format!("{}.shx", layer.name)), layer, shape_type)?;
+    let mappings = write_dbf(&dir.join(format!("{}.dbf", layer.name)), layer)?;
+    fs::write(dir.join(format!("{}.prj", layer.name)), RD_NEW_PRJ)?;
+    fs::write(dir.join(format!("{}.cpg", layer.name)), "UTF-8")?;
+
+    let mut report = BufWriter::new(File::create(dir.join(format!("{}_velden.csv", layer.name)))?);
+    writeln!(report, "veld,eigenschap,afgekapte_waarden")?;
+    for mapping in &mappings {
+      writeln!(report, "{},{},{}", mapping.field, csv_field(&mapping.property), mapping.truncated_values)?;
+    }
+    report.flush()?;
+    paths.push(shp_path);
+  }
+  Ok(paths)
+}
+
+/// Shape type van de laag: dat van de geometrie met de hoogste dimensie.
+fn shape_type_of(layer: &Layer) -> i32 {
+  layer.features.iter().filter_map(|f| f.geometry.as_ref()).map(|g| match g {
+    FeatureGeometry::Point(_) => SHAPE_POINT,
+    FeatureGeometry::MultiPoint(_) => SHAPE_MULTIPOINT,
+    FeatureGeometry::LineString(_) | FeatureGeometry::MultiLineString(_) => SHAPE_POLYLINE,
+    FeatureGeometry::Polygon(_) | FeatureGeometry::MultiPolygon(_) => SHAPE_POLYGON,
+  }).max_by_key(|shape_type| match *shape_type {
+    SHAPE_POINT => 0,
+    SHAPE_MULTIPOINT => 1,
+    SHAPE_POLYLINE => 2,
+    _ => 3,
+  }).unwrap_or(SHAPE_NULL)
+}
+
+fn write_shapes(shp_path: &Path, shx_path: &Path, layer: &Layer, shape_type: i32) -> Result<(), Box<dyn Error + Send + Sync>> {
+  let mut shp = BufWriter::new(File::create(shp_path)?);
+  let mut shx = BufWriter::new(File::create(shx_path)?);
+  shp.write_all(&[0u8; 100])?;
+  shx.write_all(&[0u8; 100])?;
+
+  let mut bounds = (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY);
+  // Posities en lengtes in 16-bit woorden, zoals het formaat voorschrijft.
+  let mut offset = 50usize;
+  for (i, feature) in layer.features.iter().enumerate() {
+    let content = match &feature.geometry {
+      Some(geometry) => match shape_content(geometry, shape_type) {
+        Some(content) => {
+          let (min_x, min_y, max_x, max_y) = geometry.bounds();
+          bounds = (bounds.0.min(min_x), bounds.1.min(min_y), bounds.2.max(max_x), bounds.3.max(max_y));
+          content
+        },
+        None => SHAPE_NULL.to_le_bytes().to_vec(),
+      },
+      None => SHAPE_NULL.to_le_bytes().to_vec(),
+    };
+    shp.write_all(&(i as i32 + 1).to_be_bytes())?;
+    shp.write_all(&((content.len() / 2) as i32).to_be_bytes())?;
+    shp.write_all(&content)?;
+    shx.write_all(&(offset as i32).to_be_bytes())?;
+    shx.write_all(&((content.len() / 2) as i32).to_be_bytes())?;
+    offset += 4 + content.len() / 2;
+  }
+  if !bounds.0.is_finite() {
+    bounds = (0.0, 0.0, 0.0, 0.0);
+  }
+
+  shp.seek(SeekFrom::Start(0))?;
+  shp.write_all(&main_header(offset, shape_type, bounds))?;
+  shp.flush()?;
+  shx.seek(SeekFrom::Start(0))?;
+  shx.write_all(&main_header(50 + 4 * layer.features.len(), shape_type, bounds))?;
+  shx.flush()?;
+  Ok(())
+}
+
+fn main_header(length_in_words: usize, shape_type: i32, bounds: (f64, f64, f64, f64)) -> Vec<u8> {
+  let mut header = Vec::with_capacity(100);
+  header.extend_from_slice(&9994i32.to_be_bytes());
+  header.extend_from_slice(&[0u8; 20]);
+  header.extend_from_slice(&(length_in_words as i32).to_be_bytes());
+  header.extend_from_slice(&1000i32.to_le_bytes());
+  header.extend_from_slice(&shape_type.to_le_bytes());
+  for v in [bounds.0, bounds.1, bounds.2, bounds.3, 0.0, 0.0, 0.0, 0.0] {
+    header.extend_from_slice(&v.to_le_bytes());
+  }
+  header
+}
+
+/// De inhoud van een shape record, of `None` als de geometrie niet bij het shape type past.
+fn shape_content(geometry: &FeatureGeometry, shape_type: i32) -> Option<Vec<u8>> {
+  let mut content = Vec::new();
+  content.extend_from_slice(&shape_type.to_le_bytes());
+  match (geometry, shape_type) {
+    (FeatureGeometry::Point(point), SHAPE_POINT) => write_point(&mut content, point),
+    (FeatureGeometry::MultiPoint(points), SHAPE_MULTIPOINT) => {
+      write_box(&mut content, geometry);
+      content.extend_from_slice(&(points.len() as i32).to_le_bytes());
+      for point in points {
+        write_point(&mut content, point);
+      }
+    },
+    (FeatureGeometry::LineString(points), SHAPE_POLYLINE) => write_parts(&mut content, geometry, std::slice::from_ref(points)),
+    (FeatureGeometry::MultiLineString(lines), SHAPE_POLYLINE) => write_parts(&mut content, geometry, lines),
+    (FeatureGeometry::Polygon(polygon), SHAPE_POLYGON) => write_parts(&mut content, geometry, &shape_rings(std::slice::from_ref(polygon))),
+    (FeatureGeometry::MultiPolygon(polygons), SHAPE_POLYGON) => write_parts(&mut content, geometry, &shape_rings(polygons)),
+    _ => return None,
+  }
+  Some(content)
+}
+
+/// Shapefiles gebruiken de omgekeerde richting van OGC: buitenringen met de klok mee, gaten ertegenin. De GML kan
+/// beide richtingen bevatten, dus elke ring wordt gecontroleerd en zo nodig omgedraaid.
+fn shape_rings(polygons: &[Polygon]) -> Vec<Vec<Point>> {
+  let ring = |line: &geo::LineString<f64>| line.coords().map(|c| Point::new(c.x, c.y)).collect::<Vec<_>>();
+  polygons.iter()
+    .map(|polygon| geo::Polygon::from(polygon).orient(Direction::Reversed))
+    .flat_map(|polygon| std::iter::once(ring(polygon.exterior())).chain(polygon.interiors().iter().map(ring)).collect::<Vec<_>>())
+    .collect()
+}
+
+fn write_parts(content: &mut Vec<u8>, geometry: &FeatureGeometry, parts: &[Vec<Point>]) {
+  write_box(content, geometry);
+  content.extend_from_slice(&(parts.len() as i32).to_le_bytes());
+  content.extend_from_slice(&(parts.iter().map(Vec::len).sum::<usize>() as i32).to_le_bytes());
+  let mut start = 0;
+  for part in parts {
+    content.extend_from_slice(&(start as i32).to_le_bytes());
+    start += part.len();
+  }
+  for point in parts.iter().flatten() {
+    write_point(content, point);
+  }
+}
+
+fn write_box(content: &mut Vec<u8>, geometry: &FeatureGeometry) {
+  let (min_x, min_y, max_x, max_y) = geometry.bounds();
+  for v in [min_x, min_y, max_x, max_y] {
+    content.extend_from_slice(&v.to_le_bytes());
+  }
+}
+
+fn write_point(content: &mut Vec<u8>, point: &Point) {
+  content.extend_from_slice(&point.x.to_le_bytes());
+  content.extend_from_slice(&point.y.to_le_bytes());
+}
+
+/// Schrijft de attributen als dBASE III tabel met tekstvelden en geeft de veldtoewijzing terug.
+fn write_dbf(path: &Path, layer: &Layer) -> Result<Vec<FieldMapping>, Box<dyn Error + Send + Sync>> {
+  let mut properties: Vec<&str> = vec!["gml_id"];
+  for feature in &layer.features {
+    let keys = feature.properties.iter().map(|(k, _)| k.as_str()).chain(feature.other_geometries.iter().map(|(k, _)| k.as_str()));
+    for key in keys {
+      if !properties.contains(&key) {
+        properties.push(key);
+      }
+    }
+  }
+  let mut mappings = field_names(&properties).into_iter().zip(properties.iter())
+    .map(|(field, property)| FieldMapping { field, property: String::from(*property), truncated_values: 0 })
+    .collect::<Vec<_>>();
+
+  let rows: Vec<Vec<String>> = layer.features.iter().map(|feature| {
+    properties.iter().map(|property| field_value(feature, property)).collect()
+  }).collect();
+  let lengths: Vec<usize> = (0..properties.len()).map(|i| {
+    rows.iter().map(|row| row[i].len()).max().unwrap_or(0).clamp(1, MAX_FIELD_LENGTH)
+  }).collect();
+
+  let mut dbf = BufWriter::new(File::create(path)?);
+  let header_length = 32 + 32 * properties.len() + 1;
+  let record_length = 1 + lengths.iter().sum::<usize>();
+  let (year, month, day) = date::utc_date(SystemTime::now());
+  dbf.write_all(&[0x03, (year - 1900) as u8, month as u8, day as u8])?;
+  dbf.write_all(&(rows.len() as u32).to_le_bytes())?;
+  dbf.write_all(&(header_length as u16).to_le_bytes())?;
+  dbf.write_all(&(record_length as u16).to_le_bytes())?;
+  dbf.write_all(&[0u8; 20])?;
+  for (mapping, length) in mappings.iter().zip(&lengths) {
+    let mut descriptor = [0u8; 32];
+    descriptor[..mapping.field.len()].copy_from_slice(mapping.field.as_bytes());
+    descriptor[11] = b'C';
+    descriptor[16] = *length as u8;
+    dbf.write_all(&descriptor)?;
+  }
+  dbf.write_all(&[0x0D])?;
+
+  for row in &rows {
+    dbf.write_all(b" ")?;
+    for ((value, length), mapping) in row.iter().zip(&lengths).zip(mappings.iter_mut()) {
+      let written = truncate(value, *length);
+      if written.len() < value.len() {
+        mapping.truncated_values += 1;
+      }
+      dbf.write_all(written.as_bytes())?;
+      dbf.write_all(&vec![b' '; length - written.len()])?;
+    }
+  }
+  dbf.write_all(&[0x1A])?;
+  dbf.flush()?;
+  Ok(mappings)
+}
+
+fn field_value(feature: &Feature, property: &str) -> String {
+  if property == "gml_id" {
+    return feature.id.clone().unwrap_or_default();
+  }
+  if let Some(geometry) = feature.other_geometries.iter().find(|(k, _)| k == property).map(|(_, g)| g) {
+    return feature_geometry_to_wkt(geometry);
+  }
+  let values: Vec<&str> = feature.properties.iter().filter(|(k, _)| k == property).map(|(_, v)| v.as_str()).collect();
+  match values.len() {
+    0 => String::new(),
+    1 => String::from(values[0]),
+    _ => json::stringify(values),
+  }
+}
+
+/// Unieke DBF-veldnamen: alleen ASCII letters, cijfers en `_`, maximaal 10 tekens, bij botsingen genummerd.
+fn field_names(properties: &[&str]) -> Vec<String> {
+  let mut used = HashSet::new();
+  properties.iter().map(|property| {
+    let sanitized: String = property.chars()
+      .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+      .collect();
+    let mut name: String = sanitized.chars().take(MAX_FIELD_NAME_LENGTH).collect();
+    let mut n = 1;
+    while !used.insert(name.to_ascii_uppercase()) {
+      let suffix = format!("_{}", n);
+      name = format!("{}{}", sanitized.chars().take(MAX_FIELD_NAME_LENGTH - suffix.len()).collect::<String>(), suffix);
+      n += 1;
+    }
+    name
+  }).collect()
+}
+
+/// Kapt een tekst af op `max` bytes, zonder een UTF-8 teken doormidden te knippen.
+fn truncate(value: &str, max: usize) -> &str {
+  if value.len() <= max {
+    return value;
+  }
+  let mut end = max;
+  while !value.is_char_boundary(end) {
+    end -= 1;
+  }
+  &value[..end]
+}
+
+fn csv_field(value: &str) -> String {
+  if value.contains(',') || value.contains('"') {
+    format!("\"{}\"", value.replace('"', "\"\""))
+  } else {
+    String::from(value)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Positief tegen de klok in, negatief met de klok mee.
+  fn signed_area(ring: &[Point]) -> f64 {
+    ring.windows(2).map(|w| w[0].x * w[1].y - w[1].x * w[0].y).sum::<f64>() / 2.0
+  }
+
+  fn square(min: f64, max: f64, counter_clockwise: bool) -> Vec<Point> {
+    let mut ring = vec![Point::new(min, min), Point::new(max, min), Point::new(max, max), Point::new(min, max), Point::new(min, min)];
+    if !counter_clockwise {
+      ring.reverse();
+    }
+    ring
+  }
+
+  #[test]
+  fn writes_exteriors_clockwise_and_holes_counter_clockwise_whatever_the_input_winding() {
+    let ogc = Polygon::new(square(0.0, 10.0, true), vec![square(2.0, 4.0, false)]);
+    let reversed = Polygon::new(square(20.0, 30.0, false), vec![square(22.0, 24.0, true)]);
+    let rings = shape_rings(&[ogc, reversed]);
+    assert_eq!(rings.len(), 4);
+    for (ring, exterior) in rings.iter().zip([true, false, true, false]) {
+      assert_eq!(ring.first(), ring.last());
+      assert_eq!(signed_area(ring) < 0.0, exterior, "{:?}", ring);
+    }
+  }
+}
@@ -0,0 +1,109 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use geo::BooleanOps;
+
+use crate::geometry::{Geometry, Point, Polygon};
+
+
+/// Standaard maximale oppervlakte van één download request, in m² (1000 km²).
+pub const DEFAULT_MAX_AREA: f64 = 1_000_000_000.0;
+
+/// Stukjes die door afrondingen bij het knippen ontstaan worden overgeslagen.
+const MIN_TILE_AREA: f64 = 0.001;
+
+/// Verdeelt een (RD New) gebied in tegels van hoogstens `max_area` m², elk een eigen geofilter.
+///
+/// Een gebied dat al klein genoeg is wordt ongewijzigd teruggegeven. Anders wordt de omhullende rechthoek in een raster
+/// van gelijke, zo vierkant mogelijke cellen verdeeld en wordt het gebied per cel bijgesneden; lege cellen vallen af.
+pub fn split(geometry: &Geometry, max_area: f64) -> Vec<Geometry> {
+  if geometry.area() <= max_area {
+    return vec![geometry.clone()];
+  }
+  let (minx, miny, maxx, maxy) = geometry.bounds();
+  let side = max_area.sqrt();
+  let columns = ((maxx - minx) / side).ceil().max(1.0) as usize;
+  let rows = ((maxy - miny) / side).ceil().max(1.0) as usize;
+  let width = (maxx - minx) / columns as f64;
+  let height = (maxy - miny) / rows as f64;
+
+  let area = geo::MultiPolygon::from(geometry);
+  let mut tiles = Vec::new();
+  for row in 0..rows {
+    for column in 0..columns {
+      let x0 = minx + column as f64 * width;
+      let y0 = miny + row as f64 * height;
+      // Grenzen van naastgelegen cellen exact gelijk houden, zodat er geen kieren ontstaan.
+      let x1 = if column + 1 == columns { maxx } else { minx + (column + 1) as f64 * width };
+      let y1 = if row + 1 == rows { maxy } else { miny + (row + 1) as f64 * height };
+      let cell = Polygon::new(vec![
+        Point::new(x0, y0), Point::new(x1, y0), Point::new(x1, y1), Point::new(x0, y1), Point::new(x0, y0),
+      ], Vec::new());
+      let cell = geo::MultiPolygon::new(vec![geo::Polygon::from(&cell)]);
+
+      let pieces: Vec<Polygon> = area.intersection(&cell).iter()
+        .map(Polygon::from)
+        .filter(|polygon| Geometry::Polygon(polygon.clone()).area() >= MIN_TILE_AREA)
+        .collect();
+      if let Some(tile) = Geometry::from_polygons(pieces) {
+        tiles.push(tile);
+      }
+    }
+  }
+  tiles
+}
+
+#[cfg(test)]
+mod tests {
+  use geo::{Area, BooleanOps};
+
+  use super::*;
+
+  fn rectangle(minx: f64, miny: f64, maxx: f64, maxy: f64) -> Polygon {
+    Polygon::new(vec![
+      Point::new(minx, miny), Point::new(maxx, miny), Point::new(maxx, maxy), Point::new(minx, maxy), Point::new(minx, miny),
+    ], Vec::new())
+  }
+
+  /// Oppervlakte van wat in precies één van beide ligt.
+  fn difference_area(a: &geo::MultiPolygon<f64>, b: &geo::MultiPolygon<f64>) -> f64 {
+    a.xor(b).unsigned_area()
+  }
+
+  #[test]
+  fn keeps_small_areas_whole() {
+    let area = Geometry::Polygon(rectangle(0.0, 0.0, 100.0, 100.0));
+    assert_eq!(split(&area, DEFAULT_MAX_AREA), vec![area]);
+  }
+
+  #[test]
+  fn splits_into_a_grid_of_tiles_no_larger_than_the_maximum() {
+    let area = Geometry::Polygon(rectangle(155000.0, 463000.0, 158000.0, 465000.0));
+    let tiles = split(&area, 1_000_000.0);
+    // 3 km bij 2 km in cellen van hoogstens 1 km².
+    assert_eq!(tiles.len(), 6);
+    assert!(tiles.iter().all(|tile| tile.area() <= 1_000_000.0 + 1e-6));
+    let total: f64 = tiles.iter().map(Geometry::area).sum();
+    assert!((total - area.area()).abs() < 1e-3, "{}", total);
+  }
+
+  #[test]
+  fn tiles_cover_the_area_exactly() {
+    // Een L-vorm met een gat; cellen die buiten de L vallen worden geen tegel.
+    let exterior = vec![
+      Point::new(0.0, 0.0), Point::new(3000.0, 0.0), Point::new(3000.0, 1000.0), Point::new(1000.0, 1000.0),
+      Point::new(1000.0, 3000.0), Point::new(0.0, 3000.0), Point::new(0.0, 0.0),
+    ];
+    let hole = vec![Point::new(200.0, 200.0), Point::new(200.0, 400.0), Point::new(400.0, 400.0), Point::new(400.0, 200.0), Point::new(200.0, 200.0)];
+    let area = Geometry::Polygon(Polygon::new(exterior, vec![hole]));
+    let tiles = split(&area, 1_000_000.0);
+    assert_eq!(tiles.len(), 5);
+
+    let union = tiles.iter().fold(geo::MultiPolygon::new(Vec::new()), |union, tile| union.union(&geo::MultiPolygon::from(tile)));
+    assert!(difference_area(&union, &geo::MultiPolygon::from(&area)) < 1e-3);
+    let total: f64 = tiles.iter().map(Geometry::area).sum();
+    assert!((total - area.area()).abs() < 1e-3, "tegels overlappen: {}", total);
+  }
+}
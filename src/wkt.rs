@@ -0,0 +1,237 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+use std::error::Error;
+
+use crate::error::WktParseError;
+use crate::geometry::{FeatureGeometry, Geometry, Point, Polygon};
+
+
+/// Leest een `POLYGON` of `MULTIPOLYGON` in Well-Known Text (WKT).
+pub fn parse(wkt: &str) -> Result<Geometry, WktParseError> {
+  let mut parser = Parser { chars: wkt.chars().collect(), pos: 0 };
+  let keyword_pos = parser.skip_whitespace();
+  let keyword = parser.keyword();
+  let geometry = match keyword.to_ascii_uppercase().as_str() {
+    "POLYGON" => Geometry::Polygon(parser.polygon()?),
+    "MULTIPOLYGON" => {
+      parser.expect('(')?;
+      let mut polygons = vec![parser.polygon()?];
+      while parser.accept(',') {
+        polygons.push(parser.polygon()?);
+      }
+      parser.expect(')')?;
+      Geometry::MultiPolygon(polygons)
+    },
+    "" => return Err(WktParseError::new("verwachtte POLYGON of MULTIPOLYGON", keyword_pos)),
+    other => return Err(WktParseError::new(format!("'{}' wordt niet ondersteund, verwachtte POLYGON of MULTIPOLYGON", other), keyword_pos)),
+  };
+  let end = parser.skip_whitespace();
+  if end < parser.chars.len() {
+    return Err(WktParseError::new(format!("onverwacht '{}' na het einde van de geometrie", parser.chars[end]), end));
+  }
+  Ok(geometry)
+}
+
+/// Leest een WKT polygon en controleert of het als geofilter gebruikt kan worden, zie `Geometry::validate`.
+pub fn parse_area(wkt: &str) -> Result<Geometry, Box<dyn Error + Send + Sync>> {
+  let geometry = parse(wkt)?;
+  geometry.validate()?;
+  Ok(geometry)
+}
+
+/// Schrijft een geometrie als WKT.
+pub fn to_wkt(geometry: &Geometry) -> String {
+  match geometry {
+    Geometry::Polygon(polygon) => format!("POLYGON{}", polygon_text(polygon)),
+    Geometry::MultiPolygon(polygons) => {
+      let parts: Vec<String> = polygons.iter().map(polygon_text).collect();
+      format!("MULTIPOLYGON({})", parts.join(","))
+    },
+  }
+}
+
+/// Schrijft een feature geometrie als WKT.
+pub fn feature_geometry_to_wkt(geometry: &FeatureGeometry) -> String {
+  match geometry {
+    FeatureGeometry::Point(point) => format!("POINT({})", point),
+    FeatureGeometry::LineString(points) => format!("LINESTRING{}", points_text(points)),
+    FeatureGeometry::Polygon(polygon) => format!("POLYGON{}", polygon_text(polygon)),
+    FeatureGeometry::MultiPoint(points) => format!("MULTIPOINT{}", points_text(points)),
+    FeatureGeometry::MultiLineString(lines) => {
+      let parts: Vec<String> = lines.iter().map(|line| points_text(line)).collect();
+      format!("MULTILINESTRING({})", parts.join(","))
+    },
+    FeatureGeometry::MultiPolygon(polygons) => {
+      let parts: Vec<String> = polygons.iter().map(polygon_text).collect();
+      format!("MULTIPOLYGON({})", parts.join(","))
+    },
+  }
+}
+
+fn points_text(points: &[Point]) -> String {
+  let points: Vec<String> = points.iter().map(|p| p.to_string()).collect();
+  format!("({})", points.join(","))
+}
+
+fn polygon_text(polygon: &Polygon) -> String {
+  let rings: Vec<String> = polygon.rings().map(|ring| points_text(ring)).collect();
+  format!("({})", rings.join(","))
+}
+
+struct Parser {
+  chars: Vec<char>,
+  pos: usize,
+}
+
+impl Parser {
+  fn skip_whitespace(&mut self) -> usize {
+    while self.pos < self.chars.len() && self.chars[self.pos].is_whitespace() {
+      self.pos += 1;
+    }
+    self.pos
+  }
+
+  fn keyword(&mut self) -> String {
+    let start = self.pos;
+    while self.pos < self.chars.len() && self.chars[self.pos].is_ascii_alphabetic() {
+      self.pos += 1;
+    }
+    self.chars[start..self.pos].iter().collect()
+  }
+
+  fn describe_current(&self) -> String {
+    match self.chars.get(self.pos) {
+      Some(c) => format!("'{}'", c),
+      None => String::from("het einde van de tekst"),
+    }
+  }
+
+  fn accept(&mut self, c: char) -> bool {
+    self.skip_whitespace();
+    if self.chars.get(self.pos) == Some(&c) {
+      self.pos += 1;
+      true
+    } else {
+      false
+    }
+  }
+
+  fn expect(&mut self, c: char) -> Result<(), WktParseError> {
+    if self.accept(c) {
+      Ok(())
+    } else {
+      Err(WktParseError::new(format!("verwachtte '{}' maar vond {}", c, self.describe_current()), self.pos))
+    }
+  }
+
+  fn polygon(&mut self) -> Result<Polygon, WktParseError> {
+    self.skip_whitespace();
+    let empty_pos = self.pos;
+    if self.keyword().eq_ignore_ascii_case("EMPTY") {
+      return Err(WktParseError::new("een leeg polygon kan niet als geofilter dienen", empty_pos));
+    }
+    self.pos = empty_pos;
+    self.expect('(')?;
+    let exterior = self.ring()?;
+    let mut interiors = Vec::new();
+    while self.accept(',') {
+      interiors.push(self.ring()?);
+    }
+    self.expect(')')?;
+    Ok(Polygon::new(exterior, interiors))
+  }
+
+  fn ring(&mut self) -> Result<Vec<Point>, WktParseError> {
+    self.expect('(')?;
+    let mut points = vec![self.point()?];
+    while self.accept(',') {
+      points.push(self.point()?);
+    }
+    self.expect(')')?;
+    Ok(points)
+  }
+
+  fn point(&mut self) -> Result<Point, WktParseError> {
+    let x = self.number()?;
+    let y = self.number()?;
+    self.skip_whitespace();
+    if let Some(c) = self.chars.get(self.pos) {
+      if *c != ',' && *c != ')' {
+        return Err(WktParseError::new(
+          format!("verwachtte ',' of ')' na punt ({} {}) maar vond '{}'; alleen 2D coördinaten worden ondersteund", x, y, c), self.pos));
+      }
+    }
+    Ok(Point::new(x, y))
+  }
+
+  fn number(&mut self) -> Result<f64, WktParseError> {
+    let start = self.skip_whitespace();
+    while self.pos < self.chars.len() && (self.chars[self.pos].is_ascii_digit() || "+-.eE".contains(self.chars[self.pos])) {
+      self.pos += 1;
+    }
+    let text: String = self.chars[start..self.pos].iter().collect();
+    if text.is_empty() {
+      return Err(WktParseError::new(format!("verwachtte een getal maar vond {}", self.describe_current()), start));
+    }
+    text.parse().map_err(|_| WktParseError::new(format!("'{}' is geen geldig getal", text), start))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  const SQUARE: &str = "POLYGON((155000 463000,155020 463000,155020 463020,155000 463020,155000 463000))";
+
+  fn error_of(wkt: &str) -> WktParseError {
+    parse(wkt).expect_err(wkt)
+  }
+
+  #[test]
+  fn parses_polygons_with_holes() {
+    let geometry = parse("polygon (( 0 0, 10 0, 10 10, 0 10, 0 0 ), (2 2, 2 4, 4 4, 4 2, 2 2))").unwrap();
+    let polygon = &geometry.polygons()[0];
+    assert_eq!(polygon.exterior.len(), 5);
+    assert_eq!(polygon.interiors.len(), 1);
+    assert_eq!(polygon.interiors[0][1], Point::new(2.0, 4.0));
+  }
+
+  #[test]
+  fn parses_multipolygons_and_numbers_in_any_notation() {
+    let geometry = parse("MULTIPOLYGON(((0 0,1e1 0,10 10,0 10,0 0)),((20 0,30.5 0,30.5 -10,20 0)))").unwrap();
+    assert_eq!(geometry.polygons().len(), 2);
+    assert_eq!(geometry.polygons()[0].exterior[1], Point::new(10.0, 0.0));
+    assert_eq!(geometry.polygons()[1].exterior[2], Point::new(30.5, -10.0));
+  }
+
+  #[test]
+  fn writes_what_it_reads() {
+    assert_eq!(to_wkt(&parse(SQUARE).unwrap()), SQUARE);
+  }
+
+  #[test]
+  fn rejects_malformed_wkt_with_the_position_of_the_error() {
+    assert_eq!(error_of("").position(), 0);
+    assert_eq!(error_of("   ").position(), 3);
+    assert_eq!(error_of("POINT(1 2)").position(), 0);
+    assert_eq!(error_of("POLYGON EMPTY").position(), 8);
+    assert_eq!(error_of("POLYGON(0 0,1 0,1 1,0 0)").position(), 8);
+    // Ring zonder afsluitend haakje.
+    assert_eq!(error_of("POLYGON((0 0,1 0,1 1,0 0)").position(), 25);
+    assert_eq!(error_of("POLYGON((0 0 5,1 0 5,1 1 5,0 0 5))").position(), 13);
+    assert_eq!(error_of("POLYGON((0 0,1 x,1 1,0 0))").position(), 15);
+    assert_eq!(error_of("POLYGON((0 0,1 0,1 1,0 0)) extra").position(), 27);
+    assert_eq!(error_of("POLYGON((0 0,1-2 0,1 1,0 0))").position(), 13);
+  }
+
+  #[test]
+  fn parse_area_also_validates() {
+    assert!(parse_area(SQUARE).is_ok());
+    // Niet gesloten.
+    let error = parse_area("POLYGON((155000 463000,155020 463000,155020 463020,155000 463020))").unwrap_err();
+    assert!(error.to_string().contains("niet gesloten"), "{}", error);
+  }
+}
@@ -0,0 +1,908 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+mod common;
+
+use std::convert::TryInto;
+use std::fs;
+use std::io::{Cursor, Read, Write};
+use std::path::Path;
+use std::process::{Command, Output};
+use std::time::Duration;
+
+use zip::write::FileOptions;
+use zip::{CompressionMethod, ZipWriter};
+
+use dkkdownload::checksum::Checksum;
+use dkkdownload::manifest::manifest_path;
+
+use common::{sample_zip, temp_dir, MockPdok, Reply};
+
+
+const POLYGON: &str = "POLYGON((155000 463000,155020 463000,155020 463010,155000 463010,155000 463000))";
+
+/// Draait dkkdownload tegen de mock, zonder configuratiebestand of omgevingsvariabelen van de gebruiker.
+///
+/// Elke mock begint weer bij `req-1`; met een eigen TMPDIR, cache en geschiedenis per mock zitten de tijdelijke
+/// ZIP-bestanden en runs van tests die tegelijk draaien elkaar niet in de weg.
+fn dkkdownload(mock: &MockPdok, args: &[&str]) -> Output {
+  command(mock).args(args).output().unwrap()
+}
+
+/// Het commando van `dkkdownload`, zonder eigen argumenten.
+fn command(mock: &MockPdok) -> Command {
+  let tmp = mock.temp_dir();
+  fs::create_dir_all(&tmp).unwrap();
+  let mut command = Command::new(env!("CARGO_BIN_EXE_dkkdownload"));
+  command
+    .arg("--base-url").arg(mock.url())
+    .arg("--retries").arg("1")
+    .env_remove("DKKDOWNLOAD_BASE_URL")
+    .env_remove("DKKDOWNLOAD_API_VERSION")
+    .env_remove("DKKDOWNLOAD_CONFIG")
+    .env("XDG_CONFIG_HOME", temp_dir("cli-config"))
+    .env_remove("DKKDOWNLOAD_HISTORY_FILE")
+    .env("XDG_CACHE_HOME", tmp.join("cache"))
+    .env("XDG_DATA_HOME", tmp.join("data"))
+    .env("TMPDIR", tmp);
+  command
+}
+
+fn stderr(output: &Output) -> String {
+  String::from_utf8_lossy(&output.stderr).into_owned()
+}
+
+#[test]
+fn downloads_zip_to_file() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Pending(Some(50)), Reply::Ready]);
+  let dir = temp_dir("cli-zip");
+  let out = dir.join("dkk.zip");
+
+  let output = dkkdownload(&mock, &["-p", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(fs::read(&out).unwrap(), sample_zip());
+  let log = stderr(&output);
+  assert!(log.contains("downloadRequestId: req-1"), "{}", log);
+  assert!(log.contains("PDOK API is bezig met processen"), "{}", log);
+  assert!(log.contains("ZIP bestand downloaden"), "{}", log);
+
+  let body = json::parse(&mock.requests_to("/full/custom")[0].body).unwrap();
+  assert_eq!(body["featuretypes"], json::array!["perceel"]);
+  assert_eq!(body["geofilter"], POLYGON);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn writes_zip_to_stdout() {
+  let mock = MockPdok::start(sample_zip());
+  let output = dkkdownload(&mock, &[POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(output.stdout, sample_zip());
+}
+
+/// De inhoud van het bestand `name` in een ZIP-bestand.
+fn zip_entry(zip: &[u8], name: &str) -> String {
+  let mut archive = zip::ZipArchive::new(Cursor::new(zip)).unwrap();
+  let mut text = String::new();
+  archive.by_name(name).unwrap().read_to_string(&mut text).unwrap();
+  text
+}
+
+#[test]
+fn writes_merged_tiles_to_stdout() {
+  let mock = MockPdok::start(sample_zip());
+  let output = dkkdownload(&mock, &["--max-area", "0.0001", POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(mock.requests_to("/full/custom").len(), 2);
+  // Beide tegels leveren dezelfde twee percelen; samengevoegd staat elk er één keer in.
+  let gml = zip_entry(&output.stdout, "kadastralekaartv5_perceel.gml");
+  assert_eq!(gml.matches("gml:id=\"p1\"").count(), 1, "{}", gml);
+  assert_eq!(gml.matches("gml:id=\"p2\"").count(), 1, "{}", gml);
+}
+
+/// Tijdelijke bestanden van dkkdownload in de TMPDIR van `mock`.
+fn temp_files(mock: &MockPdok) -> Vec<String> {
+  fs::read_dir(mock.temp_dir()).unwrap()
+    .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+    .filter(|name| name.starts_with("dkkdownload"))
+    .collect()
+}
+
+#[test]
+fn removes_temporary_zip_files_when_a_download_fails() {
+  let mock = MockPdok::start(sample_zip());
+  // De eerste tegel lukt, de tweede niet.
+  mock.on_download(vec![Reply::Zip, Reply::Truncated(100), Reply::Truncated(100)]);
+  let output = dkkdownload(&mock, &["--no-cache", "--max-area", "0.0001", POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+  assert!(output.stdout.is_empty());
+  assert_eq!(temp_files(&mock), Vec::<String>::new());
+
+  let output = dkkdownload(&mock, &["--no-cache", "--max-area", "0.0001", POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(temp_files(&mock), Vec::<String>::new());
+}
+
+#[test]
+fn resolves_layer_aliases() {
+  let mock = MockPdok::start(sample_zip());
+  let output = dkkdownload(&mock, &[POLYGON, "percelen", "Perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let body = json::parse(&mock.requests_to("/full/custom")[0].body).unwrap();
+  assert_eq!(body["featuretypes"], json::array!["perceel"]);
+}
+
+#[test]
+fn reads_geojson_without_crs_as_wgs84() {
+  let mock = MockPdok::start(sample_zip());
+  // Rond de Onze Lieve Vrouwetoren in Amersfoort, in lengte- en breedtegraden zoals QGIS het exporteert.
+  let wgs84 = r#"{"type":"Polygon","coordinates":[[[5.3872,52.1551],[5.3875,52.1551],[5.3875,52.1553],[5.3872,52.1553],[5.3872,52.1551]]]}"#;
+  let output = dkkdownload(&mock, &[wgs84, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let body = json::parse(&mock.requests_to("/full/custom")[0].body).unwrap();
+  assert!(body["geofilter"].as_str().unwrap().starts_with("POLYGON((154999.76 462991.956,155020.29 462991.956,"), "{}", body["geofilter"]);
+
+  // RD-coördinaten zonder crs: een melding over het ontbrekende crs, niet over RD New.
+  let rd = r#"{"type":"Polygon","coordinates":[[[155000,463000],[155020,463000],[155020,463010],[155000,463010],[155000,463000]]]}"#;
+  let output = dkkdownload(&mock, &[rd, "perceel"]);
+  assert_eq!(output.status.code(), Some(2));
+  assert!(stderr(&output).contains("GeoJSON zonder crs is volgens RFC 7946 in WGS84"), "{}", stderr(&output));
+  let output = dkkdownload(&mock, &["--input-crs", "EPSG:28992", rd, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let body = json::parse(&mock.requests_to("/full/custom")[1].body).unwrap();
+  assert_eq!(body["geofilter"], POLYGON);
+}
+
+#[test]
+fn rejects_unknown_layers_without_contacting_the_api() {
+  let mock = MockPdok::start(sample_zip());
+  let output = dkkdownload(&mock, &[POLYGON, "percel"]);
+  assert_eq!(output.status.code(), Some(2));
+  assert!(stderr(&output).contains("Onbekende laag 'percel'"), "{}", stderr(&output));
+  assert!(mock.requests().is_empty());
+}
+
+#[test]
+fn converts_to_geojson() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-geojson");
+
+  let output = dkkdownload(&mock, &["--format", "geojson", "-o", dir.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let geojson = json::parse(&fs::read_to_string(dir.join("perceel.geojson")).unwrap()).unwrap();
+  let ids: Vec<&str> = geojson["features"].members().filter_map(|f| f["id"].as_str()).collect();
+  assert_eq!(ids, vec!["p1", "p2"]);
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn downloads_large_areas_in_tiles() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-tiled");
+  let out = dir.join("dkk.zip");
+
+  let output = dkkdownload(&mock, &["--log-format", "json", "--max-area", "0.0001", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let events = events(&output);
+  let tiles = events.iter().find(|e| e["event"] == "tiles").unwrap();
+  assert_eq!(tiles["count"], 2);
+  let submitted: Vec<&str> = events.iter().filter(|e| e["event"] == "submitted").filter_map(|e| e["request_id"].as_str()).collect();
+  assert_eq!(submitted, ["req-1", "req-2"]);
+  let merged = events.iter().find(|e| e["event"] == "merged").unwrap();
+  assert_eq!((merged["layer"].as_str(), merged["features"].as_usize(), merged["duplicates"].as_usize()), (Some("kadastralekaartv5_perceel.gml"), Some(2), Some(2)));
+
+  // Elke tegel is een helft van het gebied.
+  let geofilters: Vec<String> = mock.requests_to("/full/custom").iter()
+    .map(|request| json::parse(&request.body).unwrap()["geofilter"].as_str().unwrap().to_string())
+    .collect();
+  assert_eq!(geofilters.len(), 2);
+  assert!(geofilters.iter().all(|geofilter| geofilter.contains("155010")), "{:?}", geofilters);
+  let gml = zip_entry(&fs::read(&out).unwrap(), "kadastralekaartv5_perceel.gml");
+  assert_eq!(gml.matches("<gml:featureMember>").count(), 2, "{}", gml);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn converts_tiled_downloads() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-tiled-geojson");
+
+  // Het gebied is 200 m²; met tegels van hoogstens 100 m² worden het twee requests.
+  let output = dkkdownload(&mock, &["--max-area", "0.0001", "--format", "geojson", "-o", dir.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(mock.requests_to("/full/custom").len(), 2);
+  let geojson = json::parse(&fs::read_to_string(dir.join("perceel.geojson")).unwrap()).unwrap();
+  let ids: Vec<&str> = geojson["features"].members().filter_map(|f| f["id"].as_str()).collect();
+  assert_eq!(ids, vec!["p1", "p2"]);
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn clips_to_the_area() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-clip");
+  // Alleen het linker perceel ligt helemaal binnen dit gebied.
+  let area = "POLYGON((155000 463000,155012 463000,155012 463010,155000 463010,155000 463000))";
+
+  let output = dkkdownload(&mock, &["--format", "geojson", "--clip", "within", "-o", dir.to_str().unwrap(), area, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let geojson = json::parse(&fs::read_to_string(dir.join("perceel.geojson")).unwrap()).unwrap();
+  assert_eq!(geojson["features"].len(), 1);
+  assert_eq!(geojson["features"][0]["id"], "p1");
+  assert!(stderr(&output).contains("perceel: 1 features binnen het gebied, 0 bijgesneden, 1 weggelaten"), "{}", stderr(&output));
+  assert!(stderr(&output).contains("  weggelaten: NL.IMKAD.KadastraalObject.2\n"), "{}", stderr(&output));
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn cuts_features_to_the_area() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-clip-cut");
+  let area = "POLYGON((155000 463000,155012 463000,155012 463010,155000 463010,155000 463000))";
+
+  let output = dkkdownload(&mock, &["--log-format", "json", "--format", "geojson", "--clip", "cut", "-o", dir.to_str().unwrap(), area, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let clipped = events(&output).into_iter().find(|e| e["event"] == "clipped").unwrap();
+  assert_eq!(clipped["clipped_ids"], json::array!["NL.IMKAD.KadastraalObject.2"]);
+  assert_eq!(clipped["dropped_ids"], json::array![]);
+  let geojson = json::parse(&fs::read_to_string(dir.join("perceel.geojson")).unwrap()).unwrap();
+  assert_eq!(geojson["features"].len(), 2);
+  // Het rechter perceel (155010-155020) is op de rand van het gebied afgesneden.
+  let p2 = geojson["features"].members().find(|f| f["id"] == "p2").unwrap();
+  let xs: Vec<f64> = p2["geometry"]["coordinates"][0].members().map(|c| c[0].as_f64().unwrap()).collect();
+  assert_eq!(xs.iter().cloned().fold(f64::MIN, f64::max), 155012.0);
+  assert_eq!(xs.iter().cloned().fold(f64::MAX, f64::min), 155010.0);
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn converts_to_geopackage() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-gpkg");
+  let out = dir.join("dkk.gpkg");
+
+  let output = dkkdownload(&mock, &["--format", "gpkg", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let connection = rusqlite::Connection::open(&out).unwrap();
+  let ids: Vec<String> = connection.prepare("SELECT gml_id FROM perceel ORDER BY fid").unwrap()
+    .query_map([], |row| row.get(0)).unwrap().map(Result::unwrap).collect();
+  assert_eq!(ids, ["p1", "p2"]);
+  let (min_x, max_x, srs_id): (f64, f64, i32) = connection
+    .query_row("SELECT min_x, max_x, srs_id FROM gpkg_contents WHERE table_name = 'perceel'", [], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+    .unwrap();
+  assert_eq!((min_x, max_x, srs_id), (155000.0, 155020.0, 28992));
+  let geometry_type: String = connection
+    .query_row("SELECT geometry_type_name FROM gpkg_geometry_columns WHERE table_name = 'perceel'", [], |row| row.get(0))
+    .unwrap();
+  assert_eq!(geometry_type, "POLYGON");
+  let indexed: i64 = connection.query_row("SELECT count(*) FROM rtree_perceel_geom", [], |row| row.get(0)).unwrap();
+  assert_eq!(indexed, 2);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn converts_to_shapefiles() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-shp");
+
+  let output = dkkdownload(&mock, &["--format", "shp", "-o", dir.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let shp = fs::read(dir.join("perceel.shp")).unwrap();
+  let le_f64 = |at: usize| f64::from_le_bytes(shp[at..at + 8].try_into().unwrap());
+  assert_eq!(i32::from_be_bytes(shp[0..4].try_into().unwrap()), 9994);
+  assert_eq!(i32::from_be_bytes(shp[24..28].try_into().unwrap()) as usize * 2, shp.len());
+  assert_eq!(i32::from_le_bytes(shp[32..36].try_into().unwrap()), 5);
+  assert_eq!((le_f64(36), le_f64(44), le_f64(52), le_f64(60)), (155000.0, 463000.0, 155020.0, 463010.0));
+  // Twee records in de index, na de header van 100 bytes.
+  assert_eq!(fs::read(dir.join("perceel.shx")).unwrap().len(), 100 + 2 * 8);
+
+  let dbf = fs::read(dir.join("perceel.dbf")).unwrap();
+  assert_eq!(u32::from_le_bytes(dbf[4..8].try_into().unwrap()), 2);
+  let records = String::from_utf8_lossy(&dbf);
+  assert!(records.contains("p1") && records.contains("p2") && records.contains("101") && records.contains("102"));
+  assert!(fs::read_to_string(dir.join("perceel.prj")).unwrap().contains("Amersfoort"));
+  assert!(fs::read_to_string(dir.join("perceel_velden.csv")).unwrap().starts_with("veld,eigenschap,afgekapte_waarden\n"));
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn converts_to_dxf() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-dxf");
+  let out = dir.join("dkk.dxf");
+
+  let output = dkkdownload(&mock, &["--format", "dxf", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let dxf = fs::read_to_string(&out).unwrap();
+  let lines: Vec<&str> = dxf.lines().map(str::trim).collect();
+  let pairs: Vec<(&str, &str)> = lines.chunks(2).map(|pair| (pair[0], pair[1])).collect();
+  assert_eq!(pairs.last(), Some(&("0", "EOF")));
+  let layers: Vec<&str> = pairs.windows(2).filter(|w| w[0] == ("0", "LAYER")).map(|w| w[1].1).collect();
+  assert_eq!(layers, ["PERCEEL", "PERCEELNUMMER"]);
+  assert_eq!(pairs.iter().filter(|pair| **pair == ("0", "POLYLINE")).count(), 2);
+  let texts: Vec<&str> = pairs.iter().filter(|pair| pair.0 == "1").map(|pair| pair.1).collect();
+  assert_eq!(texts, ["AC1009", "101", "102"]);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn fails_on_client_errors() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_submit(vec![Reply::Error(400)]);
+  let output = dkkdownload(&mock, &[POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(4));
+  assert!(stderr(&output).contains("400"), "{}", stderr(&output));
+  assert_eq!(mock.requests_to("/full/custom").len(), 1);
+}
+
+#[test]
+fn exit_codes_distinguish_failures() {
+  let mock = MockPdok::start(sample_zip());
+  let code = |args: &[&str]| dkkdownload(&mock, args).status.code();
+
+  // Ongeldige invoer, ook van clap zelf.
+  assert_eq!(code(&["POLYGON((0 0,1 0,0 0))", "perceel"]), Some(2));
+  assert_eq!(code(&["--format", "pdf", POLYGON, "perceel"]), Some(2));
+  assert_eq!(code(&["--retries", "veel", POLYGON, "perceel"]), Some(2));
+
+  mock.on_submit(vec![Reply::Error(503)]);
+  assert_eq!(code(&[POLYGON, "perceel"]), Some(3));
+
+  mock.on_status(vec![Reply::Failed]);
+  assert_eq!(code(&[POLYGON, "perceel"]), Some(5));
+
+  mock.on_submit(vec![Reply::Raw(202, "{\"downloadRequestId\":42}")]);
+  let output = dkkdownload(&mock, &[POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(5));
+  assert!(!stderr(&output).contains("panicked"), "{}", stderr(&output));
+
+  assert_eq!(code(&["-o", "/dev/null/dkk.zip", POLYGON, "perceel"]), Some(6));
+  assert_eq!(code(&["list-layers"]), Some(0));
+}
+
+#[test]
+fn reports_unreachable_api_as_network_error() {
+  let output = Command::new(env!("CARGO_BIN_EXE_dkkdownload"))
+    .args(["--base-url", "http://127.0.0.1:9", "--retries", "0", "--no-cache", "--no-history", POLYGON, "perceel"])
+    .env("XDG_CONFIG_HOME", temp_dir("cli-config"))
+    .output()
+    .unwrap();
+  assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+}
+
+#[test]
+fn resumes_an_earlier_request() {
+  let mock = MockPdok::start(sample_zip());
+  let output = dkkdownload(&mock, &["--resume", "abc"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(output.stdout, sample_zip());
+  assert!(mock.requests_to("/full/custom").is_empty());
+  assert_eq!(mock.requests_to("/full/custom/abc/status").len(), 1);
+}
+
+fn events(output: &Output) -> Vec<json::JsonValue> {
+  stderr(output).lines().map(|line| json::parse(line).unwrap_or_else(|e| panic!("geen JSON: {:?} ({})", line, e))).collect()
+}
+
+#[test]
+fn logs_json_events() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Pending(Some(40)), Reply::Ready]);
+  let dir = temp_dir("cli-events");
+  let out = dir.join("dkk.zip");
+
+  let output = dkkdownload(&mock, &["--log-format", "json", "-p", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let events = events(&output);
+  let names: Vec<&str> = events.iter().filter_map(|e| e["event"].as_str()).collect();
+  assert_eq!(names.first(), Some(&"submitted"));
+  assert_eq!(names.last(), Some(&"completed"));
+  assert!(names.contains(&"download_progress"), "{:?}", names);
+
+  assert_eq!(events[0]["request_id"], "req-1");
+  assert_eq!(events[0]["kind"], "full");
+  assert_eq!(events[1]["event"], "progress");
+  assert_eq!(events[1]["percent"], 40);
+  let ready = events.iter().find(|e| e["event"] == "ready").unwrap();
+  assert!(ready["download_url"].as_str().unwrap().starts_with(&mock.url()));
+
+  let zip = sample_zip();
+  let completed = events.last().unwrap();
+  assert_eq!(completed["path"], out.to_str().unwrap());
+  assert_eq!(completed["size"], zip.len());
+  assert_eq!(completed["sha256"], Checksum::of_bytes(&zip).sha256.as_str());
+  let last_progress = events.iter().rev().find(|e| e["event"] == "download_progress").unwrap();
+  assert_eq!(last_progress["bytes"], zip.len());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn logs_checksum_of_zip_on_stdout() {
+  let mock = MockPdok::start(sample_zip());
+  let output = dkkdownload(&mock, &["--log-format", "json", POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(output.stdout, sample_zip());
+  let completed = events(&output).pop().unwrap();
+  assert!(completed["path"].is_null());
+  assert_eq!(completed["sha256"], Checksum::of_bytes(&sample_zip()).sha256.as_str());
+}
+
+#[test]
+fn logs_json_errors() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_submit(vec![Reply::Error(400)]);
+  let output = dkkdownload(&mock, &["--log-format", "json", POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(4));
+  let error = events(&output).pop().unwrap();
+  assert_eq!(error["event"], "error");
+  assert_eq!(error["kind"], "rejected");
+  assert_eq!(error["exit_code"], 4);
+  assert_eq!(error["status"], 400);
+  assert!(error["message"].as_str().unwrap().contains("mock error"), "{}", error);
+}
+
+#[test]
+fn logs_extracted_files_and_checksum_when_extracting_while_downloading() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-events-extract");
+  let output = dkkdownload(&mock, &["--log-format", "json", "--extract", dir.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let events = events(&output);
+  let extracted = events.iter().find(|e| e["event"] == "extracted").unwrap();
+  assert_eq!(extracted["name"], "kadastralekaartv5_perceel.gml");
+  let completed = events.last().unwrap();
+  assert_eq!(completed["path"], dir.to_str().unwrap());
+  assert_eq!(completed["sha256"], Checksum::of_bytes(&sample_zip()).sha256.as_str());
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn refuses_to_overwrite_without_force() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-exists");
+  let out = dir.join("dkk.zip");
+  fs::write(&out, "vorige download").unwrap();
+
+  let output = dkkdownload(&mock, &["-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(2));
+  assert!(stderr(&output).contains("--force"), "{}", stderr(&output));
+  assert!(mock.requests().is_empty());
+  assert_eq!(fs::read_to_string(&out).unwrap(), "vorige download");
+
+  let output = dkkdownload(&mock, &["--force", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(fs::read(&out).unwrap(), sample_zip());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn keeps_previous_output_when_download_fails() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_download(vec![Reply::Truncated(100), Reply::Truncated(100)]);
+  let dir = temp_dir("cli-keep");
+  let out = dir.join("dkk.zip");
+  fs::write(&out, "vorige download").unwrap();
+
+  let output = dkkdownload(&mock, &["--force", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(3), "{}", stderr(&output));
+  assert_eq!(fs::read_to_string(&out).unwrap(), "vorige download");
+  // Wat binnen is (twee keer 100 bytes, de tweede keer hervat) blijft staan om later te hervatten.
+  assert_eq!(fs::metadata(dkkdownload::part_path(&out)).unwrap().len(), 200);
+  assert!(dkkdownload::part_info_path(&out).exists());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_damaged_zip_files() {
+  let mock = MockPdok::start(b"PK\x03\x04 dit is geen ZIP-bestand".to_vec());
+  let dir = temp_dir("cli-damaged");
+  let out = dir.join("dkk.zip");
+
+  let output = dkkdownload(&mock, &["-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+  assert!(stderr(&output).contains("beschadigd"), "{}", stderr(&output));
+  assert!(!out.exists());
+  assert!(!dkkdownload::part_path(&out).exists());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn writes_directory_formats_atomically() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-dir-exists");
+  fs::write(dir.join("perceel.geojson"), "vorige").unwrap();
+
+  let output = dkkdownload(&mock, &["--format", "geojson", "-o", dir.to_str().unwrap(), POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+  assert_eq!(fs::read_to_string(dir.join("perceel.geojson")).unwrap(), "vorige");
+  // Geen tijdelijke map of bestanden achtergelaten.
+  assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+  let output = dkkdownload(&mock, &["--force", "--format", "geojson", "-o", dir.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert!(fs::read_to_string(dir.join("perceel.geojson")).unwrap().contains("\"p1\""));
+  assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn refuses_to_overwrite_extracted_files_without_force() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-extract-exists");
+  fs::write(dir.join("perceel.gml"), "vorige").unwrap();
+  let args = ["--extract", dir.to_str().unwrap(), "--rename-layers", POLYGON, "perceel"];
+
+  let output = dkkdownload(&mock, &args);
+  assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+  assert_eq!(fs::read_to_string(dir.join("perceel.gml")).unwrap(), "vorige");
+
+  let output = dkkdownload(&mock, &[&["--force"], &args[..]].concat());
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert!(fs::read_to_string(dir.join("perceel.gml")).unwrap().contains("p1"));
+  fs::remove_file(manifest_path(&dir)).unwrap();
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn rejects_zip_files_with_a_wrong_crc() {
+  // Ongecomprimeerd, zodat een omgevallen bit in de data alleen aan de CRC te zien is.
+  let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+  zip.start_file("kadastralekaartv5_perceel.gml", FileOptions::default().compression_method(CompressionMethod::Stored)).unwrap();
+  zip.write_all(b"<gml:FeatureCollection>p1</gml:FeatureCollection>").unwrap();
+  let mut damaged = zip.finish().unwrap().into_inner();
+  let at = damaged.windows(2).position(|w| w == b"p1").unwrap();
+  damaged[at + 1] = b'9';
+
+  let mock = MockPdok::start(damaged);
+  let dir = temp_dir("cli-crc");
+  let out = dir.join("dkk.zip");
+  let output = dkkdownload(&mock, &["-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+  assert!(stderr(&output).contains("CRC klopt niet"), "{}", stderr(&output));
+  assert!(!out.exists());
+
+  // Ook naar stdout komt niets van een beschadigd ZIP-bestand.
+  let output = dkkdownload(&mock, &[POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(5), "{}", stderr(&output));
+  assert!(output.stdout.is_empty());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn writes_a_manifest_next_to_the_output() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-manifest");
+  let out = dir.join("dkk.zip");
+
+  let output = dkkdownload(&mock, &["-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let manifest = json::parse(&fs::read_to_string(dir.join("dkk.zip.manifest.json")).unwrap()).unwrap();
+  assert_eq!(manifest["requests"][0]["request_id"], "req-1");
+  assert_eq!(manifest["requests"][0]["kind"], "full");
+  assert!(manifest["requests"][0]["download_url"].as_str().unwrap().starts_with(&mock.url()));
+  let submitted_at = manifest["requests"][0]["submitted_at"].as_str().unwrap();
+  assert!(submitted_at.len() == 20 && submitted_at.ends_with('Z') && &submitted_at[10..11] == "T", "{}", submitted_at);
+  assert!(manifest["completed_at"].as_str().unwrap() >= submitted_at);
+  assert_eq!(manifest["polygon"], POLYGON);
+  assert_eq!(manifest["feature_types"], json::array!["perceel"]);
+  assert_eq!(manifest["output"]["format"], "zip");
+  assert_eq!(manifest["zip"]["sha256"], Checksum::of_bytes(&sample_zip()).sha256.as_str());
+  assert_eq!(manifest["entries"][0]["name"], "kadastralekaartv5_perceel.gml");
+  assert!(manifest["entries"][0]["size"].as_u64().unwrap() > 0);
+
+  // Een bestaand manifest wordt net als de uitvoer alleen met --force overschreven.
+  fs::remove_file(&out).unwrap();
+  let output = dkkdownload(&mock, &["-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+
+  let output = dkkdownload(&mock, &["--force", "--resume", "abc", "-o", out.to_str().unwrap()]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let manifest = json::parse(&fs::read_to_string(dir.join("dkk.zip.manifest.json")).unwrap()).unwrap();
+  assert_eq!(manifest["requests"][0]["request_id"], "abc");
+  assert!(manifest["requests"][0]["submitted_at"].is_null());
+  assert!(manifest["polygon"].is_null());
+  assert_eq!(manifest["feature_types"], json::array!["perceel"]);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+fn feature(id: &str, name: &str, x: u32) -> String {
+  format!(r#"{{"type":"Feature","properties":{{"name":"{}"}},"id":"{}","geometry":{{"type":"Polygon",
+    "coordinates":[[[{x},463000],[{x2},463000],[{x2},463010],[{x},463010],[{x},463000]]]}}}}"#, name, id, x = x, x2 = x + 10)
+}
+
+#[test]
+fn batch_downloads_each_feature_to_its_own_file() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Pending(None), Reply::Pending(None)]);
+  let dir = temp_dir("cli-batch");
+  let input = dir.join("gebieden.geojson");
+  fs::write(&input, format!(r#"{{"type":"FeatureCollection","crs":{{"type":"name","properties":{{"name":"EPSG:28992"}}}},"features":[{},{},{}]}}"#,
+    feature("1", "Noord", 155000), feature("2", "zuid/west", 155100), feature("3", "oost", 155200))).unwrap();
+  let out = dir.join("uit");
+
+  let output = dkkdownload(&mock, &["batch", input.to_str().unwrap(), "perceel", "-o", out.to_str().unwrap(), "--concurrency", "2"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  for name in ["Noord", "zuid_west", "oost"] {
+    assert_eq!(fs::read(out.join(format!("{}.zip", name))).unwrap(), sample_zip());
+    assert!(out.join(format!("{}.zip.manifest.json", name)).exists());
+  }
+  let manifest = json::parse(&fs::read_to_string(out.join("oost.zip.manifest.json")).unwrap()).unwrap();
+  assert_eq!(manifest["polygon"], "POLYGON((155200 463000,155210 463000,155210 463010,155200 463010,155200 463000))");
+
+  // Het derde gebied wordt pas ingediend als een van de eerste twee klaar is.
+  let requests = mock.requests();
+  let submits: Vec<usize> = requests.iter().enumerate().filter(|(_, r)| r.method == "POST").map(|(i, _)| i).collect();
+  let first_download = requests.iter().position(|r| r.path.ends_with("/download")).unwrap();
+  assert_eq!(submits.len(), 3);
+  assert!(submits[1] < first_download && first_download < submits[2], "{:?}", requests);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn batch_keeps_polling_while_an_area_downloads() {
+  let mock = MockPdok::start(sample_zip());
+  // Het eerste gebied is meteen klaar maar downloadt traag; het tweede komt pas bij de volgende ronde.
+  mock.on_status(vec![Reply::Ready, Reply::Pending(None), Reply::Ready]);
+  mock.on_download(vec![Reply::Delay(Duration::from_millis(2500), Box::new(Reply::Zip))]);
+  let dir = temp_dir("cli-batch-parallel");
+  let input = dir.join("gebieden.csv");
+  fs::write(&input, format!("traag,{}
+snel,{}
+", POLYGON, POLYGON)).unwrap();
+
+  let output = dkkdownload(&mock, &["--log-format", "json", "batch", input.to_str().unwrap(), "perceel", "-o", dir.to_str().unwrap()]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let completed: Vec<String> = events(&output).iter()
+    .filter(|e| e["event"] == "area_completed")
+    .map(|e| e["name"].to_string())
+    .collect();
+  assert_eq!(completed, vec!["snel", "traag"]);
+  for name in ["traag", "snel"] {
+    assert_eq!(fs::read(dir.join(format!("{}.zip", name))).unwrap(), sample_zip());
+  }
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn batch_reads_csv() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-batch-csv");
+  let input = dir.join("gebieden.csv");
+  fs::write(&input, format!("naam,wkt\n\"project 1\",\"{}\"\n# overgeslagen\nproject 2,155000,463000,155010,463010\n", POLYGON)).unwrap();
+
+  let output = dkkdownload(&mock, &["--log-format", "json", "batch", input.to_str().unwrap(), "perceel", "-o", dir.to_str().unwrap()]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert!(dir.join("project_1.zip").exists());
+  assert!(dir.join("project_2.zip").exists());
+  let completed: Vec<String> = events(&output).iter()
+    .filter(|e| e["event"] == "area_completed")
+    .map(|e| e["name"].to_string())
+    .collect();
+  assert_eq!(completed, vec!["project 1", "project 2"]);
+  let body = json::parse(&mock.requests_to("/full/custom")[0].body).unwrap();
+  assert_eq!(body["geofilter"], POLYGON);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn batch_continues_after_a_failed_area() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_submit(vec![Reply::Error(400), Reply::Accepted]);
+  let dir = temp_dir("cli-batch-failed");
+  let input = dir.join("gebieden.csv");
+  fs::write(&input, format!("mislukt,{}\ngelukt,{}\n", POLYGON, POLYGON)).unwrap();
+
+  let output = dkkdownload(&mock, &["batch", input.to_str().unwrap(), "perceel", "-o", dir.to_str().unwrap(), "--concurrency", "1"]);
+  assert_eq!(output.status.code(), Some(7), "{}", stderr(&output));
+  assert!(stderr(&output).contains("1 van 2 gebieden mislukt: mislukt"), "{}", stderr(&output));
+  assert!(dir.join("gelukt.zip").exists());
+  assert!(!dir.join("mislukt.zip").exists());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn batch_checks_all_areas_before_submitting() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-batch-invalid");
+  let input = dir.join("gebieden.csv");
+  fs::write(&input, format!("goed,{}\nfout,POLYGON((0 0,1 0,0 0))\ngoed,{}\n", POLYGON, POLYGON)).unwrap();
+
+  let output = dkkdownload(&mock, &["batch", input.to_str().unwrap(), "perceel", "-o", dir.to_str().unwrap()]);
+  assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+  assert!(stderr(&output).contains("goed.zip"), "{}", stderr(&output));
+  assert!(mock.requests().is_empty());
+
+  fs::write(&input, format!("goed,{}\nfout,POLYGON((0 0,1 0,0 0))\n", POLYGON)).unwrap();
+  let output = dkkdownload(&mock, &["batch", input.to_str().unwrap(), "perceel", "-o", dir.to_str().unwrap()]);
+  assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+  assert!(stderr(&output).contains("gebied 'fout'"), "{}", stderr(&output));
+  assert!(mock.requests().is_empty());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reuses_cached_downloads_of_the_same_area_and_layers() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-cache");
+  let out = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+  let output = dkkdownload(&mock, &["-o", &out("1.zip"), POLYGON, "perceel", "pand"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  // Zelfde gebied met een ander beginpunt, en de lagen in een andere volgorde.
+  let same_area = "POLYGON((155020 463010,155000 463010,155000 463000,155020 463000,155020 463010))";
+  let output = dkkdownload(&mock, &["--log-format", "json", "-o", &out("2.zip"), same_area, "pand", "percelen"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(mock.requests_to("/full/custom").len(), 1);
+  assert_eq!(fs::read(dir.join("2.zip")).unwrap(), sample_zip());
+  let hit = events(&output).into_iter().find(|e| e["event"] == "cache_hit").unwrap();
+  assert_eq!(hit["request_ids"], json::array!["req-1"]);
+  let manifest = json::parse(&fs::read_to_string(dir.join("2.zip.manifest.json")).unwrap()).unwrap();
+  assert_eq!(manifest["requests"][0]["request_id"], "req-1");
+  assert!(manifest["cached_at"].is_string());
+
+  // Andere lagen, --no-cache of een verlopen ZIP-bestand: een nieuw request.
+  dkkdownload(&mock, &["-o", &out("3.zip"), POLYGON, "perceel"]);
+  dkkdownload(&mock, &["--no-cache", "-o", &out("4.zip"), POLYGON, "perceel", "pand"]);
+  dkkdownload(&mock, &["--cache-ttl", "0", "-o", &out("5.zip"), POLYGON, "perceel", "pand"]);
+  assert_eq!(mock.requests_to("/full/custom").len(), 4);
+
+  // Dezelfde cache met een andere API: ook een nieuw request, bij die API.
+  let other = MockPdok::start(sample_zip());
+  let output = command(&other)
+    .args(["-o", &out("6.zip"), POLYGON, "perceel", "pand"])
+    .env("XDG_CACHE_HOME", mock.temp_dir().join("cache"))
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert_eq!(mock.requests_to("/full/custom").len(), 4);
+  assert_eq!(other.requests_to("/full/custom").len(), 1);
+  let list = String::from_utf8_lossy(&dkkdownload(&mock, &["cache", "list"]).stdout).into_owned();
+  assert!(list.contains(&format!("{}/", mock.url())) && list.contains(&format!("{}/", other.url())), "{}", list);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn lists_and_purges_the_cache() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-cache-purge");
+  let output = dkkdownload(&mock, &["-o", dir.join("dkk.zip").to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+
+  let output = dkkdownload(&mock, &["cache", "list"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let list = String::from_utf8_lossy(&output.stdout).into_owned();
+  assert!(list.contains("geldig") && list.contains("perceel") && list.contains("req-1"), "{}", list);
+  assert!(list.contains(POLYGON), "{}", list);
+  assert!(list.contains(&format!("{}/", mock.url())), "{}", list);
+
+  // Nog niet verlopen, dus zonder --all blijft het staan.
+  let output = dkkdownload(&mock, &["cache", "purge"]);
+  assert!(stderr(&output).starts_with("0 "), "{}", stderr(&output));
+  let output = dkkdownload(&mock, &["cache", "purge", "--all"]);
+  assert!(stderr(&output).starts_with("1 "), "{}", stderr(&output));
+  let output = dkkdownload(&mock, &["cache", "list"]);
+  assert!(output.stdout.is_empty());
+  assert!(stderr(&output).contains("leeg"), "{}", stderr(&output));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+/// Id van de laatste run in `history list`.
+fn last_run_id(mock: &MockPdok) -> String {
+  let output = dkkdownload(mock, &["history", "list", "-n", "1"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap().to_string()
+}
+
+#[test]
+fn records_runs_in_the_history() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Pending(Some(50)), Reply::Pending(Some(50)), Reply::Ready]);
+  let dir = temp_dir("cli-history");
+  let out = dir.join("dkk.zip");
+
+  let output = dkkdownload(&mock, &["-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let id = last_run_id(&mock);
+  let output = dkkdownload(&mock, &[POLYGON, "geen-laag"]);
+  assert_eq!(output.status.code(), Some(2));
+  dkkdownload(&mock, &["--no-history", POLYGON, "perceel"]);
+
+  let output = dkkdownload(&mock, &["history", "list"]);
+  let list = String::from_utf8_lossy(&output.stdout).into_owned();
+  assert!(list.starts_with(&id), "{}", list);
+  assert_eq!(list.matches("geslaagd").count(), 1, "{}", list);
+  assert_eq!(list.matches("mislukt (2)").count(), 1, "{}", list);
+  assert!(list.contains("downloadRequestId: req-1") && list.contains(out.to_str().unwrap()), "{}", list);
+  assert!(list.contains("geen-laag"), "{}", list);
+
+  let output = dkkdownload(&mock, &["history", "show", &id[..4], "--json"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let records: Vec<json::JsonValue> = String::from_utf8_lossy(&output.stdout).lines().map(|line| json::parse(line).unwrap()).collect();
+  let kinds: Vec<&str> = records.iter().map(|record| record["event"].as_str().unwrap()).collect();
+  // Dezelfde voortgang twee keer is één statusovergang.
+  assert_eq!(kinds, ["started", "area", "submitted", "progress", "ready", "completed", "finished"]);
+  assert!(records.iter().all(|record| record["run"] == id.as_str() && record["time"].is_string()));
+  assert_eq!(records[1]["geofilter"], POLYGON);
+  assert_eq!(records[1]["feature_types"], json::array!["perceel"]);
+  assert!(records[4]["download_url"].is_string());
+  assert_eq!(records[5]["path"], out.to_str().unwrap());
+  assert_eq!(records[6]["exit_code"], 0);
+
+  let output = dkkdownload(&mock, &["history", "show", "onbekend"]);
+  assert_eq!(output.status.code(), Some(2));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reruns_a_job_from_the_history() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-history-rerun");
+  let out = dir.join("dkk.zip");
+  let output = dkkdownload(&mock, &["--no-cache", "-o", out.to_str().unwrap(), POLYGON, "perceel"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let id = last_run_id(&mock);
+
+  // De uitvoer bestaat al; zonder --force wordt er niets ingediend.
+  let output = dkkdownload(&mock, &["history", "rerun", &id]);
+  assert_eq!(output.status.code(), Some(2), "{}", stderr(&output));
+  let output = dkkdownload(&mock, &["history", "rerun", &id, "--force"]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  assert!(stderr(&output).contains("Opnieuw: dkkdownload"), "{}", stderr(&output));
+  assert_eq!(mock.requests_to("/full/custom").len(), 2);
+  assert_eq!(fs::read(&out).unwrap(), sample_zip());
+
+  let output = dkkdownload(&mock, &["history", "show", &last_run_id(&mock)]);
+  let show = String::from_utf8_lossy(&output.stdout).into_owned();
+  assert!(show.contains(&format!("Herhaalt:  {}", id)), "{}", show);
+  assert!(show.contains("--force") && show.contains("request_id=req-2"), "{}", show);
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn reruns_a_rerun_in_its_own_directory() {
+  let mock = MockPdok::start(sample_zip());
+  let dir = temp_dir("cli-history-rerun-twice");
+  let history = dir.join("history.jsonl");
+  let history = history.to_str().unwrap();
+  // Een relatief uitvoerpad, vanuit `dir`; de herhalingen draaien vanuit een andere map.
+  let output = command(&mock)
+    .args(["--history-file", history, "--force", "--no-cache", "-o", "dkk.zip", POLYGON, "perceel"])
+    .current_dir(&dir)
+    .output()
+    .unwrap();
+  assert!(output.status.success(), "{}", stderr(&output));
+  let last_run_id = || {
+    let output = dkkdownload(&mock, &["history", "list", "-n", "1", "--history-file", history]);
+    String::from_utf8_lossy(&output.stdout).split_whitespace().next().unwrap().to_string()
+  };
+  let first = last_run_id();
+
+  // --force en --history-file staan al in de opdracht; ook een herhaling van een herhaling geeft ze niet dubbel.
+  let output = dkkdownload(&mock, &["history", "rerun", &first, "--force", "--history-file", history]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let second = last_run_id();
+  assert_ne!(second, first);
+  let output = dkkdownload(&mock, &["history", "rerun", &second, "--force", "--history-file", history]);
+  assert!(output.status.success(), "{}", stderr(&output));
+  let third = last_run_id();
+  assert_ne!(third, second);
+
+  assert_eq!(mock.requests_to("/full/custom").len(), 3);
+  assert_eq!(fs::read(dir.join("dkk.zip")).unwrap(), sample_zip());
+  assert!(!Path::new("dkk.zip").exists());
+  let output = dkkdownload(&mock, &["history", "show", &third, "--history-file", history]);
+  let show = String::from_utf8_lossy(&output.stdout).into_owned();
+  assert!(show.contains(&format!("Herhaalt:  {}", second)), "{}", show);
+  assert!(show.contains("request_id=req-3"), "{}", show);
+  fs::remove_dir_all(dir).unwrap();
+}
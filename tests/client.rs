@@ -0,0 +1,274 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+mod common;
+
+use std::fs;
+use std::time::{Duration, Instant};
+
+use dkkdownload::{DkkClient, DkkError, DownloadRequest, DownloadStatus, RetryPolicy, UnexpectedStatusCodeError};
+
+use common::{sample_zip, temp_dir, MockPdok, Reply};
+
+
+const GEOFILTER: &str = "POLYGON((155000 463000,155020 463000,155020 463010,155000 463010,155000 463000))";
+
+fn client(mock: &MockPdok) -> DkkClient {
+  let retry = RetryPolicy { max_retries: 3, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(5) };
+  DkkClient::new().with_root_url(&mock.url()).unwrap().with_retry_policy(retry)
+}
+
+#[test]
+fn submits_featuretypes_and_geofilter() {
+  let mock = MockPdok::start(sample_zip());
+  let request = client(&mock).submit_custom_request(&["perceel", "pand"], GEOFILTER).unwrap();
+  assert_eq!(request, DownloadRequest::full("req-1"));
+
+  let submits = mock.requests_to("/full/custom");
+  assert_eq!(submits.len(), 1);
+  let body = json::parse(&submits[0].body).unwrap();
+  assert_eq!(body["featuretypes"], json::array!["perceel", "pand"]);
+  assert_eq!(body["geofilter"], GEOFILTER);
+  assert_eq!(body["format"], "gml");
+  assert_eq!(submits[0].header("content-type"), Some("application/json"));
+}
+
+#[test]
+fn submits_delta_requests() {
+  let mock = MockPdok::start(sample_zip());
+  let request = client(&mock).submit_delta_request(&["perceel"], GEOFILTER, "delta-7").unwrap();
+  assert_eq!(request, DownloadRequest::delta("req-1"));
+  let body = json::parse(&mock.requests_to("/delta/custom")[0].body).unwrap();
+  assert_eq!(body["deltaid"], "delta-7");
+}
+
+#[test]
+fn reports_progress_until_ready() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Pending(None), Reply::Pending(Some(40)), Reply::Pending(Some(90)), Reply::Ready]);
+
+  let mut progress = Vec::new();
+  let url = client(&mock)
+    .wait_until_ready(&DownloadRequest::full("abc"), Duration::from_millis(1), |p| progress.push(p))
+    .unwrap();
+  assert_eq!(progress, vec![None, Some(40), Some(90)]);
+  assert_eq!(url, format!("{}/kadaster/kadastralekaart/download/v5_0/full/custom/abc/download", mock.url()));
+  assert_eq!(mock.requests_to("/full/custom/abc/status").len(), 4);
+}
+
+#[test]
+fn resolves_download_links_under_a_base_path() {
+  let mock = MockPdok::start(sample_zip());
+  let client = client(&mock).with_root_url(&format!("{}/proxy/", mock.url())).unwrap();
+  let url = client.wait_until_ready(&DownloadRequest::full("abc"), Duration::from_millis(1), |_| {}).unwrap();
+  assert_eq!(url, format!("{}/proxy/kadaster/kadastralekaart/download/v5_0/full/custom/abc/download", mock.url()));
+  assert!(mock.requests()[0].path.starts_with("/proxy/kadaster/"));
+}
+
+#[test]
+fn retries_server_errors_and_throttling() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Error(503), Reply::Throttled, Reply::Error(502), Reply::Ready]);
+  let status = client(&mock).poll_status(&DownloadRequest::full("abc")).unwrap();
+  assert!(matches!(status, DownloadStatus::Ready { .. }), "{:?}", status);
+  assert_eq!(mock.requests_to("/full/custom/abc/status").len(), 4);
+}
+
+#[test]
+fn gives_up_after_max_retries() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Error(500); 10]);
+  let err = match client(&mock).poll_status(&DownloadRequest::full("abc")).unwrap_err() {
+    DkkError::Network(err) => err,
+    err => panic!("verwacht een netwerkfout, niet {:?}", err),
+  };
+  let err = err.downcast_ref::<UnexpectedStatusCodeError>().expect("UnexpectedStatusCodeError");
+  assert_eq!(err.status().as_u16(), 500);
+  assert_eq!(mock.requests_to("/status").len(), 4);
+}
+
+#[test]
+fn retries_submits_the_server_refused() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_submit(vec![Reply::Unavailable, Reply::Throttled, Reply::Accepted]);
+  let request = client(&mock).submit_custom_request(&["perceel"], GEOFILTER).unwrap();
+  assert_eq!(request.id, "req-1");
+  assert_eq!(mock.requests_to("/full/custom").len(), 3);
+}
+
+#[test]
+fn does_not_repeat_submits_that_may_have_been_processed() {
+  let mock = MockPdok::start(sample_zip());
+  // Zonder Retry-After kan de server het request al aangemaakt hebben, net als bij een time-out.
+  mock.on_submit(vec![Reply::Error(500), Reply::Error(503), Reply::Delay(Duration::from_millis(1500), Box::new(Reply::Accepted))]);
+  let client = client(&mock).with_timeout(Duration::from_millis(300)).unwrap();
+  for expected in 1..=3 {
+    let err = client.submit_custom_request(&["perceel"], GEOFILTER).unwrap_err();
+    assert!(matches!(err, DkkError::Network(_)), "{:?}", err);
+    assert_eq!(mock.requests_to("/full/custom").len(), expected);
+  }
+}
+
+#[test]
+fn does_not_retry_client_errors() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_submit(vec![Reply::Error(400), Reply::Accepted]);
+  match client(&mock).submit_custom_request(&["perceel"], GEOFILTER).unwrap_err() {
+    DkkError::Rejected(err) => assert_eq!(err.status().as_u16(), 400),
+    err => panic!("verwacht een geweigerd request, niet {:?}", err),
+  }
+  assert_eq!(mock.requests_to("/full/custom").len(), 1);
+}
+
+#[test]
+fn reports_failed_jobs() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Pending(Some(10)), Reply::Failed]);
+  let err = client(&mock).wait_until_ready(&DownloadRequest::full("abc"), Duration::from_millis(1), |_| {}).unwrap_err();
+  assert!(matches!(err, DkkError::JobFailed(_)), "{:?}", err);
+  assert!(err.to_string().contains("abc"), "{}", err);
+}
+
+#[test]
+fn rejects_malformed_responses_without_panicking() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_submit(vec![Reply::Raw(202, "{}"), Reply::Raw(202, "geen json")]);
+  mock.on_status(vec![Reply::Raw(201, "{\"_links\":{}}"), Reply::Raw(200, "{\"progress\":1000}"), Reply::Ready]);
+  let client = client(&mock);
+
+  for _ in 0..2 {
+    let err = client.submit_custom_request(&["perceel"], GEOFILTER).unwrap_err();
+    assert_eq!(err.exit_code(), 5, "{:?}", err);
+  }
+  let request = DownloadRequest::full("abc");
+  assert!(matches!(client.poll_status(&request), Err(DkkError::JobFailed(_))));
+  let mut progress = Vec::new();
+  client.wait_until_ready(&request, Duration::from_millis(1), |p| progress.push(p)).unwrap();
+  assert_eq!(progress, vec![Some(100)]);
+}
+
+#[test]
+fn reports_connection_failures_as_network_errors() {
+  // Poort 9 (discard) is lokaal niet in gebruik.
+  let retry = RetryPolicy { max_retries: 1, initial_backoff: Duration::from_millis(1), max_backoff: Duration::from_millis(1) };
+  let client = DkkClient::new().with_root_url("http://127.0.0.1:9").unwrap().with_retry_policy(retry);
+  let err = client.submit_custom_request(&["perceel"], GEOFILTER).unwrap_err();
+  assert!(matches!(err, DkkError::Network(_)), "{:?}", err);
+}
+
+#[test]
+fn retries_submits_that_could_not_connect() {
+  // Zonder verbinding heeft de server niets aangemaakt; alleen aan de wachttijd is te zien dat er herhaald is.
+  let retry = RetryPolicy { max_retries: 2, initial_backoff: Duration::from_millis(200), max_backoff: Duration::from_millis(200) };
+  let client = DkkClient::new().with_root_url("http://127.0.0.1:9").unwrap().with_retry_policy(retry);
+  let start = Instant::now();
+  assert!(client.submit_custom_request(&["perceel"], GEOFILTER).is_err());
+  assert!(start.elapsed() >= Duration::from_millis(200), "{:?}", start.elapsed());
+}
+
+#[test]
+fn retries_timeouts() {
+  let mock = MockPdok::start(sample_zip());
+  mock.on_status(vec![Reply::Delay(Duration::from_millis(1500), Box::new(Reply::Pending(None))), Reply::Ready]);
+  let client = client(&mock).with_timeout(Duration::from_millis(300)).unwrap();
+  let url = client.wait_until_ready(&DownloadRequest::full("abc"), Duration::from_millis(1), |_| {}).unwrap();
+  assert!(url.ends_with("/abc/download"));
+  assert_eq!(mock.requests_to("/status").len(), 2);
+}
+
+#[test]
+fn finds_the_latest_delta() {
+  let mock = MockPdok::start(sample_zip());
+  mock.with_deltas(&[("d1", "2023-10-01T00:00:00Z"), ("d3", "2023-10-03T00:00:00Z"), ("d2", "2023-10-02T00:00:00Z")]);
+  let latest = client(&mock).latest_delta().unwrap().unwrap();
+  assert_eq!(latest.id, "d3");
+}
+
+#[test]
+fn downloads_to_file_and_resumes_broken_connections() {
+  let zip = sample_zip();
+  let mock = MockPdok::start(zip.clone());
+  mock.on_download(vec![Reply::Truncated(100), Reply::Zip]);
+  let dir = temp_dir("resume");
+  let path = dir.join("dkk.zip");
+
+  let url = format!("{}/kadaster/kadastralekaart/download/v5_0/full/custom/abc/download", mock.url());
+  let mut last = (0, None);
+  let size = client(&mock).download_to_file(&DownloadRequest::full("abc"), &url, &path, |written, total| last = (written, total)).unwrap();
+
+  assert_eq!(size, zip.len() as u64);
+  assert_eq!(last, (zip.len() as u64, Some(zip.len() as u64)));
+  assert_eq!(fs::read(&path).unwrap(), zip);
+  assert!(!dkkdownload::part_path(&path).exists());
+  assert!(!dkkdownload::part_info_path(&path).exists());
+  let downloads = mock.requests_to("/download");
+  assert_eq!(downloads.len(), 2);
+  assert_eq!(downloads[1].header("range"), Some("bytes=100-"));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn restarts_when_the_range_is_not_satisfiable() {
+  let zip = sample_zip();
+  let mock = MockPdok::start(zip.clone());
+  let dir = temp_dir("range");
+  let path = dir.join("dkk.zip");
+  let url = format!("{}/kadaster/kadastralekaart/download/v5_0/full/custom/abc/download", mock.url());
+  // Een .part van een eerdere, grotere download.
+  write_part(&path, vec![0u8; zip.len() + 10], "abc", &url);
+
+  client(&mock).download_to_file(&DownloadRequest::full("abc"), &url, &path, |_, _| {}).unwrap();
+  assert_eq!(fs::read(&path).unwrap(), zip);
+  assert_eq!(mock.requests_to("/download")[0].header("range"), Some(&*format!("bytes={}-", zip.len() + 10)));
+  fs::remove_dir_all(dir).unwrap();
+}
+
+/// Een `.part` met de gegevens van de download waar hij van is, zoals een afgebroken run die achterlaat.
+fn write_part(path: &std::path::Path, contents: Vec<u8>, request_id: &str, download_url: &str) {
+  fs::write(dkkdownload::part_path(path), contents).unwrap();
+  let info = json::object!{ "request_id" => request_id, "download_url" => download_url };
+  fs::write(dkkdownload::part_info_path(path), info.dump()).unwrap();
+}
+
+#[test]
+fn does_not_resume_a_part_of_another_download() {
+  let zip = sample_zip();
+  let mock = MockPdok::start(zip.clone());
+  let dir = temp_dir("resume-other");
+  let url = format!("{}/kadaster/kadastralekaart/download/v5_0/full/custom/abc/download", mock.url());
+
+  // Van een ander request, en zonder gegevens.
+  let path = dir.join("ander.zip");
+  write_part(&path, zip[..100].to_vec(), "xyz", &url);
+  client(&mock).download_to_file(&DownloadRequest::full("abc"), &url, &path, |_, _| {}).unwrap();
+  assert_eq!(fs::read(&path).unwrap(), zip);
+  let path = dir.join("onbekend.zip");
+  fs::write(dkkdownload::part_path(&path), &zip[..100]).unwrap();
+  client(&mock).download_to_file(&DownloadRequest::full("abc"), &url, &path, |_, _| {}).unwrap();
+  assert_eq!(fs::read(&path).unwrap(), zip);
+
+  assert!(mock.requests_to("/download").iter().all(|request| request.header("range").is_none()));
+  assert!(!dkkdownload::part_info_path(&path).exists());
+  fs::remove_dir_all(dir).unwrap();
+}
+
+#[test]
+fn starts_over_when_a_resumed_file_is_damaged() {
+  let zip = sample_zip();
+  let mock = MockPdok::start(zip.clone());
+  let dir = temp_dir("resume-damaged");
+  let path = dir.join("dkk.zip");
+  let url = format!("{}/kadaster/kadastralekaart/download/v5_0/full/custom/abc/download", mock.url());
+  // Zelfde download, maar wat er staat klopt niet.
+  write_part(&path, vec![0u8; 100], "abc", &url);
+
+  client(&mock).download_to_file(&DownloadRequest::full("abc"), &url, &path, |_, _| {}).unwrap();
+  assert_eq!(fs::read(&path).unwrap(), zip);
+  let downloads = mock.requests_to("/download");
+  assert_eq!(downloads.len(), 2);
+  assert_eq!(downloads[0].header("range"), Some("bytes=100-"));
+  assert_eq!(downloads[1].header("range"), None);
+  fs::remove_dir_all(dir).unwrap();
+}
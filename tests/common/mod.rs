@@ -0,0 +1,330 @@
+/*
+ * Copyright (c) 2019-2023 Martijn Heil
+ * Alle rechten voorbehouden.
+ */
+
+//! Een in-process nabootsing van de PDOK DKK Download API, zodat de tests zonder netwerk draaien.
+
+// Niet elke testcrate gebruikt alle hulpfuncties.
+#![allow(dead_code)]
+
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use zip::write::FileOptions;
+use zip::ZipWriter;
+
+
+pub const API_PATH: &str = "/kadaster/kadastralekaart/download/v5_0";
+
+/// Een antwoord dat de mock op het volgende request naar een endpoint geeft.
+#[derive(Debug, Clone)]
+pub enum Reply {
+  /// 202 met een nieuw `downloadRequestId` (submit).
+  Accepted,
+  /// 200 met optioneel een `progress` (status).
+  Pending(Option<u64>),
+  /// 201 met een download link (status).
+  Ready,
+  /// 200 met een status die aangeeft dat het download request mislukt is (status).
+  Failed,
+  /// 200 of 206 met het ZIP-bestand, afhankelijk van de Range header (download).
+  Zip,
+  /// Als `Zip`, maar de verbinding wordt na dit aantal bytes verbroken.
+  Truncated(usize),
+  /// Een foutstatus met een korte JSON body.
+  Error(u16),
+  /// 429 met `Retry-After: 0`.
+  Throttled,
+  /// 503 met `Retry-After: 0`.
+  Unavailable,
+  /// Een willekeurige status en body, bijv. om onverwachte antwoorden na te bootsen.
+  Raw(u16, &'static str),
+  /// Wacht eerst, zodat het request van de client een timeout krijgt, en geeft daarna het antwoord.
+  Delay(Duration, Box<Reply>),
+}
+
+/// Een request zoals de mock het ontvangen heeft.
+#[derive(Debug, Clone)]
+pub struct Recorded {
+  pub method: String,
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  pub body: String,
+}
+
+impl Recorded {
+  pub fn header(&self, name: &str) -> Option<&str> {
+    self.headers.iter().find(|(k, _)| k.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+  }
+}
+
+#[derive(Default)]
+struct State {
+  submit: VecDeque<Reply>,
+  status: VecDeque<Reply>,
+  download: VecDeque<Reply>,
+  deltas: Vec<(String, String)>,
+  zip: Vec<u8>,
+  requests: Vec<Recorded>,
+  next_id: usize,
+}
+
+/// Mock van de DKK Download API op een willekeurige lokale poort.
+///
+/// Zonder ingestelde antwoorden wordt elk request ingediend (202), is het direct gereed (201) en wordt `zip` geserveerd.
+pub struct MockPdok {
+  addr: SocketAddr,
+  state: Arc<Mutex<State>>,
+  running: Arc<AtomicBool>,
+}
+
+impl MockPdok {
+  pub fn start(zip: Vec<u8>) -> MockPdok {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let state = Arc::new(Mutex::new(State { zip, ..State::default() }));
+    let running = Arc::new(AtomicBool::new(true));
+
+    let (thread_state, thread_running) = (state.clone(), running.clone());
+    thread::spawn(move || {
+      for stream in listener.incoming() {
+        if !thread_running.load(Ordering::SeqCst) {
+          break;
+        }
+        if let Ok(stream) = stream {
+          let state = thread_state.clone();
+          thread::spawn(move || handle(stream, &state));
+        }
+      }
+    });
+    let mock = MockPdok { addr, state, running };
+    let _ = std::fs::remove_dir_all(mock.temp_dir());
+    mock
+  }
+
+  /// Map voor de tijdelijke bestanden, cache en geschiedenis van runs tegen deze mock. Bij het starten wordt hij
+  /// leeggemaakt, zodat er niets in staat van een eerdere test die dezelfde poort had.
+  pub fn temp_dir(&self) -> std::path::PathBuf {
+    std::env::temp_dir().join(format!("dkkdownload-test-tmp-{}", self.addr.port()))
+  }
+
+  /// Root url om aan de client of `--base-url` mee te geven.
+  pub fn url(&self) -> String {
+    format!("http://{}", self.addr)
+  }
+
+  pub fn on_submit(&self, replies: Vec<Reply>) -> &Self {
+    self.state.lock().unwrap().submit.extend(replies);
+    self
+  }
+
+  pub fn on_status(&self, replies: Vec<Reply>) -> &Self {
+    self.state.lock().unwrap().status.extend(replies);
+    self
+  }
+
+  pub fn on_download(&self, replies: Vec<Reply>) -> &Self {
+    self.state.lock().unwrap().download.extend(replies);
+    self
+  }
+
+  /// Deltas (id, timeStamp) voor het `/delta` endpoint.
+  pub fn with_deltas(&self, deltas: &[(&str, &str)]) -> &Self {
+    self.state.lock().unwrap().deltas = deltas.iter().map(|(id, ts)| (String::from(*id), String::from(*ts))).collect();
+    self
+  }
+
+  pub fn requests(&self) -> Vec<Recorded> {
+    self.state.lock().unwrap().requests.clone()
+  }
+
+  /// Requests waarvan het pad (zonder query) op `suffix` eindigt.
+  pub fn requests_to(&self, suffix: &str) -> Vec<Recorded> {
+    self.requests().into_iter().filter(|r| r.path.split('?').next().unwrap().ends_with(suffix)).collect()
+  }
+}
+
+impl Drop for MockPdok {
+  fn drop(&mut self) {
+    self.running.store(false, Ordering::SeqCst);
+    // De accept-lus wakker maken zodat de thread stopt.
+    let _ = TcpStream::connect(self.addr);
+  }
+}
+
+fn handle(stream: TcpStream, state: &Mutex<State>) {
+  let mut reader = BufReader::new(stream.try_clone().unwrap());
+  let mut request_line = String::new();
+  if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+    return;
+  }
+  let mut parts = request_line.split_whitespace();
+  let method = String::from(parts.next().unwrap_or(""));
+  let path = String::from(parts.next().unwrap_or(""));
+  let mut headers = Vec::new();
+  loop {
+    let mut line = String::new();
+    if reader.read_line(&mut line).unwrap_or(0) == 0 || line.trim().is_empty() {
+      break;
+    }
+    if let Some((key, value)) = line.split_once(':') {
+      headers.push((String::from(key.trim()), String::from(value.trim())));
+    }
+  }
+  let length: usize = headers.iter()
+    .find(|(k, _)| k.eq_ignore_ascii_case("content-length"))
+    .and_then(|(_, v)| v.parse().ok())
+    .unwrap_or(0);
+  let mut body = vec![0u8; length];
+  let _ = reader.read_exact(&mut body);
+  let request = Recorded { method, path, headers, body: String::from_utf8_lossy(&body).into_owned() };
+
+  let (reply, zip, id) = {
+    let mut state = state.lock().unwrap();
+    state.requests.push(request.clone());
+    let route = request.path.find(API_PATH).map(|i| &request.path[i + API_PATH.len()..]).unwrap_or("");
+    let route = route.split('?').next().unwrap();
+    let queue = if request.method == "POST" && route.ends_with("/custom") {
+      Some(&mut state.submit)
+    } else if route.ends_with("/status") {
+      Some(&mut state.status)
+    } else if route.ends_with("/download") {
+      Some(&mut state.download)
+    } else {
+      None
+    };
+    let reply = match queue {
+      Some(queue) => queue.pop_front().or_else(|| Some(default_reply(route, &request.method))),
+      None => None,
+    };
+    if matches!(reply, Some(Reply::Accepted)) {
+      state.next_id += 1;
+    }
+    let id = format!("req-{}", state.next_id);
+    let reply = match (reply, route) {
+      (None, "/delta") => {
+        let deltas: Vec<String> = state.deltas.iter()
+          .map(|(id, ts)| format!("{{\"id\":\"{}\",\"timeStamp\":\"{}\"}}", id, ts))
+          .collect();
+        return respond(stream, 200, &[], format!("{{\"deltas\":[{}]}}", deltas.join(",")).as_bytes(), None);
+      },
+      (reply, _) => reply,
+    };
+    (reply, state.zip.clone(), id)
+  };
+
+  match reply {
+    Some(reply) => send_reply(stream, reply, &request, &zip, &id),
+    None => respond(stream, 404, &[], b"{\"message\":\"not found\"}", None),
+  }
+}
+
+fn default_reply(route: &str, method: &str) -> Reply {
+  if method == "POST" {
+    Reply::Accepted
+  } else if route.ends_with("/status") {
+    Reply::Ready
+  } else {
+    Reply::Zip
+  }
+}
+
+fn send_reply(stream: TcpStream, reply: Reply, request: &Recorded, zip: &[u8], id: &str) {
+  match reply {
+    Reply::Accepted => respond(stream, 202, &[], format!("{{\"downloadRequestId\":\"{}\"}}", id).as_bytes(), None),
+    Reply::Pending(progress) => {
+      let body = match progress {
+        Some(progress) => format!("{{\"status\":\"RUNNING\",\"progress\":{}}}", progress),
+        None => String::from("{\"status\":\"PENDING\"}"),
+      };
+      respond(stream, 200, &[], body.as_bytes(), None)
+    },
+    Reply::Ready => {
+      // Net als PDOK een pad zonder host; de client moet het tegen zijn base url oplossen.
+      let kind = if request.path.contains("/delta/") { "delta" } else { "full" };
+      let request_id = request.path.rsplit('/').nth(1).unwrap_or(id);
+      let href = format!("{}/{}/custom/{}/download", API_PATH, kind, request_id);
+      let body = format!("{{\"status\":\"COMPLETED\",\"progress\":100,\"_links\":{{\"download\":{{\"href\":\"{}\"}}}}}}", href);
+      respond(stream, 201, &[], body.as_bytes(), None)
+    },
+    Reply::Failed => respond(stream, 200, &[], b"{\"status\":\"FAILED\",\"progress\":30}", None),
+    Reply::Zip | Reply::Truncated(_) => {
+      let cut = match reply {
+        Reply::Truncated(n) => Some(n),
+        _ => None,
+      };
+      let start: Option<usize> = request.header("range")
+        .and_then(|range| range.strip_prefix("bytes="))
+        .and_then(|range| range.trim_end_matches('-').parse().ok());
+      match start {
+        Some(start) if start < zip.len() => {
+          let content_range = format!("bytes {}-{}/{}", start, zip.len() - 1, zip.len());
+          respond(stream, 206, &[("Content-Range", content_range.as_str())], &zip[start..], cut)
+        },
+        Some(_) => respond(stream, 416, &[], b"", None),
+        None => respond(stream, 200, &[], zip, cut),
+      }
+    },
+    Reply::Error(status) => respond(stream, status, &[], b"{\"message\":\"mock error\"}", None),
+    Reply::Throttled => respond(stream, 429, &[("Retry-After", "0")], b"{\"message\":\"too many requests\"}", None),
+    Reply::Unavailable => respond(stream, 503, &[("Retry-After", "0")], b"{\"message\":\"maintenance\"}", None),
+    Reply::Raw(status, body) => respond(stream, status, &[], body.as_bytes(), None),
+    Reply::Delay(duration, reply) => {
+      thread::sleep(duration);
+      send_reply(stream, *reply, request, zip, id)
+    },
+  }
+}
+
+/// Schrijft een antwoord. Met `cut` wordt de volledige Content-Length beloofd maar alleen zoveel bytes verstuurd.
+fn respond(mut stream: TcpStream, status: u16, headers: &[(&str, &str)], body: &[u8], cut: Option<usize>) {
+  let mut head = format!("HTTP/1.1 {} Mock\r\nContent-Length: {}\r\nConnection: close\r\n", status, body.len());
+  for (key, value) in headers {
+    head.push_str(&format!("{}: {}\r\n", key, value));
+  }
+  head.push_str("\r\n");
+  let _ = stream.write_all(head.as_bytes());
+  let _ = stream.write_all(&body[..cut.unwrap_or(body.len()).min(body.len())]);
+  let _ = stream.flush();
+}
+
+pub const PERCEEL_GML: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<gml:FeatureCollection xmlns:gml="http://www.opengis.net/gml/3.2" xmlns:kk="http://kadaster.nl/schemas/kadastralekaart">
+<gml:featureMember><kk:Perceel gml:id="p1">
+<kk:identificatie><kk:NEN3610ID><kk:namespace>NL.IMKAD.KadastraalObject</kk:namespace><kk:lokaalID>1</kk:lokaalID></kk:NEN3610ID></kk:identificatie>
+<kk:kadastraleAanduiding><kk:TypeKadastraleAanduiding><kk:sectie>A</kk:sectie><kk:perceelnummer>101</kk:perceelnummer></kk:TypeKadastraleAanduiding></kk:kadastraleAanduiding>
+<kk:perceelnummerRotatie>0</kk:perceelnummerRotatie>
+<kk:plaatscoordinaten><gml:Point><gml:pos>155005 463005</gml:pos></gml:Point></kk:plaatscoordinaten>
+<kk:begrenzingPerceel><gml:Polygon><gml:exterior><gml:LinearRing><gml:posList>155000 463000 155010 463000 155010 463010 155000 463010 155000 463000</gml:posList></gml:LinearRing></gml:exterior></gml:Polygon></kk:begrenzingPerceel>
+</kk:Perceel></gml:featureMember>
+<gml:featureMember><kk:Perceel gml:id="p2">
+<kk:identificatie><kk:NEN3610ID><kk:namespace>NL.IMKAD.KadastraalObject</kk:namespace><kk:lokaalID>2</kk:lokaalID></kk:NEN3610ID></kk:identificatie>
+<kk:kadastraleAanduiding><kk:TypeKadastraleAanduiding><kk:sectie>A</kk:sectie><kk:perceelnummer>102</kk:perceelnummer></kk:TypeKadastraleAanduiding></kk:kadastraleAanduiding>
+<kk:perceelnummerRotatie>0</kk:perceelnummerRotatie>
+<kk:plaatscoordinaten><gml:Point><gml:pos>155015 463005</gml:pos></gml:Point></kk:plaatscoordinaten>
+<kk:begrenzingPerceel><gml:Polygon><gml:exterior><gml:LinearRing><gml:posList>155010 463000 155020 463000 155020 463010 155010 463010 155010 463000</gml:posList></gml:LinearRing></gml:exterior></gml:Polygon></kk:begrenzingPerceel>
+</kk:Perceel></gml:featureMember>
+</gml:FeatureCollection>
+"#;
+
+/// Een ZIP-bestand zoals PDOK het levert, met een perceellaag van twee percelen.
+pub fn sample_zip() -> Vec<u8> {
+  let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+  zip.start_file("kadastralekaartv5_perceel.gml", FileOptions::default()).unwrap();
+  zip.write_all(PERCEEL_GML.as_bytes()).unwrap();
+  zip.finish().unwrap().into_inner()
+}
+
+/// Een unieke lege map voor de uitvoer van een test.
+pub fn temp_dir(name: &str) -> std::path::PathBuf {
+  let dir = std::env::temp_dir().join(format!("dkkdownload-test-{}-{}", name, std::process::id()));
+  let _ = std::fs::remove_dir_all(&dir);
+  std::fs::create_dir_all(&dir).unwrap();
+  dir
+}